@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS cached_files (
+    id SERIAL PRIMARY KEY,
+    object_id INTEGER NOT NULL,
+    object_type VARCHAR NOT NULL,
+    message_id BIGINT NOT NULL,
+    chat_id BIGINT NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS uc_cached_files_object_id_object_type
+    ON cached_files (object_id, object_type);
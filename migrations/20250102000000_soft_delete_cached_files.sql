@@ -0,0 +1,11 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+DROP INDEX IF EXISTS uc_cached_files_object_id_object_type;
+
+CREATE UNIQUE INDEX IF NOT EXISTS uc_cached_files_object_id_object_type
+    ON cached_files (object_id, object_type)
+    WHERE deleted_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS ix_cached_files_deleted_at
+    ON cached_files (deleted_at)
+    WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    actor VARCHAR NOT NULL,
+    action VARCHAR NOT NULL,
+    object_id INTEGER NOT NULL,
+    object_type VARCHAR NOT NULL,
+    cached_file_id INTEGER,
+    result VARCHAR NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS ix_audit_log_created_at ON audit_log (created_at);
+
+CREATE INDEX IF NOT EXISTS ix_audit_log_object_id_object_type
+    ON audit_log (object_id, object_type);
@@ -0,0 +1,11 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS title TEXT;
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS authors TEXT;
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS source_id INTEGER;
+
+CREATE INDEX IF NOT EXISTS ix_cached_files_title_trgm
+    ON cached_files USING GIN (title gin_trgm_ops);
+
+CREATE INDEX IF NOT EXISTS ix_cached_files_authors_trgm
+    ON cached_files USING GIN (authors gin_trgm_ops);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS api_key_usage (
+    api_key VARCHAR NOT NULL,
+    day DATE NOT NULL,
+    files_cached BIGINT NOT NULL DEFAULT 0,
+    bytes_served BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (api_key, day)
+);
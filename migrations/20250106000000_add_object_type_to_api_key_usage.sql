@@ -0,0 +1,8 @@
+ALTER TABLE api_key_usage ADD COLUMN IF NOT EXISTS object_type VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE api_key_usage ADD COLUMN IF NOT EXISTS requests BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE api_key_usage ADD COLUMN IF NOT EXISTS cache_misses BIGINT NOT NULL DEFAULT 0;
+
+ALTER TABLE api_key_usage DROP CONSTRAINT IF EXISTS api_key_usage_pkey;
+ALTER TABLE api_key_usage ADD PRIMARY KEY (api_key, day, object_type);
+
+CREATE INDEX IF NOT EXISTS ix_api_key_usage_day ON api_key_usage (day);
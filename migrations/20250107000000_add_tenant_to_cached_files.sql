@@ -0,0 +1,7 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS tenant VARCHAR NOT NULL DEFAULT 'default';
+
+DROP INDEX IF EXISTS uc_cached_files_object_id_object_type;
+
+CREATE UNIQUE INDEX IF NOT EXISTS uc_cached_files_tenant_object_id_object_type
+    ON cached_files (tenant, object_id, object_type)
+    WHERE deleted_at IS NULL;
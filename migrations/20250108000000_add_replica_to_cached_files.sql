@@ -0,0 +1,2 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS replica_chat_id BIGINT;
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS replica_message_id BIGINT;
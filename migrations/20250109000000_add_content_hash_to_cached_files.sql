@@ -0,0 +1,8 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS content_hash VARCHAR;
+
+CREATE INDEX IF NOT EXISTS ix_cached_files_tenant_content_hash
+    ON cached_files (tenant, content_hash)
+    WHERE deleted_at IS NULL AND content_hash IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS ix_cached_files_chat_id_message_id
+    ON cached_files (chat_id, message_id);
@@ -0,0 +1,78 @@
+-- Hash-partition cached_files by object_id so lookups on (tenant, object_id,
+-- object_type) only touch one partition. Converts the table in place once;
+-- later runs see it is already partitioned and do nothing.
+DO $$
+DECLARE
+    id_seq TEXT := pg_get_serial_sequence('cached_files', 'id');
+BEGIN
+    IF (SELECT relkind FROM pg_class WHERE oid = 'cached_files'::regclass) = 'p' THEN
+        RETURN;
+    END IF;
+
+    ALTER TABLE cached_files RENAME TO cached_files_unpartitioned;
+
+    CREATE TABLE cached_files (
+        id INTEGER NOT NULL,
+        object_id INTEGER NOT NULL,
+        object_type VARCHAR NOT NULL,
+        message_id BIGINT NOT NULL,
+        chat_id BIGINT NOT NULL,
+        deleted_at TIMESTAMPTZ,
+        title TEXT,
+        authors TEXT,
+        source_id INTEGER,
+        tenant VARCHAR NOT NULL DEFAULT 'default',
+        replica_chat_id BIGINT,
+        replica_message_id BIGINT,
+        content_hash VARCHAR,
+        CONSTRAINT pk_cached_files PRIMARY KEY (id, object_id)
+    ) PARTITION BY HASH (object_id);
+
+    EXECUTE format('ALTER TABLE cached_files ALTER COLUMN id SET DEFAULT nextval(%L)', id_seq);
+
+    FOR i IN 0..15 LOOP
+        EXECUTE format(
+            'CREATE TABLE cached_files_p%s PARTITION OF cached_files FOR VALUES WITH (MODULUS 16, REMAINDER %s)',
+            i, i
+        );
+    END LOOP;
+
+    INSERT INTO cached_files (
+        id, object_id, object_type, message_id, chat_id, deleted_at, title, authors,
+        source_id, tenant, replica_chat_id, replica_message_id, content_hash
+    )
+    SELECT
+        id, object_id, object_type, message_id, chat_id, deleted_at, title, authors,
+        source_id, tenant, replica_chat_id, replica_message_id, content_hash
+    FROM cached_files_unpartitioned;
+
+    EXECUTE format('ALTER SEQUENCE %s OWNED BY cached_files.id', id_seq);
+
+    DROP TABLE cached_files_unpartitioned;
+
+    CREATE UNIQUE INDEX uc_cached_files_tenant_object_id_object_type
+        ON cached_files (tenant, object_id, object_type)
+        WHERE deleted_at IS NULL;
+
+    CREATE INDEX ix_cached_files_deleted_at
+        ON cached_files (deleted_at)
+        WHERE deleted_at IS NOT NULL;
+
+    CREATE INDEX ix_cached_files_title_trgm
+        ON cached_files USING GIN (title gin_trgm_ops);
+
+    CREATE INDEX ix_cached_files_authors_trgm
+        ON cached_files USING GIN (authors gin_trgm_ops);
+
+    CREATE INDEX ix_cached_files_tenant_content_hash
+        ON cached_files (tenant, content_hash)
+        WHERE deleted_at IS NULL AND content_hash IS NOT NULL;
+
+    CREATE INDEX ix_cached_files_chat_id_message_id
+        ON cached_files (chat_id, message_id);
+
+    CREATE INDEX ix_cached_files_tenant_chat_id
+        ON cached_files (tenant, chat_id)
+        WHERE deleted_at IS NULL;
+END
+$$;
@@ -0,0 +1 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS file_size BIGINT;
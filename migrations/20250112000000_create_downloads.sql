@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS downloads (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    tenant VARCHAR NOT NULL,
+    api_key VARCHAR NOT NULL,
+    object_id INTEGER NOT NULL,
+    object_type VARCHAR NOT NULL,
+    bytes BIGINT NOT NULL,
+    duration_ms BIGINT NOT NULL,
+    client VARCHAR
+);
+
+CREATE INDEX IF NOT EXISTS ix_downloads_created_at ON downloads (created_at);
+
+CREATE INDEX IF NOT EXISTS ix_downloads_object_id_object_type
+    ON downloads (object_id, object_type);
@@ -0,0 +1,2 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS file_id VARCHAR;
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS file_unique_id VARCHAR;
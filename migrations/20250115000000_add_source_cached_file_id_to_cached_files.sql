@@ -0,0 +1,5 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS source_cached_file_id INTEGER;
+
+CREATE INDEX IF NOT EXISTS cached_files_source_cached_file_id
+    ON cached_files (source_cached_file_id)
+    WHERE source_cached_file_id IS NOT NULL;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS api_keys (
+    name VARCHAR PRIMARY KEY,
+    salt VARCHAR NOT NULL,
+    hash VARCHAR NOT NULL,
+    tenant VARCHAR NOT NULL DEFAULT 'default',
+    daily_cached_files BIGINT,
+    daily_bytes BIGINT,
+    monthly_bytes BIGINT,
+    download_rate_limit BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,3 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS encryption_key_id VARCHAR;
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS encrypted_key VARCHAR;
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS encryption_nonce VARCHAR;
@@ -0,0 +1 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS compression VARCHAR;
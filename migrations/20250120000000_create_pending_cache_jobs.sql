@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS pending_cache_jobs (
+    tenant VARCHAR NOT NULL,
+    object_id INTEGER NOT NULL,
+    object_type VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (tenant, object_id, object_type)
+);
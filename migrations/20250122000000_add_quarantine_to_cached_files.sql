@@ -0,0 +1,17 @@
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS quarantined_at TIMESTAMPTZ;
+ALTER TABLE cached_files ADD COLUMN IF NOT EXISTS quarantine_reason VARCHAR;
+
+CREATE INDEX IF NOT EXISTS ix_cached_files_quarantined
+    ON cached_files (tenant, quarantined_at)
+    WHERE quarantined_at IS NOT NULL AND deleted_at IS NULL;
+
+-- Kept per object rather than per row, so failures add up across the file
+-- being cached again.
+CREATE TABLE IF NOT EXISTS download_failures (
+    tenant VARCHAR NOT NULL,
+    object_id INTEGER NOT NULL,
+    object_type VARCHAR NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failed_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (tenant, object_id, object_type)
+);
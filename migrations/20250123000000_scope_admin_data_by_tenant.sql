@@ -0,0 +1,7 @@
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS admin BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE audit_log ADD COLUMN IF NOT EXISTS tenant VARCHAR NOT NULL DEFAULT 'default';
+
+CREATE INDEX IF NOT EXISTS ix_audit_log_tenant_created_at ON audit_log (tenant, created_at);
+
+ALTER TABLE api_key_usage ADD COLUMN IF NOT EXISTS tenant VARCHAR NOT NULL DEFAULT 'default';
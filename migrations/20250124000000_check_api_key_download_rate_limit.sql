@@ -0,0 +1,4 @@
+-- Zero would block every download of the key; rows already holding it are
+-- left alone and read as unthrottled.
+ALTER TABLE api_keys
+    ADD CONSTRAINT ck_api_keys_download_rate_limit_positive CHECK (download_rate_limit > 0) NOT VALID;
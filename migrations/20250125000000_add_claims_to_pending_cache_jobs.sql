@@ -0,0 +1,3 @@
+ALTER TABLE pending_cache_jobs ADD COLUMN IF NOT EXISTS claimed_by VARCHAR;
+
+ALTER TABLE pending_cache_jobs ADD COLUMN IF NOT EXISTS claimed_until TIMESTAMPTZ;
@@ -0,0 +1,476 @@
+use std::{borrow::Cow, num::NonZeroU64, sync::Arc, time::Duration};
+
+use axum::http::{
+    header::{AsHeaderName, AUTHORIZATION},
+    HeaderMap,
+};
+use base64::{engine::general_purpose, Engine};
+use futures::future::BoxFuture;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use moka::future::Cache;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::log;
+
+use crate::{
+    config::{default_tenant, ApiKey, Quota, CONFIG},
+    repository::ApiKeyRepository,
+    serializers::StoredApiKey,
+    views::Database,
+};
+
+pub const AUTH_STATIC: &str = "static";
+pub const AUTH_DATABASE: &str = "database";
+pub const AUTH_JWT: &str = "jwt";
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+pub type AuthFuture<'a> = BoxFuture<'a, Option<Arc<ApiKey>>>;
+
+/// Resolves a request's credentials to the key it acts as; `None` answers
+/// 401. Embedders can supply their own through
+/// [`ServerBuilder::authenticator`](crate::views::ServerBuilder::authenticator).
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap) -> AuthFuture<'a>;
+}
+
+/// The authenticator selected by `AUTH_BACKEND`.
+pub fn authenticator_from_config(db: Database) -> Arc<dyn Authenticator> {
+    match CONFIG.auth_backend.as_str() {
+        AUTH_STATIC => Arc::new(StaticKeys::from_config()),
+        AUTH_DATABASE => Arc::new(DatabaseKeys::new(db)),
+        AUTH_JWT => Arc::new(Jwt::from_config()),
+        other => panic!("Unknown AUTH_BACKEND: {other}"),
+    }
+}
+
+pub fn hash_api_key(salt: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(key.as_bytes());
+
+    hex::encode(hasher.finalize())
+}
+
+fn find_api_key<'a>(
+    api_keys: impl IntoIterator<Item = &'a Arc<ApiKey>>,
+    key: &str,
+) -> Option<Arc<ApiKey>> {
+    api_keys
+        .into_iter()
+        .find(|api_key| {
+            let hash = hash_api_key(&api_key.salt, key);
+
+            bool::from(
+                hash.as_bytes()
+                    .ct_eq(api_key.hash.to_lowercase().as_bytes()),
+            )
+        })
+        .cloned()
+}
+
+fn header(headers: &HeaderMap, name: impl AsHeaderName) -> Option<&str> {
+    headers
+        .get(name)
+        .and_then(|header| header.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// The token of an `Authorization: Bearer <token>` header; the scheme is
+/// case-insensitive.
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = header(headers, AUTHORIZATION)?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    scheme
+        .eq_ignore_ascii_case("Bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// The password of an `Authorization: Basic` header, for clients such as
+/// WebDAV mounts that only do Basic auth; the user name is ignored.
+fn basic_password(headers: &HeaderMap) -> Option<String> {
+    let value = header(headers, AUTHORIZATION)?;
+    let (scheme, credentials) = value.split_once(' ')?;
+
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+
+    let credentials = general_purpose::STANDARD.decode(credentials.trim()).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+
+    Some(password.to_string()).filter(|v| !v.is_empty())
+}
+
+/// The plaintext key from `X-Api-Key`, else from `Authorization`, either as
+/// a bearer token, a Basic password or the raw header value.
+fn presented_key(headers: &HeaderMap) -> Option<Cow<'_, str>> {
+    header(headers, API_KEY_HEADER)
+        .or_else(|| bearer(headers))
+        .map(Cow::Borrowed)
+        .or_else(|| basic_password(headers).map(Cow::Owned))
+        .or_else(|| header(headers, AUTHORIZATION).map(Cow::Borrowed))
+}
+
+/// Salted key hashes, e.g. from `API_KEYS`; the plaintext key comes in
+/// `X-Api-Key` or `Authorization`, bare, as `Bearer <key>` or as the Basic
+/// password.
+pub struct StaticKeys {
+    api_keys: Vec<Arc<ApiKey>>,
+}
+
+impl StaticKeys {
+    pub fn new(api_keys: Vec<ApiKey>) -> Self {
+        Self {
+            api_keys: api_keys.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// `API_KEYS`, plus the deprecated plaintext `API_KEY` if it's still set.
+    /// Without any key every request would be refused, so that doesn't start.
+    pub fn from_config() -> Self {
+        let mut api_keys = CONFIG.api_keys.clone();
+
+        if let Some(key) = &CONFIG.legacy_api_key {
+            log::warn!("API_KEY is deprecated, move it to API_KEYS as a salted hash");
+
+            api_keys.push(legacy_api_key(key));
+        }
+
+        assert!(
+            !api_keys.is_empty(),
+            "API_KEYS (or the deprecated API_KEY) is required with AUTH_BACKEND=static"
+        );
+
+        Self::new(api_keys)
+    }
+}
+
+/// The old single key had full access, so it stays an admin key.
+fn legacy_api_key(key: &str) -> ApiKey {
+    let salt = hex::encode(rand::random::<[u8; 16]>());
+
+    ApiKey {
+        name: "api_key".to_string(),
+        hash: hash_api_key(&salt, key),
+        salt,
+        tenant: default_tenant(),
+        quota: Quota::default(),
+        download_rate_limit: None,
+        admin: true,
+    }
+}
+
+impl Authenticator for StaticKeys {
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap) -> AuthFuture<'a> {
+        let api_key = presented_key(headers).and_then(|key| find_api_key(&self.api_keys, &key));
+
+        Box::pin(async move { api_key })
+    }
+}
+
+impl From<StoredApiKey> for ApiKey {
+    fn from(row: StoredApiKey) -> Self {
+        ApiKey {
+            name: row.name,
+            salt: row.salt,
+            hash: row.hash,
+            tenant: row.tenant,
+            quota: Quota {
+                daily_cached_files: row.daily_cached_files,
+                daily_bytes: row.daily_bytes,
+                monthly_bytes: row.monthly_bytes,
+            },
+            // Rows from before the check constraint may still hold zero,
+            // which is taken as no limit rather than blocking every download.
+            download_rate_limit: row
+                .download_rate_limit
+                .and_then(|v| u64::try_from(v).ok())
+                .and_then(NonZeroU64::new),
+            admin: row.admin,
+        }
+    }
+}
+
+/// Like [`StaticKeys`], but read from the `api_keys` table and reloaded every
+/// `API_KEYS_REFRESH_SECS`, so keys can be added or revoked without a restart.
+pub struct DatabaseKeys {
+    db: Database,
+    api_keys: Cache<(), Arc<Vec<Arc<ApiKey>>>>,
+}
+
+impl DatabaseKeys {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db,
+            api_keys: Cache::builder()
+                .max_capacity(1)
+                .time_to_live(Duration::from_secs(CONFIG.api_keys_refresh_secs))
+                .build(),
+        }
+    }
+
+    async fn load(&self) -> Result<Arc<Vec<Arc<ApiKey>>>, sqlx::Error> {
+        let rows = ApiKeyRepository::new(self.db.clone()).get_all().await?;
+
+        Ok(Arc::new(
+            rows.into_iter().map(|row| Arc::new(row.into())).collect(),
+        ))
+    }
+}
+
+impl Authenticator for DatabaseKeys {
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let key = presented_key(headers)?;
+
+            let api_keys = match self.api_keys.try_get_with((), self.load()).await {
+                Ok(v) => v,
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    return None;
+                }
+            };
+
+            find_api_key(api_keys.iter(), &key)
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default = "default_tenant")]
+    tenant: String,
+    #[serde(default)]
+    quota: Quota,
+    download_rate_limit: Option<NonZeroU64>,
+    #[serde(default)]
+    admin: bool,
+}
+
+/// `Authorization: Bearer` tokens signed with `JWT_SECRET` (HS256). `sub`
+/// names the key for usage accounting; `tenant`, `quota`,
+/// `download_rate_limit` and `admin` claims are optional.
+pub struct Jwt {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl Jwt {
+    pub fn new(secret: &str, issuer: Option<&str>, audience: Option<&str>) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+
+        if let Some(issuer) = issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        match audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        Self {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+        }
+    }
+
+    pub fn from_config() -> Self {
+        Self::new(
+            CONFIG
+                .jwt_secret
+                .as_deref()
+                .expect("JWT_SECRET is required with AUTH_BACKEND=jwt"),
+            CONFIG.jwt_issuer.as_deref(),
+            CONFIG.jwt_audience.as_deref(),
+        )
+    }
+}
+
+impl Authenticator for Jwt {
+    fn authenticate<'a>(&'a self, headers: &'a HeaderMap) -> AuthFuture<'a> {
+        let claims = bearer(headers)
+            .and_then(|token| decode::<Claims>(token, &self.key, &self.validation).ok())
+            .map(|data| data.claims);
+
+        let api_key = claims.map(|claims| {
+            Arc::new(ApiKey {
+                name: claims.sub,
+                salt: String::new(),
+                hash: String::new(),
+                tenant: claims.tenant,
+                quota: claims.quota,
+                download_rate_limit: claims.download_rate_limit,
+                admin: claims.admin,
+            })
+        });
+
+        Box::pin(async move { api_key })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+
+        headers
+    }
+
+    fn api_key(name: &str, salt: &str, key: &str) -> ApiKey {
+        ApiKey {
+            name: name.to_string(),
+            salt: salt.to_string(),
+            hash: hash_api_key(salt, key),
+            tenant: default_tenant(),
+            quota: Quota::default(),
+            download_rate_limit: None,
+            admin: false,
+        }
+    }
+
+    #[test]
+    fn hash_is_hex_sha256_of_salt_and_key() {
+        assert_eq!(
+            hash_api_key("s", "secret"),
+            "6f5230739913f9336fde5a028acf6cb6337d06050c55aac793adc3f4658811eb"
+        );
+    }
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        assert_eq!(
+            bearer(&headers(&[("authorization", "Bearer abc")])),
+            Some("abc")
+        );
+        assert_eq!(
+            bearer(&headers(&[("authorization", "bearer  abc ")])),
+            Some("abc")
+        );
+        assert_eq!(bearer(&headers(&[("authorization", "Bearer ")])), None);
+        assert_eq!(bearer(&headers(&[("authorization", "Basic abc")])), None);
+        assert_eq!(bearer(&headers(&[("authorization", "abc")])), None);
+    }
+
+    #[test]
+    fn basic_takes_the_password_and_ignores_the_user() {
+        let encoded = general_purpose::STANDARD.encode("user:pa:ss");
+
+        assert_eq!(
+            basic_password(&headers(&[("authorization", &format!("Basic {encoded}"))])),
+            Some("pa:ss".to_string())
+        );
+    }
+
+    #[test]
+    fn basic_without_a_password_is_rejected() {
+        for credentials in ["user:", "user", ""] {
+            let encoded = general_purpose::STANDARD.encode(credentials);
+
+            assert_eq!(
+                basic_password(&headers(&[("authorization", &format!("Basic {encoded}"))])),
+                None,
+                "{credentials:?}"
+            );
+        }
+
+        assert_eq!(
+            basic_password(&headers(&[("authorization", "Basic not-base64!")])),
+            None
+        );
+    }
+
+    #[test]
+    fn x_api_key_wins_over_authorization() {
+        let headers = headers(&[
+            ("x-api-key", "from-header"),
+            ("authorization", "Bearer other"),
+        ]);
+
+        assert_eq!(presented_key(&headers).as_deref(), Some("from-header"));
+    }
+
+    #[test]
+    fn authorization_is_read_as_bearer_basic_or_raw() {
+        let encoded = general_purpose::STANDARD.encode("user:basic");
+
+        for (value, expected) in [
+            ("Bearer token".to_string(), "token"),
+            (format!("Basic {encoded}"), "basic"),
+            ("raw".to_string(), "raw"),
+        ] {
+            assert_eq!(
+                presented_key(&headers(&[("authorization", &value)])).as_deref(),
+                Some(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn blank_credentials_are_not_presented() {
+        assert_eq!(presented_key(&HeaderMap::new()), None);
+        assert_eq!(presented_key(&headers(&[("x-api-key", "  ")])), None);
+    }
+
+    #[test]
+    fn finds_the_key_whose_hash_matches() {
+        let keys = [
+            Arc::new(api_key("first", "a", "one")),
+            Arc::new(api_key("second", "b", "two")),
+        ];
+
+        assert_eq!(
+            find_api_key(&keys, "two").map(|v| v.name.clone()),
+            Some("second".to_string())
+        );
+        assert!(find_api_key(&keys, "three").is_none());
+        // The salt is part of the hash, so another key's salt doesn't match.
+        assert!(find_api_key(&keys[..1], "two").is_none());
+    }
+
+    #[test]
+    fn stored_hashes_match_regardless_of_case() {
+        let mut key = api_key("upper", "s", "secret");
+        key.hash = key.hash.to_uppercase();
+
+        assert!(find_api_key(&[Arc::new(key)], "secret").is_some());
+    }
+
+    #[tokio::test]
+    async fn static_keys_authenticate_presented_keys() {
+        let keys = StaticKeys::new(vec![api_key("main", "s", "secret")]);
+
+        let found = keys
+            .authenticate(&headers(&[("authorization", "Bearer secret")]))
+            .await;
+        assert_eq!(found.map(|v| v.name.clone()), Some("main".to_string()));
+
+        assert!(keys
+            .authenticate(&headers(&[("x-api-key", "wrong")]))
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn legacy_key_is_an_admin_key_with_a_fresh_salt() {
+        let key = legacy_api_key("secret");
+
+        assert!(key.admin);
+        assert_ne!(key.salt, legacy_api_key("secret").salt);
+        assert!(find_api_key(&[Arc::new(key)], "secret").is_some());
+    }
+}
@@ -0,0 +1,308 @@
+//! Operations CLI. Talks to a running server over the API, or with `--db`
+//! straight to the database, configured from the same environment as the server.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    pin::pin,
+    process::ExitCode,
+};
+
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use dotenvy::dotenv;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use telegram_files_cache_server::{
+    client::Client,
+    download_from_cache, get_database,
+    services::{audit::ACTOR_CLI, cache_file_on_demand, delete_from_cache, find_cached_file},
+    CachedFile, CachedFileRepository, Database, ObjectType,
+};
+
+type CliError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Parser)]
+#[command(name = "cache-admin", about = "Operate a telegram files cache server")]
+struct Cli {
+    /// Server root, without the `/api/v1` prefix.
+    #[arg(long, env = "CACHE_ADMIN_URL", default_value = "http://localhost:8080")]
+    url: String,
+
+    #[arg(long, env = "CACHE_ADMIN_API_KEY")]
+    api_key: Option<String>,
+
+    /// Work on the database directly instead of going through the API.
+    #[arg(long)]
+    db: bool,
+
+    /// Tenant to act on with `--db`; over the API it's the key's tenant.
+    #[arg(long, default_value = "default")]
+    tenant: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Live files and bytes per object type.
+    Stats,
+    /// Messages and bytes per storage chat.
+    ChatStats,
+    /// Looks a file up, caching it first if needed.
+    Cache {
+        object_id: i32,
+        object_type: ObjectType,
+    },
+    /// Soft-deletes a file along with its conversions.
+    Delete {
+        object_id: i32,
+        object_type: ObjectType,
+    },
+    /// Downloads a file and checks it against its recorded size and hash.
+    Verify {
+        object_id: i32,
+        object_type: ObjectType,
+    },
+    /// Writes every cached file as JSON lines. Needs `--db`.
+    Export {
+        /// Defaults to stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Restores rows written by `export`, skipping ids that exist. Needs `--db`.
+    Import {
+        /// Defaults to stdin.
+        #[arg(long)]
+        input: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv().ok();
+
+    let cli = Cli::parse();
+
+    let result = if cli.db {
+        run_with_db(cli.command, cli.tenant).await
+    } else {
+        match cli.api_key {
+            Some(api_key) => run_with_api(cli.command, Client::new(cli.url, api_key)).await,
+            None => Err("--api-key or CACHE_ADMIN_API_KEY is required without --db".into()),
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run_with_api(command: Command, client: Client) -> Result<(), CliError> {
+    match command {
+        Command::Stats => print_json(&client.cache_stats().await?),
+        Command::ChatStats => print_json(&client.chat_stats().await?),
+        Command::Cache {
+            object_id,
+            object_type,
+        } => match client
+            .get_cached_file(object_id, object_type.as_str(), None)
+            .await?
+        {
+            Some(cached_file) => print_json(&cached_file),
+            None => Err("the file can't be cached".into()),
+        },
+        Command::Delete {
+            object_id,
+            object_type,
+        } => match client
+            .delete_cached_file(object_id, object_type.as_str())
+            .await?
+        {
+            Some(cached_file) => print_json(&cached_file),
+            None => Err("the file isn't cached".into()),
+        },
+        Command::Verify {
+            object_id,
+            object_type,
+        } => {
+            let cached_file = client
+                .get_cached_file(object_id, object_type.as_str(), None)
+                .await?
+                .ok_or("the file can't be cached")?;
+
+            let download = client
+                .download(object_id, object_type.as_str(), None)
+                .await?
+                .ok_or("the file can't be downloaded")?;
+
+            verify(
+                cached_file.content_hash.as_deref(),
+                cached_file.file_size,
+                download.bytes_stream(),
+            )
+            .await
+        }
+        Command::Export { .. } | Command::Import { .. } => {
+            Err("export and import need --db".into())
+        }
+    }
+}
+
+async fn run_with_db(command: Command, tenant: String) -> Result<(), CliError> {
+    let db = get_database().await;
+
+    match command {
+        Command::Stats => print_json(&CachedFileRepository::new(db).get_stats().await?),
+        Command::ChatStats => print_json(&CachedFileRepository::new(db).get_chat_stats().await?),
+        Command::Cache {
+            object_id,
+            object_type,
+        } => {
+            if !object_type.is_accepted() {
+                return Err(format!("unknown object type {object_type}").into());
+            }
+
+            let cached_file =
+                match find_cached_file(tenant.clone(), object_id, object_type.clone(), db.clone())
+                    .await
+                {
+                    Some(v) => Some(v),
+                    None => {
+                        cache_file_on_demand(ACTOR_CLI, tenant, object_id, object_type, db).await
+                    }
+                };
+
+            match cached_file {
+                Some(cached_file) => print_json(&cached_file),
+                None => Err("the file can't be cached".into()),
+            }
+        }
+        Command::Delete {
+            object_id,
+            object_type,
+        } => match delete_from_cache(ACTOR_CLI, tenant, object_id, object_type, db).await {
+            Some(cached_file) => print_json(&cached_file),
+            None => Err("the file isn't cached".into()),
+        },
+        Command::Verify {
+            object_id,
+            object_type,
+        } => {
+            let cached_file = find_cached_file(tenant, object_id, object_type, db.clone())
+                .await
+                .ok_or("the file isn't cached")?;
+
+            let content_hash = cached_file.content_hash.clone();
+            let file_size = cached_file.file_size;
+
+            let download = download_from_cache(cached_file, db)
+                .await
+                .ok_or("the file can't be downloaded")?;
+
+            verify(content_hash.as_deref(), file_size, download.body).await
+        }
+        Command::Export { output } => export(db, output).await,
+        Command::Import { input } => import(db, input).await,
+    }
+}
+
+fn print_json(value: &impl Serialize) -> Result<(), CliError> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+
+    Ok(())
+}
+
+async fn verify<E>(
+    content_hash: Option<&str>,
+    file_size: Option<i64>,
+    body: impl Stream<Item = Result<Bytes, E>>,
+) -> Result<(), CliError>
+where
+    E: Into<CliError>,
+{
+    let expected_hash = content_hash.ok_or("no content hash is recorded for the file")?;
+
+    let mut body = pin!(body);
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(Into::into)?;
+
+        size += chunk.len() as i64;
+        hasher.update(&chunk);
+    }
+
+    let hash = hex::encode(hasher.finalize());
+
+    if hash != expected_hash {
+        return Err(format!("hash mismatch: recorded {expected_hash}, got {hash}").into());
+    }
+
+    if let Some(expected_size) = file_size.filter(|v| *v != size) {
+        return Err(format!("size mismatch: recorded {expected_size}, got {size}").into());
+    }
+
+    println!("ok: {size} bytes, sha256 {hash}");
+
+    Ok(())
+}
+
+async fn export(db: Database, output: Option<PathBuf>) -> Result<(), CliError> {
+    let cached_files = CachedFileRepository::new(db).get_all().await?;
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    for cached_file in &cached_files {
+        serde_json::to_writer(&mut writer, cached_file)?;
+        writeln!(writer)?;
+    }
+
+    writer.flush()?;
+
+    eprintln!("exported {} cached files", cached_files.len());
+
+    Ok(())
+}
+
+async fn import(db: Database, input: Option<PathBuf>) -> Result<(), CliError> {
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(std::io::stdin().lock()),
+    };
+
+    let mut cached_files: Vec<CachedFile> = vec![];
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        cached_files.push(
+            serde_json::from_str(&line).map_err(|err| format!("line {}: {err}", number + 1))?,
+        );
+    }
+
+    let restored = CachedFileRepository::new(db).restore(&cached_files).await?;
+
+    eprintln!(
+        "imported {restored} of {} cached files, the rest already existed",
+        cached_files.len()
+    );
+
+    Ok(())
+}
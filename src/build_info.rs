@@ -0,0 +1,41 @@
+use std::borrow::Cow;
+
+use axum_prometheus::metrics::gauge;
+use chrono::{DateTime, Utc};
+
+pub const BUILD_INFO: &str = "build_info";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BuildInfo {
+    pub version: Cow<'static, str>,
+    pub git_commit: Cow<'static, str>,
+    pub build_time: Option<DateTime<Utc>>,
+    pub features: Vec<Cow<'static, str>>,
+}
+
+pub fn get_build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").into(),
+        git_commit: env!("GIT_COMMIT").into(),
+        build_time: env!("BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|v| DateTime::from_timestamp(v, 0)),
+        features: env!("BUILD_FEATURES")
+            .split(',')
+            .filter(|v| !v.is_empty())
+            .map(Cow::from)
+            .collect(),
+    }
+}
+
+/// Exposes the running build as a constant `build_info` gauge so dashboards can
+/// join on version and commit.
+pub fn register_build_info_metric() {
+    gauge!(
+        BUILD_INFO,
+        "version" => env!("CARGO_PKG_VERSION"),
+        "git_commit" => env!("GIT_COMMIT")
+    )
+    .set(1.0);
+}
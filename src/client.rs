@@ -0,0 +1,466 @@
+//! Typed async client for the HTTP API, enabled by the `client` feature.
+
+use base64::{engine::general_purpose, Engine};
+use bytes::Bytes;
+use futures::Stream;
+use reqwest::{header, Method, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    build_info::BuildInfo,
+    serializers::{
+        AuditLogEntry, BookManifest, CacheStats, CachedFile, CachedFileDetails, ChatStats,
+        DownloadEntry, TimeseriesPoint, TopBook, UsageRow,
+    },
+    services::{
+        bots::BotStats,
+        cache_jobs::CacheJobProgress,
+        flags::FlagState,
+        rehost::{RehostProgress, RehostRequest},
+        snapshot::{RestoreResult, SnapshotLocation},
+        CacheData,
+    },
+    views::{
+        DownloadQuery, EditCaptionRequest, GetAuditLogQuery, GetCachedFileQuery, GetDownloadsQuery,
+        GetQuarantineQuery, GetTimeseriesQuery, GetTopQuery, GetUsageQuery, LogFilter,
+        RemapChatRequest, RemapChatResult, SearchQuery, SetFlagRequest,
+    },
+};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Request(reqwest::Error),
+    /// The server answered with an unexpected status; the body is kept for context.
+    Status(StatusCode, String),
+    /// A download response lacked a header or had one that didn't decode.
+    InvalidHeader(&'static str),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Request(err) => write!(f, "request failed: {err}"),
+            ClientError::Status(status, body) => {
+                write!(f, "server responded with {status}: {body}")
+            }
+            ClientError::InvalidHeader(name) => write!(f, "missing or invalid {name} header"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        ClientError::Request(err)
+    }
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+/// A file being downloaded, with the metadata the server sends in headers.
+pub struct Download {
+    pub filename: String,
+    pub caption: String,
+    /// `HIT`, `STALE`, `MISS` or `REVALIDATED`, from `X-Cache`.
+    pub cache_status: Option<String>,
+    response: Response,
+}
+
+impl Download {
+    fn from_response(response: Response) -> ClientResult<Self> {
+        let headers = response.headers();
+
+        let decode_header = |name: &'static str| -> ClientResult<String> {
+            let value = headers.get(name).ok_or(ClientError::InvalidHeader(name))?;
+
+            general_purpose::STANDARD
+                .decode(value.as_bytes())
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok())
+                .ok_or(ClientError::InvalidHeader(name))
+        };
+
+        let filename = decode_header("x-filename-b64")?;
+        let caption = decode_header("x-caption-b64")?;
+        let cache_status = headers
+            .get("x-cache")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        Ok(Download {
+            filename,
+            caption,
+            cache_status,
+            response,
+        })
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.response.content_length()
+    }
+
+    /// Reads the whole file into memory.
+    pub async fn bytes(self) -> ClientResult<Bytes> {
+        Ok(self.response.bytes().await?)
+    }
+
+    pub fn bytes_stream(self) -> impl Stream<Item = ClientResult<Bytes>> {
+        futures::StreamExt::map(self.response.bytes_stream(), |chunk| Ok(chunk?))
+    }
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl Client {
+    /// `base_url` is the server root, without the `/api/v1` prefix.
+    pub fn new(base_url: impl Into<String>, api_key: impl Into<String>) -> Self {
+        Self::with_http_client(reqwest::Client::new(), base_url, api_key)
+    }
+
+    pub fn with_http_client(
+        http: reqwest::Client,
+        base_url: impl Into<String>,
+        api_key: impl Into<String>,
+    ) -> Self {
+        Client {
+            http,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: api_key.into(),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}/api/v1{path}", self.base_url))
+            .header(header::AUTHORIZATION, &self.api_key)
+    }
+
+    async fn send(request: RequestBuilder) -> ClientResult<Response> {
+        let response = request.send().await?;
+
+        if response.status().is_client_error() || response.status().is_server_error() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+
+            return Err(ClientError::Status(status, body));
+        }
+
+        Ok(response)
+    }
+
+    async fn json<T: DeserializeOwned>(request: RequestBuilder) -> ClientResult<T> {
+        Ok(Self::send(request).await?.json().await?)
+    }
+
+    /// For endpoints that answer 204 when there's nothing to return.
+    async fn optional_json<T: DeserializeOwned>(
+        request: RequestBuilder,
+    ) -> ClientResult<Option<T>> {
+        let response = Self::send(request).await?;
+
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+
+        Ok(Some(response.json().await?))
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &(impl Serialize + ?Sized),
+    ) -> ClientResult<T> {
+        Self::json(self.request(Method::GET, path).query(query)).await
+    }
+
+    /// Looks up a cached file, caching it first if needed. `None` when the
+    /// book can't be cached.
+    pub async fn get_cached_file(
+        &self,
+        object_id: i32,
+        object_type: &str,
+        convert: Option<&str>,
+    ) -> ClientResult<Option<CachedFile>> {
+        let query = GetCachedFileQuery {
+            copy: false,
+            convert: convert.map(|v| v.to_string()),
+        };
+
+        Self::optional_json(
+            self.request(Method::GET, &format!("/{object_id}/{object_type}/"))
+                .query(&query),
+        )
+        .await
+    }
+
+    /// Like `get_cached_file`, but has the server post a fresh copy of the
+    /// message and returns where it is.
+    pub async fn get_cached_file_copy(
+        &self,
+        object_id: i32,
+        object_type: &str,
+        convert: Option<&str>,
+    ) -> ClientResult<Option<CacheData>> {
+        let query = GetCachedFileQuery {
+            copy: true,
+            convert: convert.map(|v| v.to_string()),
+        };
+
+        Self::optional_json(
+            self.request(Method::GET, &format!("/{object_id}/{object_type}/"))
+                .query(&query),
+        )
+        .await
+    }
+
+    /// Every cached and available format of a book; `None` if neither the
+    /// library nor the cache knows it.
+    pub async fn book_manifest(&self, object_id: i32) -> ClientResult<Option<BookManifest>> {
+        Self::optional_json(self.request(Method::GET, &format!("/book/{object_id}"))).await
+    }
+
+    /// Starts a download; the body is read from the returned `Download`.
+    pub async fn download(
+        &self,
+        object_id: i32,
+        object_type: &str,
+        convert: Option<&str>,
+    ) -> ClientResult<Option<Download>> {
+        let query = DownloadQuery {
+            convert: convert.map(|v| v.to_string()),
+        };
+
+        let response = Self::send(
+            self.request(
+                Method::GET,
+                &format!("/download/{object_id}/{object_type}/"),
+            )
+            .query(&query),
+        )
+        .await?;
+
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+
+        Download::from_response(response).map(Some)
+    }
+
+    pub async fn delete_cached_file(
+        &self,
+        object_id: i32,
+        object_type: &str,
+    ) -> ClientResult<Option<CachedFile>> {
+        Self::optional_json(self.request(Method::DELETE, &format!("/{object_id}/{object_type}/")))
+            .await
+    }
+
+    /// Restores a deleted file before it's purged; `None` when there's nothing
+    /// to restore. Fails with a 409 status when a file was cached in its place.
+    pub async fn undelete_cached_file(
+        &self,
+        object_id: i32,
+        object_type: &str,
+    ) -> ClientResult<Option<CachedFile>> {
+        let path = format!("/{object_id}/{object_type}/undelete");
+
+        match Self::json(self.request(Method::POST, &path)).await {
+            Err(ClientError::Status(StatusCode::NOT_FOUND, _)) => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    pub async fn edit_caption(
+        &self,
+        object_id: i32,
+        object_type: &str,
+        request: &EditCaptionRequest,
+    ) -> ClientResult<CachedFile> {
+        Self::json(
+            self.request(
+                Method::PATCH,
+                &format!("/{object_id}/{object_type}/caption"),
+            )
+            .json(request),
+        )
+        .await
+    }
+
+    pub async fn info(&self) -> ClientResult<BuildInfo> {
+        self.get_json("/info", &()).await
+    }
+
+    pub async fn top_books(&self, query: &GetTopQuery) -> ClientResult<Vec<TopBook>> {
+        self.get_json("/stats/top", query).await
+    }
+
+    pub async fn timeseries(
+        &self,
+        query: &GetTimeseriesQuery,
+    ) -> ClientResult<Vec<TimeseriesPoint>> {
+        self.get_json("/stats/timeseries", query).await
+    }
+
+    /// Starts an update run in the background.
+    pub async fn update_cache(&self) -> ClientResult<()> {
+        Self::send(self.request(Method::POST, "/update_cache")).await?;
+
+        Ok(())
+    }
+
+    /// Starts purging soft-deleted files in the background.
+    pub async fn purge_deleted(&self) -> ClientResult<()> {
+        Self::send(self.request(Method::POST, "/purge_deleted")).await?;
+
+        Ok(())
+    }
+
+    pub async fn audit_log(&self, query: &GetAuditLogQuery) -> ClientResult<Vec<AuditLogEntry>> {
+        self.get_json("/admin/audit_log", query).await
+    }
+
+    pub async fn downloads(&self, query: &GetDownloadsQuery) -> ClientResult<Vec<DownloadEntry>> {
+        self.get_json("/admin/downloads", query).await
+    }
+
+    pub async fn search(&self, query: &SearchQuery) -> ClientResult<Vec<CachedFileDetails>> {
+        self.get_json("/admin/search", query).await
+    }
+
+    pub async fn quarantined(&self, query: &GetQuarantineQuery) -> ClientResult<Vec<CachedFile>> {
+        self.get_json("/admin/quarantine", query).await
+    }
+
+    /// Caches a quarantined file again; `None` when that failed. Fails with a
+    /// 404 status when the file isn't quarantined.
+    pub async fn repair_quarantined(
+        &self,
+        object_id: i32,
+        object_type: &str,
+    ) -> ClientResult<Option<CachedFile>> {
+        Self::optional_json(self.request(
+            Method::POST,
+            &format!("/admin/quarantine/{object_id}/{object_type}/repair"),
+        ))
+        .await
+    }
+
+    /// Serves a quarantined file as it is again; `None` when it isn't quarantined.
+    pub async fn release_quarantined(
+        &self,
+        object_id: i32,
+        object_type: &str,
+    ) -> ClientResult<Option<CachedFile>> {
+        let path = format!("/admin/quarantine/{object_id}/{object_type}/release");
+
+        match Self::json(self.request(Method::POST, &path)).await {
+            Err(ClientError::Status(StatusCode::NOT_FOUND, _)) => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    /// Deletes a quarantined file; `None` when it isn't quarantined.
+    pub async fn delete_quarantined(
+        &self,
+        object_id: i32,
+        object_type: &str,
+    ) -> ClientResult<Option<CachedFile>> {
+        let path = format!("/admin/quarantine/{object_id}/{object_type}");
+
+        match Self::json(self.request(Method::DELETE, &path)).await {
+            Err(ClientError::Status(StatusCode::NOT_FOUND, _)) => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    pub async fn usage(&self, query: &GetUsageQuery) -> ClientResult<Vec<UsageRow>> {
+        self.get_json("/admin/usage", query).await
+    }
+
+    pub async fn remap_chat(&self, request: &RemapChatRequest) -> ClientResult<RemapChatResult> {
+        Self::json(
+            self.request(Method::POST, "/admin/remap_chat")
+                .json(request),
+        )
+        .await
+    }
+
+    pub async fn bots(&self) -> ClientResult<Vec<BotStats>> {
+        self.get_json("/admin/bots", &()).await
+    }
+
+    pub async fn cache_jobs(&self) -> ClientResult<Vec<CacheJobProgress>> {
+        self.get_json("/admin/cache_jobs", &()).await
+    }
+
+    /// Live files and bytes per object type, across all tenants.
+    pub async fn cache_stats(&self) -> ClientResult<Vec<CacheStats>> {
+        self.get_json("/admin/cache_stats", &()).await
+    }
+
+    pub async fn chat_stats(&self) -> ClientResult<Vec<ChatStats>> {
+        self.get_json("/admin/chat_stats", &()).await
+    }
+
+    pub async fn flags(&self) -> ClientResult<Vec<FlagState>> {
+        self.get_json("/admin/flags", &()).await
+    }
+
+    pub async fn set_flag(&self, name: &str, enabled: bool) -> ClientResult<FlagState> {
+        Self::json(
+            self.request(Method::PUT, &format!("/admin/flags/{name}"))
+                .json(&SetFlagRequest { enabled }),
+        )
+        .await
+    }
+
+    /// Drops the shared override, returning the flag's state without it.
+    pub async fn delete_flag(&self, name: &str) -> ClientResult<FlagState> {
+        Self::json(self.request(Method::DELETE, &format!("/admin/flags/{name}"))).await
+    }
+
+    pub async fn log_level(&self) -> ClientResult<LogFilter> {
+        self.get_json("/admin/log_level", &()).await
+    }
+
+    pub async fn set_log_level(&self, filter: &str) -> ClientResult<LogFilter> {
+        Self::json(
+            self.request(Method::PUT, "/admin/log_level")
+                .json(&LogFilter {
+                    filter: filter.to_string(),
+                }),
+        )
+        .await
+    }
+
+    /// Starts a re-host job; fails with 409 while another one is running.
+    pub async fn rehost(&self, request: &RehostRequest) -> ClientResult<()> {
+        Self::send(self.request(Method::POST, "/admin/rehost").json(request)).await?;
+
+        Ok(())
+    }
+
+    pub async fn rehost_progress(&self) -> ClientResult<Option<RehostProgress>> {
+        Self::optional_json(self.request(Method::GET, "/admin/rehost")).await
+    }
+
+    pub async fn create_snapshot(&self) -> ClientResult<SnapshotLocation> {
+        Self::json(self.request(Method::POST, "/admin/snapshot")).await
+    }
+
+    pub async fn restore_snapshot(
+        &self,
+        location: &SnapshotLocation,
+    ) -> ClientResult<RestoreResult> {
+        Self::json(
+            self.request(Method::POST, "/admin/snapshot/restore")
+                .json(location),
+        )
+        .await
+    }
+}
@@ -1,26 +1,387 @@
+use std::{
+    collections::HashMap,
+    num::{NonZeroU64, NonZeroUsize},
+    str::FromStr,
+};
+
 use once_cell::sync::Lazy;
+use serde::Deserialize;
 
-pub struct Config {
+use crate::object_type::ObjectType;
+
+/// An API key is stored as `hex(sha256(salt + key))`; the plaintext key never
+/// reaches the config.
+#[derive(Deserialize, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub salt: String,
+    pub hash: String,
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    #[serde(default)]
+    pub quota: Quota,
+    /// Bytes per second across all of the key's downloads.
+    pub download_rate_limit: Option<NonZeroU64>,
+    /// Admin keys may use the routes that reach across tenants or change
+    /// the whole server, and see every tenant's logs and usage.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl ApiKey {
+    /// The tenant listings are narrowed to; `None` for admins, who see all.
+    pub fn visible_tenant(&self) -> Option<&str> {
+        (!self.admin).then_some(self.tenant.as_str())
+    }
+}
+
+pub(crate) fn default_tenant() -> String {
+    "default".to_string()
+}
+
+/// Per-key limits; a missing limit means unlimited.
+#[derive(Deserialize, Clone, Default)]
+pub struct Quota {
+    pub daily_cached_files: Option<i64>,
+    pub daily_bytes: Option<i64>,
+    pub monthly_bytes: Option<i64>,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        self.daily_cached_files.is_none()
+            && self.daily_bytes.is_none()
+            && self.monthly_bytes.is_none()
+    }
+}
+
+/// Per-tenant overrides; anything left unset falls back to the global settings.
+#[derive(Deserialize, Clone, Default)]
+pub struct TenantConfig {
+    pub files_url: Option<String>,
+    pub files_api_key: Option<String>,
+    pub files_edit_caption: Option<bool>,
+    #[serde(default)]
+    pub upload_chat_ids: Vec<i64>,
+    /// Spare chats, in order, that take over from `upload_chat_ids` as those
+    /// fill up.
+    #[serde(default)]
+    pub rollover_chat_ids: Vec<i64>,
+    pub temp_channel_id: Option<i64>,
+    pub backup_chat_id: Option<i64>,
+    pub library_source: Option<String>,
+}
+
+impl TenantConfig {
+    /// Spreads uploads by object id across as many chats as there are
+    /// `upload_chat_ids`, passing over full ones for the next in line there or
+    /// in `rollover_chat_ids`; `None` lets telegram_files pick its default chat.
+    pub fn upload_chat_id(&self, object_id: i32, is_full: impl Fn(i64) -> bool) -> Option<i64> {
+        let width = self.upload_chat_ids.len();
+
+        if width == 0 {
+            return None;
+        }
+
+        let all: Vec<i64> = self
+            .upload_chat_ids
+            .iter()
+            .chain(&self.rollover_chat_ids)
+            .copied()
+            .collect();
+
+        let mut chat_ids: Vec<i64> = all
+            .iter()
+            .copied()
+            .filter(|chat_id| !is_full(*chat_id))
+            .take(width)
+            .collect();
+
+        // With every chat full, the last ones keep taking uploads.
+        if chat_ids.is_empty() {
+            chat_ids = all[all.len() - width..].to_vec();
+        }
+
+        Some(chat_ids[object_id.unsigned_abs() as usize % chat_ids.len()])
+    }
+}
+
+/// A book_library catalog besides the one at `LIBRARY_URL`.
+#[derive(Deserialize, Clone)]
+pub struct LibrarySource {
+    pub url: String,
     pub api_key: String,
+}
+
+/// An endpoint that gets cache events POSTed to it, signed with `secret`.
+#[derive(Deserialize, Clone)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: String,
+    /// Event names to deliver; all of them when unset.
+    pub events: Option<Vec<String>>,
+}
+
+impl Webhook {
+    pub fn accepts(&self, event: &str) -> bool {
+        self.events
+            .as_ref()
+            .is_none_or(|events| events.iter().any(|v| v == event))
+    }
+}
+
+/// The source name of the catalog at `LIBRARY_URL`.
+pub const DEFAULT_LIBRARY_SOURCE: &str = "default";
+
+/// How an object type is sent to Telegram; audio and video get the built-in player.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    #[default]
+    Document,
+    Audio,
+    Video,
+}
+
+impl MediaKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaKind::Document => "document",
+            MediaKind::Audio => "audio",
+            MediaKind::Video => "video",
+        }
+    }
+}
+
+/// The HTTP version used toward an upstream. `Auto` negotiates HTTP/2 over
+/// TLS and stays on HTTP/1.1 otherwise; `Http2` assumes the upstream speaks
+/// it even over plain HTTP.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    #[default]
+    Auto,
+    Http1,
+    Http2,
+}
+
+/// PEM files for TLS toward an upstream: a client certificate and key for
+/// mutual TLS, and CAs to trust on top of the system roots.
+#[derive(Deserialize, Clone, Default)]
+pub struct UpstreamTls {
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub ca_bundle: Option<String>,
+}
+
+pub struct Config {
+    pub api_keys: Vec<ApiKey>,
+    /// The plaintext key from before `API_KEYS`; still accepted, as an admin
+    /// key of the default tenant, but hashed at startup like the others.
+    pub legacy_api_key: Option<String>,
+    pub auth_backend: String,
+    pub api_keys_refresh_secs: u64,
+    pub jwt_secret: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
 
     pub postgres_user: String,
     pub postgres_password: String,
     pub postgres_host: String,
     pub postgres_port: u32,
     pub postgres_db: String,
+    pub postgres_replica_url: Option<String>,
+    pub run_migrations: bool,
+
+    pub postgres_max_connections: u32,
+    pub postgres_min_connections: u32,
+    pub postgres_acquire_timeout: u64,
+    pub postgres_idle_timeout: u64,
+    pub postgres_statement_timeout: u64,
+
+    pub http_pool_max_idle_per_host: Option<usize>,
+    pub http_pool_idle_timeout_secs: u64,
+    pub http_tcp_keepalive_secs: u64,
+    pub http_connect_timeout_secs: u64,
+    pub upstream_proxies: HashMap<String, String>,
+    pub dns_cache_ttl_secs: u64,
+    pub dns_overrides: HashMap<String, Vec<std::net::IpAddr>>,
+    pub upstream_http_versions: HashMap<String, HttpVersion>,
+    pub upstream_tls: HashMap<String, UpstreamTls>,
+    pub http2_keep_alive_interval_secs: Option<u64>,
+    pub http2_adaptive_window: bool,
+
+    pub mock_upstreams: bool,
+    pub mock_upstreams_port: u16,
 
     pub downloader_api_key: String,
     pub downloader_url: String,
+    pub downloader_fallback_urls: Vec<String>,
+    pub downloader_health_check_interval_secs: NonZeroU64,
+    pub conversion_targets: Vec<String>,
+    /// Other names clients use for an object type, e.g. `azw3` for `mobi`;
+    /// files are always cached under the name an alias maps to.
+    pub object_type_aliases: HashMap<String, String>,
+    /// Types the API accepts besides the ones `ObjectType` knows.
+    pub custom_object_types: Vec<String>,
 
     pub library_api_key: String,
     pub library_url: String,
+    pub library_sources: HashMap<String, LibrarySource>,
+    pub book_cache_ttl_secs: u64,
+    pub book_cache_capacity: u64,
+    pub book_library_concurrency: NonZeroUsize,
 
     pub files_api_key: String,
     pub files_url: String,
+    /// Whether telegram_files has `/api/v1/files/edit_caption/`; released
+    /// versions don't, so captions can't be edited through them.
+    pub files_edit_caption: bool,
+    pub download_resume_attempts: u32,
+    pub download_rate_limit: Option<NonZeroU64>,
+    pub max_concurrent_downloads: Option<NonZeroUsize>,
+    pub download_retry_after_secs: u64,
+    /// Telegram operations per second, shared by uploads, downloads, copies
+    /// and the rest; unlimited when unset.
+    pub telegram_rate_limit: Option<NonZeroU64>,
+    /// Caps on single operations on top of that, e.g. `{"upload": 5}`.
+    pub telegram_op_rate_limits: HashMap<String, NonZeroU64>,
+    /// Requests per minute background jobs may send to an upstream, e.g.
+    /// `{"downloader": 60, "book_library": 300}`; requests made while
+    /// serving the API don't count against it.
+    pub background_request_budgets: HashMap<String, NonZeroU64>,
+    /// Size of the reads and frames files are streamed in.
+    pub stream_chunk_size: usize,
+    /// How far a download may read ahead of a slow client.
+    pub download_window_bytes: usize,
+
+    /// Files fetched from the downloader at once while caching.
+    pub cache_fetch_concurrency: usize,
+    /// Files uploaded to Telegram at once while caching.
+    pub cache_upload_concurrency: usize,
+    /// Files a batch, like the update run, caches at once.
+    pub cache_batch_concurrency: usize,
+
+    /// Threads of the runtime background jobs run on, apart from requests.
+    pub job_worker_threads: usize,
+    /// Seconds a job, by name (e.g. `update_cache`), may run before it's
+    /// cancelled; jobs not listed run until they finish.
+    pub job_timeouts: HashMap<String, u64>,
 
     pub bot_tokens: Vec<String>,
     pub temp_channel_id: i64,
 
+    pub bot_api_url: Option<String>,
+    pub max_upload_size: Option<u64>,
+    pub max_cover_size: u64,
+
+    pub media_kinds: HashMap<String, MediaKind>,
+
+    /// `gzip` or `zstd`; stored files are left as they are when unset.
+    /// Only downloads through the API are decompressed, so copies and
+    /// `file_id`s of compressed files carry the compressed bytes, named
+    /// `<name>.gz` or `<name>.zst`.
+    pub compression: Option<String>,
+    pub compressed_object_types: Vec<String>,
+
+    pub backup_chat_id: Option<i64>,
+
+    /// Base64 AES-256 master keys by id; rows keep the id of the key their
+    /// data key is wrapped with, so retired keys stay here until re-cached.
+    pub encryption_keys: HashMap<String, String>,
+    /// The key new uploads are encrypted with; uploads stay plaintext when unset.
+    /// Only downloads through the API are decrypted, so copies and `file_id`s
+    /// of encrypted files carry the ciphertext.
+    pub encryption_key_id: Option<String>,
+
+    pub tenants: HashMap<String, TenantConfig>,
+
+    pub purge_after_days: i64,
+    /// How often the leader purges files deleted over `purge_after_days` ago;
+    /// 0 leaves purging to requests over the API.
+    pub purge_interval_hours: u64,
+
+    /// Failed downloads of a file, within `quarantine_failure_window_hours`
+    /// of each other, after which it's quarantined instead of cached again;
+    /// 0 never quarantines on failures.
+    pub quarantine_after_failures: i32,
+    pub quarantine_failure_window_hours: i32,
+
+    pub snapshot_chat_id: Option<i64>,
+    pub snapshot_interval_hours: NonZeroU64,
+    /// Snapshots are uploaded in parts of at most this many bytes, below the
+    /// Bot API's 50 MB upload limit.
+    pub snapshot_part_bytes: u64,
+    /// The tenant whose telegram_files server reads `SNAPSHOT_CHAT_ID` back.
+    pub snapshot_tenant: String,
+
+    /// Names this replica in leases; random per start when unset.
+    pub instance_id: Option<String>,
+    /// Seconds a lease lasts without renewal, i.e. how long a dead leader
+    /// holds up scheduled tasks.
+    pub lease_ttl_secs: u64,
+    /// How often a replica waiting on another one's upload of the same file
+    /// checks whether it's done.
+    pub cache_claim_poll_ms: u64,
+
+    /// Seconds files being cached get to finish once shutdown starts.
+    pub shutdown_drain_secs: u64,
+    /// Where downloads wait for their upload, so an interrupted job doesn't
+    /// download again; nothing is staged when unset.
+    pub cache_staging_dir: Option<String>,
+
+    /// Seconds after which a cached file is served as is but cached again in
+    /// the background; files are never refreshed when unset.
+    pub revalidate_after_secs: Option<u64>,
+
+    pub cache_stats_interval_secs: NonZeroU64,
+
+    /// What a storage chat can take; admins are warned once a chat reaches
+    /// `chat_limit_warn_ratio` of either, and uploads roll over to the next
+    /// chat once it reaches one.
+    pub chat_message_limit: Option<i64>,
+    pub chat_bytes_limit: Option<i64>,
+    pub chat_limit_warn_ratio: f64,
+
+    pub slow_request_threshold_ms: u64,
+
+    pub json_field_case: String,
+    pub json_envelope: bool,
+    pub public_base_url: Option<String>,
+
+    /// Serves the read-only WebDAV view under `/api/v1/dav/`.
+    pub webdav: bool,
+
+    pub log_filter: String,
+
+    pub metrics_exporter: String,
+    pub statsd_host: String,
+    pub statsd_port: u16,
+    pub statsd_prefix: Option<String>,
+
+    pub pushgateway_url: Option<String>,
+
+    pub nats_url: Option<String>,
+    pub cache_queue_subject: String,
+    pub cache_queue_group: String,
+    pub cache_queue_concurrency: usize,
+    pub event_subjects: HashMap<String, String>,
+    pub webhooks: Vec<Webhook>,
+
+    pub feature_flags: HashMap<String, bool>,
+    pub feature_flags_refresh_secs: NonZeroU64,
+
+    pub self_test: bool,
+    pub self_test_object_id: Option<i32>,
+    pub self_test_object_type: Option<String>,
+
+    pub record_downloads: bool,
+    pub downloads_retention_days: i64,
+
+    pub admin_chat_id: Option<i64>,
+    pub alert_error_threshold: u64,
+    pub alert_window_secs: NonZeroU64,
+
     pub sentry_dsn: String,
 }
 
@@ -28,32 +389,470 @@ fn get_env(env: &'static str) -> String {
     std::env::var(env).unwrap_or_else(|_| panic!("Cannot get the {} env variable", env))
 }
 
+fn get_env_or(env: &'static str, default: &str) -> String {
+    std::env::var(env).unwrap_or_else(|_| default.to_string())
+}
+
+fn get_env_optional(env: &'static str) -> Option<String> {
+    std::env::var(env).ok().filter(|v| !v.is_empty())
+}
+
+/// For rates, caps and intervals, where zero would let nothing through or
+/// never tick; it's refused along with anything else that isn't a positive
+/// integer.
+fn get_positive_env<T: FromStr>(env: &'static str) -> Option<T> {
+    get_env_optional(env).map(|v| parse_positive(env, v))
+}
+
+fn get_positive_env_or<T: FromStr>(env: &'static str, default: &str) -> T {
+    parse_positive(env, get_env_or(env, default))
+}
+
+fn parse_positive<T: FromStr>(env: &'static str, v: String) -> T {
+    v.parse()
+        .unwrap_or_else(|_| panic!("{env} must be a positive integer, got {v:?}"))
+}
+
+/// A window is counted in semaphore permits, which are taken a `u32` at a
+/// time, and one smaller than a chunk would hold a single chunk at most.
+fn get_window_env(env: &'static str, chunk_size: usize) -> usize {
+    let window: usize = get_env_or(env, "1048576").parse().unwrap();
+
+    if window == 0 || window < chunk_size || window > u32::MAX as usize {
+        panic!(
+            "{env} must be between STREAM_CHUNK_SIZE ({chunk_size}) and {}, got {window}",
+            u32::MAX
+        );
+    }
+
+    window
+}
+
+/// Required, unless the upstream is mocked and `mock` can stand in.
+fn get_upstream_env(env: &'static str, mock: Option<&str>) -> String {
+    match mock {
+        Some(mock) => get_env_or(env, mock),
+        None => get_env(env),
+    }
+}
+
 impl Config {
     pub fn load() -> Config {
+        let mock_upstreams: bool = get_env_or("MOCK_UPSTREAMS", "false").parse().unwrap();
+        let mock_upstreams_port: u16 = get_env_or("MOCK_UPSTREAMS_PORT", "8081").parse().unwrap();
+
+        // The fakes in `mock_upstreams` replace every upstream, so their URLs
+        // win over anything configured.
+        let mock_url = |path: &str| {
+            mock_upstreams.then(|| format!("http://127.0.0.1:{mock_upstreams_port}{path}"))
+        };
+        let mock_value = |value: &'static str| mock_upstreams.then_some(value);
+
+        let stream_chunk_size = get_env_or("STREAM_CHUNK_SIZE", "65536").parse().unwrap();
+
         Config {
-            api_key: get_env("API_KEY"),
+            api_keys: serde_json::from_str(&get_env_or("API_KEYS", "[]")).unwrap(),
+            legacy_api_key: get_env_optional("API_KEY"),
+            auth_backend: get_env_or("AUTH_BACKEND", "static"),
+            api_keys_refresh_secs: get_env_or("API_KEYS_REFRESH_SECS", "30").parse().unwrap(),
+            jwt_secret: get_env_optional("JWT_SECRET"),
+            jwt_issuer: get_env_optional("JWT_ISSUER"),
+            jwt_audience: get_env_optional("JWT_AUDIENCE"),
 
             postgres_user: get_env("POSTGRES_USER"),
             postgres_password: get_env("POSTGRES_PASSWORD"),
             postgres_host: get_env("POSTGRES_HOST"),
             postgres_port: get_env("POSTGRES_PORT").parse().unwrap(),
             postgres_db: get_env("POSTGRES_DB"),
+            postgres_replica_url: get_env_optional("POSTGRES_REPLICA_URL"),
+            run_migrations: get_env_or("RUN_MIGRATIONS", "true").parse().unwrap(),
+
+            postgres_max_connections: get_env_or("POSTGRES_MAX_CONNECTIONS", "10")
+                .parse()
+                .unwrap(),
+            postgres_min_connections: get_env_or("POSTGRES_MIN_CONNECTIONS", "0").parse().unwrap(),
+            postgres_acquire_timeout: get_env_or("POSTGRES_ACQUIRE_TIMEOUT", "300")
+                .parse()
+                .unwrap(),
+            postgres_idle_timeout: get_env_or("POSTGRES_IDLE_TIMEOUT", "600").parse().unwrap(),
+            postgres_statement_timeout: get_env_or("POSTGRES_STATEMENT_TIMEOUT", "0")
+                .parse()
+                .unwrap(),
+
+            http_pool_max_idle_per_host: get_env_optional("HTTP_POOL_MAX_IDLE_PER_HOST")
+                .map(|v| v.parse().unwrap()),
+            http_pool_idle_timeout_secs: get_env_or("HTTP_POOL_IDLE_TIMEOUT_SECS", "90")
+                .parse()
+                .unwrap(),
+            http_tcp_keepalive_secs: get_env_or("HTTP_TCP_KEEPALIVE_SECS", "60").parse().unwrap(),
+            http_connect_timeout_secs: get_env_or("HTTP_CONNECT_TIMEOUT_SECS", "10")
+                .parse()
+                .unwrap(),
+            upstream_proxies: serde_json::from_str(&get_env_or("UPSTREAM_PROXIES", "{}")).unwrap(),
+            dns_cache_ttl_secs: get_env_or("DNS_CACHE_TTL_SECS", "60").parse().unwrap(),
+            dns_overrides: serde_json::from_str(&get_env_or("DNS_OVERRIDES", "{}")).unwrap(),
+            upstream_http_versions: serde_json::from_str(&get_env_or(
+                "UPSTREAM_HTTP_VERSIONS",
+                "{}",
+            ))
+            .unwrap(),
+            upstream_tls: serde_json::from_str(&get_env_or("UPSTREAM_TLS", "{}")).unwrap(),
+            http2_keep_alive_interval_secs: get_env_optional("HTTP2_KEEP_ALIVE_INTERVAL_SECS")
+                .map(|v| v.parse().unwrap()),
+            http2_adaptive_window: get_env_or("HTTP2_ADAPTIVE_WINDOW", "false")
+                .parse()
+                .unwrap(),
+
+            mock_upstreams,
+            mock_upstreams_port,
+
+            downloader_api_key: get_upstream_env("DOWNLOADER_API_KEY", mock_value("mock")),
+            downloader_url: mock_url("/downloader").unwrap_or_else(|| get_env("DOWNLOADER_URL")),
+            downloader_fallback_urls: serde_json::from_str(&get_env_or(
+                "DOWNLOADER_FALLBACK_URLS",
+                "[]",
+            ))
+            .unwrap(),
+            conversion_targets: serde_json::from_str(&get_env_or(
+                "CONVERSION_TARGETS",
+                r#"["epub", "mobi", "azw3"]"#,
+            ))
+            .unwrap(),
+            object_type_aliases: serde_json::from_str(&get_env_or("OBJECT_TYPE_ALIASES", "{}"))
+                .unwrap(),
+            custom_object_types: serde_json::from_str(&get_env_or("CUSTOM_OBJECT_TYPES", "[]"))
+                .unwrap(),
+            downloader_health_check_interval_secs: get_positive_env_or(
+                "DOWNLOADER_HEALTH_CHECK_INTERVAL_SECS",
+                "30",
+            ),
+
+            library_api_key: get_upstream_env("LIBRARY_API_KEY", mock_value("mock")),
+            library_url: mock_url("/library").unwrap_or_else(|| get_env("LIBRARY_URL")),
+            library_sources: serde_json::from_str(&get_env_or("LIBRARY_SOURCES", "{}")).unwrap(),
+            book_cache_ttl_secs: get_env_or("BOOK_CACHE_TTL_SECS", "300").parse().unwrap(),
+            book_cache_capacity: get_env_or("BOOK_CACHE_CAPACITY", "10000").parse().unwrap(),
+            book_library_concurrency: get_positive_env_or("BOOK_LIBRARY_CONCURRENCY", "8"),
+
+            files_api_key: get_upstream_env("FILES_SERVER_API_KEY", mock_value("mock")),
+            files_url: mock_url("/files").unwrap_or_else(|| get_env("FILES_SERVER_URL")),
+            files_edit_caption: mock_upstreams
+                || get_env_or("FILES_SERVER_EDIT_CAPTION", "false")
+                    .parse()
+                    .unwrap(),
+            download_resume_attempts: get_env_or("DOWNLOAD_RESUME_ATTEMPTS", "3").parse().unwrap(),
+            download_rate_limit: get_positive_env("DOWNLOAD_RATE_LIMIT"),
+            max_concurrent_downloads: get_positive_env("MAX_CONCURRENT_DOWNLOADS"),
+            download_retry_after_secs: get_env_or("DOWNLOAD_RETRY_AFTER_SECS", "5")
+                .parse()
+                .unwrap(),
+            telegram_rate_limit: get_positive_env("TELEGRAM_RATE_LIMIT"),
+            telegram_op_rate_limits: serde_json::from_str(&get_env_or(
+                "TELEGRAM_OP_RATE_LIMITS",
+                "{}",
+            ))
+            .unwrap(),
+            background_request_budgets: serde_json::from_str(&get_env_or(
+                "BACKGROUND_REQUEST_BUDGETS",
+                "{}",
+            ))
+            .unwrap(),
+            stream_chunk_size,
+            download_window_bytes: get_window_env("DOWNLOAD_WINDOW_BYTES", stream_chunk_size),
+
+            cache_fetch_concurrency: get_env_or("CACHE_FETCH_CONCURRENCY", "4").parse().unwrap(),
+            cache_upload_concurrency: get_env_or("CACHE_UPLOAD_CONCURRENCY", "2").parse().unwrap(),
+            cache_batch_concurrency: get_env_or("CACHE_BATCH_CONCURRENCY", "8").parse().unwrap(),
+
+            job_worker_threads: get_env_or("JOB_WORKER_THREADS", "2").parse().unwrap(),
+            job_timeouts: serde_json::from_str(&get_env_or("JOB_TIMEOUTS", "{}")).unwrap(),
+
+            bot_tokens: serde_json::from_str(&get_upstream_env(
+                "BOT_TOKENS",
+                mock_value(r#"["0:mock"]"#),
+            ))
+            .unwrap(),
+            temp_channel_id: get_upstream_env("TEMP_CHANNEL_ID", mock_value("-1000000000002"))
+                .parse()
+                .unwrap(),
+
+            bot_api_url: mock_url("/").or_else(|| get_env_optional("BOT_API_URL")),
+            max_upload_size: get_env_optional("MAX_UPLOAD_SIZE").map(|v| v.parse().unwrap()),
+            max_cover_size: get_env_or("MAX_COVER_SIZE", "5242880").parse().unwrap(),
+
+            media_kinds: serde_json::from_str(&get_env_or("MEDIA_KINDS", "{}")).unwrap(),
+
+            compression: get_env_optional("COMPRESSION"),
+            compressed_object_types: serde_json::from_str(&get_env_or(
+                "COMPRESSED_OBJECT_TYPES",
+                r#"["fb2"]"#,
+            ))
+            .unwrap(),
+
+            backup_chat_id: get_env_optional("BACKUP_CHAT_ID").map(|v| v.parse().unwrap()),
+
+            encryption_keys: serde_json::from_str(&get_env_or("ENCRYPTION_KEYS", "{}")).unwrap(),
+            encryption_key_id: get_env_optional("ENCRYPTION_KEY_ID"),
+
+            tenants: serde_json::from_str(&get_env_or("TENANTS", "{}")).unwrap(),
+
+            purge_after_days: get_env_or("PURGE_AFTER_DAYS", "7").parse().unwrap(),
+            purge_interval_hours: get_env_or("PURGE_INTERVAL_HOURS", "24").parse().unwrap(),
+
+            quarantine_after_failures: get_env_or("QUARANTINE_AFTER_FAILURES", "3")
+                .parse()
+                .unwrap(),
+            quarantine_failure_window_hours: get_env_or("QUARANTINE_FAILURE_WINDOW_HOURS", "24")
+                .parse()
+                .unwrap(),
+
+            snapshot_chat_id: get_env_optional("SNAPSHOT_CHAT_ID").map(|v| v.parse().unwrap()),
+            snapshot_interval_hours: get_positive_env_or("SNAPSHOT_INTERVAL_HOURS", "24"),
+            snapshot_part_bytes: get_env_or("SNAPSHOT_PART_BYTES", "47185920")
+                .parse()
+                .unwrap(),
+            snapshot_tenant: get_env_or("SNAPSHOT_TENANT", "default"),
+
+            instance_id: get_env_optional("INSTANCE_ID"),
+            lease_ttl_secs: get_env_or("LEASE_TTL_SECS", "30").parse().unwrap(),
+            cache_claim_poll_ms: get_env_or("CACHE_CLAIM_POLL_MS", "500").parse().unwrap(),
+
+            shutdown_drain_secs: get_env_or("SHUTDOWN_DRAIN_SECS", "30").parse().unwrap(),
+            cache_staging_dir: get_env_optional("CACHE_STAGING_DIR"),
+
+            revalidate_after_secs: get_env_optional("REVALIDATE_AFTER_SECS")
+                .map(|v| v.parse().unwrap()),
 
-            downloader_api_key: get_env("DOWNLOADER_API_KEY"),
-            downloader_url: get_env("DOWNLOADER_URL"),
+            log_filter: get_env_or("LOG_FILTER", "info"),
 
-            library_api_key: get_env("LIBRARY_API_KEY"),
-            library_url: get_env("LIBRARY_URL"),
+            metrics_exporter: get_env_or("METRICS_EXPORTER", "prometheus"),
+            statsd_host: get_env_or("STATSD_HOST", "127.0.0.1"),
+            statsd_port: get_env_or("STATSD_PORT", "8125").parse().unwrap(),
+            statsd_prefix: get_env_optional("STATSD_PREFIX"),
 
-            files_api_key: get_env("FILES_SERVER_API_KEY"),
-            files_url: get_env("FILES_SERVER_URL"),
+            pushgateway_url: get_env_optional("PUSHGATEWAY_URL"),
 
-            bot_tokens: serde_json::from_str(&get_env("BOT_TOKENS")).unwrap(),
-            temp_channel_id: get_env("TEMP_CHANNEL_ID").parse().unwrap(),
+            nats_url: get_env_optional("NATS_URL"),
+            cache_queue_subject: get_env_or("CACHE_QUEUE_SUBJECT", "cache.requests"),
+            cache_queue_group: get_env_or("CACHE_QUEUE_GROUP", "telegram_files_cache_server"),
+            cache_queue_concurrency: get_env_or("CACHE_QUEUE_CONCURRENCY", "4").parse().unwrap(),
+            event_subjects: serde_json::from_str(&get_env_or("EVENT_SUBJECTS", "{}")).unwrap(),
+            webhooks: serde_json::from_str(&get_env_or("WEBHOOKS", "[]")).unwrap(),
+
+            feature_flags: serde_json::from_str(&get_env_or("FEATURE_FLAGS", "{}")).unwrap(),
+            feature_flags_refresh_secs: get_positive_env_or("FEATURE_FLAGS_REFRESH_SECS", "30"),
+
+            self_test: get_env_or("SELF_TEST", "false").parse().unwrap(),
+            self_test_object_id: get_env_optional("SELF_TEST_OBJECT_ID")
+                .map(|v| v.parse().unwrap()),
+            self_test_object_type: get_env_optional("SELF_TEST_OBJECT_TYPE"),
+
+            record_downloads: get_env_or("RECORD_DOWNLOADS", "false").parse().unwrap(),
+            downloads_retention_days: get_env_or("DOWNLOADS_RETENTION_DAYS", "90")
+                .parse()
+                .unwrap(),
+
+            admin_chat_id: get_env_optional("ADMIN_CHAT_ID").map(|v| v.parse().unwrap()),
+            alert_error_threshold: get_env_or("ALERT_ERROR_THRESHOLD", "50").parse().unwrap(),
+            alert_window_secs: get_positive_env_or("ALERT_WINDOW_SECS", "300"),
+
+            slow_request_threshold_ms: get_env_or("SLOW_REQUEST_THRESHOLD_MS", "30000")
+                .parse()
+                .unwrap(),
+
+            json_field_case: get_env_or("JSON_FIELD_CASE", "snake_case"),
+            json_envelope: get_env_or("JSON_ENVELOPE", "false").parse().unwrap(),
+            public_base_url: get_env_optional("PUBLIC_BASE_URL"),
+
+            webdav: get_env_or("WEBDAV", "false").parse().unwrap(),
+
+            cache_stats_interval_secs: get_positive_env_or("CACHE_STATS_INTERVAL_SECS", "60"),
+
+            chat_message_limit: get_env_optional("CHAT_MESSAGE_LIMIT").map(|v| v.parse().unwrap()),
+            chat_bytes_limit: get_env_optional("CHAT_BYTES_LIMIT").map(|v| v.parse().unwrap()),
+            chat_limit_warn_ratio: get_env_or("CHAT_LIMIT_WARN_RATIO", "0.9").parse().unwrap(),
 
             sentry_dsn: get_env("SENTRY_DSN"),
         }
     }
 }
 
+/// Bots on the hosted Bot API can't send files over 50 MB; a local
+/// telegram-bot-api server raises that to 2000 MB.
+const HOSTED_BOT_API_UPLOAD_LIMIT: u64 = 50 * 1024 * 1024;
+const LOCAL_BOT_API_UPLOAD_LIMIT: u64 = 2000 * 1024 * 1024;
+
+impl Config {
+    pub fn max_upload_size(&self) -> u64 {
+        self.max_upload_size.unwrap_or(match self.bot_api_url {
+            Some(_) => LOCAL_BOT_API_UPLOAD_LIMIT,
+            None => HOSTED_BOT_API_UPLOAD_LIMIT,
+        })
+    }
+
+    /// The primary downloader followed by its fallbacks, in order of preference.
+    pub fn downloader_urls(&self) -> Vec<String> {
+        std::iter::once(self.downloader_url.clone())
+            .chain(self.downloader_fallback_urls.iter().cloned())
+            .collect()
+    }
+
+    pub fn http_version(&self, upstream: &str) -> HttpVersion {
+        self.upstream_http_versions
+            .get(upstream)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn media_kind(&self, object_type: &str) -> MediaKind {
+        self.media_kinds
+            .get(object_type)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn canonical_object_type(&self, object_type: String) -> ObjectType {
+        match self.object_type_aliases.get(&object_type) {
+            Some(canonical) => ObjectType::from(canonical.as_str()),
+            None => ObjectType::from(object_type),
+        }
+    }
+
+    /// Canonical names for a library's list of types, without duplicates when
+    /// it lists a type under two names.
+    pub fn canonical_object_types(&self, object_types: &[String]) -> Vec<ObjectType> {
+        let mut canonical: Vec<ObjectType> = vec![];
+
+        for object_type in object_types {
+            let object_type = self.canonical_object_type(object_type.clone());
+            if !canonical.contains(&object_type) {
+                canonical.push(object_type);
+            }
+        }
+
+        canonical
+    }
+
+    pub fn slow_request_threshold(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.slow_request_threshold_ms)
+    }
+
+    fn tenant(&self, tenant: &str) -> Option<&TenantConfig> {
+        self.tenants.get(tenant)
+    }
+
+    /// Catalogs may reuse object ids, so each tenant reads from a single one.
+    pub fn library_source(&self, tenant: &str) -> &str {
+        self.tenant(tenant)
+            .and_then(|t| t.library_source.as_deref())
+            .unwrap_or(DEFAULT_LIBRARY_SOURCE)
+    }
+
+    /// Returns the base URL and API key of a library source.
+    pub fn library(&self, source: &str) -> Option<(&str, &str)> {
+        if source == DEFAULT_LIBRARY_SOURCE {
+            return Some((&self.library_url, &self.library_api_key));
+        }
+
+        self.library_sources
+            .get(source)
+            .map(|v| (v.url.as_str(), v.api_key.as_str()))
+    }
+
+    pub fn files_url(&self, tenant: &str) -> &str {
+        self.tenant(tenant)
+            .and_then(|t| t.files_url.as_deref())
+            .unwrap_or(&self.files_url)
+    }
+
+    pub fn files_api_key(&self, tenant: &str) -> &str {
+        self.tenant(tenant)
+            .and_then(|t| t.files_api_key.as_deref())
+            .unwrap_or(&self.files_api_key)
+    }
+
+    pub fn files_edit_caption(&self, tenant: &str) -> bool {
+        self.tenant(tenant)
+            .and_then(|t| t.files_edit_caption)
+            .unwrap_or(self.files_edit_caption)
+    }
+
+    pub fn temp_channel_id(&self, tenant: &str) -> i64 {
+        self.tenant(tenant)
+            .and_then(|t| t.temp_channel_id)
+            .unwrap_or(self.temp_channel_id)
+    }
+
+    pub fn backup_chat_id(&self, tenant: &str) -> Option<i64> {
+        self.tenant(tenant)
+            .and_then(|t| t.backup_chat_id)
+            .or(self.backup_chat_id)
+    }
+
+    /// See [`TenantConfig::upload_chat_id`]; `None` for a tenant without
+    /// settings too.
+    pub fn upload_chat_id(
+        &self,
+        tenant: &str,
+        object_id: i32,
+        is_full: impl Fn(i64) -> bool,
+    ) -> Option<i64> {
+        self.tenant(tenant)?.upload_chat_id(object_id, is_full)
+    }
+}
+
 pub static CONFIG: Lazy<Config> = Lazy::new(Config::load);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant(upload_chat_ids: &[i64], rollover_chat_ids: &[i64]) -> TenantConfig {
+        TenantConfig {
+            upload_chat_ids: upload_chat_ids.to_vec(),
+            rollover_chat_ids: rollover_chat_ids.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn no_upload_chats_leaves_the_choice_to_telegram_files() {
+        assert_eq!(tenant(&[], &[-3]).upload_chat_id(1, |_| false), None);
+    }
+
+    #[test]
+    fn uploads_are_spread_by_object_id() {
+        let tenant = tenant(&[-1, -2], &[-3]);
+
+        assert_eq!(tenant.upload_chat_id(0, |_| false), Some(-1));
+        assert_eq!(tenant.upload_chat_id(1, |_| false), Some(-2));
+        assert_eq!(tenant.upload_chat_id(2, |_| false), Some(-1));
+        assert_eq!(tenant.upload_chat_id(-1, |_| false), Some(-2));
+    }
+
+    #[test]
+    fn a_full_chat_rolls_over_to_the_next_in_line() {
+        let tenant = tenant(&[-1, -2], &[-3, -4]);
+        let is_full = |chat_id| chat_id == -1;
+
+        assert_eq!(tenant.upload_chat_id(0, is_full), Some(-2));
+        assert_eq!(tenant.upload_chat_id(1, is_full), Some(-3));
+    }
+
+    #[test]
+    fn fewer_free_chats_than_upload_chats_narrow_the_spread() {
+        let tenant = tenant(&[-1, -2], &[-3]);
+        let is_full = |chat_id| chat_id != -3;
+
+        assert_eq!(tenant.upload_chat_id(0, is_full), Some(-3));
+        assert_eq!(tenant.upload_chat_id(1, is_full), Some(-3));
+    }
+
+    #[test]
+    fn with_every_chat_full_the_last_ones_keep_taking_uploads() {
+        let tenant = tenant(&[-1, -2], &[-3, -4]);
+
+        assert_eq!(tenant.upload_chat_id(0, |_| true), Some(-3));
+        assert_eq!(tenant.upload_chat_id(1, |_| true), Some(-4));
+    }
+}
@@ -1,6 +1,39 @@
+use std::time::Duration;
+
 use crate::config::CONFIG;
 
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    PgPool,
+};
+
+#[derive(Clone)]
+pub struct Database {
+    pub primary: PgPool,
+    pub replica: PgPool,
+}
+
+impl Database {
+    /// Pool for pure lookups; falls back to the primary when no replica is configured.
+    pub fn reader(&self) -> &PgPool {
+        &self.replica
+    }
+
+    pub fn writer(&self) -> &PgPool {
+        &self.primary
+    }
+}
+
+pub async fn get_database() -> Database {
+    let primary = get_pg_pool().await;
+
+    let replica = match &CONFIG.postgres_replica_url {
+        Some(url) => create_pool(url.parse().unwrap()).await,
+        None => primary.clone(),
+    };
+
+    Database { primary, replica }
+}
 
 pub async fn get_pg_pool() -> PgPool {
     let database_url: String = format!(
@@ -12,10 +45,34 @@ pub async fn get_pg_pool() -> PgPool {
         CONFIG.postgres_db
     );
 
+    create_pool(database_url.parse().unwrap()).await
+}
+
+async fn create_pool(mut connect_options: PgConnectOptions) -> PgPool {
+    // Timeouts are configured in seconds, except statement timeout which is in
+    // milliseconds as Postgres expects; 0 disables idle and statement timeouts.
+    if CONFIG.postgres_statement_timeout > 0 {
+        connect_options = connect_options.options([(
+            "statement_timeout",
+            CONFIG.postgres_statement_timeout.to_string(),
+        )]);
+    }
+
+    let idle_timeout = match CONFIG.postgres_idle_timeout {
+        0 => None,
+        v => Some(Duration::from_secs(v)),
+    };
+
     PgPoolOptions::new()
-        .max_connections(10)
-        .acquire_timeout(std::time::Duration::from_secs(300))
-        .connect(&database_url)
+        .max_connections(CONFIG.postgres_max_connections)
+        .min_connections(CONFIG.postgres_min_connections)
+        .acquire_timeout(Duration::from_secs(CONFIG.postgres_acquire_timeout))
+        .idle_timeout(idle_timeout)
+        .connect_with(connect_options)
         .await
         .unwrap()
 }
+
+pub async fn run_migrations(pool: &PgPool) {
+    sqlx::migrate!("./migrations").run(pool).await.unwrap();
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Guard, Object, Result, Schema,
+    SimpleObject,
+};
+use chrono::Utc;
+
+use crate::{
+    config::{ApiKey, CONFIG},
+    db::Database,
+    repository::{CachedFileRepository, DownloadRepository},
+    serializers::{CacheStats, CachedFile, CachedFileFilter, ChatStats, TopBook},
+    services::{
+        book_library::{get_book, types::BookWithRemote},
+        cache_jobs::{get_cache_jobs, CacheJobProgress},
+        flags::{get_flag_state, Flag, FlagState},
+        rehost::{get_rehost_progress, RehostProgress},
+    },
+    views::parse_period,
+};
+
+pub type CacheSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// Requests carry the caller's key and the database in their context data.
+pub fn build_schema() -> CacheSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(8)
+        .finish()
+}
+
+#[derive(SimpleObject)]
+pub struct Book {
+    pub id: u32,
+    pub title: String,
+    pub lang: String,
+    pub file_type: String,
+    pub uploaded: String,
+    pub authors: String,
+    pub caption: String,
+}
+
+impl From<BookWithRemote> for Book {
+    fn from(book: BookWithRemote) -> Self {
+        Book {
+            id: book.id,
+            title: book.title.clone(),
+            lang: book.lang.clone(),
+            file_type: book.file_type.clone(),
+            uploaded: book.uploaded.clone(),
+            authors: book.get_authors(),
+            caption: book.get_caption(),
+        }
+    }
+}
+
+#[ComplexObject]
+impl CachedFile {
+    /// Current metadata from the library, which may differ from what was
+    /// recorded at caching time.
+    async fn book(&self) -> Result<Book> {
+        let book = get_book(CONFIG.library_source(&self.tenant), self.object_id).await?;
+
+        Ok(book.into())
+    }
+}
+
+/// Fields that reach across tenants or show server-wide state; the same
+/// ones that are admin-only in the REST API.
+struct AdminGuard;
+
+impl Guard for AdminGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        if ctx.data::<Arc<ApiKey>>()?.admin {
+            Ok(())
+        } else {
+            Err("forbidden".into())
+        }
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn cached_files(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<CachedFileFilter>,
+        #[graphql(default = 20)] limit: i64,
+        #[graphql(default = 0)] offset: i64,
+    ) -> Result<Vec<CachedFile>> {
+        let api_key = ctx.data::<Arc<ApiKey>>()?;
+        let db = ctx.data::<Database>()?;
+
+        let cached_files = CachedFileRepository::new(db.clone())
+            .filter(
+                &api_key.tenant,
+                &filter.unwrap_or_default(),
+                limit.clamp(1, 500),
+                offset.max(0),
+            )
+            .await?;
+
+        Ok(cached_files)
+    }
+
+    async fn cached_file(
+        &self,
+        ctx: &Context<'_>,
+        object_id: i32,
+        object_type: String,
+    ) -> Result<Option<CachedFile>> {
+        let filter = CachedFileFilter {
+            object_id: Some(object_id),
+            object_types: Some(vec![object_type]),
+            ..Default::default()
+        };
+
+        Ok(self
+            .cached_files(ctx, Some(filter), 1, 0)
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    /// Live files and bytes per object type, across all tenants.
+    #[graphql(guard = "AdminGuard")]
+    async fn cache_stats(&self, ctx: &Context<'_>) -> Result<Vec<CacheStats>> {
+        let db = ctx.data::<Database>()?;
+
+        Ok(CachedFileRepository::new(db.clone()).get_stats().await?)
+    }
+
+    /// Messages and bytes per storage chat, across all tenants.
+    #[graphql(guard = "AdminGuard")]
+    async fn chat_stats(&self, ctx: &Context<'_>) -> Result<Vec<ChatStats>> {
+        let db = ctx.data::<Database>()?;
+
+        Ok(CachedFileRepository::new(db.clone())
+            .get_chat_stats()
+            .await?)
+    }
+
+    async fn top_books(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = "7d")] period: String,
+        object_type: Option<String>,
+        #[graphql(default = 50)] limit: i64,
+    ) -> Result<Vec<TopBook>> {
+        let api_key = ctx.data::<Arc<ApiKey>>()?;
+        let db = ctx.data::<Database>()?;
+
+        let period = parse_period(&period).ok_or("invalid period")?;
+
+        Ok(DownloadRepository::new(db.clone())
+            .top(
+                &api_key.tenant,
+                Utc::now() - period,
+                object_type,
+                limit.clamp(1, 500),
+            )
+            .await?)
+    }
+
+    #[graphql(guard = "AdminGuard")]
+    async fn cache_jobs(&self) -> Vec<CacheJobProgress> {
+        get_cache_jobs()
+    }
+
+    #[graphql(guard = "AdminGuard")]
+    async fn rehost_progress(&self) -> Option<RehostProgress> {
+        get_rehost_progress()
+    }
+
+    #[graphql(guard = "AdminGuard")]
+    async fn flags(&self) -> Vec<FlagState> {
+        Flag::ALL.iter().map(|flag| get_flag_state(*flag)).collect()
+    }
+}
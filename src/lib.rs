@@ -0,0 +1,32 @@
+//! The cache server as a library: mount [`get_router`] into another axum app,
+//! or call the service layer directly, e.g. [`get_cached_file_or_cache`].
+//! [`ServerBuilder`] takes an existing pool, storage backend, authenticator,
+//! [`CacheHooks`] and route prefix.
+//!
+//! Everything else is still read from the environment, see [`config::CONFIG`].
+
+pub mod auth;
+pub mod build_info;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod config;
+pub mod db;
+pub mod graphql;
+pub mod logging;
+pub mod metrics_exporter;
+pub mod mock_upstreams;
+pub mod negotiation;
+pub mod object_type;
+pub mod repository;
+pub mod self_test;
+pub mod serializers;
+pub mod services;
+pub mod views;
+pub mod webdav;
+
+pub use db::{get_database, Database};
+pub use object_type::ObjectType;
+pub use repository::CachedFileRepository;
+pub use serializers::CachedFile;
+pub use services::{download_from_cache, get_cached_file_or_cache, hooks::CacheHooks};
+pub use views::{get_router, ServerBuilder};
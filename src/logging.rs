@@ -0,0 +1,45 @@
+use once_cell::sync::OnceCell;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+static LOG_FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+/// Builds the global filter layer and keeps a handle so it can be swapped at runtime.
+pub fn reloadable_filter(directives: &str) -> reload::Layer<EnvFilter, Registry> {
+    let (layer, handle) = reload::Layer::new(EnvFilter::new(directives));
+
+    LOG_FILTER
+        .set(handle)
+        .expect("log filter is already initialized");
+
+    layer
+}
+
+pub fn get_log_filter() -> Option<String> {
+    LOG_FILTER
+        .get()?
+        .with_current(|filter| filter.to_string())
+        .ok()
+}
+
+pub fn set_log_filter(directives: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let filter = EnvFilter::try_new(directives)?;
+
+    LOG_FILTER
+        .get()
+        .ok_or("log filter is not initialized")?
+        .reload(filter)?;
+
+    // `log` records are dropped before reaching tracing unless the `log` crate's
+    // own max level is raised along with the filter.
+    tracing::log::set_max_level(match LevelFilter::current() {
+        LevelFilter::OFF => tracing::log::LevelFilter::Off,
+        LevelFilter::ERROR => tracing::log::LevelFilter::Error,
+        LevelFilter::WARN => tracing::log::LevelFilter::Warn,
+        LevelFilter::INFO => tracing::log::LevelFilter::Info,
+        LevelFilter::DEBUG => tracing::log::LevelFilter::Debug,
+        _ => tracing::log::LevelFilter::Trace,
+    });
+
+    Ok(())
+}
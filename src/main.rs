@@ -1,18 +1,18 @@
-pub mod config;
-pub mod db;
-pub mod repository;
-pub mod serializers;
-pub mod services;
-pub mod views;
-
 use dotenvy::dotenv;
-use sentry::{integrations::debug_images::DebugImagesIntegration, types::Dsn, ClientOptions};
+use sentry::{
+    integrations::{debug_images::DebugImagesIntegration, panic::PanicIntegration},
+    types::Dsn,
+    ClientOptions,
+};
 use sentry_tracing::EventFilter;
 use std::{net::SocketAddr, str::FromStr};
 use tracing::info;
-use tracing_subscriber::{filter, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::views::get_router;
+use telegram_files_cache_server::{
+    config, get_router, logging, self_test,
+    services::{drain, notifier::ErrorCountLayer},
+};
 
 #[tokio::main]
 async fn main() {
@@ -21,9 +21,11 @@ async fn main() {
     let options = ClientOptions {
         dsn: Some(Dsn::from_str(&config::CONFIG.sentry_dsn).unwrap()),
         default_integrations: false,
+        release: sentry::release_name!(),
         ..Default::default()
     }
-    .add_integration(DebugImagesIntegration::new());
+    .add_integration(DebugImagesIntegration::new())
+    .add_integration(PanicIntegration::new());
 
     let _guard = sentry::init(options);
 
@@ -33,17 +35,30 @@ async fn main() {
     });
 
     tracing_subscriber::registry()
+        .with(logging::reloadable_filter(&config::CONFIG.log_filter))
         .with(tracing_subscriber::fmt::layer().with_target(false))
-        .with(filter::LevelFilter::INFO)
         .with(sentry_layer)
+        .with(ErrorCountLayer)
         .init();
 
+    if std::env::args().any(|arg| arg == "--self-test") || config::CONFIG.self_test {
+        std::process::exit(self_test::run().await);
+    }
+
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
 
     let app = get_router().await;
 
     info!("Start webserver...");
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let server = axum::serve(listener, app).with_graceful_shutdown(drain::shutdown_signal());
+
+    // Streams to slow clients don't hold the shutdown past the deadline.
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = drain::deadline() => {},
+    }
+
+    drain::finish().await;
     info!("Webserver shutdown...")
 }
@@ -0,0 +1,38 @@
+use axum_prometheus::{
+    metrics::set_global_recorder,
+    metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle},
+    utils::SECONDS_DURATION_BUCKETS,
+};
+use metrics_exporter_statsd::StatsdBuilder;
+
+use crate::config::CONFIG;
+
+pub const EXPORTER_PROMETHEUS: &str = "prometheus";
+pub const EXPORTER_STATSD: &str = "statsd";
+
+/// Installs the global metrics recorder selected by `METRICS_EXPORTER`.
+///
+/// The Prometheus handle is always returned because the HTTP metrics layer needs
+/// one; with statsd it is never fed and `/metrics` isn't mounted.
+pub fn install_metrics_recorder() -> PrometheusHandle {
+    let prometheus = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("_duration_seconds".to_string()),
+            SECONDS_DURATION_BUCKETS,
+        )
+        .unwrap();
+
+    match CONFIG.metrics_exporter.as_str() {
+        EXPORTER_PROMETHEUS => prometheus.install_recorder().unwrap(),
+        EXPORTER_STATSD => {
+            let recorder = StatsdBuilder::from(CONFIG.statsd_host.clone(), CONFIG.statsd_port)
+                .build(CONFIG.statsd_prefix.as_deref())
+                .unwrap();
+
+            set_global_recorder(recorder).unwrap();
+
+            prometheus.build_recorder().handle()
+        }
+        other => panic!("Unknown METRICS_EXPORTER: {other}"),
+    }
+}
@@ -0,0 +1,583 @@
+//! In-process fakes of book_library, the downloader, telegram_files and the
+//! Bot API, so the server runs with none of them. Enabled with
+//! `MOCK_UPSTREAMS`, which points every upstream URL here.
+//!
+//! Books are generated from their id and files from the book and object type,
+//! so results are the same across restarts. "Telegram" keeps messages in
+//! memory and forgets them on restart, like a wiped chat would.
+//!
+//! [`seed`] fills the cache with synthetic files for `POST /api/v1/dev/seed`.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart, Path, Query, Request},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::log;
+
+use crate::{
+    config::CONFIG, repository::CachedFileRepository,
+    services::book_library::types::BookWithRemote, views::Database,
+};
+
+/// Every positive book id exists; the first `MOCK_BOOKS` count as recently
+/// uploaded, so that's what `update_cache` goes through.
+pub const MOCK_BOOKS: u32 = 100;
+pub const MOCK_OBJECT_TYPES: [&str; 2] = ["fb2", "epub"];
+
+/// Files are 16 to 128 KiB depending on the book, so stats have some spread.
+const MOCK_FILE_SIZE_STEP: usize = 16 * 1024;
+
+/// Where telegram_files puts uploads that don't name a chat.
+const MOCK_UPLOAD_CHAT_ID: i64 = -1000000000001;
+
+struct Message {
+    data: Bytes,
+    caption: Option<String>,
+    text: Option<String>,
+}
+
+#[derive(Default)]
+struct Telegram {
+    messages: HashMap<(i64, i64), Message>,
+    last_message_id: i64,
+}
+
+impl Telegram {
+    fn store(&mut self, chat_id: i64, message: Message) -> i64 {
+        self.last_message_id += 1;
+        self.messages
+            .insert((chat_id, self.last_message_id), message);
+
+        self.last_message_id
+    }
+}
+
+static TELEGRAM: Lazy<Mutex<Telegram>> = Lazy::new(Default::default);
+
+/// Serves the fakes on `MOCK_UPSTREAMS_PORT`.
+pub async fn start_mock_upstreams() {
+    let library = Router::new()
+        .route(
+            "/api/v1/sources",
+            get(|| async { Json(json!({ "id": 1 })) }),
+        )
+        .route("/api/v1/books/base/", get(get_books))
+        .route("/api/v1/books/{book_id}", get(get_book))
+        .route(
+            "/api/v1/books/{book_id}/cover",
+            get(|| async { StatusCode::NOT_FOUND }),
+        );
+
+    let downloader = Router::new()
+        .route("/", get(|| async { StatusCode::OK }))
+        .route(
+            "/download/{source_id}/{remote_id}/{object_type}",
+            get(download),
+        )
+        .route("/filename/{object_id}/{object_type}", get(get_filename));
+
+    let files = Router::new()
+        .route("/api/v1/files/upload/", post(upload))
+        .route(
+            "/api/v1/files/download_by_message/{chat_id}/{message_id}",
+            get(download_by_message),
+        )
+        .route("/api/v1/files/edit_caption/", post(edit_caption));
+
+    let router = Router::new()
+        .nest("/library", library)
+        .nest("/downloader", downloader)
+        .nest("/files", files)
+        // teloxide puts the token in the first segment: `/bot{token}/{method}`.
+        .route("/{bot}/{method}", post(bot_api));
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", CONFIG.mock_upstreams_port))
+        .await
+        .unwrap();
+
+    log::warn!(
+        "Upstreams are mocked on port {}, nothing is sent to them",
+        CONFIG.mock_upstreams_port
+    );
+
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+}
+
+fn book(book_id: u32) -> Value {
+    let author_id = book_id % 10 + 1;
+
+    json!({
+        "id": book_id,
+        "remote_id": book_id,
+        "title": format!("Mock book {book_id}"),
+        "lang": "en",
+        "file_type": MOCK_OBJECT_TYPES[0],
+        "uploaded": "2024-01-01",
+        "authors": [{
+            "id": author_id,
+            "first_name": "Mock",
+            "last_name": format!("Author {author_id}"),
+            "middle_name": "",
+        }],
+        "source": { "id": 1 },
+        "available_types": MOCK_OBJECT_TYPES,
+    })
+}
+
+fn book_exists(book_id: u32) -> bool {
+    book_id > 0
+}
+
+async fn get_book(Path(book_id): Path<u32>) -> Response {
+    if !book_exists(book_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    Json(book(book_id)).into_response()
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    page: u32,
+    size: u32,
+}
+
+async fn get_books(Query(PageQuery { page, size }): Query<PageQuery>) -> Json<Value> {
+    let size = size.max(1);
+    let first = (page.max(1) - 1) * size + 1;
+
+    let items: Vec<Value> = (first..first + size)
+        .filter(|book_id| *book_id <= MOCK_BOOKS)
+        .map(|book_id| json!({ "id": book_id, "available_types": MOCK_OBJECT_TYPES }))
+        .collect();
+
+    Json(json!({
+        "items": items,
+        "total": MOCK_BOOKS,
+        "page": page,
+        "size": size,
+        "pages": MOCK_BOOKS.div_ceil(size),
+    }))
+}
+
+/// The same bytes for the same book and type, so deduplication and upload
+/// verification behave as they would for real files.
+fn generate_file(book_id: u32, object_type: &str) -> Bytes {
+    let line = format!("Mock book {book_id}, {object_type}\n");
+
+    let size = MOCK_FILE_SIZE_STEP * (1 + book_id as usize % 8);
+
+    line.bytes().cycle().take(size).collect()
+}
+
+fn mock_filename(book_id: u32, object_type: &str) -> String {
+    format!("mock_book_{book_id}.{object_type}")
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    convert: Option<String>,
+}
+
+async fn download(
+    Path((_source_id, remote_id, object_type)): Path<(u32, u32, String)>,
+    Query(DownloadQuery { convert }): Query<DownloadQuery>,
+) -> Response {
+    if !book_exists(remote_id) {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+
+    let object_type = convert.unwrap_or(object_type);
+    let filename = general_purpose::STANDARD.encode(mock_filename(remote_id, &object_type));
+
+    (
+        [("x-filename-b64-ascii", filename)],
+        generate_file(remote_id, &object_type),
+    )
+        .into_response()
+}
+
+async fn get_filename(Path((object_id, object_type)): Path<(u32, String)>) -> Json<Value> {
+    let filename = mock_filename(object_id, &object_type);
+
+    Json(json!({ "filename": filename, "filename_ascii": filename }))
+}
+
+/// The parts of a multipart form by name.
+async fn read_form(mut multipart: Multipart) -> Result<HashMap<String, Bytes>, StatusCode> {
+    let mut form = HashMap::new();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        let value = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        form.insert(name, value);
+    }
+
+    Ok(form)
+}
+
+fn text_field(form: &HashMap<String, Bytes>, name: &str) -> Option<String> {
+    form.get(name)
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+}
+
+fn parse_field<T: std::str::FromStr>(form: &HashMap<String, Bytes>, name: &str) -> Option<T> {
+    text_field(form, name).and_then(|v| v.parse().ok())
+}
+
+async fn upload(multipart: Multipart) -> Response {
+    let form = match read_form(multipart).await {
+        Ok(v) => v,
+        Err(status) => return status.into_response(),
+    };
+
+    let chat_id = parse_field(&form, "chat_id").unwrap_or(MOCK_UPLOAD_CHAT_ID);
+
+    let message_id = TELEGRAM.lock().unwrap().store(
+        chat_id,
+        Message {
+            data: form.get("file").cloned().unwrap_or_default(),
+            caption: text_field(&form, "caption"),
+            text: None,
+        },
+    );
+
+    Json(json!({
+        "backend": "mock",
+        "data": {
+            "chat_id": chat_id,
+            "message_id": message_id,
+            "file_id": format!("mock-{chat_id}-{message_id}"),
+            "file_unique_id": format!("mock-{message_id}"),
+        },
+    }))
+    .into_response()
+}
+
+fn range_start(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(header::RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes=")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+async fn download_by_message(
+    Path((chat_id, message_id)): Path<(i64, i64)>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(data) = TELEGRAM
+        .lock()
+        .unwrap()
+        .messages
+        .get(&(chat_id, message_id))
+        .map(|message| message.data.clone())
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "detail": "message not found" })),
+        )
+            .into_response();
+    };
+
+    match range_start(&headers) {
+        Some(start) if start < data.len() => (
+            StatusCode::PARTIAL_CONTENT,
+            [(
+                header::CONTENT_RANGE,
+                format!("bytes {start}-{}/{}", data.len() - 1, data.len()),
+            )],
+            data.slice(start..),
+        )
+            .into_response(),
+        Some(_) => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+        None => data.into_response(),
+    }
+}
+
+async fn edit_caption(multipart: Multipart) -> StatusCode {
+    let form = match read_form(multipart).await {
+        Ok(v) => v,
+        Err(status) => return status,
+    };
+
+    let (Some(chat_id), Some(message_id)) = (
+        parse_field(&form, "chat_id"),
+        parse_field(&form, "message_id"),
+    ) else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    match TELEGRAM
+        .lock()
+        .unwrap()
+        .messages
+        .get_mut(&(chat_id, message_id))
+    {
+        Some(message) => {
+            message.caption = text_field(&form, "caption");
+            StatusCode::OK
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+fn bot_ok(result: Value) -> Response {
+    Json(json!({ "ok": true, "result": result })).into_response()
+}
+
+fn bot_error(description: &str) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "ok": false,
+            "error_code": 400,
+            "description": format!("Bad Request: {description}"),
+        })),
+    )
+        .into_response()
+}
+
+/// A message as the Bot API returns it, with only what teloxide requires.
+fn message_json(chat_id: i64, message_id: i64, message: &Message) -> Value {
+    let mut value = json!({
+        "message_id": message_id,
+        "date": Utc::now().timestamp(),
+        "chat": { "id": chat_id, "type": "channel", "title": "mock" },
+    });
+
+    match &message.text {
+        Some(text) => value["text"] = json!(text),
+        None => {
+            value["document"] = json!({
+                "file_id": format!("mock-{chat_id}-{message_id}"),
+                "file_unique_id": format!("mock-{message_id}"),
+                "file_size": message.data.len(),
+            });
+
+            if let Some(caption) = &message.caption {
+                value["caption"] = json!(caption);
+            }
+        }
+    }
+
+    value
+}
+
+/// The methods the server calls; teloxide sends multipart for file uploads
+/// and JSON otherwise. Method names are case-insensitive, as with Telegram.
+async fn bot_api(Path((_bot, method)): Path<(String, String)>, request: Request<Body>) -> Response {
+    let method = method.to_ascii_lowercase();
+
+    if method == "senddocument" {
+        return match Multipart::from_request(request, &()).await {
+            Ok(multipart) => send_document(multipart).await,
+            Err(err) => err.into_response(),
+        };
+    }
+
+    let params = match Json::<Value>::from_request(request, &()).await {
+        Ok(Json(v)) => v,
+        Err(err) => return err.into_response(),
+    };
+
+    let chat_id = params["chat_id"].as_i64().unwrap_or_default();
+    let message_id = params["message_id"].as_i64().unwrap_or_default();
+
+    let mut telegram = TELEGRAM.lock().unwrap();
+
+    match method.as_str() {
+        "copymessage" => {
+            let from_chat_id = params["from_chat_id"].as_i64().unwrap_or_default();
+
+            let Some(message) = telegram.messages.get(&(from_chat_id, message_id)) else {
+                return bot_error("message to copy not found");
+            };
+
+            let copy = Message {
+                data: message.data.clone(),
+                caption: message.caption.clone(),
+                text: message.text.clone(),
+            };
+            let new_message_id = telegram.store(chat_id, copy);
+
+            bot_ok(json!({ "message_id": new_message_id }))
+        }
+        "deletemessage" => match telegram.messages.remove(&(chat_id, message_id)) {
+            Some(_) => bot_ok(json!(true)),
+            None => bot_error("message to delete not found"),
+        },
+        "editmessagecaption" => match telegram.messages.get_mut(&(chat_id, message_id)) {
+            Some(message) => {
+                message.caption = params["caption"].as_str().map(str::to_string);
+                bot_ok(message_json(chat_id, message_id, message))
+            }
+            None => bot_error("message to edit not found"),
+        },
+        "sendmessage" => {
+            let message = Message {
+                data: Bytes::new(),
+                caption: None,
+                text: params["text"].as_str().map(str::to_string),
+            };
+            let message_id = telegram.store(chat_id, message);
+
+            bot_ok(message_json(
+                chat_id,
+                message_id,
+                &telegram.messages[&(chat_id, message_id)],
+            ))
+        }
+        _ => bot_error(&format!("method {method} isn't mocked")),
+    }
+}
+
+async fn send_document(multipart: Multipart) -> Response {
+    let form = match read_form(multipart).await {
+        Ok(v) => v,
+        Err(status) => return status.into_response(),
+    };
+
+    let Some(chat_id) = parse_field(&form, "chat_id") else {
+        return bot_error("chat_id is required");
+    };
+
+    // teloxide sends the file as its own part, referenced by `attach://{name}`.
+    let document = match text_field(&form, "document")
+        .as_deref()
+        .and_then(|v| v.strip_prefix("attach://"))
+    {
+        Some(name) => form.get(name),
+        None => form.get("document"),
+    };
+
+    let message = Message {
+        data: document.cloned().unwrap_or_default(),
+        caption: text_field(&form, "caption"),
+        text: None,
+    };
+
+    let mut telegram = TELEGRAM.lock().unwrap();
+    let message_id = telegram.store(chat_id, message);
+
+    bot_ok(message_json(
+        chat_id,
+        message_id,
+        &telegram.messages[&(chat_id, message_id)],
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct SeedRequest {
+    pub count: u32,
+    /// How many of them to soft-delete long enough ago for `purge_deleted`.
+    #[serde(default)]
+    pub deleted: u32,
+}
+
+#[derive(Serialize)]
+pub struct SeedResult {
+    pub created: u64,
+    pub first_object_id: i32,
+    pub last_object_id: i32,
+}
+
+/// A row for [`CachedFileRepository::create_seeded`].
+pub struct SeededFile {
+    pub object_id: i32,
+    pub object_type: String,
+    pub message_id: i64,
+    pub chat_id: i64,
+    pub title: String,
+    pub authors: String,
+    pub tenant: String,
+    pub content_hash: String,
+    pub file_size: i64,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Caches `count` books after the tenant's highest object id (and after the
+/// ones `update_cache` would fetch), alternating object types, with their
+/// files stored in the fake Telegram so they can be downloaded.
+pub async fn seed(
+    tenant: String,
+    SeedRequest { count, deleted }: SeedRequest,
+    db: Database,
+) -> Result<SeedResult, sqlx::Error> {
+    let cached_file_repo = CachedFileRepository::new(db);
+
+    let first_object_id = cached_file_repo
+        .get_max_object_id(&tenant)
+        .await?
+        .unwrap_or_default()
+        .max(MOCK_BOOKS as i32)
+        + 1;
+
+    let deleted_at = Utc::now() - Duration::days(CONFIG.purge_after_days + 1);
+
+    let seeded_files: Vec<SeededFile> = (0..count)
+        .map(|i| {
+            let object_id = first_object_id + i as i32;
+            let object_type = MOCK_OBJECT_TYPES[i as usize % MOCK_OBJECT_TYPES.len()];
+
+            // Read back like a real response, so titles and authors come out
+            // the way caching would store them.
+            let book: BookWithRemote = serde_json::from_value(book(object_id as u32)).unwrap();
+            let data = generate_file(object_id as u32, object_type);
+
+            let content_hash = hex::encode(Sha256::digest(&data));
+            let file_size = data.len() as i64;
+
+            let message_id = TELEGRAM.lock().unwrap().store(
+                MOCK_UPLOAD_CHAT_ID,
+                Message {
+                    data,
+                    caption: None,
+                    text: None,
+                },
+            );
+
+            SeededFile {
+                object_id,
+                object_type: object_type.to_string(),
+                message_id,
+                chat_id: MOCK_UPLOAD_CHAT_ID,
+                authors: book.get_authors(),
+                title: book.title,
+                tenant: tenant.clone(),
+                content_hash,
+                file_size,
+                deleted_at: (i < deleted).then_some(deleted_at),
+            }
+        })
+        .collect();
+
+    let created = cached_file_repo.create_seeded(&seeded_files).await?;
+
+    Ok(SeedResult {
+        created,
+        first_object_id,
+        last_object_id: first_object_id + count as i32 - 1,
+    })
+}
@@ -0,0 +1,99 @@
+//! MessagePack responses for callers that ask for them with `Accept`; JSON
+//! otherwise.
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use tracing::log;
+
+pub const MSGPACK: &str = "application/msgpack";
+
+const MSGPACK_MEDIA_TYPES: [&str; 3] =
+    [MSGPACK, "application/x-msgpack", "application/vnd.msgpack"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    MessagePack,
+}
+
+/// Whether any entry of an `Accept`-style header list matches; quality values
+/// are only checked for being zero.
+fn accepts_any(header: &str, matches: impl Fn(&str) -> bool) -> bool {
+    header.split(',').any(|entry| {
+        let mut params = entry.split(';').map(str::trim);
+
+        let value = params.next().unwrap_or_default();
+        let rejected = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+
+        !rejected && matches(value)
+    })
+}
+
+/// Whether `Accept-Encoding` takes `coding`, explicitly or through `*`.
+pub fn accepts_encoding(accept_encoding: &str, coding: &str) -> bool {
+    accepts_any(accept_encoding, |v| {
+        v == "*" || v.eq_ignore_ascii_case(coding)
+    })
+}
+
+impl ResponseFormat {
+    /// MessagePack when any acceptable media type names it.
+    pub fn from_accept(accept: &str) -> Self {
+        let accepts_msgpack = accepts_any(accept, |media_type| {
+            MSGPACK_MEDIA_TYPES
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(media_type))
+        });
+
+        if accepts_msgpack {
+            ResponseFormat::MessagePack
+        } else {
+            ResponseFormat::Json
+        }
+    }
+
+    pub fn render<T: Serialize>(self, value: T) -> Response {
+        let mut response = match self {
+            ResponseFormat::Json => Json(value).into_response(),
+            // Named fields keep the payload self-describing, like the JSON.
+            ResponseFormat::MessagePack => match rmp_serde::to_vec_named(&value) {
+                Ok(body) => ([(header::CONTENT_TYPE, MSGPACK)], body).into_response(),
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            },
+        };
+
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept"));
+
+        response
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ResponseFormat {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map(ResponseFormat::from_accept)
+            .unwrap_or(ResponseFormat::Json))
+    }
+}
@@ -0,0 +1,169 @@
+//! The kind of file a row caches: a book format, a cover, or a conversion
+//! between formats. Rows and the library may name any type, so unknown names
+//! are kept as [`ObjectType::Other`]; the API only accepts them when they are
+//! listed in `CUSTOM_OBJECT_TYPES`, so a misspelt type can't become a cache key.
+
+use std::fmt;
+
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    Decode, Encode, Postgres, Type,
+};
+
+use crate::config::CONFIG;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ObjectType {
+    Fb2,
+    Epub,
+    Mobi,
+    Azw3,
+    Pdf,
+    Djvu,
+    Doc,
+    Docx,
+    Rtf,
+    Txt,
+    Html,
+    /// Comes from book_library instead of the downloader.
+    Cover,
+    /// `{source}.{target}`, e.g. `fb2.epub`, so conversions never collide with
+    /// files the source provides natively.
+    Converted(String),
+    Other(String),
+}
+
+const KNOWN: [ObjectType; 12] = [
+    ObjectType::Fb2,
+    ObjectType::Epub,
+    ObjectType::Mobi,
+    ObjectType::Azw3,
+    ObjectType::Pdf,
+    ObjectType::Djvu,
+    ObjectType::Doc,
+    ObjectType::Docx,
+    ObjectType::Rtf,
+    ObjectType::Txt,
+    ObjectType::Html,
+    ObjectType::Cover,
+];
+
+impl ObjectType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ObjectType::Fb2 => "fb2",
+            ObjectType::Epub => "epub",
+            ObjectType::Mobi => "mobi",
+            ObjectType::Azw3 => "azw3",
+            ObjectType::Pdf => "pdf",
+            ObjectType::Djvu => "djvu",
+            ObjectType::Doc => "doc",
+            ObjectType::Docx => "docx",
+            ObjectType::Rtf => "rtf",
+            ObjectType::Txt => "txt",
+            ObjectType::Html => "html",
+            ObjectType::Cover => "cover",
+            ObjectType::Converted(name) | ObjectType::Other(name) => name,
+        }
+    }
+
+    pub fn is_cover(&self) -> bool {
+        *self == ObjectType::Cover
+    }
+
+    /// The source and target types of a conversion.
+    pub fn conversion(&self) -> Option<(&str, &str)> {
+        match self {
+            ObjectType::Converted(name) => name.split_once('.'),
+            _ => None,
+        }
+    }
+
+    /// This type converted to `target`.
+    pub fn converted_to(&self, target: &ObjectType) -> ObjectType {
+        ObjectType::Converted(format!("{self}.{target}"))
+    }
+
+    /// Whether the API takes this type: known ones and `CUSTOM_OBJECT_TYPES`,
+    /// and conversions between those.
+    pub fn is_accepted(&self) -> bool {
+        let is_listed = |name: &str| {
+            KNOWN.iter().any(|v| v.as_str() == name)
+                || CONFIG.custom_object_types.iter().any(|v| v == name)
+        };
+
+        match self {
+            ObjectType::Converted(_) => self
+                .conversion()
+                .is_some_and(|(source, target)| is_listed(source) && is_listed(target)),
+            ObjectType::Other(name) => is_listed(name),
+            _ => true,
+        }
+    }
+}
+
+impl From<String> for ObjectType {
+    fn from(value: String) -> Self {
+        if let Some(known) = KNOWN.iter().find(|v| v.as_str() == value) {
+            return known.clone();
+        }
+
+        if value.contains('.') {
+            ObjectType::Converted(value)
+        } else {
+            ObjectType::Other(value)
+        }
+    }
+}
+
+impl From<&str> for ObjectType {
+    fn from(value: &str) -> Self {
+        ObjectType::from(value.to_string())
+    }
+}
+
+impl fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl serde::Serialize for ObjectType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ObjectType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(ObjectType::from(String::deserialize(deserializer)?))
+    }
+}
+
+async_graphql::scalar!(ObjectType);
+
+impl Type<Postgres> for ObjectType {
+    fn type_info() -> PgTypeInfo {
+        <String as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <String as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Postgres> for ObjectType {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        <&str as Encode<Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for ObjectType {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(ObjectType::from(<String as Decode<Postgres>>::decode(
+            value,
+        )?))
+    }
+}
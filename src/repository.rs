@@ -1,4 +1,25 @@
-use crate::{serializers::CachedFile, views::Database};
+use futures::stream::BoxStream;
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::{
+    mock_upstreams::SeededFile,
+    object_type::ObjectType,
+    serializers::{
+        AccessTimes, ApiKeyUsageTotals, AuditLogEntry, CacheStats, CachedFile, CachedFileFilter,
+        ChatStats, DownloadEntry, FeatureFlag, PendingCacheJob, StoredApiKey, TimeseriesPoint,
+        TopBook, UsageRow,
+    },
+    services::{downloads::DownloadRecord, usage::UsageDelta},
+    views::Database,
+};
+
+/// Makes user input match literally inside a LIKE pattern.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
 
 pub struct CachedFileRepository {
     db: Database,
@@ -11,20 +32,1387 @@ impl CachedFileRepository {
 
     pub async fn delete_by_object_id_object_type(
         &self,
+        tenant: String,
+        object_id: i32,
+        object_type: ObjectType,
+    ) -> Result<CachedFile, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            UPDATE cached_files
+            SET deleted_at = now()
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3 AND deleted_at IS NULL
+            RETURNING *
+            "#,
+            tenant,
+            object_id,
+            object_type.as_str()
+        )
+        .fetch_one(self.db.writer())
+        .await
+    }
+
+    /// Brings back the most recently deleted row, as long as it hasn't been
+    /// purged and no file has been cached in its place.
+    pub async fn undelete(
+        &self,
+        tenant: &str,
+        object_id: i32,
+        object_type: &ObjectType,
+    ) -> Result<Option<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            UPDATE cached_files
+            SET deleted_at = NULL
+            WHERE object_id = $2 AND id = (
+                SELECT id FROM cached_files
+                WHERE tenant = $1 AND object_id = $2 AND object_type = $3
+                  AND deleted_at IS NOT NULL
+                ORDER BY deleted_at DESC
+                LIMIT 1
+            )
+            RETURNING *
+            "#,
+            tenant,
+            object_id,
+            object_type.as_str()
+        )
+        .fetch_optional(self.db.writer())
+        .await
+    }
+
+    /// Holds a live file back from serving; `None` if it's gone or already
+    /// quarantined.
+    pub async fn quarantine(
+        &self,
+        id: i32,
+        object_id: i32,
+        reason: &str,
+    ) -> Result<Option<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            UPDATE cached_files
+            SET quarantined_at = now(), quarantine_reason = $3
+            WHERE id = $1 AND object_id = $2 AND deleted_at IS NULL AND quarantined_at IS NULL
+            RETURNING *
+            "#,
+            id,
+            object_id,
+            reason
+        )
+        .fetch_optional(self.db.writer())
+        .await
+    }
+
+    pub async fn release_quarantine(
+        &self,
+        tenant: &str,
+        object_id: i32,
+        object_type: &ObjectType,
+    ) -> Result<Option<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            UPDATE cached_files
+            SET quarantined_at = NULL, quarantine_reason = NULL
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3
+              AND deleted_at IS NULL AND quarantined_at IS NOT NULL
+            RETURNING *
+            "#,
+            tenant,
+            object_id,
+            object_type.as_str()
+        )
+        .fetch_optional(self.db.writer())
+        .await
+    }
+
+    pub async fn find_quarantined(
+        &self,
+        tenant: &str,
+        object_id: i32,
+        object_type: &ObjectType,
+    ) -> Result<Option<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            SELECT * FROM cached_files
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3
+              AND deleted_at IS NULL AND quarantined_at IS NOT NULL
+            "#,
+            tenant,
+            object_id,
+            object_type.as_str()
+        )
+        .fetch_optional(self.db.reader())
+        .await
+    }
+
+    /// Quarantined files, oldest first.
+    pub async fn get_quarantined(
+        &self,
+        tenant: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            SELECT * FROM cached_files
+            WHERE tenant = $1 AND deleted_at IS NULL AND quarantined_at IS NOT NULL
+            ORDER BY quarantined_at
+            LIMIT $2 OFFSET $3
+            "#,
+            tenant,
+            limit,
+            offset
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    /// Soft-deletes the files converted from the given one.
+    pub async fn delete_derived(
+        &self,
+        source_cached_file_id: i32,
         object_id: i32,
-        object_type: String,
+    ) -> Result<Vec<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            UPDATE cached_files
+            SET deleted_at = now()
+            WHERE object_id = $2 AND source_cached_file_id = $1 AND deleted_at IS NULL
+            RETURNING *
+            "#,
+            source_cached_file_id,
+            object_id
+        )
+        .fetch_all(self.db.writer())
+        .await
+    }
+
+    /// Leaves a field as is when `None` is passed for it.
+    pub async fn update_metadata(
+        &self,
+        id: i32,
+        object_id: i32,
+        title: Option<String>,
+        authors: Option<String>,
     ) -> Result<CachedFile, sqlx::Error> {
         sqlx::query_as!(
             CachedFile,
+            r#"
+            UPDATE cached_files
+            SET title = COALESCE($3, title), authors = COALESCE($4, authors)
+            WHERE id = $1 AND object_id = $2
+            RETURNING *
+            "#,
+            id,
+            object_id,
+            title,
+            authors
+        )
+        .fetch_one(self.db.writer())
+        .await
+    }
+
+    pub async fn search(
+        &self,
+        tenant: &str,
+        query: &str,
+        limit: i64,
+    ) -> Result<Vec<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            SELECT * FROM cached_files
+            WHERE tenant = $1 AND deleted_at IS NULL
+              AND (title % $2 OR authors % $2 OR title ILIKE '%' || $2 || '%')
+            ORDER BY GREATEST(similarity(title, $2), similarity(authors, $2)) DESC, id
+            LIMIT $3
+            "#,
+            tenant,
+            query,
+            limit
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    pub async fn get_access_times(&self, ids: &[i32]) -> Result<Vec<AccessTimes>, sqlx::Error> {
+        sqlx::query_as!(
+            AccessTimes,
+            r#"
+            SELECT
+                c.id AS cached_file_id,
+                (
+                    SELECT MAX(d.created_at) FROM downloads d
+                    WHERE d.object_id = c.object_id AND d.object_type = c.object_type
+                      AND d.tenant = c.tenant
+                ) AS last_accessed
+            FROM cached_files c
+            WHERE c.id = ANY($1)
+            "#,
+            ids
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    pub async fn get_deleted_before(
+        &self,
+        deleted_before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            SELECT * FROM cached_files
+            WHERE deleted_at IS NOT NULL AND deleted_at < $1
+            "#,
+            deleted_before
+        )
+        .fetch_all(self.db.writer())
+        .await
+    }
+
+    pub async fn remap_chat(
+        &self,
+        tenant: &str,
+        from_chat_id: i64,
+        to_chat_id: i64,
+    ) -> Result<u64, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE cached_files
+            SET chat_id = CASE WHEN chat_id = $2 THEN $3 ELSE chat_id END,
+                replica_chat_id = CASE
+                    WHEN replica_chat_id = $2 THEN $3 ELSE replica_chat_id
+                END
+            WHERE tenant = $1 AND (chat_id = $2 OR replica_chat_id = $2)
+            "#,
+            tenant,
+            from_chat_id,
+            to_chat_id
+        )
+        .execute(self.db.writer())
+        .await
+        .map(|v| v.rows_affected())
+    }
+
+    pub async fn find_by_content_hash(
+        &self,
+        tenant: &str,
+        content_hash: &str,
+    ) -> Result<Option<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            SELECT * FROM cached_files
+            WHERE tenant = $1 AND content_hash = $2 AND deleted_at IS NULL
+              AND quarantined_at IS NULL
+            ORDER BY id
+            LIMIT 1
+            "#,
+            tenant,
+            content_hash
+        )
+        .fetch_optional(self.db.writer())
+        .await
+    }
+
+    /// Counts other rows (soft-deleted or not) still pointing at the same
+    /// message, as their stored copy or as its replica.
+    pub async fn count_message_references(
+        &self,
+        id: i32,
+        chat_id: i64,
+        message_id: i64,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!" FROM cached_files
+            WHERE id != $1
+              AND (
+                  (chat_id = $2 AND message_id = $3)
+                  OR (replica_chat_id = $2 AND replica_message_id = $3)
+              )
+            "#,
+            id,
+            chat_id,
+            message_id
+        )
+        .fetch_one(self.db.writer())
+        .await
+    }
+
+    pub async fn get_by_chat(
+        &self,
+        tenant: &str,
+        chat_id: i64,
+    ) -> Result<Vec<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            SELECT * FROM cached_files
+            WHERE tenant = $1 AND chat_id = $2 AND deleted_at IS NULL
+            ORDER BY id
+            "#,
+            tenant,
+            chat_id
+        )
+        .fetch_all(self.db.writer())
+        .await
+    }
+
+    pub async fn update_location(
+        &self,
+        id: i32,
+        object_id: i32,
+        chat_id: i64,
+        message_id: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE cached_files
+            SET chat_id = $3, message_id = $4
+            WHERE id = $1 AND object_id = $2
+            "#,
+            id,
+            object_id,
+            chat_id,
+            message_id
+        )
+        .execute(self.db.writer())
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn get_stats(&self) -> Result<Vec<CacheStats>, sqlx::Error> {
+        sqlx::query_as!(
+            CacheStats,
+            r#"
+            SELECT
+                object_type,
+                COUNT(*) AS "files!",
+                COALESCE(SUM(file_size), 0)::bigint AS "bytes!"
+            FROM cached_files
+            WHERE deleted_at IS NULL
+            GROUP BY object_type
+            "#
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    /// Messages and bytes per chat, replicas included.
+    pub async fn get_chat_stats(&self) -> Result<Vec<ChatStats>, sqlx::Error> {
+        sqlx::query_as!(
+            ChatStats,
+            r#"
+            WITH messages AS (
+                SELECT chat_id, message_id, MAX(file_size) AS file_size
+                FROM cached_files
+                GROUP BY chat_id, message_id
+                UNION ALL
+                SELECT replica_chat_id, replica_message_id, MAX(file_size)
+                FROM cached_files
+                WHERE replica_chat_id IS NOT NULL AND replica_message_id IS NOT NULL
+                GROUP BY replica_chat_id, replica_message_id
+            )
+            SELECT
+                chat_id AS "chat_id!",
+                COUNT(*) AS "messages!",
+                COALESCE(SUM(file_size), 0)::bigint AS "bytes!"
+            FROM messages
+            GROUP BY chat_id
+            ORDER BY chat_id
+            "#
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    /// Newest first. Built at runtime since every filter is optional.
+    pub async fn filter(
+        &self,
+        tenant: &str,
+        filter: &CachedFileFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<CachedFile>, sqlx::Error> {
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM cached_files WHERE tenant = ");
+        query.push_bind(tenant);
+
+        if !filter.include_deleted {
+            query.push(" AND deleted_at IS NULL");
+        }
+        if let Some(object_id) = filter.object_id {
+            query.push(" AND object_id = ").push_bind(object_id);
+        }
+        if let Some(object_types) = &filter.object_types {
+            query
+                .push(" AND object_type = ANY(")
+                .push_bind(object_types)
+                .push(")");
+        }
+        if let Some(source_id) = filter.source_id {
+            query.push(" AND source_id = ").push_bind(source_id);
+        }
+        if let Some(chat_id) = filter.chat_id {
+            query.push(" AND chat_id = ").push_bind(chat_id);
+        }
+        if let Some(title) = &filter.title {
+            query
+                .push(" AND title ILIKE ")
+                .push_bind(format!("%{}%", escape_like(title)));
+        }
+        if let Some(authors) = &filter.authors {
+            query
+                .push(" AND authors ILIKE ")
+                .push_bind(format!("%{}%", escape_like(authors)));
+        }
+        if let Some(min_file_size) = filter.min_file_size {
+            query.push(" AND file_size >= ").push_bind(min_file_size);
+        }
+        if let Some(max_file_size) = filter.max_file_size {
+            query.push(" AND file_size <= ").push_bind(max_file_size);
+        }
+
+        query.push(" ORDER BY id DESC LIMIT ").push_bind(limit);
+        query.push(" OFFSET ").push_bind(offset);
+
+        query
+            .build_query_as::<CachedFile>()
+            .fetch_all(self.db.reader())
+            .await
+    }
+
+    pub async fn get_by_object_id(
+        &self,
+        tenant: &str,
+        object_id: i32,
+    ) -> Result<Vec<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            SELECT * FROM cached_files
+            WHERE tenant = $1 AND object_id = $2 AND deleted_at IS NULL AND quarantined_at IS NULL
+            ORDER BY object_type
+            "#,
+            tenant,
+            object_id
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    // The WebDAV directory names below follow `webdav::dav_name`.
+
+    pub async fn get_dav_authors(&self, tenant: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT replace(COALESCE(NULLIF(authors, ''), 'Unknown'), '/', '_') AS "author!"
+            FROM cached_files
+            WHERE tenant = $1 AND deleted_at IS NULL AND quarantined_at IS NULL
+                AND object_type <> 'cover'
+            ORDER BY 1
+            "#,
+            tenant
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    pub async fn get_dav_titles(
+        &self,
+        tenant: &str,
+        author: &str,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT replace(COALESCE(NULLIF(title, ''), 'Untitled'), '/', '_') AS "title!"
+            FROM cached_files
+            WHERE tenant = $1 AND deleted_at IS NULL AND quarantined_at IS NULL
+                AND object_type <> 'cover'
+                AND replace(COALESCE(NULLIF(authors, ''), 'Unknown'), '/', '_') = $2
+            ORDER BY 1
+            "#,
+            tenant,
+            author
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    pub async fn get_dav_files(
+        &self,
+        tenant: &str,
+        author: &str,
+        title: &str,
+    ) -> Result<Vec<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(
+            CachedFile,
+            r#"
+            SELECT * FROM cached_files
+            WHERE tenant = $1 AND deleted_at IS NULL AND quarantined_at IS NULL
+                AND object_type <> 'cover'
+                AND replace(COALESCE(NULLIF(authors, ''), 'Unknown'), '/', '_') = $2
+                AND replace(COALESCE(NULLIF(title, ''), 'Untitled'), '/', '_') = $3
+            ORDER BY object_id, object_type
+            "#,
+            tenant,
+            author,
+            title
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    pub async fn get_all(&self) -> Result<Vec<CachedFile>, sqlx::Error> {
+        sqlx::query_as!(CachedFile, r#"SELECT * FROM cached_files ORDER BY id"#)
+            .fetch_all(self.db.reader())
+            .await
+    }
+
+    /// Every row, read as the stream is consumed rather than all at once.
+    pub fn stream_all(&self) -> BoxStream<'_, Result<CachedFile, sqlx::Error>> {
+        sqlx::query_as!(CachedFile, r#"SELECT * FROM cached_files ORDER BY id"#)
+            .fetch(self.db.reader())
+    }
+
+    /// Inserts rows with their original ids, skipping ids that already exist.
+    pub async fn restore(&self, cached_files: &[CachedFile]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.db.writer().begin().await?;
+
+        let mut restored = 0;
+
+        for cached_file in cached_files {
+            restored += sqlx::query!(
+                r#"
+                INSERT INTO cached_files
+                    (id, object_id, object_type, message_id, chat_id, deleted_at,
+                     title, authors, source_id, tenant, replica_chat_id, replica_message_id,
+                     content_hash, file_size, file_id, file_unique_id, source_cached_file_id,
+                     encryption_key_id, encrypted_key, encryption_nonce, compression, cached_at,
+                     quarantined_at, quarantine_reason)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16,
+                        $17, $18, $19, $20, $21, $22, $23, $24)
+                ON CONFLICT DO NOTHING
+                "#,
+                cached_file.id,
+                cached_file.object_id,
+                cached_file.object_type.as_str(),
+                cached_file.message_id,
+                cached_file.chat_id,
+                cached_file.deleted_at,
+                cached_file.title,
+                cached_file.authors,
+                cached_file.source_id,
+                cached_file.tenant,
+                cached_file.replica_chat_id,
+                cached_file.replica_message_id,
+                cached_file.content_hash,
+                cached_file.file_size,
+                cached_file.file_id,
+                cached_file.file_unique_id,
+                cached_file.source_cached_file_id,
+                cached_file.encryption_key_id,
+                cached_file.encrypted_key,
+                cached_file.encryption_nonce,
+                cached_file.compression,
+                cached_file.cached_at,
+                cached_file.quarantined_at,
+                cached_file.quarantine_reason
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+
+        sqlx::query!(
+            r#"
+            SELECT setval(
+                pg_get_serial_sequence('cached_files', 'id'),
+                (SELECT COALESCE(MAX(id), 1) FROM cached_files)
+            )
+            "#
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(restored)
+    }
+
+    pub async fn get_max_object_id(&self, tenant: &str) -> Result<Option<i32>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"SELECT MAX(object_id) FROM cached_files WHERE tenant = $1"#,
+            tenant
+        )
+        .fetch_one(self.db.reader())
+        .await
+    }
+
+    pub async fn create_seeded(&self, seeded_files: &[SeededFile]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.db.writer().begin().await?;
+
+        let mut created = 0;
+
+        for seeded_file in seeded_files {
+            created += sqlx::query!(
+                r#"
+                INSERT INTO cached_files
+                    (object_id, object_type, message_id, chat_id, title, authors, tenant,
+                     content_hash, file_size, deleted_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                ON CONFLICT DO NOTHING
+                "#,
+                seeded_file.object_id,
+                seeded_file.object_type,
+                seeded_file.message_id,
+                seeded_file.chat_id,
+                seeded_file.title,
+                seeded_file.authors,
+                seeded_file.tenant,
+                seeded_file.content_hash,
+                seeded_file.file_size,
+                seeded_file.deleted_at
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+
+        tx.commit().await?;
+
+        Ok(created)
+    }
+
+    pub async fn purge(&self, id: i32, object_id: i32) -> Result<(), sqlx::Error> {
+        sqlx::query!(
             r#"
             DELETE FROM cached_files
-            WHERE object_id = $1 AND object_type = $2
+            WHERE id = $1 AND object_id = $2 AND deleted_at IS NOT NULL
+            "#,
+            id,
+            object_id
+        )
+        .execute(self.db.writer())
+        .await
+        .map(|_| ())
+    }
+}
+
+pub struct AuditLogRepository {
+    db: Database,
+}
+
+impl AuditLogRepository {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(
+        &self,
+        actor: &str,
+        tenant: &str,
+        action: &str,
+        object_id: i32,
+        object_type: &str,
+        cached_file_id: Option<i32>,
+        result: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_log
+                (actor, tenant, action, object_id, object_type, cached_file_id, result)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            actor,
+            tenant,
+            action,
+            object_id,
+            object_type,
+            cached_file_id,
+            result
+        )
+        .execute(self.db.writer())
+        .await
+        .map(|_| ())
+    }
+
+    pub fn stream_all(&self) -> BoxStream<'_, Result<AuditLogEntry, sqlx::Error>> {
+        sqlx::query_as!(AuditLogEntry, r#"SELECT * FROM audit_log ORDER BY id"#)
+            .fetch(self.db.reader())
+    }
+
+    pub async fn restore(&self, entries: &[AuditLogEntry]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.db.writer().begin().await?;
+
+        let mut restored = 0;
+
+        for entry in entries {
+            restored += sqlx::query!(
+                r#"
+                INSERT INTO audit_log (
+                    id, created_at, actor, action, object_id, object_type, cached_file_id,
+                    result, tenant
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                ON CONFLICT DO NOTHING
+                "#,
+                entry.id,
+                entry.created_at,
+                entry.actor,
+                entry.action,
+                entry.object_id,
+                entry.object_type,
+                entry.cached_file_id,
+                entry.result,
+                entry.tenant
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+
+        sqlx::query!(
+            r#"
+            SELECT setval(
+                pg_get_serial_sequence('audit_log', 'id'),
+                (SELECT COALESCE(MAX(id), 1) FROM audit_log)
+            )
+            "#
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(restored)
+    }
+
+    pub async fn timeseries(
+        &self,
+        tenant: &str,
+        actor: &str,
+        action: &str,
+        since: chrono::DateTime<chrono::Utc>,
+        interval_secs: i64,
+    ) -> Result<Vec<TimeseriesPoint>, sqlx::Error> {
+        sqlx::query_as!(
+            TimeseriesPoint,
+            r#"
+            SELECT
+                to_timestamp(floor(extract(epoch FROM created_at) / $5) * $5) AS "bucket!",
+                COUNT(*) AS "count!"
+            FROM audit_log
+            WHERE tenant = $1 AND actor = $2 AND action = $3 AND created_at >= $4
+            GROUP BY 1
+            ORDER BY 1
+            "#,
+            tenant,
+            actor,
+            action,
+            since,
+            interval_secs as f64
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    /// Entries of `tenant`, or of every tenant when `None`.
+    pub async fn list(
+        &self,
+        tenant: Option<&str>,
+        object_id: Option<i32>,
+        object_type: Option<String>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<AuditLogEntry>, sqlx::Error> {
+        sqlx::query_as!(
+            AuditLogEntry,
+            r#"
+            SELECT * FROM audit_log
+            WHERE ($1::varchar IS NULL OR tenant = $1)
+              AND ($2::int IS NULL OR object_id = $2)
+              AND ($3::varchar IS NULL OR object_type = $3)
+            ORDER BY created_at DESC, id DESC
+            LIMIT $4 OFFSET $5
+            "#,
+            tenant,
+            object_id,
+            object_type,
+            limit,
+            offset
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+}
+
+pub struct ApiKeyRepository {
+    db: Database,
+}
+
+impl ApiKeyRepository {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    pub async fn get_all(&self) -> Result<Vec<StoredApiKey>, sqlx::Error> {
+        sqlx::query_as!(StoredApiKey, r#"SELECT * FROM api_keys ORDER BY name"#)
+            .fetch_all(self.db.reader())
+            .await
+    }
+}
+
+pub struct ApiKeyUsageRepository {
+    db: Database,
+}
+
+impl ApiKeyUsageRepository {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    pub async fn get_totals(&self, api_key: &str) -> Result<ApiKeyUsageTotals, sqlx::Error> {
+        sqlx::query_as!(
+            ApiKeyUsageTotals,
+            r#"
+            SELECT
+                COALESCE(SUM(files_cached) FILTER (WHERE day = (now() AT TIME ZONE 'utc')::date), 0)::bigint AS "files_cached_today!",
+                COALESCE(SUM(bytes_served) FILTER (WHERE day = (now() AT TIME ZONE 'utc')::date), 0)::bigint AS "bytes_served_today!",
+                COALESCE(SUM(bytes_served), 0)::bigint AS "bytes_served_month!"
+            FROM api_key_usage
+            WHERE api_key = $1
+              AND day >= date_trunc('month', now() AT TIME ZONE 'utc')::date
+            "#,
+            api_key
+        )
+        .fetch_one(self.db.reader())
+        .await
+    }
+
+    pub async fn add(
+        &self,
+        api_key: &str,
+        tenant: &str,
+        object_type: &str,
+        delta: &UsageDelta,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO api_key_usage (
+                api_key, tenant, day, object_type, requests, cache_misses, files_cached,
+                bytes_served
+            )
+            VALUES ($1, $2, (now() AT TIME ZONE 'utc')::date, $3, $4, $5, $6, $7)
+            ON CONFLICT (api_key, day, object_type) DO UPDATE
+            SET requests = api_key_usage.requests + EXCLUDED.requests,
+                cache_misses = api_key_usage.cache_misses + EXCLUDED.cache_misses,
+                files_cached = api_key_usage.files_cached + EXCLUDED.files_cached,
+                bytes_served = api_key_usage.bytes_served + EXCLUDED.bytes_served
+            "#,
+            api_key,
+            tenant,
+            object_type,
+            delta.requests,
+            delta.cache_misses,
+            delta.files_cached,
+            delta.bytes_served
+        )
+        .execute(self.db.writer())
+        .await
+        .map(|_| ())
+    }
+
+    /// Usage of `tenant`'s keys, or of every key when `None`.
+    pub async fn list(
+        &self,
+        tenant: Option<&str>,
+        from: chrono::NaiveDate,
+        to: chrono::NaiveDate,
+    ) -> Result<Vec<UsageRow>, sqlx::Error> {
+        sqlx::query_as!(
+            UsageRow,
+            r#"
+            SELECT day, api_key, object_type, requests, cache_misses, files_cached, bytes_served
+            FROM api_key_usage
+            WHERE ($1::varchar IS NULL OR tenant = $1) AND day >= $2 AND day <= $3
+            ORDER BY day, api_key, object_type
+            "#,
+            tenant,
+            from,
+            to
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+}
+
+pub struct DownloadRepository {
+    db: Database,
+}
+
+impl DownloadRepository {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    pub async fn create(&self, download: &DownloadRecord) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO downloads
+                (tenant, api_key, object_id, object_type, bytes, duration_ms, client)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            download.tenant,
+            download.api_key,
+            download.object_id,
+            download.object_type,
+            download.bytes,
+            download.duration_ms,
+            download.client
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Downloads in `tenant`, or in every tenant when `None`.
+    pub async fn list(
+        &self,
+        tenant: Option<&str>,
+        object_id: Option<i32>,
+        object_type: Option<String>,
+        api_key: Option<String>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<DownloadEntry>, sqlx::Error> {
+        sqlx::query_as!(
+            DownloadEntry,
+            r#"
+            SELECT * FROM downloads
+            WHERE ($1::varchar IS NULL OR tenant = $1)
+              AND ($2::int IS NULL OR object_id = $2)
+              AND ($3::varchar IS NULL OR object_type = $3)
+              AND ($4::varchar IS NULL OR api_key = $4)
+            ORDER BY created_at DESC, id DESC
+            LIMIT $5 OFFSET $6
+            "#,
+            tenant,
+            object_id,
+            object_type,
+            api_key,
+            limit,
+            offset
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    pub async fn top(
+        &self,
+        tenant: &str,
+        since: chrono::DateTime<chrono::Utc>,
+        object_type: Option<String>,
+        limit: i64,
+    ) -> Result<Vec<TopBook>, sqlx::Error> {
+        sqlx::query_as!(
+            TopBook,
+            r#"
+            SELECT
+                d.object_id,
+                (
+                    SELECT title FROM cached_files
+                    WHERE object_id = d.object_id AND tenant = $1 AND title IS NOT NULL
+                    LIMIT 1
+                ) AS title,
+                COUNT(*) AS "downloads!"
+            FROM downloads d
+            WHERE d.tenant = $1
+              AND d.created_at >= $2
+              AND ($3::varchar IS NULL OR d.object_type = $3)
+            GROUP BY d.object_id
+            ORDER BY "downloads!" DESC, d.object_id
+            LIMIT $4
+            "#,
+            tenant,
+            since,
+            object_type,
+            limit
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    pub async fn timeseries(
+        &self,
+        tenant: &str,
+        since: chrono::DateTime<chrono::Utc>,
+        interval_secs: i64,
+    ) -> Result<Vec<TimeseriesPoint>, sqlx::Error> {
+        sqlx::query_as!(
+            TimeseriesPoint,
+            r#"
+            SELECT
+                to_timestamp(floor(extract(epoch FROM created_at) / $3) * $3) AS "bucket!",
+                COUNT(*) AS "count!"
+            FROM downloads
+            WHERE tenant = $1 AND created_at >= $2
+            GROUP BY 1
+            ORDER BY 1
+            "#,
+            tenant,
+            since,
+            interval_secs as f64
+        )
+        .fetch_all(self.db.reader())
+        .await
+    }
+
+    pub async fn delete_before(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(r#"DELETE FROM downloads WHERE created_at < $1"#, before)
+            .execute(self.db.writer())
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+pub struct FeatureFlagRepository {
+    db: Database,
+}
+
+impl FeatureFlagRepository {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    pub async fn get_all(&self) -> Result<Vec<FeatureFlag>, sqlx::Error> {
+        sqlx::query_as!(FeatureFlag, r#"SELECT * FROM feature_flags ORDER BY name"#)
+            .fetch_all(self.db.reader())
+            .await
+    }
+
+    pub async fn set(&self, name: &str, enabled: bool) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO feature_flags (name, enabled)
+            VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE
+            SET enabled = EXCLUDED.enabled, updated_at = now()
+            "#,
+            name,
+            enabled
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(r#"DELETE FROM feature_flags WHERE name = $1"#, name)
+            .execute(self.db.writer())
+            .await?;
+
+        Ok(())
+    }
+}
+
+pub struct LeaseRepository {
+    db: Database,
+}
+
+impl LeaseRepository {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    /// Takes the lease, or extends it if `holder` already has it; false while
+    /// someone else holds an unexpired one.
+    pub async fn acquire(
+        &self,
+        name: &str,
+        holder: &str,
+        ttl_secs: u64,
+    ) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO leases (name, holder, expires_at)
+            VALUES ($1, $2, now() + make_interval(secs => $3))
+            ON CONFLICT (name) DO UPDATE
+            SET holder = EXCLUDED.holder, expires_at = EXCLUDED.expires_at
+            WHERE leases.holder = EXCLUDED.holder OR leases.expires_at < now()
+            RETURNING name
+            "#,
+            name,
+            holder,
+            ttl_secs as f64
+        )
+        .fetch_optional(self.db.writer())
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    pub async fn release(&self, name: &str, holder: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"DELETE FROM leases WHERE name = $1 AND holder = $2"#,
+            name,
+            holder
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
+}
+
+pub struct PendingCacheJobRepository {
+    db: Database,
+}
+
+impl PendingCacheJobRepository {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    pub async fn create(
+        &self,
+        tenant: &str,
+        object_id: i32,
+        object_type: &ObjectType,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO pending_cache_jobs (tenant, object_id, object_type)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+            tenant,
+            object_id,
+            object_type.as_str()
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(
+        &self,
+        tenant: &str,
+        object_id: i32,
+        object_type: &ObjectType,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            DELETE FROM pending_cache_jobs
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3
+            "#,
+            tenant,
+            object_id,
+            object_type.as_str()
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
+
+    pub fn stream_all(&self) -> BoxStream<'_, Result<PendingCacheJob, sqlx::Error>> {
+        sqlx::query_as!(
+            PendingCacheJob,
+            r#"SELECT * FROM pending_cache_jobs ORDER BY created_at"#
+        )
+        .fetch(self.db.reader())
+    }
+
+    /// Inserts jobs as they were, skipping ones that are already pending.
+    pub async fn restore(&self, jobs: &[PendingCacheJob]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.db.writer().begin().await?;
+
+        let mut restored = 0;
+
+        for job in jobs {
+            restored += sqlx::query!(
+                r#"
+                INSERT INTO pending_cache_jobs (tenant, object_id, object_type, created_at)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT DO NOTHING
+                "#,
+                job.tenant,
+                job.object_id,
+                job.object_type.as_str(),
+                job.created_at
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+
+        tx.commit().await?;
+
+        Ok(restored)
+    }
+
+    /// Claims up to `limit` of the oldest jobs nobody holds, for `lease_secs`.
+    /// Jobs stay in the table until [`Self::complete`], so one whose holder
+    /// dies is picked up again once its claim runs out.
+    pub async fn claim(
+        &self,
+        holder: &str,
+        limit: i64,
+        lease_secs: u64,
+    ) -> Result<Vec<PendingCacheJob>, sqlx::Error> {
+        sqlx::query_as!(
+            PendingCacheJob,
+            r#"
+            UPDATE pending_cache_jobs
+            SET claimed_by = $1, claimed_until = now() + make_interval(secs => $3)
+            WHERE (tenant, object_id, object_type) IN (
+                SELECT tenant, object_id, object_type
+                FROM pending_cache_jobs
+                WHERE claimed_until IS NULL OR claimed_until < now()
+                ORDER BY created_at
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
             RETURNING *
             "#,
+            holder,
+            limit,
+            lease_secs as f64
+        )
+        .fetch_all(self.db.writer())
+        .await
+    }
+
+    /// Extends the claims `holder` still has.
+    pub async fn renew(&self, holder: &str, lease_secs: u64) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE pending_cache_jobs
+            SET claimed_until = now() + make_interval(secs => $2)
+            WHERE claimed_by = $1
+            "#,
+            holder,
+            lease_secs as f64
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes a job `holder` finished.
+    pub async fn complete(&self, holder: &str, job: &PendingCacheJob) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            DELETE FROM pending_cache_jobs
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3 AND claimed_by = $4
+            "#,
+            job.tenant,
+            job.object_id,
+            job.object_type.as_str(),
+            holder
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Hands a job `holder` didn't finish back to whoever claims next.
+    pub async fn unclaim(&self, holder: &str, job: &PendingCacheJob) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE pending_cache_jobs
+            SET claimed_by = NULL, claimed_until = NULL
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3 AND claimed_by = $4
+            "#,
+            job.tenant,
+            job.object_id,
+            job.object_type.as_str(),
+            holder
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
+}
+
+pub struct DownloadFailureRepository {
+    db: Database,
+}
+
+impl DownloadFailureRepository {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    /// Counts a failed download and returns the failures so far, starting
+    /// over when the previous one is older than `window_hours`.
+    pub async fn record(
+        &self,
+        tenant: &str,
+        object_id: i32,
+        object_type: &str,
+        window_hours: i32,
+    ) -> Result<i32, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO download_failures (tenant, object_id, object_type, failures, last_failed_at)
+            VALUES ($1, $2, $3, 1, now())
+            ON CONFLICT (tenant, object_id, object_type) DO UPDATE
+            SET failures = CASE
+                    WHEN download_failures.last_failed_at < now() - make_interval(hours => $4)
+                    THEN 1
+                    ELSE download_failures.failures + 1
+                END,
+                last_failed_at = now()
+            RETURNING failures
+            "#,
+            tenant,
             object_id,
-            object_type
+            object_type,
+            window_hours
         )
-        .fetch_one(&self.db)
+        .fetch_one(self.db.writer())
         .await
     }
+
+    pub async fn reset(
+        &self,
+        tenant: &str,
+        object_id: i32,
+        object_type: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            DELETE FROM download_failures
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3
+            "#,
+            tenant,
+            object_id,
+            object_type
+        )
+        .execute(self.db.writer())
+        .await?;
+
+        Ok(())
+    }
 }
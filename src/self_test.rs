@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+
+use futures::TryStreamExt;
+use teloxide::requests::Requester;
+use tracing::log;
+
+use crate::{
+    config::CONFIG,
+    db::{get_database, Database},
+    object_type::ObjectType,
+    services::{
+        book_library, bots::new_bot, download_from_cache, downloader, get_cached_file_or_cache,
+        telegram_files,
+    },
+};
+
+type CheckResult = Result<String, Box<dyn std::error::Error + Send + Sync>>;
+
+const DATABASE_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Runs the startup checks and returns the process exit code.
+pub async fn run() -> i32 {
+    let mut failed = false;
+
+    let mut report = |name: &str, result: CheckResult| match result {
+        Ok(details) => log::info!("self-test {}: ok ({})", name, details),
+        Err(err) => {
+            failed = true;
+            log::error!("self-test {}: FAILED ({})", name, err);
+        }
+    };
+
+    // Pool creation retries until the acquire timeout and then panics; run it on
+    // its own task with a shorter deadline so a bad config is reported like any
+    // other failed check.
+    let connect = tokio::time::timeout(DATABASE_CONNECT_TIMEOUT, tokio::spawn(get_database()));
+    let db = match connect.await {
+        Ok(Ok(db)) => {
+            report("database", check_database(&db).await);
+            report("migrations", check_migrations(&db).await);
+            Some(db)
+        }
+        Ok(Err(err)) => {
+            report("database", Err(err.into()));
+            None
+        }
+        Err(err) => {
+            report("database", Err(err.into()));
+            None
+        }
+    };
+
+    report(
+        "library",
+        ping(
+            &book_library::CLIENT,
+            &CONFIG.library_url,
+            &CONFIG.library_api_key,
+        )
+        .await,
+    );
+    for (source, library) in &CONFIG.library_sources {
+        report(
+            &format!("library:{source}"),
+            ping(&book_library::CLIENT, &library.url, &library.api_key).await,
+        );
+    }
+    report(
+        "downloader",
+        ping(
+            &downloader::CLIENT,
+            &CONFIG.downloader_url,
+            &CONFIG.downloader_api_key,
+        )
+        .await,
+    );
+    report(
+        "telegram_files",
+        ping(
+            &telegram_files::CLIENT,
+            &CONFIG.files_url,
+            &CONFIG.files_api_key,
+        )
+        .await,
+    );
+    report("bots", check_bots().await);
+
+    if let (Some(db), Some(object_id), Some(object_type)) = (
+        db,
+        CONFIG.self_test_object_id,
+        CONFIG.self_test_object_type.clone(),
+    ) {
+        report(
+            "end_to_end",
+            check_end_to_end(db, object_id, ObjectType::from(object_type)).await,
+        );
+    }
+
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+async fn check_database(db: &Database) -> CheckResult {
+    sqlx::query!("SELECT 1 AS ok")
+        .fetch_one(db.writer())
+        .await?;
+    sqlx::query!("SELECT 1 AS ok")
+        .fetch_one(db.reader())
+        .await?;
+
+    Ok("primary and replica reachable".to_string())
+}
+
+async fn check_migrations(db: &Database) -> CheckResult {
+    let migrator = sqlx::migrate!("./migrations");
+
+    if CONFIG.run_migrations {
+        migrator.run(db.writer()).await?;
+    }
+
+    // `_sqlx_migrations` is managed by sqlx itself, so it isn't checked at compile time.
+    let applied: HashSet<i64> =
+        sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(db.reader())
+            .await?
+            .into_iter()
+            .collect();
+
+    let pending: Vec<String> = migrator
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .map(|m| m.version.to_string())
+        .collect();
+
+    if !pending.is_empty() {
+        return Err(format!("pending migrations: {}", pending.join(", ")).into());
+    }
+
+    Ok(format!("{} applied", applied.len()))
+}
+
+/// Only checks that the service answers; none of the upstreams expose a health endpoint.
+async fn ping(client: &reqwest::Client, url: &str, api_key: &str) -> CheckResult {
+    let response = client
+        .get(url)
+        .header("Authorization", api_key)
+        .send()
+        .await?;
+
+    if response.status().is_server_error() {
+        return Err(format!("responded with {}", response.status()).into());
+    }
+
+    Ok(format!("responded with {}", response.status()))
+}
+
+async fn check_bots() -> CheckResult {
+    let mut usernames = vec![];
+
+    for token in &CONFIG.bot_tokens {
+        let me = new_bot(token.clone()).get_me().await?;
+        usernames.push(me.username().to_string());
+    }
+
+    Ok(usernames.join(", "))
+}
+
+async fn check_end_to_end(db: Database, object_id: i32, object_type: ObjectType) -> CheckResult {
+    let cached_file =
+        get_cached_file_or_cache("default".to_string(), object_id, object_type, db.clone())
+            .await
+            .ok_or("could not cache the test object")?;
+
+    let data = download_from_cache(cached_file, db)
+        .await
+        .ok_or("could not download the test object")?;
+
+    let size = data
+        .body
+        .try_fold(0, |size, chunk| async move { Ok(size + chunk.len()) })
+        .await?;
+
+    Ok(format!("{} ({} bytes)", data.filename, size))
+}
@@ -1,8 +1,289 @@
-#[derive(sqlx::FromRow, serde::Serialize)]
+use axum::http::StatusCode;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    config::{default_tenant, CONFIG},
+    object_type::ObjectType,
+};
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, async_graphql::SimpleObject)]
+#[graphql(complex)]
 pub struct CachedFile {
     pub id: i32,
     pub object_id: i32,
-    pub object_type: String,
+    pub object_type: ObjectType,
     pub message_id: i64,
     pub chat_id: i64,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub title: Option<String>,
+    pub authors: Option<String>,
+    pub source_id: Option<i32>,
+    pub tenant: String,
+    pub replica_chat_id: Option<i64>,
+    pub replica_message_id: Option<i64>,
+    pub content_hash: Option<String>,
+    pub file_size: Option<i64>,
+    /// Lets bots send the file straight from Telegram instead of downloading it here.
+    pub file_id: Option<String>,
+    pub file_unique_id: Option<String>,
+    /// The row a converted file was made from.
+    pub source_cached_file_id: Option<i32>,
+    /// Master key the data key is wrapped with; unset for plaintext files.
+    #[graphql(skip)]
+    pub encryption_key_id: Option<String>,
+    #[graphql(skip)]
+    pub encrypted_key: Option<String>,
+    #[graphql(skip)]
+    pub encryption_nonce: Option<String>,
+    /// Codec the stored file is compressed with.
+    pub compression: Option<String>,
+    /// When the stored copy was uploaded; older snapshots don't have it.
+    #[serde(default = "Utc::now")]
+    pub cached_at: DateTime<Utc>,
+    /// Set while the file is held back from serving for review.
+    pub quarantined_at: Option<DateTime<Utc>>,
+    pub quarantine_reason: Option<String>,
+}
+
+/// A cached file with its book metadata and access times, so listings don't
+/// need a lookup per row.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CachedFileDetails {
+    #[serde(flatten)]
+    pub cached_file: CachedFile,
+    /// The latest recorded download; downloads are only kept with
+    /// `RECORD_DOWNLOADS`.
+    pub last_accessed: Option<DateTime<Utc>>,
+}
+
+/// Links a metadata response to the endpoint that downloads the file.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct WithDownloadUrl<T> {
+    #[serde(flatten)]
+    pub inner: T,
+    /// Absolute; `None` when the request gave no host and `PUBLIC_BASE_URL`
+    /// isn't set.
+    pub download_url: Option<String>,
+}
+
+/// One format of a book; `file_size` and `content_hash` are only known once
+/// it is cached.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BookFormat {
+    pub object_type: ObjectType,
+    pub cached: bool,
+    pub file_size: Option<i64>,
+    pub content_hash: Option<String>,
+}
+
+/// Every format of a book in one response: what the library offers, then
+/// anything else cached for it, such as conversions.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BookManifest {
+    pub object_id: i32,
+    pub title: Option<String>,
+    pub authors: Option<String>,
+    pub formats: Vec<WithDownloadUrl<BookFormat>>,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct AccessTimes {
+    pub cached_file_id: i32,
+    pub last_accessed: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub actor: String,
+    pub action: String,
+    pub object_id: i32,
+    pub object_type: String,
+    pub cached_file_id: Option<i32>,
+    pub result: String,
+    /// Older snapshots predate tenants in the audit log.
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, Clone, Default)]
+pub struct ApiKeyUsageTotals {
+    pub files_cached_today: i64,
+    pub bytes_served_today: i64,
+    pub bytes_served_month: i64,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct UsageRow {
+    pub day: NaiveDate,
+    pub api_key: String,
+    pub object_type: String,
+    pub requests: i64,
+    pub cache_misses: i64,
+    pub files_cached: i64,
+    pub bytes_served: i64,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, async_graphql::SimpleObject)]
+pub struct CacheStats {
+    pub object_type: String,
+    pub files: i64,
+    pub bytes: i64,
+}
+
+/// What a storage chat holds. Deduplicated files share a message, and
+/// deleted files keep theirs until they're purged.
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, async_graphql::SimpleObject)]
+pub struct ChatStats {
+    pub chat_id: i64,
+    pub messages: i64,
+    pub bytes: i64,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct DownloadEntry {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub tenant: String,
+    pub api_key: String,
+    pub object_id: i32,
+    pub object_type: String,
+    pub bytes: i64,
+    pub duration_ms: i64,
+    pub client: Option<String>,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, async_graphql::SimpleObject)]
+pub struct TopBook {
+    pub object_id: i32,
+    pub title: Option<String>,
+    pub downloads: i64,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct TimeseriesPoint {
+    pub bucket: DateTime<Utc>,
+    pub count: i64,
+}
+
+/// A row of `api_keys`, used when `AUTH_BACKEND` is `database`.
+#[derive(sqlx::FromRow)]
+pub struct StoredApiKey {
+    pub name: String,
+    pub salt: String,
+    pub hash: String,
+    pub tenant: String,
+    pub daily_cached_files: Option<i64>,
+    pub daily_bytes: Option<i64>,
+    pub monthly_bytes: Option<i64>,
+    pub download_rate_limit: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub admin: bool,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct FeatureFlag {
+    pub name: String,
+    pub enabled: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct PendingCacheJob {
+    pub tenant: String,
+    pub object_id: i32,
+    pub object_type: ObjectType,
+    pub created_at: DateTime<Utc>,
+    /// The replica working on the job, until `claimed_until`.
+    #[serde(default)]
+    pub claimed_by: Option<String>,
+    #[serde(default)]
+    pub claimed_until: Option<DateTime<Utc>>,
+}
+
+/// Filters for browsing cached files; unset fields match everything.
+#[derive(Default, async_graphql::InputObject)]
+pub struct CachedFileFilter {
+    pub object_id: Option<i32>,
+    pub object_types: Option<Vec<String>>,
+    pub source_id: Option<i32>,
+    pub chat_id: Option<i64>,
+    /// Case-insensitive substring of the title.
+    pub title: Option<String>,
+    /// Case-insensitive substring of the authors.
+    pub authors: Option<String>,
+    pub min_file_size: Option<i64>,
+    pub max_file_size: Option<i64>,
+    #[graphql(default)]
+    pub include_deleted: bool,
+}
+
+pub const FIELD_CASE_SNAKE: &str = "snake_case";
+pub const FIELD_CASE_CAMEL: &str = "camelCase";
+
+fn camel_case() -> bool {
+    match CONFIG.json_field_case.as_str() {
+        FIELD_CASE_SNAKE => false,
+        FIELD_CASE_CAMEL => true,
+        other => panic!("Unknown JSON_FIELD_CASE: {other}"),
+    }
+}
+
+/// Whether `JSON_FIELD_CASE` or `JSON_ENVELOPE` change responses at all.
+pub fn shapes_responses() -> bool {
+    camel_case() || CONFIG.json_envelope
+}
+
+/// Applies `JSON_FIELD_CASE` and `JSON_ENVELOPE` to a serialized response body.
+pub fn shape_response(value: Value) -> Value {
+    let value = if camel_case() {
+        to_camel_case_keys(value)
+    } else {
+        value
+    };
+
+    if CONFIG.json_envelope {
+        json!({ "data": value, "error": null })
+    } else {
+        value
+    }
+}
+
+/// The envelope answered in place of a failed request's plain-text body.
+pub fn error_envelope(status: StatusCode, message: &str) -> Value {
+    json!({
+        "data": null,
+        "error": { "status": status.as_u16(), "message": message },
+    })
+}
+
+fn to_camel_case_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (to_camel_case(&key), to_camel_case_keys(value)))
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(to_camel_case_keys).collect()),
+        value => value,
+    }
+}
+
+fn to_camel_case(key: &str) -> String {
+    let mut words = key.split('_').filter(|word| !word.is_empty());
+
+    let mut result = words.next().unwrap_or_default().to_owned();
+
+    for word in words {
+        let mut chars = word.chars();
+
+        if let Some(first) = chars.next() {
+            result.extend(first.to_uppercase());
+            result.push_str(chars.as_str());
+        }
+    }
+
+    result
 }
@@ -0,0 +1,77 @@
+use tracing::log;
+
+use crate::{repository::AuditLogRepository, views::Database};
+
+pub enum AuditAction {
+    Create,
+    Delete,
+    Undelete,
+    Repair,
+    Quarantine,
+    Release,
+    Recache,
+    Purge,
+    Rehost,
+    EditCaption,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Delete => "delete",
+            AuditAction::Undelete => "undelete",
+            AuditAction::Repair => "repair",
+            AuditAction::Quarantine => "quarantine",
+            AuditAction::Release => "release",
+            AuditAction::Recache => "recache",
+            AuditAction::Purge => "purge",
+            AuditAction::Rehost => "rehost",
+            AuditAction::EditCaption => "edit_caption",
+        }
+    }
+}
+
+pub const ACTOR_API: &str = "api";
+pub const ACTOR_UPDATE_CACHE: &str = "update_cache";
+pub const ACTOR_PURGE_DELETED: &str = "purge_deleted";
+pub const ACTOR_REHOST: &str = "rehost";
+pub const ACTOR_QUEUE: &str = "queue";
+pub const ACTOR_CLI: &str = "cli";
+pub const ACTOR_RESUME: &str = "resume";
+pub const ACTOR_REVALIDATE: &str = "revalidate";
+
+pub const RESULT_OK: &str = "ok";
+pub const RESULT_FAILED: &str = "failed";
+pub const RESULT_NOT_FOUND: &str = "not_found";
+
+/// Audit writes are best effort: a failure is logged but never fails the
+/// mutation being audited.
+#[allow(clippy::too_many_arguments)]
+pub async fn record(
+    db: &Database,
+    actor: &str,
+    tenant: &str,
+    action: AuditAction,
+    object_id: i32,
+    object_type: &str,
+    cached_file_id: Option<i32>,
+    result: &str,
+) {
+    let audit_log_repo = AuditLogRepository::new(db.clone());
+
+    if let Err(err) = audit_log_repo
+        .create(
+            actor,
+            tenant,
+            action.as_str(),
+            object_id,
+            object_type,
+            cached_file_id,
+            result,
+        )
+        .await
+    {
+        log::error!("{:?}", err);
+    }
+}
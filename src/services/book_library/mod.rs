@@ -1,36 +1,59 @@
 pub mod types;
 
+use futures::{StreamExt, TryStreamExt};
+use moka::future::Cache;
 use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 
-use crate::config::CONFIG;
+use crate::{
+    config::CONFIG,
+    services::{
+        http_client::build_client,
+        instrument::observe,
+        throttle::background_turn,
+        trace_context::{traceparent, TRACEPARENT},
+    },
+};
 
 use self::types::{BaseBook, Page};
 
-pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| build_client("book_library"));
+
+fn get_library(
+    source: &str,
+) -> Result<(&'static str, &'static str), Box<dyn std::error::Error + Send + Sync>> {
+    CONFIG
+        .library(source)
+        .ok_or_else(|| format!("unknown library source {source}").into())
+}
 
 async fn _make_request<T>(
+    source: &str,
     url: &str,
     params: Vec<(&str, String)>,
 ) -> Result<T, Box<dyn std::error::Error + Send + Sync>>
 where
     T: DeserializeOwned,
 {
-    let formated_url = format!("{}{}", CONFIG.library_url, url);
+    let (library_url, library_api_key) = get_library(source)?;
 
-    let response = CLIENT
-        .get(formated_url)
-        .query(&params)
-        .header("Authorization", CONFIG.library_api_key.clone())
-        .send()
-        .await;
+    let formated_url = format!("{library_url}{url}");
 
-    let response = match response {
-        Ok(v) => v,
-        Err(err) => return Err(Box::new(err)),
-    };
+    background_turn("book_library").await;
+
+    let response = observe("book_library", &formated_url, async {
+        CLIENT
+            .get(&formated_url)
+            .query(&params)
+            .header("Authorization", library_api_key)
+            .header(TRACEPARENT, traceparent())
+            .send()
+            .await?
+            .error_for_status()
+    })
+    .await;
 
-    let response = match response.error_for_status() {
+    let response = match response {
         Ok(v) => v,
         Err(err) => return Err(Box::new(err)),
     };
@@ -41,17 +64,114 @@ where
     }
 }
 
-pub async fn get_sources() -> Result<types::Source, Box<dyn std::error::Error + Send + Sync>> {
-    _make_request("/api/v1/sources", vec![]).await
+/// Telegram only accepts JPEG thumbnails of up to 200 kB.
+const MAX_THUMBNAIL_SIZE: usize = 200 * 1024;
+
+/// Returns the JPEG cover of the book, or `None` if it has none.
+pub async fn download_book_cover(
+    source: &str,
+    book_id: i32,
+) -> Result<Option<reqwest::Response>, Box<dyn std::error::Error + Send + Sync>> {
+    let (library_url, library_api_key) = get_library(source)?;
+
+    let url = format!("{library_url}/api/v1/books/{book_id}/cover");
+
+    background_turn("book_library").await;
+
+    let response = observe("book_library", &url, async {
+        CLIENT
+            .get(&url)
+            .header("Authorization", library_api_key)
+            .header(TRACEPARENT, traceparent())
+            .send()
+            .await
+    })
+    .await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let response = response.error_for_status()?;
+
+    let is_jpeg = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"image/jpeg"));
+
+    if !is_jpeg {
+        return Ok(None);
+    }
+
+    Ok(Some(response))
+}
+
+/// Returns the book cover if it can be used as a Telegram thumbnail.
+pub async fn get_book_cover(
+    source: &str,
+    book_id: i32,
+) -> Result<Option<bytes::Bytes>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(response) = download_book_cover(source, book_id).await? else {
+        return Ok(None);
+    };
+
+    let cover = response.bytes().await?;
+
+    if cover.len() > MAX_THUMBNAIL_SIZE {
+        return Ok(None);
+    }
+
+    Ok(Some(cover))
 }
 
+pub async fn get_sources(
+    source: &str,
+) -> Result<types::Source, Box<dyn std::error::Error + Send + Sync>> {
+    _make_request(source, "/api/v1/sources", vec![]).await
+}
+
+/// Book metadata rarely changes, and a single download needs it more than once.
+/// Keyed by library source too, as catalogs reuse book ids.
+static BOOKS: Lazy<Cache<(String, i32), types::BookWithRemote>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_live(std::time::Duration::from_secs(CONFIG.book_cache_ttl_secs))
+        .max_capacity(CONFIG.book_cache_capacity)
+        .build()
+});
+
 pub async fn get_book(
+    source: &str,
     book_id: i32,
 ) -> Result<types::BookWithRemote, Box<dyn std::error::Error + Send + Sync>> {
-    _make_request(format!("/api/v1/books/{book_id}").as_str(), vec![]).await
+    let key = (source.to_string(), book_id);
+
+    if let Some(book) = BOOKS.get(&key).await {
+        return Ok(book);
+    }
+
+    let book: types::BookWithRemote =
+        _make_request(source, format!("/api/v1/books/{book_id}").as_str(), vec![]).await?;
+
+    BOOKS.insert(key, book.clone()).await;
+
+    Ok(book)
+}
+
+/// book_library has no batch lookup, so this fans out to `get_book` with a
+/// bounded number of requests in flight.
+pub async fn get_books_by_ids(
+    source: &str,
+    book_ids: &[i32],
+) -> Result<Vec<types::BookWithRemote>, Box<dyn std::error::Error + Send + Sync>> {
+    futures::stream::iter(book_ids.iter().copied())
+        .map(|book_id| get_book(source, book_id))
+        .buffer_unordered(CONFIG.book_library_concurrency.get())
+        .try_collect()
+        .await
 }
 
 pub async fn get_books(
+    source: &str,
     page: u32,
     page_size: u32,
     uploaded_gte: String,
@@ -64,5 +184,5 @@ pub async fn get_books(
         ("uploaded_lte", uploaded_lte),
     ];
 
-    _make_request("/api/v1/books/base/", params).await
+    _make_request(source, "/api/v1/books/base/", params).await
 }
@@ -23,6 +23,8 @@ pub struct Book {
     pub uploaded: String,
     pub authors: Vec<BookAuthor>,
     pub source: Source,
+    #[serde(default)]
+    pub available_types: Vec<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -35,6 +37,8 @@ pub struct BookWithRemote {
     pub uploaded: String,
     pub authors: Vec<BookAuthor>,
     pub source: Source,
+    #[serde(default)]
+    pub available_types: Vec<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -54,6 +58,7 @@ impl BookWithRemote {
             uploaded: book.uploaded,
             authors: book.authors,
             source: book.source,
+            available_types: book.available_types,
         }
     }
 }
@@ -81,6 +86,21 @@ impl BookAuthor {
 }
 
 impl BookWithRemote {
+    pub fn get_authors(&self) -> String {
+        self.authors
+            .iter()
+            .map(|a| {
+                [&a.last_name, &a.first_name, &a.middle_name]
+                    .into_iter()
+                    .filter(|v| !v.is_empty())
+                    .map(String::as_str)
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
     pub fn get_caption(self) -> String {
         let BookWithRemote { title, authors, .. } = self;
 
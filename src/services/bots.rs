@@ -1,30 +1,131 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
 };
 
+use axum_prometheus::metrics::counter;
 use once_cell::sync::Lazy;
-
-use teloxide::Bot;
+use serde::{Deserialize, Serialize};
+use teloxide::{Bot, RequestError};
 
 use crate::config;
 
+/// Builds a bot that talks to the configured Bot API server.
+pub fn new_bot(token: String) -> Bot {
+    let bot = Bot::new(token);
+
+    match &config::CONFIG.bot_api_url {
+        Some(url) => bot.set_api_url(reqwest::Url::parse(url).unwrap()),
+        None => bot,
+    }
+}
+
+struct BotSlot {
+    /// The numeric part of the token, safe to show in logs and metrics.
+    id: String,
+    bot: Bot,
+    requests: AtomicU64,
+    rate_limited: AtomicU64,
+    limited_until: Mutex<Option<Instant>>,
+}
+
+impl BotSlot {
+    fn limited_until(&self) -> Option<Instant> {
+        self.limited_until
+            .lock()
+            .unwrap()
+            .filter(|until| *until > Instant::now())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BotStats {
+    pub id: String,
+    pub requests: u64,
+    pub rate_limited: u64,
+    pub limited_for_secs: u64,
+}
+
+/// Rotates requests across the configured bots, skipping any that Telegram
+/// has asked to back off until their retry window passes.
 pub struct RoundRobinBot {
-    bot_tokens: Arc<Vec<String>>,
+    slots: Vec<BotSlot>,
     current_index: AtomicUsize,
 }
 
 impl RoundRobinBot {
     pub fn new(bot_tokens: Vec<String>) -> Self {
+        let slots = bot_tokens
+            .into_iter()
+            .map(|token| BotSlot {
+                id: token.split(':').next().unwrap_or_default().to_string(),
+                bot: new_bot(token),
+                requests: AtomicU64::new(0),
+                rate_limited: AtomicU64::new(0),
+                limited_until: Mutex::new(None),
+            })
+            .collect();
+
         RoundRobinBot {
-            bot_tokens: Arc::new(bot_tokens),
+            slots,
             current_index: AtomicUsize::new(0),
         }
     }
 
     pub fn get_bot(&self) -> Bot {
-        let index = self.current_index.fetch_add(1, Ordering::Relaxed) % self.bot_tokens.len();
-        Bot::new(self.bot_tokens[index].clone())
+        let start = self.current_index.fetch_add(1, Ordering::Relaxed);
+        let len = self.slots.len();
+
+        let available = (0..len)
+            .map(|offset| &self.slots[(start + offset) % len])
+            .find(|slot| slot.limited_until().is_none());
+
+        // With every bot limited, the one that frees up first is the best bet.
+        let slot = available.unwrap_or_else(|| {
+            self.slots
+                .iter()
+                .min_by_key(|slot| slot.limited_until())
+                .unwrap()
+        });
+
+        slot.requests.fetch_add(1, Ordering::Relaxed);
+        counter!("telegram_bot_requests_total", "bot" => slot.id.clone()).increment(1);
+
+        slot.bot.clone()
+    }
+
+    /// Takes the bot out of rotation for as long as Telegram asked; returns
+    /// whether the error was a rate limit.
+    pub fn report_error(&self, bot: &Bot, err: &RequestError) -> bool {
+        let RequestError::RetryAfter(retry_after) = err else {
+            return false;
+        };
+
+        if let Some(slot) = self.slots.iter().find(|s| s.bot.token() == bot.token()) {
+            *slot.limited_until.lock().unwrap() = Some(Instant::now() + retry_after.duration());
+            slot.rate_limited.fetch_add(1, Ordering::Relaxed);
+            counter!("telegram_bot_rate_limited_total", "bot" => slot.id.clone()).increment(1);
+        }
+
+        true
+    }
+
+    pub fn get_stats(&self) -> Vec<BotStats> {
+        self.slots
+            .iter()
+            .map(|slot| BotStats {
+                id: slot.id.clone(),
+                requests: slot.requests.load(Ordering::Relaxed),
+                rate_limited: slot.rate_limited.load(Ordering::Relaxed),
+                limited_for_secs: slot
+                    .limited_until()
+                    .map(|until| (until - Instant::now()).as_secs())
+                    .unwrap_or(0),
+            })
+            .collect()
     }
 }
 
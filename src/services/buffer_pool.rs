@@ -0,0 +1,103 @@
+//! Read buffers shared between streams, so large files don't allocate a
+//! buffer per read. Frames are split off a buffer as `Bytes` without copying;
+//! once they are dropped downstream, the next read reuses their memory.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use bytes::{BufMut, BytesMut};
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::CONFIG;
+
+use super::download_utils::BodyStream;
+
+/// Idle buffers kept beyond this are freed.
+const MAX_IDLE_BUFFERS: usize = 64;
+
+pub struct BufferPool {
+    idle: Mutex<Vec<BytesMut>>,
+    chunk_size: usize,
+}
+
+pub static BUFFER_POOL: Lazy<BufferPool> = Lazy::new(|| BufferPool {
+    idle: Mutex::new(vec![]),
+    chunk_size: CONFIG.stream_chunk_size,
+});
+
+impl BufferPool {
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn take(&'static self) -> PooledBuffer {
+        let buffer = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.chunk_size));
+
+        PooledBuffer { pool: self, buffer }
+    }
+}
+
+/// Goes back to its pool when dropped.
+pub struct PooledBuffer {
+    pool: &'static BufferPool,
+    buffer: BytesMut,
+}
+
+impl Deref for PooledBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+
+        let mut idle = self.pool.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_BUFFERS {
+            idle.push(buffer);
+        }
+    }
+}
+
+/// Streams `reader` in frames of up to `STREAM_CHUNK_SIZE`, read straight
+/// into a pooled buffer.
+pub fn read_frames(mut reader: impl AsyncRead + Send + Unpin + 'static) -> BodyStream {
+    async_stream::stream! {
+        let chunk_size = BUFFER_POOL.chunk_size();
+        let mut buffer = BUFFER_POOL.take();
+
+        loop {
+            // Reclaims the memory of frames already dropped downstream.
+            buffer.reserve(chunk_size);
+
+            match reader.read_buf(&mut (&mut *buffer).limit(chunk_size)).await {
+                Ok(0) => return,
+                Ok(_) => yield Ok(buffer.split().freeze()),
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            }
+        }
+    }
+    .boxed()
+}
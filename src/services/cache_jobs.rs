@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use axum_prometheus::metrics::gauge;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+pub const CACHE_JOBS_IN_PROGRESS: &str = "cache_jobs_in_progress";
+pub const CACHE_JOBS_BYTES_TRANSFERRED: &str = "cache_jobs_bytes_transferred";
+pub const CACHE_JOBS_BYTES_EXPECTED: &str = "cache_jobs_bytes_expected";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, async_graphql::Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheJobStage {
+    Downloading,
+    Uploading,
+}
+
+#[derive(Serialize, Deserialize, Clone, async_graphql::SimpleObject)]
+pub struct CacheJobProgress {
+    pub id: u64,
+    pub tenant: String,
+    pub object_id: i32,
+    pub object_type: String,
+    pub stage: CacheJobStage,
+    pub started_at: DateTime<Utc>,
+    pub bytes_transferred: u64,
+    /// From the upstream Content-Length; `None` for chunked responses.
+    pub total_bytes: Option<u64>,
+}
+
+static CACHE_JOBS: Lazy<Mutex<HashMap<u64, CacheJobProgress>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Tracks one file being cached; the job is listed until this is dropped.
+pub struct CacheJob {
+    id: u64,
+}
+
+impl CacheJob {
+    pub fn start(tenant: &str, object_id: i32, object_type: &str) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+        CACHE_JOBS.lock().unwrap().insert(
+            id,
+            CacheJobProgress {
+                id,
+                tenant: tenant.to_string(),
+                object_id,
+                object_type: object_type.to_string(),
+                stage: CacheJobStage::Downloading,
+                started_at: Utc::now(),
+                bytes_transferred: 0,
+                total_bytes: None,
+            },
+        );
+
+        gauge!(CACHE_JOBS_IN_PROGRESS).increment(1.0);
+
+        CacheJob { id }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut CacheJobProgress)) {
+        if let Some(progress) = CACHE_JOBS.lock().unwrap().get_mut(&self.id) {
+            f(progress);
+        }
+    }
+
+    pub fn set_total(&self, total_bytes: Option<u64>) {
+        self.update(|p| {
+            let previous = p.total_bytes.unwrap_or(0) as f64;
+            p.total_bytes = total_bytes;
+
+            gauge!(CACHE_JOBS_BYTES_EXPECTED).increment(total_bytes.unwrap_or(0) as f64 - previous);
+        });
+    }
+
+    pub fn add_bytes(&self, bytes: usize) {
+        self.update(|p| p.bytes_transferred += bytes as u64);
+
+        gauge!(CACHE_JOBS_BYTES_TRANSFERRED).increment(bytes as f64);
+    }
+
+    pub fn set_stage(&self, stage: CacheJobStage) {
+        self.update(|p| p.stage = stage);
+    }
+}
+
+impl Drop for CacheJob {
+    fn drop(&mut self) {
+        let Some(progress) = CACHE_JOBS.lock().unwrap().remove(&self.id) else {
+            return;
+        };
+
+        gauge!(CACHE_JOBS_IN_PROGRESS).decrement(1.0);
+        gauge!(CACHE_JOBS_BYTES_TRANSFERRED).decrement(progress.bytes_transferred as f64);
+        gauge!(CACHE_JOBS_BYTES_EXPECTED).decrement(progress.total_bytes.unwrap_or(0) as f64);
+    }
+}
+
+/// Files currently being cached, oldest first.
+pub fn get_cache_jobs() -> Vec<CacheJobProgress> {
+    let mut jobs: Vec<CacheJobProgress> = CACHE_JOBS.lock().unwrap().values().cloned().collect();
+    jobs.sort_by_key(|v| v.id);
+
+    jobs
+}
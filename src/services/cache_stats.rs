@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+
+use axum_prometheus::metrics::gauge;
+use tracing::log;
+
+use crate::{
+    config::CONFIG, repository::CachedFileRepository, serializers::ChatStats, views::Database,
+};
+
+use super::{leader::is_leader, notifier::notify_admins, rollover::update_full_chats};
+
+pub const CACHED_FILES: &str = "cached_files";
+pub const CACHED_BYTES: &str = "cached_bytes";
+pub const CHAT_MESSAGES: &str = "chat_messages";
+pub const CHAT_BYTES: &str = "chat_bytes";
+
+pub async fn start_cache_stats_updater(db: Database) {
+    let cached_file_repo = CachedFileRepository::new(db);
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        CONFIG.cache_stats_interval_secs.get(),
+    ));
+
+    let mut seen_object_types: HashSet<String> = HashSet::new();
+    let mut seen_chats: HashSet<i64> = HashSet::new();
+    let mut near_limit_chats: HashSet<i64> = HashSet::new();
+
+    loop {
+        interval.tick().await;
+
+        let stats = match cached_file_repo.get_stats().await {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("{:?}", err);
+                continue;
+            }
+        };
+
+        // Formats that no longer have any rows would otherwise keep reporting
+        // their last value.
+        let mut gone = seen_object_types.clone();
+
+        for row in stats {
+            gone.remove(&row.object_type);
+
+            gauge!(CACHED_FILES, "object_type" => row.object_type.clone()).set(row.files as f64);
+            gauge!(CACHED_BYTES, "object_type" => row.object_type.clone()).set(row.bytes as f64);
+
+            seen_object_types.insert(row.object_type);
+        }
+
+        for object_type in gone {
+            gauge!(CACHED_FILES, "object_type" => object_type.clone()).set(0.0);
+            gauge!(CACHED_BYTES, "object_type" => object_type).set(0.0);
+        }
+
+        let chat_stats = match cached_file_repo.get_chat_stats().await {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("{:?}", err);
+                continue;
+            }
+        };
+
+        let mut gone = seen_chats.clone();
+
+        for row in &chat_stats {
+            gone.remove(&row.chat_id);
+
+            gauge!(CHAT_MESSAGES, "chat_id" => row.chat_id.to_string()).set(row.messages as f64);
+            gauge!(CHAT_BYTES, "chat_id" => row.chat_id.to_string()).set(row.bytes as f64);
+
+            seen_chats.insert(row.chat_id);
+        }
+
+        for chat_id in gone {
+            gauge!(CHAT_MESSAGES, "chat_id" => chat_id.to_string()).set(0.0);
+            gauge!(CHAT_BYTES, "chat_id" => chat_id.to_string()).set(0.0);
+        }
+
+        for chat_id in update_full_chats(&chat_stats) {
+            let message = format!("Chat {chat_id} is full, new uploads move on to the next chat");
+
+            log::warn!("{}", message);
+
+            if is_leader() {
+                notify_admins(format!("⚠️ {message}")).await;
+            }
+        }
+
+        warn_near_limits(&chat_stats, &mut near_limit_chats).await;
+    }
+}
+
+/// What `stats` is close to running out of, if anything.
+fn near_limits(stats: &ChatStats) -> Vec<String> {
+    let near = |used: i64, limit: Option<i64>| {
+        limit.filter(|limit| used as f64 >= *limit as f64 * CONFIG.chat_limit_warn_ratio)
+    };
+
+    let mut limits = vec![];
+
+    if let Some(limit) = near(stats.messages, CONFIG.chat_message_limit) {
+        limits.push(format!("{} of {} messages", stats.messages, limit));
+    }
+
+    if let Some(limit) = near(stats.bytes, CONFIG.chat_bytes_limit) {
+        limits.push(format!("{} of {} bytes", stats.bytes, limit));
+    }
+
+    limits
+}
+
+/// Warns once per chat as it gets close to a limit, and again only after it
+/// dropped below and came back. Only the leader warns, so admins hear it once.
+async fn warn_near_limits(chat_stats: &[ChatStats], near_limit_chats: &mut HashSet<i64>) {
+    for stats in chat_stats {
+        let limits = near_limits(stats);
+
+        if limits.is_empty() {
+            near_limit_chats.remove(&stats.chat_id);
+            continue;
+        }
+
+        if !is_leader() || !near_limit_chats.insert(stats.chat_id) {
+            continue;
+        }
+
+        let message = format!(
+            "Chat {} is close to its limits: {}",
+            stats.chat_id,
+            limits.join(", ")
+        );
+
+        log::warn!("{}", message);
+
+        notify_admins(format!("⚠️ {message}")).await;
+    }
+}
@@ -0,0 +1,111 @@
+//! Compression of stored files for `COMPRESSED_OBJECT_TYPES`, formats like
+//! fb2 that are mostly text. The codec is kept on the row; downloads are
+//! passed through with `Content-Encoding` when the client accepts the codec
+//! and decompressed otherwise.
+
+use std::io::SeekFrom;
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use futures::TryStreamExt;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio_util::io::StreamReader;
+
+use crate::config::CONFIG;
+
+use super::{
+    buffer_pool::read_frames,
+    download_utils::{BodyStream, HashedFile},
+};
+
+type CompressionError = Box<dyn std::error::Error + Send + Sync>;
+
+pub const CODEC_GZIP: &str = "gzip";
+pub const CODEC_ZSTD: &str = "zstd";
+
+/// The codec a row names, as the `Content-Encoding` token it is sent with.
+pub fn codec(name: &str) -> Option<&'static str> {
+    match name {
+        CODEC_GZIP => Some(CODEC_GZIP),
+        CODEC_ZSTD => Some(CODEC_ZSTD),
+        _ => None,
+    }
+}
+
+/// What a file compressed with `codec` is named with, after its own name.
+/// Copies and `file_id`s hand out the stored bytes, so the name says so.
+pub fn file_extension(codec: &str) -> &'static str {
+    match codec {
+        CODEC_GZIP => "gz",
+        _ => "zst",
+    }
+}
+
+fn configured_codec(object_type: &str) -> Option<&'static str> {
+    let name = CONFIG.compression.as_deref()?;
+
+    if !CONFIG.compressed_object_types.iter().any(|v| v == object_type) {
+        return None;
+    }
+
+    match codec(name) {
+        Some(v) => Some(v),
+        None => panic!("Unknown COMPRESSION: {name}"),
+    }
+}
+
+/// Compresses the file when its type is configured for it and the result is
+/// actually smaller; otherwise the file comes back as it was. The result is
+/// hashed like the original, so the upload is verified against what was sent.
+pub async fn compress_file(
+    mut file: HashedFile,
+    object_type: &str,
+) -> Result<(HashedFile, Option<&'static str>), CompressionError> {
+    let Some(codec) = configured_codec(object_type) else {
+        return Ok((file, None));
+    };
+
+    let input = BufReader::new(file.file.try_clone().await?);
+    let mut frames = match codec {
+        CODEC_GZIP => read_frames(GzipEncoder::new(input)),
+        _ => read_frames(ZstdEncoder::new(input)),
+    };
+
+    let mut output = tokio::fs::File::from_std(tempfile::tempfile()?);
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+
+    while let Some(frame) = frames.try_next().await? {
+        hasher.update(&frame);
+        size += frame.len() as u64;
+        output.write_all(&frame).await?;
+    }
+
+    // The clone shares the file position, so this rewinds `file` too.
+    file.file.seek(SeekFrom::Start(0)).await?;
+
+    if size >= file.size {
+        return Ok((file, None));
+    }
+
+    output.flush().await?;
+    output.seek(SeekFrom::Start(0)).await?;
+
+    Ok((
+        HashedFile {
+            file: output,
+            size,
+            sha256: hex::encode(hasher.finalize()),
+        },
+        Some(codec),
+    ))
+}
+
+pub fn decompress_body(body: BodyStream, codec: &str) -> BodyStream {
+    let input = StreamReader::new(body);
+
+    match codec {
+        CODEC_GZIP => read_frames(GzipDecoder::new(input)),
+        _ => read_frames(ZstdDecoder::new(input)),
+    }
+}
@@ -0,0 +1,31 @@
+use crate::{config::CONFIG, object_type::ObjectType};
+
+/// Resolves the object type to cache for a request, or `None` if the type
+/// isn't accepted or the conversion isn't allowed. `convert` may name an alias.
+pub fn resolve_object_type(object_type: ObjectType, convert: Option<String>) -> Option<ObjectType> {
+    if !object_type.is_accepted() {
+        return None;
+    }
+
+    let Some(target) = convert.map(|v| CONFIG.canonical_object_type(v)) else {
+        // Converted object types can also be requested by name.
+        return match object_type.conversion() {
+            Some((_, target)) if !is_conversion_target(target) => None,
+            _ => Some(object_type),
+        };
+    };
+
+    if target == object_type
+        || object_type.is_cover()
+        || object_type.conversion().is_some()
+        || !is_conversion_target(target.as_str())
+    {
+        return None;
+    }
+
+    Some(object_type.converted_to(&target))
+}
+
+fn is_conversion_target(target: &str) -> bool {
+    CONFIG.conversion_targets.iter().any(|v| v == target)
+}
@@ -0,0 +1,10 @@
+use crate::services::downloader::FilenameData;
+
+pub fn cover_filename(object_id: i32) -> FilenameData {
+    let filename = format!("cover_{object_id}.jpg");
+
+    FilenameData {
+        filename: filename.clone(),
+        filename_ascii: filename,
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use tracing::log;
+
+use crate::{
+    config::CONFIG,
+    repository::CachedFileRepository,
+    serializers::{BookFormat, BookManifest, CachedFile, CachedFileDetails, WithDownloadUrl},
+    views::Database,
+};
+
+use super::book_library::get_book;
+
+/// Adds the last download to `cached_files`, keeping their order; when each
+/// was cached is on the row itself.
+pub async fn get_cached_file_details(
+    cached_files: Vec<CachedFile>,
+    db: Database,
+) -> Result<Vec<CachedFileDetails>, sqlx::Error> {
+    let ids: Vec<i32> = cached_files.iter().map(|v| v.id).collect();
+
+    let access_times: HashMap<_, _> = CachedFileRepository::new(db)
+        .get_access_times(&ids)
+        .await?
+        .into_iter()
+        .map(|v| (v.cached_file_id, v))
+        .collect();
+
+    Ok(cached_files
+        .into_iter()
+        .map(|cached_file| CachedFileDetails {
+            last_accessed: access_times
+                .get(&cached_file.id)
+                .and_then(|v| v.last_accessed),
+            cached_file,
+        })
+        .collect())
+}
+
+/// `None` when neither the library nor the cache knows the book. The cached
+/// rows alone are enough if the library can't be reached.
+pub async fn get_book_manifest(
+    tenant: &str,
+    object_id: i32,
+    db: Database,
+    download_url: impl Fn(i32, &str) -> Option<String>,
+) -> Result<Option<BookManifest>, sqlx::Error> {
+    let cached_files = CachedFileRepository::new(db)
+        .get_by_object_id(tenant, object_id)
+        .await?;
+
+    let book = match get_book(CONFIG.library_source(tenant), object_id).await {
+        Ok(v) => Some(v),
+        Err(err) => {
+            log::warn!("Can't get book {}: {:?}", object_id, err);
+            None
+        }
+    };
+
+    let (title, authors, available_types) = match &book {
+        Some(book) => (
+            Some(book.title.clone()),
+            Some(book.get_authors()),
+            CONFIG.canonical_object_types(&book.available_types),
+        ),
+        None => match cached_files.first() {
+            Some(v) => (v.title.clone(), v.authors.clone(), vec![]),
+            None => return Ok(None),
+        },
+    };
+
+    let mut cached: HashMap<&str, &CachedFile> = cached_files
+        .iter()
+        .map(|v| (v.object_type.as_str(), v))
+        .collect();
+
+    let mut formats: Vec<BookFormat> = available_types
+        .iter()
+        .map(|object_type| match cached.remove(object_type.as_str()) {
+            Some(v) => cached_format(v),
+            None => BookFormat {
+                object_type: object_type.clone(),
+                cached: false,
+                file_size: None,
+                content_hash: None,
+            },
+        })
+        .collect();
+
+    formats.extend(
+        cached_files
+            .iter()
+            .filter(|v| cached.contains_key(v.object_type.as_str()))
+            .map(cached_format),
+    );
+
+    Ok(Some(BookManifest {
+        object_id,
+        title,
+        authors,
+        formats: formats
+            .into_iter()
+            .map(|format| WithDownloadUrl {
+                download_url: download_url(object_id, format.object_type.as_str()),
+                inner: format,
+            })
+            .collect(),
+    }))
+}
+
+fn cached_format(cached_file: &CachedFile) -> BookFormat {
+    BookFormat {
+        object_type: cached_file.object_type.clone(),
+        cached: true,
+        file_size: cached_file.file_size,
+        content_hash: cached_file.content_hash.clone(),
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use tracing::log;
+
+use crate::config::CONFIG;
+
+struct Entry {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+}
+
+/// Shared by every client, so a host resolved for one upstream is reused by the rest.
+static ENTRIES: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn get_cached(host: &str, fresh_only: bool) -> Option<Vec<SocketAddr>> {
+    let ttl = Duration::from_secs(CONFIG.dns_cache_ttl_secs);
+
+    ENTRIES
+        .lock()
+        .unwrap()
+        .get(host)
+        .filter(|entry| !fresh_only || entry.resolved_at.elapsed() < ttl)
+        .map(|entry| entry.addrs.clone())
+}
+
+async fn lookup(host: String) -> Result<Vec<SocketAddr>, std::io::Error> {
+    if let Some(addrs) = get_cached(&host, true) {
+        return Ok(addrs);
+    }
+
+    let resolved = tokio::net::lookup_host((host.as_str(), 0))
+        .await
+        .map(|addrs| addrs.collect::<Vec<SocketAddr>>());
+
+    match resolved {
+        Ok(addrs) => {
+            ENTRIES.lock().unwrap().insert(
+                host,
+                Entry {
+                    addrs: addrs.clone(),
+                    resolved_at: Instant::now(),
+                },
+            );
+
+            Ok(addrs)
+        }
+        Err(err) => match get_cached(&host, false) {
+            Some(addrs) => {
+                log::warn!(
+                    "Resolving {} failed, using stale addresses: {:?}",
+                    host,
+                    err
+                );
+                Ok(addrs)
+            }
+            None => Err(err),
+        },
+    }
+}
+
+/// Caches lookups for `DNS_CACHE_TTL_SECS`. When a refresh fails the last
+/// known addresses are served instead, so a flaky resolver doesn't fail
+/// requests to hosts that haven't moved.
+pub struct CachingResolver;
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+
+        Box::pin(async move {
+            let addrs: Addrs = Box::new(lookup(host).await?.into_iter());
+
+            Ok(addrs)
+        })
+    }
+}
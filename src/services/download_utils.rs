@@ -1,24 +1,152 @@
-use std::io::{Seek, SeekFrom, Write};
+use std::{
+    future::Future,
+    io::{Seek, SeekFrom, Write},
+    sync::Arc,
+};
 
-use bytes::Buf;
-use futures::TryStreamExt;
+use bytes::{Buf, Bytes};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use reqwest::Response;
+use sha2::{Digest, Sha256};
 use tempfile::SpooledTempFile;
-use tokio::io::AsyncRead;
-use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::{mpsc, Semaphore},
+};
+use tracing::log;
+
+use super::{buffer_pool::BUFFER_POOL, throttle::RateLimiter, trace_context::propagate};
+
+pub type BodyStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Reads `body` on its own task, ahead of the consumer by at most `window`
+/// bytes: a frame holds its share of the window until the consumer asks for
+/// the next one, and upstream reads pause while the window is full.
+pub fn windowed_body(mut body: BodyStream, window: usize) -> BodyStream {
+    // Permits are acquired a u32 at a time, so a larger window couldn't be.
+    let window = u32::try_from(window.clamp(1, Semaphore::MAX_PERMITS)).unwrap_or(u32::MAX);
+    let permits = Arc::new(Semaphore::new(window as usize));
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    tokio::spawn(propagate(async move {
+        while let Some(chunk) = body.next().await {
+            // A frame larger than the window takes all of it.
+            let len = chunk
+                .as_ref()
+                .map_or(1, |v| v.len())
+                .clamp(1, window as usize) as u32;
+
+            let permit = tokio::select! {
+                permit = permits.clone().acquire_many_owned(len) => permit.unwrap(),
+                // The client went away.
+                _ = sender.closed() => return,
+            };
+
+            let failed = chunk.is_err();
+
+            if sender.send((chunk, permit)).is_err() || failed {
+                return;
+            }
+        }
+    }));
+
+    async_stream::stream! {
+        while let Some((chunk, permit)) = receiver.recv().await {
+            yield chunk;
+            drop(permit);
+        }
+    }
+    .boxed()
+}
 
 pub struct DownloadResult {
-    pub response: Response,
+    pub body: BodyStream,
     pub filename: String,
     pub filename_ascii: String,
     pub caption: String,
+    /// The codec `body` is still compressed with, for `Content-Encoding`.
+    pub content_encoding: Option<&'static str>,
+    /// Hex SHA-256 of the file as it was downloaded from the source, before
+    /// any compression; unset for rows cached before hashes were recorded.
+    pub content_hash: Option<String>,
+}
+
+/// Passes the body's frames on no faster than every one of `limiters` allows.
+/// Frames over `STREAM_CHUNK_SIZE` are split, without copying, so the pace
+/// stays even.
+pub fn throttled_body(mut body: BodyStream, limiters: Vec<Arc<RateLimiter>>) -> BodyStream {
+    let chunk_size = BUFFER_POOL.chunk_size();
+
+    async_stream::stream! {
+        while let Some(chunk) = body.next().await {
+            let mut chunk = match chunk {
+                Ok(v) => v,
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            };
+
+            while !chunk.is_empty() {
+                let frame = chunk.split_to(chunk.len().min(chunk_size));
+
+                for limiter in &limiters {
+                    limiter.acquire(frame.len()).await;
+                }
+
+                yield Ok(frame);
+            }
+        }
+    }
+    .boxed()
 }
 
-pub fn get_response_async_read(it: Response) -> impl AsyncRead {
-    it.bytes_stream()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-        .into_async_read()
-        .compat()
+/// Streams the response body; when the connection drops partway, `resume` is
+/// asked for the rest starting at the given offset, up to `max_resumes` times.
+pub fn resumable_body<F, Fut>(response: Response, resume: F, max_resumes: u32) -> BodyStream
+where
+    F: Fn(u64) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Response, Box<dyn std::error::Error + Send + Sync>>> + Send,
+{
+    async_stream::stream! {
+        let mut stream = response.bytes_stream().boxed();
+        let mut offset: u64 = 0;
+        let mut resumes = 0;
+
+        loop {
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    offset += chunk.len() as u64;
+                    yield Ok(chunk);
+                }
+                Some(Err(err)) => {
+                    if resumes >= max_resumes {
+                        yield Err(std::io::Error::other(err));
+                        return;
+                    }
+
+                    resumes += 1;
+                    log::warn!(
+                        "Download dropped at {} bytes, resuming ({}/{}): {:?}",
+                        offset,
+                        resumes,
+                        max_resumes,
+                        err
+                    );
+
+                    match resume(offset).await {
+                        Ok(response) => stream = response.bytes_stream().boxed(),
+                        Err(err) => {
+                            yield Err(std::io::Error::other(err));
+                            return;
+                        }
+                    }
+                }
+                None => return,
+            }
+        }
+    }
+    .boxed()
 }
 
 pub async fn response_to_tempfile(res: &mut Response) -> Option<(SpooledTempFile, usize)> {
@@ -53,3 +181,55 @@ pub async fn response_to_tempfile(res: &mut Response) -> Option<(SpooledTempFile
 
     Some((tmp_file, data_size))
 }
+
+pub struct HashedFile {
+    pub file: tokio::fs::File,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Spools a response to an anonymous temp file, hashing the content on the way.
+/// `on_chunk` is told the size of every chunk written.
+pub async fn response_to_hashed_file(
+    res: Response,
+    mut on_chunk: impl FnMut(usize),
+) -> Result<HashedFile, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+
+    let mut stream = res.bytes_stream();
+
+    while let Some(chunk) = stream.try_next().await? {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        file.write_all(&chunk).await?;
+        on_chunk(chunk.len());
+    }
+
+    file.flush().await?;
+    file.seek(SeekFrom::Start(0)).await?;
+
+    Ok(HashedFile {
+        file,
+        size,
+        sha256: hex::encode(hasher.finalize()),
+    })
+}
+
+/// Hashes a response body without keeping it.
+pub async fn response_digest(
+    res: Response,
+) -> Result<(u64, String), Box<dyn std::error::Error + Send + Sync>> {
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+
+    let mut stream = res.bytes_stream();
+
+    while let Some(chunk) = stream.try_next().await? {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+    }
+
+    Ok((size, hex::encode(hasher.finalize())))
+}
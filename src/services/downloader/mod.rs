@@ -1,10 +1,129 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use base64::{engine::general_purpose, Engine};
 use once_cell::sync::Lazy;
 use reqwest::{Response, StatusCode};
 use serde::Deserialize;
+use tracing::log;
+
+use crate::{
+    config::CONFIG,
+    services::{
+        filenames::sanitize_filename,
+        http_client::build_client,
+        instrument::observe,
+        throttle::background_turn,
+        trace_context::{traceparent, TRACEPARENT},
+    },
+};
+
+pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| build_client("downloader"));
+
+struct Endpoint {
+    url: String,
+    healthy: AtomicBool,
+}
+
+static ENDPOINTS: Lazy<Vec<Endpoint>> = Lazy::new(|| {
+    CONFIG
+        .downloader_urls()
+        .into_iter()
+        .map(|url| Endpoint {
+            url,
+            healthy: AtomicBool::new(true),
+        })
+        .collect()
+});
+
+/// Healthy endpoints first; unhealthy ones are still tried as a last resort.
+fn endpoints_by_health() -> Vec<&'static Endpoint> {
+    let (healthy, unhealthy): (Vec<_>, Vec<_>) = ENDPOINTS
+        .iter()
+        .partition(|endpoint| endpoint.healthy.load(Ordering::Relaxed));
+
+    healthy.into_iter().chain(unhealthy).collect()
+}
+
+/// Sends `path` to each downloader in turn until one succeeds. Client errors
+/// are the downloader's answer, not a failure, so they aren't retried.
+async fn request_with_failover<F, Fut>(
+    path: &str,
+    send: F,
+) -> Result<Response, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Response, reqwest::Error>>,
+{
+    background_turn("downloader").await;
+
+    let mut last_error: Option<Box<dyn std::error::Error + Send + Sync>> = None;
+
+    for endpoint in endpoints_by_health() {
+        let url = format!("{}{path}", endpoint.url);
+
+        let err = match send(url).await {
+            Ok(response) => {
+                endpoint.healthy.store(true, Ordering::Relaxed);
+                return Ok(response);
+            }
+            Err(err) if err.status().is_some_and(|s| s.is_client_error()) => {
+                return Err(Box::new(err));
+            }
+            Err(err) => err,
+        };
+
+        if endpoint.healthy.swap(false, Ordering::Relaxed) {
+            log::warn!("Downloader {} is failing: {}", endpoint.url, err);
+        }
+
+        last_error = Some(Box::new(err));
+    }
+
+    Err(last_error.unwrap())
+}
+
+/// Marks endpoints healthy again once they answer; only runs with fallbacks configured.
+pub async fn start_downloader_health_checks() {
+    if ENDPOINTS.len() < 2 {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        CONFIG.downloader_health_check_interval_secs.get(),
+    ));
 
-use crate::config::CONFIG;
+    loop {
+        interval.tick().await;
 
-pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+        for endpoint in ENDPOINTS.iter() {
+            let healthy = match CLIENT
+                .get(&endpoint.url)
+                .header("Authorization", &CONFIG.downloader_api_key)
+                .header(TRACEPARENT, traceparent())
+                .send()
+                .await
+            {
+                Ok(response) => !response.status().is_server_error(),
+                Err(_) => false,
+            };
+
+            if endpoint.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                log::warn!(
+                    "Downloader {} is {}",
+                    endpoint.url,
+                    if healthy {
+                        "healthy again"
+                    } else {
+                        "unhealthy"
+                    }
+                );
+            }
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct FilenameData {
@@ -12,22 +131,31 @@ pub struct FilenameData {
     pub filename_ascii: String,
 }
 
+/// Downloads the book, converted to `convert` by the downloader if given.
 pub async fn download_from_downloader(
     source_id: u32,
     remote_id: u32,
     object_type: String,
+    convert: Option<&str>,
 ) -> Result<Option<Response>, Box<dyn std::error::Error + Send + Sync>> {
-    let url = format!(
-        "{}/download/{source_id}/{remote_id}/{object_type}",
-        CONFIG.downloader_url
-    );
-
-    let response = CLIENT
-        .get(url)
-        .header("Authorization", &CONFIG.downloader_api_key)
-        .send()
-        .await?
-        .error_for_status()?;
+    let path = match convert {
+        Some(target) => format!("/download/{source_id}/{remote_id}/{object_type}?convert={target}"),
+        None => format!("/download/{source_id}/{remote_id}/{object_type}"),
+    };
+
+    let response = request_with_failover(&path, |url| async move {
+        observe("downloader", &url, async {
+            CLIENT
+                .get(&url)
+                .header("Authorization", &CONFIG.downloader_api_key)
+                .header(TRACEPARENT, traceparent())
+                .send()
+                .await?
+                .error_for_status()
+        })
+        .await
+    })
+    .await?;
 
     if response.status() == StatusCode::NO_CONTENT {
         return Ok(None);
@@ -36,21 +164,35 @@ pub async fn download_from_downloader(
     Ok(Some(response))
 }
 
+pub fn get_response_filename(response: &Response) -> String {
+    let base64_encoder = general_purpose::STANDARD;
+
+    sanitize_filename(
+        std::str::from_utf8(
+            &base64_encoder
+                .decode(response.headers().get("x-filename-b64-ascii").unwrap())
+                .unwrap(),
+        )
+        .unwrap(),
+    )
+}
+
 pub async fn get_filename(
     object_id: i32,
     object_type: String,
 ) -> Result<FilenameData, Box<dyn std::error::Error + Send + Sync>> {
-    let url = format!(
-        "{}/filename/{object_id}/{object_type}",
-        CONFIG.downloader_url
-    );
-
-    let response = CLIENT
-        .get(url)
-        .header("Authorization", &CONFIG.downloader_api_key)
-        .send()
-        .await?
-        .error_for_status()?;
+    let path = format!("/filename/{object_id}/{object_type}");
+
+    let response = request_with_failover(&path, |url| async move {
+        CLIENT
+            .get(url)
+            .header("Authorization", &CONFIG.downloader_api_key)
+            .header(TRACEPARENT, traceparent())
+            .send()
+            .await?
+            .error_for_status()
+    })
+    .await?;
 
     match response.json::<FilenameData>().await {
         Ok(v) => Ok(v),
@@ -0,0 +1,116 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicI64, Ordering},
+    time::Instant,
+};
+
+use chrono::Duration;
+use tracing::log;
+
+use crate::{config::CONFIG, repository::DownloadRepository, views::Database};
+
+use super::leader::is_leader;
+
+pub struct DownloadRecord {
+    pub tenant: String,
+    pub api_key: String,
+    pub object_id: i32,
+    pub object_type: String,
+    pub bytes: i64,
+    pub duration_ms: i64,
+    pub client: Option<String>,
+}
+
+/// Tracks a download stream and, if download recording is enabled, stores it
+/// once the body is dropped. Aborted or failed transfers are not recorded.
+pub struct DownloadRecorder {
+    db: Database,
+    record: Option<DownloadRecord>,
+    started: Instant,
+    bytes: AtomicI64,
+    completed: AtomicBool,
+}
+
+impl DownloadRecorder {
+    pub fn new(
+        db: Database,
+        tenant: String,
+        api_key: String,
+        object_id: i32,
+        object_type: String,
+        client: Option<String>,
+        started: Instant,
+    ) -> Self {
+        Self {
+            db,
+            record: Some(DownloadRecord {
+                tenant,
+                api_key,
+                object_id,
+                object_type,
+                bytes: 0,
+                duration_ms: 0,
+                client,
+            }),
+            started,
+            bytes: AtomicI64::new(0),
+            completed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as i64, Ordering::Relaxed);
+    }
+
+    pub fn complete(&self) {
+        self.completed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for DownloadRecorder {
+    fn drop(&mut self) {
+        if !CONFIG.record_downloads || !self.completed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let Some(mut record) = self.record.take() else {
+            return;
+        };
+
+        record.bytes = self.bytes.load(Ordering::Relaxed);
+        record.duration_ms = self.started.elapsed().as_millis() as i64;
+
+        let download_repo = DownloadRepository::new(self.db.clone());
+
+        tokio::spawn(async move {
+            if let Err(err) = download_repo.create(&record).await {
+                log::error!("{:?}", err);
+            }
+        });
+    }
+}
+
+pub async fn start_downloads_retention(db: Database) {
+    if !CONFIG.record_downloads {
+        return;
+    }
+
+    let download_repo = DownloadRepository::new(db);
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        if !is_leader() {
+            continue;
+        }
+
+        let before = chrono::offset::Utc::now() - Duration::days(CONFIG.downloads_retention_days);
+
+        match download_repo.delete_before(before).await {
+            Ok(0) => (),
+            Ok(deleted) => log::info!("Deleted {} expired download records", deleted),
+            Err(err) => log::error!("{:?}", err),
+        }
+    }
+}
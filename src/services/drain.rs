@@ -0,0 +1,273 @@
+//! Graceful shutdown. Once draining starts no new files get cached; they're
+//! stored as pending jobs instead, and files already being cached get
+//! `SHUTDOWN_DRAIN_SECS` to finish. Whatever is still running then is stored
+//! too, and replicas pick pending jobs up as they go.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use futures::StreamExt;
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::{sync::Notify, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::log;
+
+use crate::{
+    config::CONFIG, object_type::ObjectType, repository::PendingCacheJobRepository,
+    serializers::PendingCacheJob, views::Database,
+};
+
+use super::{
+    audit::ACTOR_RESUME,
+    cache_file_on_demand,
+    executor::run_batch,
+    leader::{instance_id, renew_interval},
+};
+
+const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+
+struct InFlight {
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+}
+
+static DRAINING: Lazy<CancellationToken> = Lazy::new(CancellationToken::new);
+
+static DRAIN_STARTED_AT: OnceCell<Instant> = OnceCell::new();
+
+static IN_FLIGHT: Lazy<Mutex<HashMap<u64, InFlight>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static IDLE: Notify = Notify::const_new();
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn is_draining() -> bool {
+    DRAINING.is_cancelled()
+}
+
+/// Resolves once draining starts.
+pub async fn draining() {
+    DRAINING.cancelled().await
+}
+
+pub fn start_draining() {
+    DRAIN_STARTED_AT.get_or_init(Instant::now);
+    DRAINING.cancel();
+}
+
+/// A file being cached; it's listed until this is dropped.
+pub struct InFlightGuard {
+    id: u64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        in_flight.remove(&self.id);
+
+        if in_flight.is_empty() {
+            IDLE.notify_waiters();
+        }
+    }
+}
+
+/// Lists a file about to be cached; `None` once draining, when it should be
+/// stored with [`persist`] instead.
+pub fn track(
+    tenant: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+    db: &Database,
+) -> Option<InFlightGuard> {
+    if is_draining() {
+        return None;
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    IN_FLIGHT.lock().unwrap().insert(
+        id,
+        InFlight {
+            tenant: tenant.to_string(),
+            object_id,
+            object_type: object_type.clone(),
+            db: db.clone(),
+        },
+    );
+
+    Some(InFlightGuard { id })
+}
+
+/// Stores a file to be cached after the restart.
+pub async fn persist(db: &Database, tenant: &str, object_id: i32, object_type: &ObjectType) {
+    if let Err(err) = PendingCacheJobRepository::new(db.clone())
+        .create(tenant, object_id, object_type)
+        .await
+    {
+        log::error!("{:?}", err);
+    }
+}
+
+/// Waits for SIGTERM or Ctrl-C, then starts draining.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                log::error!("{:?}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    log::info!("Shutting down, draining in-flight cache jobs...");
+
+    start_draining();
+}
+
+/// Resolves `SHUTDOWN_DRAIN_SECS` after draining started.
+pub async fn deadline() {
+    draining().await;
+
+    let started_at = *DRAIN_STARTED_AT.get_or_init(Instant::now);
+
+    tokio::time::sleep_until(started_at + Duration::from_secs(CONFIG.shutdown_drain_secs)).await;
+}
+
+/// Waits for files being cached to finish until the deadline, then stores
+/// the ones that didn't.
+pub async fn finish() {
+    let idle = async {
+        loop {
+            let notified = IDLE.notified();
+
+            if IN_FLIGHT.lock().unwrap().is_empty() {
+                return;
+            }
+
+            notified.await;
+        }
+    };
+
+    tokio::select! {
+        _ = idle => return,
+        _ = deadline() => {},
+    }
+
+    let unfinished: Vec<InFlight> = IN_FLIGHT.lock().unwrap().drain().map(|(_, v)| v).collect();
+
+    log::warn!(
+        "Drain deadline passed, storing {} unfinished cache jobs",
+        unfinished.len()
+    );
+
+    for job in unfinished {
+        persist(&job.db, &job.tenant, job.object_id, &job.object_type).await;
+    }
+}
+
+/// Caches pending jobs, stored by this or other replicas, until draining.
+/// Jobs are claimed a batch at a time and only removed once cached, so the
+/// ones a replica dies holding go to another after `LEASE_TTL_SECS`.
+pub async fn start_resuming_pending(db: Database) {
+    let repo = PendingCacheJobRepository::new(db.clone());
+
+    let mut interval = tokio::time::interval(RESUME_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = draining() => return,
+        }
+
+        while !is_draining() {
+            let jobs = match repo
+                .claim(
+                    instance_id(),
+                    CONFIG.cache_batch_concurrency.max(1) as i64,
+                    CONFIG.lease_ttl_secs,
+                )
+                .await
+            {
+                Ok(v) => v,
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    break;
+                }
+            };
+
+            if jobs.is_empty() {
+                break;
+            }
+
+            log::info!("Resuming {} pending cache jobs", jobs.len());
+
+            resume_batch(&repo, jobs, &db).await;
+        }
+    }
+}
+
+async fn resume_batch(repo: &PendingCacheJobRepository, jobs: Vec<PendingCacheJob>, db: &Database) {
+    let work = run_batch(jobs, |job| async move {
+        cache_file_on_demand(
+            ACTOR_RESUME,
+            job.tenant.clone(),
+            job.object_id,
+            job.object_type.clone(),
+            db.clone(),
+        )
+        .await;
+
+        // Draining cuts caching short and stores the job again, which finds
+        // it still here; it's left for whoever claims it next.
+        let result = if is_draining() {
+            repo.unclaim(instance_id(), &job).await
+        } else {
+            repo.complete(instance_id(), &job).await
+        };
+
+        if let Err(err) = result {
+            log::error!("{:?}", err);
+        }
+    })
+    .for_each(|_| async {});
+
+    tokio::pin!(work);
+
+    let mut renew = tokio::time::interval(renew_interval());
+    renew.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = &mut work => return,
+            _ = renew.tick() => {
+                if let Err(err) = repo.renew(instance_id(), CONFIG.lease_ttl_secs).await {
+                    log::error!("{:?}", err);
+                }
+            }
+        }
+    }
+}
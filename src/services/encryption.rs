@@ -0,0 +1,255 @@
+//! Envelope encryption of files before they reach Telegram. Every file gets a
+//! random data key, kept on its row wrapped with a master key from
+//! `ENCRYPTION_KEYS`. Content is sealed in AES-256-GCM segments so downloads
+//! are decrypted as they stream; a segment's nonce is the row's nonce prefix
+//! plus its index, and the associated data marks the last segment, so a
+//! truncated file fails to decrypt instead of coming out short.
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use base64::{engine::general_purpose, Engine};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{config::CONFIG, serializers::CachedFile};
+
+use super::download_utils::{BodyStream, HashedFile};
+
+type EncryptionError = Box<dyn std::error::Error + Send + Sync>;
+
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const NONCE_PREFIX_SIZE: usize = 8;
+const DATA_KEY_SIZE: usize = 32;
+
+/// What a row keeps to decrypt its file.
+pub struct Encryption {
+    pub key_id: String,
+    /// The wrapping nonce followed by the wrapped data key, base64.
+    pub encrypted_key: String,
+    /// The nonce prefix of the content segments, base64.
+    pub nonce: String,
+}
+
+impl Encryption {
+    pub fn of(cached_file: &CachedFile) -> Option<Self> {
+        Some(Self {
+            key_id: cached_file.encryption_key_id.clone()?,
+            encrypted_key: cached_file.encrypted_key.clone()?,
+            nonce: cached_file.encryption_nonce.clone()?,
+        })
+    }
+}
+
+fn master_key(key_id: &str) -> Result<Aes256Gcm, EncryptionError> {
+    let key = CONFIG
+        .encryption_keys
+        .get(key_id)
+        .ok_or_else(|| format!("unknown encryption key {key_id}"))?;
+
+    let key = general_purpose::STANDARD.decode(key)?;
+
+    Aes256Gcm::new_from_slice(&key)
+        .map_err(|_| format!("encryption key {key_id} isn't {DATA_KEY_SIZE} bytes").into())
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn segment_nonce(prefix: &[u8], index: u32) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+fn seal_segment(
+    cipher: &Aes256Gcm,
+    prefix: &[u8],
+    index: u32,
+    last: bool,
+    data: &[u8],
+) -> Result<Vec<u8>, EncryptionError> {
+    let nonce = segment_nonce(prefix, index);
+    let payload = Payload {
+        msg: data,
+        aad: &[last as u8],
+    };
+
+    cipher
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| format!("can't encrypt segment {index}").into())
+}
+
+fn open_segment(
+    cipher: &Aes256Gcm,
+    prefix: &[u8],
+    index: u32,
+    last: bool,
+    data: &[u8],
+) -> std::io::Result<Bytes> {
+    let nonce = segment_nonce(prefix, index);
+    let payload = Payload {
+        msg: data,
+        aad: &[last as u8],
+    };
+
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), payload)
+        .map(Bytes::from)
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("can't decrypt segment {index}"),
+            )
+        })
+}
+
+/// Encrypts the file with a new data key when `ENCRYPTION_KEY_ID` is set,
+/// otherwise hands it back untouched. The result is hashed like the plaintext
+/// was, so the upload is verified against what was actually sent.
+pub async fn encrypt_file(
+    mut file: HashedFile,
+) -> Result<(HashedFile, Option<Encryption>), EncryptionError> {
+    let Some(key_id) = &CONFIG.encryption_key_id else {
+        return Ok((file, None));
+    };
+
+    let master = master_key(key_id)?;
+
+    let data_key = random_bytes::<DATA_KEY_SIZE>();
+    let wrap_nonce = random_bytes::<NONCE_SIZE>();
+    let prefix = random_bytes::<NONCE_PREFIX_SIZE>();
+
+    let wrapped_key = master
+        .encrypt(
+            Nonce::from_slice(&wrap_nonce),
+            Payload {
+                msg: &data_key,
+                aad: key_id.as_bytes(),
+            },
+        )
+        .map_err(|_| "can't wrap the data key")?;
+
+    let cipher = Aes256Gcm::new_from_slice(&data_key).unwrap();
+
+    let mut output = tokio::fs::File::from_std(tempfile::tempfile()?);
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+
+    let mut buffer = vec![0; SEGMENT_SIZE];
+    let mut remaining = file.size;
+    let mut index: u32 = 0;
+
+    // An empty file still gets its one, last, segment.
+    loop {
+        let len = remaining.min(SEGMENT_SIZE as u64) as usize;
+        file.file.read_exact(&mut buffer[..len]).await?;
+        remaining -= len as u64;
+
+        let segment = seal_segment(&cipher, &prefix, index, remaining == 0, &buffer[..len])?;
+
+        hasher.update(&segment);
+        size += segment.len() as u64;
+        output.write_all(&segment).await?;
+
+        if remaining == 0 {
+            break;
+        }
+
+        index += 1;
+    }
+
+    output.flush().await?;
+    output.seek(std::io::SeekFrom::Start(0)).await?;
+
+    let encryption = Encryption {
+        key_id: key_id.clone(),
+        encrypted_key: general_purpose::STANDARD.encode([&wrap_nonce[..], &wrapped_key].concat()),
+        nonce: general_purpose::STANDARD.encode(prefix),
+    };
+
+    Ok((
+        HashedFile {
+            file: output,
+            size,
+            sha256: hex::encode(hasher.finalize()),
+        },
+        Some(encryption),
+    ))
+}
+
+/// Decrypts a download as it streams. Fails up front when the master key the
+/// row was encrypted with is no longer configured.
+pub fn decrypt_body(
+    body: BodyStream,
+    encryption: &Encryption,
+) -> Result<BodyStream, EncryptionError> {
+    let master = master_key(&encryption.key_id)?;
+
+    let wrapped_key = general_purpose::STANDARD.decode(&encryption.encrypted_key)?;
+    if wrapped_key.len() < NONCE_SIZE {
+        return Err("malformed data key".into());
+    }
+    let (wrap_nonce, wrapped_key) = wrapped_key.split_at(NONCE_SIZE);
+
+    let data_key = master
+        .decrypt(
+            Nonce::from_slice(wrap_nonce),
+            Payload {
+                msg: wrapped_key,
+                aad: encryption.key_id.as_bytes(),
+            },
+        )
+        .map_err(|_| "can't unwrap the data key")?;
+
+    let cipher = Aes256Gcm::new_from_slice(&data_key).map_err(|_| "malformed data key")?;
+
+    let prefix = general_purpose::STANDARD.decode(&encryption.nonce)?;
+    if prefix.len() != NONCE_PREFIX_SIZE {
+        return Err("malformed nonce".into());
+    }
+
+    Ok(async_stream::stream! {
+        let mut body = body;
+        let mut buffer = BytesMut::new();
+        let mut index: u32 = 0;
+
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => buffer.extend_from_slice(&chunk),
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            }
+
+            // Only a segment with more after it is known not to be the last.
+            while buffer.len() > SEGMENT_SIZE + TAG_SIZE {
+                let segment = buffer.split_to(SEGMENT_SIZE + TAG_SIZE);
+
+                match open_segment(&cipher, &prefix, index, false, &segment) {
+                    Ok(v) => yield Ok(v),
+                    Err(err) => {
+                        yield Err(err);
+                        return;
+                    }
+                }
+
+                index += 1;
+            }
+        }
+
+        yield open_segment(&cipher, &prefix, index, true, &buffer);
+    }
+    .boxed())
+}
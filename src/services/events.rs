@@ -0,0 +1,114 @@
+//! Cache changes published to NATS and webhooks, for consumers that want a push feed
+//! instead of polling the API.
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::log;
+
+use crate::{
+    config::{Webhook, CONFIG},
+    serializers::CachedFile,
+};
+
+use super::{nats::get_nats_client, webhooks::deliver};
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum CacheEvent<'a> {
+    Cached {
+        cached_file: &'a CachedFile,
+    },
+    /// A soft-deleted file was purged for good.
+    Evicted {
+        cached_file: &'a CachedFile,
+    },
+    Deleted {
+        cached_file: &'a CachedFile,
+    },
+    /// A deleted file was brought back before it was purged.
+    Undeleted {
+        cached_file: &'a CachedFile,
+    },
+    /// A file held back from serving until it's reviewed.
+    Quarantined {
+        cached_file: &'a CachedFile,
+    },
+    /// A quarantined file was cleared for serving again.
+    Released {
+        cached_file: &'a CachedFile,
+    },
+    /// A file whose message went missing; `succeeded` is whether it was
+    /// cached again.
+    Repair {
+        cached_file: &'a CachedFile,
+        succeeded: bool,
+    },
+    UpdateFinished {
+        tenant: &'a str,
+        success: bool,
+        cached: u64,
+        failed: u64,
+        skipped_pages: usize,
+    },
+}
+
+impl CacheEvent<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheEvent::Cached { .. } => "cached",
+            CacheEvent::Evicted { .. } => "evicted",
+            CacheEvent::Deleted { .. } => "deleted",
+            CacheEvent::Undeleted { .. } => "undeleted",
+            CacheEvent::Quarantined { .. } => "quarantined",
+            CacheEvent::Released { .. } => "released",
+            CacheEvent::Repair { .. } => "repair",
+            CacheEvent::UpdateFinished { .. } => "update_finished",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    event: CacheEvent<'a>,
+}
+
+/// Publishes to the subject `EVENT_SUBJECTS` maps the event to and to every
+/// webhook that accepts it. Delivery is best effort and never fails the
+/// change being reported.
+pub async fn publish(event: CacheEvent<'_>) {
+    let name = event.name();
+
+    let subject = CONFIG.event_subjects.get(name);
+    let webhooks: Vec<&'static Webhook> = CONFIG
+        .webhooks
+        .iter()
+        .filter(|webhook| webhook.accepts(name))
+        .collect();
+
+    if subject.is_none() && webhooks.is_empty() {
+        return;
+    }
+
+    let payload: Bytes = serde_json::to_vec(&Envelope {
+        timestamp: Utc::now(),
+        event,
+    })
+    .unwrap()
+    .into();
+
+    // Deliveries retry with backoff, so they don't hold up the caller.
+    for webhook in webhooks {
+        tokio::spawn(deliver(webhook, name, payload.clone()));
+    }
+
+    let (Some(subject), Some(client)) = (subject, get_nats_client().await) else {
+        return;
+    };
+
+    if let Err(err) = client.publish(subject.clone(), payload).await {
+        log::error!("{:?}", err);
+    }
+}
@@ -0,0 +1,49 @@
+//! Shared limits on caching work. Fetching from the downloader and uploading
+//! to Telegram have different bottlenecks, so each stage gets its own limit
+//! across every caller (API, queue and batch runs alike); a batch runs as fast
+//! as its slower stage allows without flooding the other.
+
+use std::{future::Future, sync::Arc};
+
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::CONFIG;
+
+pub enum Stage {
+    /// Getting the file from the downloader.
+    Fetch,
+    /// Sending the file to Telegram.
+    Upload,
+}
+
+static FETCH_SLOTS: Lazy<Arc<Semaphore>> =
+    Lazy::new(|| Arc::new(Semaphore::new(CONFIG.cache_fetch_concurrency.max(1))));
+
+static UPLOAD_SLOTS: Lazy<Arc<Semaphore>> =
+    Lazy::new(|| Arc::new(Semaphore::new(CONFIG.cache_upload_concurrency.max(1))));
+
+/// Waits for room in `stage`; the slot is held until the permit is dropped.
+pub async fn enter(stage: Stage) -> OwnedSemaphorePermit {
+    let slots: &Arc<Semaphore> = match stage {
+        Stage::Fetch => &FETCH_SLOTS,
+        Stage::Upload => &UPLOAD_SLOTS,
+    };
+
+    slots.clone().acquire_owned().await.unwrap()
+}
+
+/// Runs `f` over `items`, `CACHE_BATCH_CONCURRENCY` at a time, yielding the
+/// results as they finish. Items that made it past one stage and wait on the
+/// next stay bounded by that, instead of piling up temporary files.
+pub fn run_batch<I, F, Fut>(items: I, f: F) -> impl Stream<Item = Fut::Output>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future,
+{
+    futures::stream::iter(items)
+        .map(f)
+        .buffer_unordered(CONFIG.cache_batch_concurrency.max(1))
+}
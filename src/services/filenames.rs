@@ -0,0 +1,90 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::services::downloader::FilenameData;
+
+/// Most filesystems cap a name at 255 bytes.
+const MAX_FILENAME_BYTES: usize = 255;
+/// Extensions longer than this are more likely part of the title.
+const MAX_EXTENSION_CHARS: usize = 16;
+
+const FALLBACK_FILENAME: &str = "file";
+
+fn is_allowed(c: char) -> bool {
+    !c.is_control()
+        && !matches!(
+            c,
+            '"' | '\u{200B}'..='\u{200F}' | '\u{2028}'..='\u{202E}' | '\u{FEFF}'
+        )
+}
+
+/// Cuts `value` to at most `max_bytes` without splitting a character.
+fn truncate(value: &str, max_bytes: usize) -> &str {
+    let mut end = value.len().min(max_bytes);
+
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &value[..end]
+}
+
+/// Shortens the name to the limit, keeping the extension intact.
+fn limit_length(filename: &str) -> String {
+    if filename.len() <= MAX_FILENAME_BYTES {
+        return filename.to_string();
+    }
+
+    let (stem, extension) = match filename.rsplit_once('.') {
+        Some((stem, extension))
+            if !stem.is_empty() && extension.chars().count() <= MAX_EXTENSION_CHARS =>
+        {
+            (stem, format!(".{extension}"))
+        }
+        _ => (filename, String::new()),
+    };
+
+    format!(
+        "{}{extension}",
+        truncate(stem, MAX_FILENAME_BYTES - extension.len()).trim_end()
+    )
+}
+
+/// Normalizes a filename to NFC and strips anything that could escape a
+/// directory or break a header: path separators, quotes, control and
+/// invisible formatting characters. Never returns an empty name.
+pub fn sanitize_filename(filename: &str) -> String {
+    let cleaned: String = filename
+        .nfc()
+        .map(|c| if matches!(c, '/' | '\\') { '_' } else { c })
+        .filter(|c| is_allowed(*c))
+        .collect();
+
+    // Leading dots would hide the file or make it `..`.
+    let cleaned = cleaned.trim().trim_start_matches('.').trim_start();
+
+    if cleaned.is_empty() {
+        return FALLBACK_FILENAME.to_string();
+    }
+
+    limit_length(cleaned)
+}
+
+/// Like `sanitize_filename`, but limited to printable ASCII so it can go into
+/// a header as is.
+pub fn sanitize_filename_ascii(filename: &str) -> String {
+    let ascii: String = sanitize_filename(filename)
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .collect();
+
+    sanitize_filename(&ascii)
+}
+
+impl FilenameData {
+    pub fn sanitized(self) -> Self {
+        FilenameData {
+            filename: sanitize_filename(&self.filename),
+            filename_ascii: sanitize_filename_ascii(&self.filename_ascii),
+        }
+    }
+}
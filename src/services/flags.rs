@@ -0,0 +1,105 @@
+use std::{borrow::Cow, collections::HashMap, sync::RwLock};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::log;
+
+use crate::{config::CONFIG, db::Database, repository::FeatureFlagRepository};
+
+/// Behaviors that can be switched per deployment.
+#[derive(Clone, Copy)]
+pub enum Flag {
+    /// Reuse the Telegram message of an identical, already cached payload.
+    Dedup,
+    /// Read every upload back and retry it if Telegram stored something else.
+    VerifyUploads,
+}
+
+impl Flag {
+    pub const ALL: &'static [Flag] = &[Flag::Dedup, Flag::VerifyUploads];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::Dedup => "dedup",
+            Flag::VerifyUploads => "verify_uploads",
+        }
+    }
+
+    fn default_enabled(&self) -> bool {
+        match self {
+            Flag::Dedup => true,
+            Flag::VerifyUploads => true,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Flag> {
+        Flag::ALL.iter().copied().find(|flag| flag.name() == name)
+    }
+}
+
+#[derive(Serialize, Deserialize, async_graphql::SimpleObject)]
+pub struct FlagState {
+    pub name: Cow<'static, str>,
+    pub enabled: bool,
+    /// `default`, `config` (FEATURE_FLAGS of this instance) or `database` (shared override).
+    pub source: Cow<'static, str>,
+}
+
+/// Overrides stored in the database, shared by every instance.
+static DB_OVERRIDES: Lazy<RwLock<HashMap<String, bool>>> = Lazy::new(Default::default);
+
+pub fn get_flag_state(flag: Flag) -> FlagState {
+    let name = flag.name();
+
+    if let Some(enabled) = DB_OVERRIDES.read().unwrap().get(name) {
+        return FlagState {
+            name: name.into(),
+            enabled: *enabled,
+            source: "database".into(),
+        };
+    }
+
+    if let Some(enabled) = CONFIG.feature_flags.get(name) {
+        return FlagState {
+            name: name.into(),
+            enabled: *enabled,
+            source: "config".into(),
+        };
+    }
+
+    FlagState {
+        name: name.into(),
+        enabled: flag.default_enabled(),
+        source: "default".into(),
+    }
+}
+
+pub fn is_enabled(flag: Flag) -> bool {
+    get_flag_state(flag).enabled
+}
+
+pub async fn reload_flags(db: &Database) -> Result<(), sqlx::Error> {
+    let overrides = FeatureFlagRepository::new(db.clone()).get_all().await?;
+
+    *DB_OVERRIDES.write().unwrap() = overrides
+        .into_iter()
+        .map(|flag| (flag.name, flag.enabled))
+        .collect();
+
+    Ok(())
+}
+
+/// Picks up overrides changed through other instances.
+pub async fn start_flags_refresher(db: Database) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        CONFIG.feature_flags_refresh_secs.get(),
+    ));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = reload_flags(&db).await {
+            log::error!("{:?}", err);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Extension points for embedders, registered through
+//! [`ServerBuilder::hooks`](crate::views::ServerBuilder::hooks).
+
+use std::sync::{Arc, RwLock};
+
+use once_cell::sync::Lazy;
+
+use crate::serializers::CachedFile;
+
+/// Called synchronously from the cache paths, so implementations should be
+/// quick and spawn anything slow. Every method defaults to doing nothing.
+pub trait CacheHooks: Send + Sync + 'static {
+    fn on_cache_start(&self, _tenant: &str, _object_id: i32, _object_type: &str) {}
+
+    fn on_cached(&self, _cached_file: &CachedFile) {}
+
+    /// Called for every soft-deleted row, conversions included.
+    fn on_delete(&self, _cached_file: &CachedFile) {}
+
+    /// Called when a soft-deleted row is brought back before its purge.
+    fn on_undelete(&self, _cached_file: &CachedFile) {}
+
+    /// Called when a file is held back from serving; see `quarantine_reason`.
+    fn on_quarantine(&self, _cached_file: &CachedFile) {}
+
+    /// Caching produced no file; the cause is in the logs.
+    fn on_error(&self, _tenant: &str, _object_id: i32, _object_type: &str) {}
+}
+
+static HOOKS: Lazy<RwLock<Vec<Arc<dyn CacheHooks>>>> = Lazy::new(Default::default);
+
+pub fn register_hooks(hooks: Arc<dyn CacheHooks>) {
+    HOOKS.write().unwrap().push(hooks);
+}
+
+pub fn run_hooks(f: impl Fn(&dyn CacheHooks)) {
+    for hooks in HOOKS.read().unwrap().iter() {
+        f(hooks.as_ref());
+    }
+}
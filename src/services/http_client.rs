@@ -0,0 +1,85 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{
+    config::{HttpVersion, UpstreamTls, CONFIG},
+    services::dns::CachingResolver,
+};
+
+/// Builds the client for one upstream. Each upstream module keeps its client
+/// in a static so connections, and their TLS sessions, are reused across calls.
+pub fn build_client(upstream: &str) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .dns_resolver(Arc::new(CachingResolver))
+        .pool_idle_timeout(Duration::from_secs(CONFIG.http_pool_idle_timeout_secs))
+        .tcp_keepalive(Duration::from_secs(CONFIG.http_tcp_keepalive_secs))
+        .connect_timeout(Duration::from_secs(CONFIG.http_connect_timeout_secs));
+
+    if let Some(max_idle) = CONFIG.http_pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+
+    builder = match CONFIG.http_version(upstream) {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+
+    // Pings keep idle HTTP/2 connections from being dropped between update runs.
+    if let Some(interval) = CONFIG.http2_keep_alive_interval_secs {
+        builder = builder
+            .http2_keep_alive_interval(Duration::from_secs(interval))
+            .http2_keep_alive_while_idle(true);
+    }
+
+    if CONFIG.http2_adaptive_window {
+        builder = builder.http2_adaptive_window(true);
+    }
+
+    // Port 0 keeps the port from the URL.
+    for (host, ips) in &CONFIG.dns_overrides {
+        let addrs: Vec<SocketAddr> = ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+        builder = builder.resolve_to_addrs(host, &addrs);
+    }
+
+    if let Some(proxy) = CONFIG.upstream_proxies.get(upstream) {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).unwrap());
+    }
+
+    if let Some(tls) = CONFIG.upstream_tls.get(upstream) {
+        builder = with_tls(builder, upstream, tls);
+    }
+
+    builder.build().unwrap()
+}
+
+fn read_pem(upstream: &str, path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| panic!("UPSTREAM_TLS[{upstream}]: {path}: {err}"))
+}
+
+fn with_tls(
+    mut builder: reqwest::ClientBuilder,
+    upstream: &str,
+    tls: &UpstreamTls,
+) -> reqwest::ClientBuilder {
+    if let Some(path) = &tls.ca_bundle {
+        let certs = reqwest::Certificate::from_pem_bundle(&read_pem(upstream, path)).unwrap();
+
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            let identity = reqwest::Identity::from_pkcs8_pem(
+                &read_pem(upstream, cert),
+                &read_pem(upstream, key),
+            )
+            .unwrap();
+
+            builder.identity(identity)
+        }
+        (None, None) => builder,
+        _ => panic!("UPSTREAM_TLS[{upstream}]: client_cert and client_key go together"),
+    }
+}
@@ -0,0 +1,94 @@
+use std::{future::Future, time::Instant};
+
+use axum_prometheus::metrics::{counter, histogram, Counter};
+use tracing::log;
+
+use crate::config::CONFIG;
+
+pub const UPSTREAM_REQUEST_DURATION_SECONDS: &str = "upstream_request_duration_seconds";
+pub const CACHE_LOOKUPS_TOTAL: &str = "cache_lookups_total";
+pub const CACHE_POPULATIONS_TOTAL: &str = "cache_populations_total";
+pub const BYTES_SERVED_TOTAL: &str = "bytes_served_total";
+pub const BYTES_UPLOADED_TOTAL: &str = "bytes_uploaded_total";
+pub const UPLOAD_VERIFICATION_FAILURES_TOTAL: &str = "upload_verification_failures_total";
+pub const TELEGRAM_THROTTLE_SECONDS: &str = "telegram_throttle_seconds";
+pub const BACKGROUND_THROTTLE_SECONDS: &str = "background_throttle_seconds";
+
+/// Times a call to an upstream dependency and records it under `upstream`,
+/// labeled with whether it succeeded. Calls slower than the configured
+/// threshold are logged along with `context`.
+pub async fn observe<T, E, F>(upstream: &'static str, context: &str, request: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let started = Instant::now();
+    let result = request.await;
+    let elapsed = started.elapsed();
+
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+
+    if elapsed >= CONFIG.slow_request_threshold() {
+        log::warn!(
+            "Slow {} call ({}) took {:?}: {}",
+            upstream,
+            outcome,
+            elapsed,
+            context
+        );
+    }
+
+    histogram!(
+        UPSTREAM_REQUEST_DURATION_SECONDS,
+        "upstream" => upstream,
+        "outcome" => outcome
+    )
+    .record(elapsed.as_secs_f64());
+
+    result
+}
+
+pub fn record_cache_lookup(object_type: &str, hit: bool) {
+    counter!(
+        CACHE_LOOKUPS_TOTAL,
+        "object_type" => object_type.to_string(),
+        "result" => if hit { "hit" } else { "miss" }
+    )
+    .increment(1);
+}
+
+pub fn record_cache_population(object_type: &str, success: bool) {
+    counter!(
+        CACHE_POPULATIONS_TOTAL,
+        "object_type" => object_type.to_string(),
+        "outcome" => if success { "ok" } else { "error" }
+    )
+    .increment(1);
+}
+
+pub fn bytes_served_counter(object_type: &str) -> Counter {
+    counter!(BYTES_SERVED_TOTAL, "object_type" => object_type.to_string())
+}
+
+pub fn record_bytes_uploaded(object_type: &str, bytes: u64) {
+    counter!(BYTES_UPLOADED_TOTAL, "object_type" => object_type.to_string()).increment(bytes);
+}
+
+pub fn record_upload_verification_failure(object_type: &str) {
+    counter!(UPLOAD_VERIFICATION_FAILURES_TOTAL, "object_type" => object_type.to_string())
+        .increment(1);
+}
+
+/// Time an operation waited for its turn at Telegram.
+pub fn record_telegram_throttle(op: &'static str, waited: std::time::Duration) {
+    histogram!(TELEGRAM_THROTTLE_SECONDS, "op" => op).record(waited.as_secs_f64());
+}
+
+/// Time a background job waited on its request budget for `upstream`.
+pub fn record_background_throttle(
+    upstream: &'static str,
+    job: &'static str,
+    waited: std::time::Duration,
+) {
+    histogram!(BACKGROUND_THROTTLE_SECONDS, "upstream" => upstream, "job" => job)
+        .record(waited.as_secs_f64());
+}
@@ -0,0 +1,181 @@
+//! Coordination between replicas through leases in Postgres. One replica is
+//! elected leader and runs the scheduled tasks, while all of them serve
+//! traffic; runs triggered over the API take a lease of their own so only the
+//! first replica to get the request does the work.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use axum_prometheus::metrics::gauge;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::log;
+
+use crate::{config::CONFIG, repository::LeaseRepository, views::Database};
+
+use super::trace_context::new_trace_id;
+
+const LEADER_LEASE: &str = "leader";
+
+const IS_LEADER_METRIC: &str = "is_leader";
+
+static INSTANCE_ID: Lazy<String> = Lazy::new(|| {
+    CONFIG
+        .instance_id
+        .clone()
+        .unwrap_or_else(|| hex::encode(rand::thread_rng().gen::<[u8; 8]>()))
+});
+
+static IS_LEADER: AtomicBool = AtomicBool::new(false);
+
+/// Renewing three times per TTL lets a lease survive a missed renewal.
+pub fn renew_interval() -> Duration {
+    Duration::from_secs((CONFIG.lease_ttl_secs / 3).max(1))
+}
+
+async fn acquire(repo: &LeaseRepository, name: &str, holder: &str) -> bool {
+    match repo.acquire(name, holder, CONFIG.lease_ttl_secs).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            false
+        }
+    }
+}
+
+/// How this replica names itself in leases and claims.
+pub fn instance_id() -> &'static str {
+    &INSTANCE_ID
+}
+
+pub fn is_leader() -> bool {
+    IS_LEADER.load(Ordering::Relaxed)
+}
+
+/// Keeps trying to take the leader lease and renews it while held. A replica
+/// that can't reach the database steps down, since the lease may have
+/// expired meanwhile.
+pub async fn start_leader_election(db: Database) {
+    let repo = LeaseRepository::new(db);
+
+    let mut interval = tokio::time::interval(renew_interval());
+
+    loop {
+        interval.tick().await;
+
+        let is_leader = acquire(&repo, LEADER_LEASE, &INSTANCE_ID).await;
+
+        if IS_LEADER.swap(is_leader, Ordering::Relaxed) != is_leader {
+            if is_leader {
+                log::info!("Instance {} is now the leader", *INSTANCE_ID);
+            } else {
+                log::warn!("Instance {} is no longer the leader", *INSTANCE_ID);
+            }
+        }
+
+        gauge!(IS_LEADER_METRIC).set(if is_leader { 1.0 } else { 0.0 });
+    }
+}
+
+/// A lease taken for one piece of work, renewed in the background until it's
+/// released. Holders are per claim, so a second claim from the same replica is
+/// turned away too. One dropped without [`Claim::release`] expires after the TTL.
+pub struct Claim {
+    repo: LeaseRepository,
+    name: String,
+    holder: String,
+    renewer: JoinHandle<()>,
+    lost: CancellationToken,
+}
+
+impl Claim {
+    /// `None` while someone else holds `name`.
+    pub async fn take(db: Database, name: String) -> Option<Claim> {
+        let repo = LeaseRepository::new(db.clone());
+        let holder = format!("{}/{}", *INSTANCE_ID, new_trace_id());
+
+        if !acquire(&repo, &name, &holder).await {
+            return None;
+        }
+
+        let lost = CancellationToken::new();
+
+        let renewer = tokio::spawn({
+            let repo = LeaseRepository::new(db);
+            let name = name.clone();
+            let holder = holder.clone();
+            let lost = lost.clone();
+
+            async move {
+                let mut interval = tokio::time::interval(renew_interval());
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+
+                    // Unrenewed, it may already be someone else's, so the
+                    // work stops rather than risk running twice.
+                    if !acquire(&repo, &name, &holder).await {
+                        log::warn!("Lost the lease on {} while holding it", name);
+                        lost.cancel();
+                        return;
+                    }
+                }
+            }
+        });
+
+        Some(Claim {
+            repo,
+            name,
+            holder,
+            renewer,
+            lost,
+        })
+    }
+
+    /// Runs `work` while the lease holds; `None` if it was lost first, in
+    /// which case `work` is dropped unfinished.
+    pub async fn guard<F: Future>(&self, work: F) -> Option<F::Output> {
+        tokio::select! {
+            output = work => Some(output),
+            _ = self.lost.cancelled() => None,
+        }
+    }
+
+    pub async fn release(self) {
+        self.renewer.abort();
+
+        if let Err(err) = self.repo.release(&self.name, &self.holder).await {
+            log::error!("{:?}", err);
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.renewer.abort();
+    }
+}
+
+/// Runs `job` unless another replica is already running `name`, holding the
+/// lease until it's done. Losing the lease stops `job`.
+pub async fn run_exclusive<F>(db: Database, name: String, job: F)
+where
+    F: Future<Output = ()>,
+{
+    let Some(claim) = Claim::take(db, name.clone()).await else {
+        log::info!("Skipping {}, it is already running", name);
+        return;
+    };
+
+    if claim.guard(job).await.is_none() {
+        log::warn!("Stopped {}, its lease was lost", name);
+    }
+
+    claim.release().await;
+}
@@ -1,200 +1,1320 @@
+pub mod audit;
 pub mod book_library;
 pub mod bots;
+pub mod buffer_pool;
+pub mod cache_jobs;
+pub mod cache_stats;
+pub mod compression;
+pub mod conversion;
+pub mod covers;
+pub mod details;
+pub mod dns;
 pub mod download_utils;
 pub mod downloader;
+pub mod downloads;
+pub mod drain;
+pub mod encryption;
+pub mod events;
+pub mod executor;
+pub mod filenames;
+pub mod flags;
+pub mod hooks;
+pub mod http_client;
+pub mod instrument;
+pub mod leader;
+pub mod nats;
+pub mod notifier;
+pub mod pushgateway;
+pub mod quarantine;
+pub mod queue;
+pub mod quota;
+pub mod rehost;
+pub mod rollover;
+pub mod snapshot;
+pub mod staging;
+pub mod storage;
 pub mod telegram_files;
+pub mod throttle;
+pub mod trace_context;
+pub mod usage;
+pub mod webhooks;
+pub mod workers;
+
+use std::{future::Future, io::SeekFrom, sync::Arc};
 
 use chrono::Duration;
+use futures::StreamExt;
 use moka::future::Cache;
 use once_cell::sync::Lazy;
-use serde::Serialize;
+use rand::Rng;
+use sentry::{Hub, SentryFutureExt};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
 use teloxide::{
     requests::Requester,
     types::{ChatId, MessageId, Recipient},
+    RequestError,
 };
+use tokio::io::AsyncSeekExt;
 use tracing::log;
 
-use crate::{config, repository::CachedFileRepository, serializers::CachedFile, views::Database};
+use crate::{
+    config, object_type::ObjectType, repository::CachedFileRepository, serializers::CachedFile,
+    views::Database,
+};
 
 use self::{
-    book_library::{get_book, get_books, types::BaseBook},
+    audit::{
+        AuditAction, ACTOR_API, ACTOR_PURGE_DELETED, ACTOR_REVALIDATE, ACTOR_UPDATE_CACHE,
+        RESULT_FAILED, RESULT_NOT_FOUND, RESULT_OK,
+    },
+    book_library::{
+        download_book_cover, get_book, get_book_cover, get_books, get_books_by_ids,
+        types::{BaseBook, BookWithRemote, Page},
+    },
     bots::ROUND_ROBIN_BOT,
-    download_utils::DownloadResult,
-    downloader::{download_from_downloader, get_filename, FilenameData},
-    telegram_files::{download_from_telegram_files, upload_to_telegram_files, UploadData},
+    cache_jobs::{CacheJob, CacheJobStage},
+    compression::{codec, compress_file, decompress_body, file_extension},
+    covers::cover_filename,
+    download_utils::{
+        response_digest, response_to_hashed_file, resumable_body, DownloadResult, HashedFile,
+    },
+    downloader::{download_from_downloader, get_filename, get_response_filename, FilenameData},
+    encryption::{decrypt_body, encrypt_file, Encryption},
+    events::CacheEvent,
+    executor::{run_batch, Stage},
+    flags::{is_enabled, Flag},
+    hooks::run_hooks,
+    instrument::{
+        record_bytes_uploaded, record_cache_lookup, record_cache_population,
+        record_upload_verification_failure,
+    },
+    leader::{is_leader, run_exclusive, Claim},
+    notifier::notify_admins,
+    pushgateway::push_job_metrics,
+    quarantine::{find_quarantined, quarantine, record_download_failure},
+    rollover::upload_chat_id,
+    staging::{clear_staged, find_staged, stage},
+    storage::storage,
+    telegram_files::{ChatMigrated, UploadData, UploadMedia},
+    throttle::{telegram_turn, TelegramOp},
+    trace_context::propagate,
 };
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct CacheData {
     pub id: Option<i32>,
     pub object_id: i32,
-    pub object_type: String,
+    pub object_type: ObjectType,
     pub message_id: i32,
     pub chat_id: i64,
 }
 
-pub static TEMP_MESSAGES: Lazy<Cache<i32, MessageId>> = Lazy::new(|| {
+/// Spawns a background job on the [`workers`] runtime with its own Sentry
+/// scope, tagged with the job name so failures can be told apart from request
+/// errors.
+pub fn spawn_job<F>(name: &'static str, job: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| scope.set_tag("job", name));
+
+    workers::spawn(
+        name,
+        trace_context::scope(
+            trace_context::new_trace_id(),
+            trace_context::job_scope(name, job),
+        )
+        .bind_hub(hub),
+    );
+}
+
+pub static TEMP_MESSAGES: Lazy<Cache<i32, (ChatId, MessageId)>> = Lazy::new(|| {
     Cache::builder()
         .time_to_idle(std::time::Duration::from_secs(16))
         .max_capacity(4098)
-        .async_eviction_listener(|_data_id, message_id, _cause| {
+        .async_eviction_listener(|_data_id, (chat_id, message_id), _cause| {
             Box::pin(async move {
+                telegram_turn(TelegramOp::Delete).await;
+
                 let bot = ROUND_ROBIN_BOT.get_bot();
-                let _ = bot
-                    .delete_message(
-                        Recipient::Id(ChatId(config::CONFIG.temp_channel_id)),
-                        message_id,
-                    )
-                    .await;
+                let _ = bot.delete_message(Recipient::Id(chat_id), message_id).await;
             })
         })
         .build()
 });
 
-pub async fn get_cached_file_or_cache(
+pub async fn find_cached_file(
+    tenant: String,
     object_id: i32,
-    object_type: String,
+    object_type: ObjectType,
     db: Database,
 ) -> Option<CachedFile> {
-    let cached_file = sqlx::query_as!(
+    select_cached_file(&tenant, object_id, &object_type, db.reader()).await
+}
+
+/// Like [`find_cached_file`], but on the primary; checks made under a
+/// population claim can't go by a replica that hasn't seen the last upload.
+async fn find_cached_file_primary(
+    tenant: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+    db: &Database,
+) -> Option<CachedFile> {
+    select_cached_file(tenant, object_id, object_type, db.writer()).await
+}
+
+async fn select_cached_file(
+    tenant: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+    pool: &PgPool,
+) -> Option<CachedFile> {
+    sqlx::query_as!(
         CachedFile,
         r#"
         SELECT * FROM cached_files
-        WHERE object_id = $1 AND object_type = $2"#,
+        WHERE tenant = $1 AND object_id = $2 AND object_type = $3
+          AND deleted_at IS NULL AND quarantined_at IS NULL"#,
+        tenant,
+        object_id,
+        object_type.as_str()
+    )
+    .fetch_optional(pool)
+    .await
+    .unwrap()
+}
+
+pub async fn get_cached_file_or_cache(
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) -> Option<CachedFile> {
+    let cached_file =
+        find_cached_file(tenant.clone(), object_id, object_type.clone(), db.clone()).await;
+
+    record_cache_lookup(object_type.as_str(), cached_file.is_some());
+
+    match cached_file {
+        Some(cached_file) => Some(cached_file),
+        None => cache_file_on_demand(ACTOR_API, tenant, object_id, object_type, db).await,
+    }
+}
+
+/// Caches a missing file on behalf of `actor` and records it in the audit log.
+pub async fn cache_file_on_demand(
+    actor: &str,
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) -> Option<CachedFile> {
+    let cached_file = cache_file(tenant.clone(), object_id, object_type.clone(), db.clone()).await;
+
+    record_cache_population(object_type.as_str(), cached_file.is_some());
+
+    audit::record(
+        &db,
+        actor,
+        &tenant,
+        AuditAction::Create,
         object_id,
-        object_type
+        object_type.as_str(),
+        cached_file.as_ref().map(|v| v.id),
+        if cached_file.is_some() {
+            RESULT_OK
+        } else {
+            RESULT_FAILED
+        },
+    )
+    .await;
+
+    cached_file
+}
+
+/// Soft-deletes a file along with its conversions, on behalf of `actor`.
+pub async fn delete_from_cache(
+    actor: &str,
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) -> Option<CachedFile> {
+    let cached_file: Option<CachedFile> = sqlx::query_as!(
+        CachedFile,
+        r#"UPDATE cached_files
+            SET deleted_at = now()
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3 AND deleted_at IS NULL
+            RETURNING *"#,
+        tenant,
+        object_id,
+        object_type.as_str()
+    )
+    .fetch_optional(db.writer())
+    .await
+    .unwrap();
+
+    audit::record(
+        &db,
+        actor,
+        &tenant,
+        AuditAction::Delete,
+        object_id,
+        object_type.as_str(),
+        cached_file.as_ref().map(|v| v.id),
+        if cached_file.is_some() {
+            RESULT_OK
+        } else {
+            RESULT_NOT_FOUND
+        },
+    )
+    .await;
+
+    if let Some(cached_file) = &cached_file {
+        run_hooks(|hooks| hooks.on_delete(cached_file));
+
+        events::publish(CacheEvent::Deleted { cached_file }).await;
+
+        delete_derived_files(&db, actor, cached_file).await;
+    }
+
+    cached_file
+}
+
+/// Brings back a deleted file during its grace period, on behalf of `actor`.
+/// Its conversions stay deleted and are made again when requested. `Err`
+/// means a file has been cached in its place since.
+pub async fn undelete_from_cache(
+    actor: &str,
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) -> Result<Option<CachedFile>, CachedFile> {
+    let current =
+        match find_cached_file(tenant.clone(), object_id, object_type.clone(), db.clone()).await {
+            Some(v) => Some(v),
+            None => find_quarantined(&tenant, object_id, &object_type, &db).await,
+        };
+
+    if let Some(cached_file) = current {
+        return Err(cached_file);
+    }
+
+    let cached_file = CachedFileRepository::new(db.clone())
+        .undelete(&tenant, object_id, &object_type)
+        .await
+        .unwrap();
+
+    audit::record(
+        &db,
+        actor,
+        &tenant,
+        AuditAction::Undelete,
+        object_id,
+        object_type.as_str(),
+        cached_file.as_ref().map(|v| v.id),
+        if cached_file.is_some() {
+            RESULT_OK
+        } else {
+            RESULT_NOT_FOUND
+        },
+    )
+    .await;
+
+    if let Some(cached_file) = &cached_file {
+        run_hooks(|hooks| hooks.on_undelete(cached_file));
+
+        events::publish(CacheEvent::Undeleted { cached_file }).await;
+    }
+
+    Ok(cached_file)
+}
+
+/// Conversions of a deleted file would otherwise outlive a fix to the source.
+async fn delete_derived_files(db: &Database, actor: &str, cached_file: &CachedFile) {
+    let derived = match CachedFileRepository::new(db.clone())
+        .delete_derived(cached_file.id, cached_file.object_id)
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            return;
+        }
+    };
+
+    for derived_file in derived {
+        audit::record(
+            db,
+            actor,
+            &derived_file.tenant,
+            AuditAction::Delete,
+            derived_file.object_id,
+            derived_file.object_type.as_str(),
+            Some(derived_file.id),
+            RESULT_OK,
+        )
+        .await;
+
+        run_hooks(|hooks| hooks.on_delete(&derived_file));
+
+        events::publish(CacheEvent::Deleted {
+            cached_file: &derived_file,
+        })
+        .await;
+    }
+}
+
+/// Points every row of a migrated group at its new supergroup id, whether the
+/// group holds the stored copy or its replica.
+pub async fn handle_chat_migration(
+    db: &Database,
+    tenant: &str,
+    from_chat_id: i64,
+    to_chat_id: i64,
+) {
+    let cached_file_repo = CachedFileRepository::new(db.clone());
+
+    match cached_file_repo
+        .remap_chat(tenant, from_chat_id, to_chat_id)
+        .await
+    {
+        Ok(updated) => log::warn!(
+            "Chat {} migrated to {}, remapped {} cached files",
+            from_chat_id,
+            to_chat_id,
+            updated
+        ),
+        Err(err) => log::error!("{:?}", err),
+    }
+}
+
+pub async fn get_cached_file_copy(original: CachedFile, db: Database) -> CacheData {
+    let mut bot = ROUND_ROBIN_BOT.get_bot();
+
+    let temp_channel_id = config::CONFIG.temp_channel_id(&original.tenant);
+
+    telegram_turn(TelegramOp::Copy).await;
+
+    let mut result = bot
+        .copy_message(
+            Recipient::Id(ChatId(temp_channel_id)),
+            Recipient::Id(ChatId(original.chat_id)),
+            MessageId(original.message_id.try_into().unwrap()),
+        )
+        .await;
+
+    // A rate limit says nothing about the message, so try another bot before
+    // treating the copy as broken.
+    if let Err(err) = &result {
+        if ROUND_ROBIN_BOT.report_error(&bot, err) {
+            bot = ROUND_ROBIN_BOT.get_bot();
+
+            telegram_turn(TelegramOp::Copy).await;
+
+            result = bot
+                .copy_message(
+                    Recipient::Id(ChatId(temp_channel_id)),
+                    Recipient::Id(ChatId(original.chat_id)),
+                    MessageId(original.message_id.try_into().unwrap()),
+                )
+                .await;
+        }
+    }
+
+    let message_id = match result {
+        Ok(v) => v,
+        Err(RequestError::MigrateToChatId(new_chat_id)) => {
+            handle_chat_migration(&db, &original.tenant, original.chat_id, new_chat_id.0).await;
+
+            telegram_turn(TelegramOp::Copy).await;
+
+            bot.copy_message(
+                Recipient::Id(ChatId(temp_channel_id)),
+                Recipient::Id(new_chat_id),
+                MessageId(original.message_id.try_into().unwrap()),
+            )
+            .await
+            .unwrap()
+        }
+        Err(_) => {
+            sqlx::query!(
+                r#"
+                UPDATE cached_files
+                SET deleted_at = now()
+                WHERE id = $1 AND object_id = $2
+                "#,
+                original.id,
+                original.object_id
+            )
+            .execute(db.writer())
+            .await
+            .unwrap();
+
+            audit::record(
+                &db,
+                ACTOR_API,
+                &original.tenant,
+                AuditAction::Repair,
+                original.object_id,
+                original.object_type.as_str(),
+                Some(original.id),
+                RESULT_OK,
+            )
+            .await;
+
+            events::publish(CacheEvent::Repair {
+                cached_file: &original,
+                succeeded: true,
+            })
+            .await;
+
+            let new_original = get_cached_file_or_cache(
+                original.tenant.clone(),
+                original.object_id,
+                original.object_type.clone(),
+                db,
+            )
+            .await
+            .unwrap();
+
+            telegram_turn(TelegramOp::Copy).await;
+
+            bot.copy_message(
+                Recipient::Id(ChatId(temp_channel_id)),
+                Recipient::Id(ChatId(new_original.chat_id)),
+                MessageId(new_original.message_id.try_into().unwrap()),
+            )
+            .await
+            .unwrap()
+        }
+    };
+
+    TEMP_MESSAGES
+        .insert(original.id, (ChatId(temp_channel_id), message_id))
+        .await;
+
+    CacheData {
+        id: None,
+        object_id: original.object_id,
+        object_type: original.object_type,
+        message_id: message_id.0,
+        chat_id: temp_channel_id,
+    }
+}
+
+fn population_claim_name(tenant: &str, object_id: i32, object_type: &ObjectType) -> String {
+    format!("cache:{tenant}:{object_id}:{object_type}")
+}
+
+/// Claims the population of a file across replicas. Whoever misses on it
+/// while it's claimed waits for the claim to end and reuses the result; if
+/// the holder failed, the next one to claim it tries again.
+async fn claim_population(
+    tenant: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+    db: &Database,
+) -> Result<Claim, CachedFile> {
+    let name = population_claim_name(tenant, object_id, object_type);
+
+    loop {
+        let claim = Claim::take(db.clone(), name.clone()).await;
+
+        // Checked after claiming too, as it may have finished in between.
+        if let Some(cached_file) =
+            find_cached_file_primary(tenant, object_id, object_type, db).await
+        {
+            if let Some(claim) = claim {
+                claim.release().await;
+            }
+
+            return Err(cached_file);
+        }
+
+        if let Some(claim) = claim {
+            return Ok(claim);
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(
+            config::CONFIG.cache_claim_poll_ms,
+        ))
+        .await;
+    }
+}
+
+pub async fn cache_file(
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) -> Option<CachedFile> {
+    let Some(_in_flight) = drain::track(&tenant, object_id, &object_type, &db) else {
+        drain::persist(&db, &tenant, object_id, &object_type).await;
+        return None;
+    };
+
+    // Caching it again is what quarantine is there to stop.
+    if find_quarantined(&tenant, object_id, &object_type, &db)
+        .await
+        .is_some()
+    {
+        return None;
+    }
+
+    let claim = match claim_population(&tenant, object_id, &object_type, &db).await {
+        Ok(v) => v,
+        Err(cached_file) => return Some(cached_file),
+    };
+
+    populate(tenant, object_id, object_type, db, claim).await
+}
+
+/// Whether the file is past `REVALIDATE_AFTER_SECS` and due to be cached again.
+pub fn is_stale(cached_file: &CachedFile) -> bool {
+    config::CONFIG.revalidate_after_secs.is_some_and(|secs| {
+        chrono::offset::Utc::now() - cached_file.cached_at > Duration::seconds(secs as i64)
+    })
+}
+
+/// Caches a stale file again; the stale copy stays in use until the new one
+/// replaces it. Does nothing while it's already being cached, here or on
+/// another replica.
+pub async fn revalidate_file(
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) {
+    let Some(_in_flight) = drain::track(&tenant, object_id, &object_type, &db) else {
+        return;
+    };
+
+    let name = population_claim_name(&tenant, object_id, &object_type);
+
+    let Some(claim) = Claim::take(db.clone(), name).await else {
+        return;
+    };
+
+    // It may have been refreshed, deleted or quarantined since the request saw it.
+    let current = find_cached_file_primary(&tenant, object_id, &object_type, &db).await;
+
+    if !current.is_some_and(|v| is_stale(&v)) {
+        claim.release().await;
+        return;
+    }
+
+    let cached_file = populate(
+        tenant.clone(),
+        object_id,
+        object_type.clone(),
+        db.clone(),
+        claim,
+    )
+    .await;
+
+    record_cache_population(object_type.as_str(), cached_file.is_some());
+
+    audit::record(
+        &db,
+        ACTOR_REVALIDATE,
+        &tenant,
+        AuditAction::Recache,
+        object_id,
+        object_type.as_str(),
+        cached_file.as_ref().map(|v| v.id),
+        if cached_file.is_some() {
+            RESULT_OK
+        } else {
+            RESULT_FAILED
+        },
+    )
+    .await;
+}
+
+/// Uploads a file under `claim`, which is released once it's done; the
+/// upload is dropped if the claim is lost, as another replica may take over.
+async fn populate(
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+    claim: Claim,
+) -> Option<CachedFile> {
+    run_hooks(|hooks| hooks.on_cache_start(&tenant, object_id, object_type.as_str()));
+
+    let cached_file = claim
+        .guard(upload_file(
+            tenant.clone(),
+            object_id,
+            object_type.clone(),
+            db.clone(),
+        ))
+        .await
+        .unwrap_or_else(|| {
+            log::warn!(
+                "Stopped caching {} {} of {}, its claim was lost",
+                object_id,
+                object_type,
+                tenant
+            );
+            None
+        });
+
+    match &cached_file {
+        Some(cached_file) => {
+            run_hooks(|hooks| hooks.on_cached(cached_file));
+
+            events::publish(CacheEvent::Cached { cached_file }).await;
+        }
+        None => run_hooks(|hooks| hooks.on_error(&tenant, object_id, object_type.as_str())),
+    }
+
+    claim.release().await;
+
+    // Likely cut short by the shutdown, e.g. its source was turned away; the
+    // staged download is kept for the restart.
+    if cached_file.is_none() && drain::is_draining() {
+        drain::persist(&db, &tenant, object_id, &object_type).await;
+    } else {
+        clear_staged(&db, &tenant, object_id, &object_type).await;
+    }
+
+    cached_file
+}
+
+/// Downloads the file from its upstream into a temp file, with its filename.
+async fn fetch_file(
+    book: &BookWithRemote,
+    library_source: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+    job: &CacheJob,
+) -> Option<(HashedFile, String)> {
+    // Covers come from book_library, so only downloader fetches take a slot,
+    // held until the download is done.
+    let _fetch_slot = if object_type.is_cover() {
+        None
+    } else {
+        Some(executor::enter(Stage::Fetch).await)
+    };
+
+    let downloader_result = if object_type.is_cover() {
+        download_book_cover(library_source, object_id).await
+    } else if let Some((source, target)) = object_type.conversion() {
+        download_from_downloader(
+            book.source.id,
+            book.remote_id,
+            source.to_string(),
+            Some(target),
+        )
+        .await
+    } else {
+        download_from_downloader(
+            book.source.id,
+            book.remote_id,
+            object_type.to_string(),
+            None,
+        )
+        .await
+    };
+
+    let downloader_result = match downloader_result {
+        Ok(v) => match v {
+            Some(v) => v,
+            None => return None,
+        },
+        Err(err) => {
+            log::error!("{:?}", err);
+            return None;
+        }
+    };
+
+    job.set_total(downloader_result.content_length());
+
+    let filename = if object_type.is_cover() {
+        cover_filename(object_id).filename
+    } else {
+        get_response_filename(&downloader_result)
+    };
+
+    let file = match response_to_hashed_file(downloader_result, |bytes| job.add_bytes(bytes)).await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            return None;
+        }
+    };
+
+    Some((file, filename))
+}
+
+async fn upload_file(
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) -> Option<CachedFile> {
+    let library_source = config::CONFIG.library_source(&tenant);
+
+    let book = match get_book(library_source, object_id).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            return None;
+        }
+    };
+
+    // Conversions are made from the cached original so deleting it can take
+    // them along.
+    let source_cached_file_id = match object_type.conversion() {
+        Some((source, _)) => {
+            let source_file = Box::pin(get_cached_file_or_cache(
+                tenant.clone(),
+                object_id,
+                ObjectType::from(source),
+                db.clone(),
+            ))
+            .await?;
+
+            Some(source_file.id)
+        }
+        None => None,
+    };
+
+    let job = CacheJob::start(&tenant, object_id, object_type.as_str());
+
+    let (file, filename) = match find_staged(&tenant, object_id, &object_type).await {
+        Some(v) => v,
+        None => {
+            let (file, filename) =
+                fetch_file(&book, library_source, object_id, &object_type, &job).await?;
+
+            match stage(&db, &tenant, object_id, &object_type, file, &filename).await {
+                Ok(file) => (file, filename),
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    return None;
+                }
+            }
+        }
+    };
+
+    let title = book.title.clone();
+    let authors = book.get_authors();
+    let source_id = book.source.id as i32;
+
+    let content_hash = file.sha256.clone();
+    let file_size = file.size as i64;
+
+    let max_upload_size = if object_type.is_cover() {
+        config::CONFIG.max_cover_size
+    } else {
+        config::CONFIG.max_upload_size()
+    };
+    if file.size > max_upload_size {
+        log::warn!(
+            "{} {} is {} bytes, over the {} bytes upload limit",
+            object_id,
+            object_type,
+            file.size,
+            max_upload_size
+        );
+        return None;
+    }
+
+    let cached_file_repo = CachedFileRepository::new(db.clone());
+
+    let duplicate = if is_enabled(Flag::Dedup) {
+        match cached_file_repo
+            .find_by_content_hash(&tenant, &content_hash)
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("{:?}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Identical payloads share one Telegram message, and with it the way it
+    // was compressed and encrypted; purge keeps it until the last row
+    // referencing it is gone.
+    let (
+        chat_id,
+        message_id,
+        replica_chat_id,
+        replica_message_id,
+        file_id,
+        file_unique_id,
+        compression,
+        encryption,
+    ) = match duplicate {
+        Some(v) => (
+            v.chat_id,
+            v.message_id,
+            v.replica_chat_id,
+            v.replica_message_id,
+            v.file_id.clone(),
+            v.file_unique_id.clone(),
+            v.compression.clone(),
+            Encryption::of(&v),
+        ),
+        None => {
+            // A missing cover only costs the thumbnail.
+            let thumbnail = if object_type.is_cover() {
+                None
+            } else {
+                match get_book_cover(library_source, object_id).await {
+                    Ok(v) => v,
+                    Err(err) => {
+                        log::warn!("Can't get cover of {}: {:?}", object_id, err);
+                        None
+                    }
+                }
+            };
+
+            let (file, compression) = match compress_file(file, object_type.as_str()).await {
+                Ok(v) => v,
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    return None;
+                }
+            };
+
+            let filename = match compression {
+                Some(codec) => format!("{filename}.{}", file_extension(codec)),
+                None => filename,
+            };
+
+            let (file, encryption) = match encrypt_file(file).await {
+                Ok(v) => v,
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    return None;
+                }
+            };
+
+            let upload_slot = executor::enter(Stage::Upload).await;
+
+            job.set_stage(CacheJobStage::Uploading);
+
+            let upload_size = file.size;
+
+            let media = UploadMedia {
+                kind: config::CONFIG.media_kind(object_type.as_str()),
+                title: title.clone(),
+                performer: authors.clone(),
+                thumbnail,
+            };
+
+            let UploadData {
+                chat_id,
+                message_id,
+                file_id,
+                file_unique_id,
+            } = match upload_verified(
+                &tenant,
+                object_type.as_str(),
+                upload_chat_id(&tenant, object_id),
+                file,
+                filename,
+                book.get_caption(),
+                media,
+            )
+            .await
+            {
+                Ok(v) => v,
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    return None;
+                }
+            };
+
+            drop(upload_slot);
+
+            record_bytes_uploaded(object_type.as_str(), upload_size);
+
+            match replicate_message(&tenant, chat_id, message_id).await {
+                Some((replica_chat_id, replica_message_id)) => (
+                    chat_id,
+                    message_id,
+                    Some(replica_chat_id),
+                    Some(replica_message_id),
+                    file_id,
+                    file_unique_id,
+                    compression.map(str::to_string),
+                    encryption,
+                ),
+                None => (
+                    chat_id,
+                    message_id,
+                    None,
+                    None,
+                    file_id,
+                    file_unique_id,
+                    compression.map(str::to_string),
+                    encryption,
+                ),
+            }
+        }
+    };
+
+    let mut tx = db.writer().begin().await.unwrap();
+
+    // A revalidated file replaces the stale row only now, so it's served
+    // until its new copy is in place; purging takes the old message later.
+    sqlx::query!(
+        r#"UPDATE cached_files SET deleted_at = now()
+            WHERE tenant = $1 AND object_id = $2 AND object_type = $3 AND deleted_at IS NULL"#,
+        tenant,
+        object_id,
+        object_type.as_str()
+    )
+    .execute(&mut *tx)
+    .await
+    .unwrap();
+
+    let cached_file = sqlx::query_as!(
+        CachedFile,
+        r#"INSERT INTO cached_files
+                (object_id, object_type, message_id, chat_id, title, authors, source_id, tenant,
+                 replica_chat_id, replica_message_id, content_hash, file_size, file_id,
+                 file_unique_id, source_cached_file_id, encryption_key_id, encrypted_key,
+                 encryption_nonce, compression)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17,
+                    $18, $19)
+            RETURNING *"#,
+        object_id,
+        object_type.as_str(),
+        message_id,
+        chat_id,
+        title,
+        authors,
+        source_id,
+        tenant,
+        replica_chat_id,
+        replica_message_id,
+        content_hash,
+        file_size,
+        file_id,
+        file_unique_id,
+        source_cached_file_id,
+        encryption.as_ref().map(|v| v.key_id.clone()),
+        encryption.as_ref().map(|v| v.encrypted_key.clone()),
+        encryption.as_ref().map(|v| v.nonce.clone()),
+        compression
     )
-    .fetch_optional(&db)
+    .fetch_one(&mut *tx)
     .await
     .unwrap();
 
-    match cached_file {
-        Some(cached_file) => Some(cached_file),
-        None => cache_file(object_id, object_type, db).await,
-    }
+    tx.commit().await.unwrap();
+
+    Some(cached_file)
 }
 
-pub async fn get_cached_file_copy(original: CachedFile, db: Database) -> CacheData {
-    let bot = ROUND_ROBIN_BOT.get_bot();
+const UPLOAD_ATTEMPTS: u32 = 3;
 
-    let message_id = match bot
-        .copy_message(
-            Recipient::Id(ChatId(config::CONFIG.temp_channel_id)),
-            Recipient::Id(ChatId(original.chat_id)),
-            MessageId(original.message_id.try_into().unwrap()),
-        )
-        .await
-    {
-        Ok(v) => v,
-        Err(_) => {
-            sqlx::query!(
-                r#"
-                DELETE FROM cached_files
-                WHERE id = $1
-                "#,
-                original.id
+/// Uploads the file and reads it back to make sure Telegram stored it intact;
+/// a mismatching message is deleted and the upload retried.
+async fn upload_verified(
+    tenant: &str,
+    object_type: &str,
+    chat_id: Option<i64>,
+    mut file: HashedFile,
+    filename: String,
+    caption: String,
+    media: UploadMedia,
+) -> Result<UploadData, Box<dyn std::error::Error + Send + Sync>> {
+    let mut attempt = 1;
+
+    loop {
+        // The clone shares the file position, so rewinding `file` rewinds it too.
+        let upload_file = HashedFile {
+            file: file.file.try_clone().await?,
+            size: file.size,
+            sha256: file.sha256.clone(),
+        };
+
+        let data = storage()
+            .upload(
+                tenant,
+                chat_id,
+                upload_file,
+                filename.clone(),
+                caption.clone(),
+                &media,
             )
-            .execute(&db)
-            .await
-            .unwrap();
+            .await?;
 
-            let new_original =
-                get_cached_file_or_cache(original.object_id, original.object_type.clone(), db)
-                    .await
-                    .unwrap();
+        if !is_enabled(Flag::VerifyUploads) {
+            return Ok(data);
+        }
 
-            bot.copy_message(
-                Recipient::Id(ChatId(config::CONFIG.temp_channel_id)),
-                Recipient::Id(ChatId(new_original.chat_id)),
-                MessageId(new_original.message_id.try_into().unwrap()),
+        let err = match verify_upload(tenant, &data, &file).await {
+            Ok(()) => return Ok(data),
+            Err(err) => err,
+        };
+
+        record_upload_verification_failure(object_type);
+
+        telegram_turn(TelegramOp::Delete).await;
+
+        let bot = ROUND_ROBIN_BOT.get_bot();
+        let _ = bot
+            .delete_message(
+                Recipient::Id(ChatId(data.chat_id)),
+                MessageId(data.message_id.try_into().unwrap()),
             )
-            .await
-            .unwrap()
+            .await;
+
+        if attempt >= UPLOAD_ATTEMPTS {
+            return Err(err);
         }
-    };
 
-    TEMP_MESSAGES.insert(original.id, message_id).await;
+        log::warn!(
+            "Upload of {} failed verification (attempt {}/{}): {}",
+            filename,
+            attempt,
+            UPLOAD_ATTEMPTS,
+            err
+        );
 
-    CacheData {
-        id: None,
-        object_id: original.object_id,
-        object_type: original.object_type,
-        message_id: message_id.0,
-        chat_id: config::CONFIG.temp_channel_id,
+        file.file.seek(SeekFrom::Start(0)).await?;
+        attempt += 1;
     }
 }
 
-pub async fn cache_file(object_id: i32, object_type: String, db: Database) -> Option<CachedFile> {
-    let book = match get_book(object_id).await {
-        Ok(v) => v,
-        Err(err) => {
+async fn verify_upload(
+    tenant: &str,
+    data: &UploadData,
+    expected: &HashedFile,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let response = storage()
+        .download(tenant, data.message_id, data.chat_id)
+        .await?;
+
+    let (size, sha256) = response_digest(response).await?;
+
+    if size != expected.size || sha256 != expected.sha256 {
+        return Err(format!(
+            "expected {} bytes ({}), got {} bytes ({})",
+            expected.size, expected.sha256, size, sha256
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The storage can't edit captions, e.g. telegram_files without the endpoint.
+#[derive(Debug)]
+pub struct CaptionEditUnsupported;
+
+impl std::fmt::Display for CaptionEditUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the storage can't edit captions")
+    }
+}
+
+impl std::error::Error for CaptionEditUnsupported {}
+
+/// Edits the caption of the stored message, which every deduplicated row
+/// sharing it sees too, and then of its replica. Both go through the storage;
+/// the replica is kept in sync on a best-effort basis.
+pub async fn edit_caption(
+    cached_file: &CachedFile,
+    caption: String,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let storage = storage();
+
+    if !storage.can_edit_caption(&cached_file.tenant) {
+        return Err(Box::new(CaptionEditUnsupported));
+    }
+
+    storage
+        .edit_caption(
+            &cached_file.tenant,
+            cached_file.chat_id,
+            cached_file.message_id,
+            caption.clone(),
+        )
+        .await?;
+
+    if let (Some(chat_id), Some(message_id)) =
+        (cached_file.replica_chat_id, cached_file.replica_message_id)
+    {
+        if let Err(err) = storage
+            .edit_caption(&cached_file.tenant, chat_id, message_id, caption)
+            .await
+        {
             log::error!("{:?}", err);
-            return None;
         }
-    };
+    }
 
-    let downloader_result =
-        match download_from_downloader(book.source.id, book.remote_id, object_type.clone()).await {
-            Ok(v) => match v {
-                Some(v) => v,
-                None => return None,
-            },
-            Err(err) => {
-                log::error!("{:?}", err);
-                return None;
-            }
-        };
+    Ok(())
+}
 
-    let UploadData {
-        chat_id,
-        message_id,
-    } = match upload_to_telegram_files(downloader_result, book.get_caption()).await {
-        Ok(v) => v,
+/// Copies a freshly uploaded message into the tenant's backup chat, if one is configured.
+async fn replicate_message(tenant: &str, chat_id: i64, message_id: i64) -> Option<(i64, i64)> {
+    let backup_chat_id = config::CONFIG.backup_chat_id(tenant)?;
+
+    telegram_turn(TelegramOp::Copy).await;
+
+    let bot = ROUND_ROBIN_BOT.get_bot();
+
+    match bot
+        .copy_message(
+            Recipient::Id(ChatId(backup_chat_id)),
+            Recipient::Id(ChatId(chat_id)),
+            MessageId(message_id.try_into().unwrap()),
+        )
+        .await
+    {
+        Ok(v) => Some((backup_chat_id, v.0.into())),
         Err(err) => {
+            ROUND_ROBIN_BOT.report_error(&bot, &err);
             log::error!("{:?}", err);
-            return None;
+            None
         }
-    };
+    }
+}
 
-    Some(
-        sqlx::query_as!(
-            CachedFile,
-            r#"INSERT INTO cached_files (object_id, object_type, message_id, chat_id)
-            VALUES ($1, $2, $3, $4)
-            RETURNING *"#,
-            object_id,
-            object_type,
-            message_id,
-            chat_id
+async fn record_repair(db: &Database, cached_data: &CachedFile, succeeded: bool) {
+    audit::record(
+        db,
+        ACTOR_API,
+        &cached_data.tenant,
+        AuditAction::Repair,
+        cached_data.object_id,
+        cached_data.object_type.as_str(),
+        Some(cached_data.id),
+        if succeeded { RESULT_OK } else { RESULT_FAILED },
+    )
+    .await;
+
+    events::publish(CacheEvent::Repair {
+        cached_file: cached_data,
+        succeeded,
+    })
+    .await;
+}
+
+/// Deletes a file whose download failed so it's cached again, unless it
+/// keeps failing, in which case it's quarantined instead.
+async fn discard_broken(db: &Database, cached_data: &CachedFile) {
+    if record_download_failure(db, ACTOR_API, cached_data).await {
+        return;
+    }
+
+    let result = CachedFileRepository::new(db.clone())
+        .delete_by_object_id_object_type(
+            cached_data.tenant.clone(),
+            cached_data.object_id,
+            cached_data.object_type.clone(),
         )
-        .fetch_one(&db)
+        .await;
+
+    record_repair(db, cached_data, result.is_ok()).await;
+}
+
+async fn get_object_filename(
+    object_id: i32,
+    object_type: ObjectType,
+) -> Result<FilenameData, Box<dyn std::error::Error + Send + Sync>> {
+    if object_type.is_cover() {
+        return Ok(cover_filename(object_id));
+    }
+
+    // The downloader names a converted file after the format it was converted to.
+    let object_type = match object_type.conversion() {
+        Some((_, target)) => target.to_string(),
+        None => object_type.to_string(),
+    };
+
+    get_filename(object_id, object_type)
         .await
-        .unwrap(),
-    )
+        .map(FilenameData::sanitized)
 }
 
 pub async fn download_from_cache(cached_data: CachedFile, db: Database) -> Option<DownloadResult> {
-    let response_task = tokio::task::spawn(download_from_telegram_files(
-        cached_data.message_id,
-        cached_data.chat_id,
-    ));
-    let filename_task = tokio::task::spawn(get_filename(
+    download_from_cache_encoded(cached_data, db, |_| false).await
+}
+
+/// Like [`download_from_cache`], but a compressed file is left compressed
+/// when `accepts` takes its codec; the result's `content_encoding` says so.
+pub async fn download_from_cache_encoded(
+    cached_data: CachedFile,
+    db: Database,
+    accepts: impl Fn(&str) -> bool,
+) -> Option<DownloadResult> {
+    let storage = storage();
+
+    let response_task = tokio::task::spawn(propagate({
+        let storage = storage.clone();
+        let tenant = cached_data.tenant.clone();
+        let (message_id, chat_id) = (cached_data.message_id, cached_data.chat_id);
+
+        async move { storage.download(&tenant, message_id, chat_id).await }
+    }));
+    let filename_task = tokio::task::spawn(propagate(get_object_filename(
         cached_data.object_id,
         cached_data.object_type.clone(),
-    ));
-    let book_task = tokio::task::spawn(get_book(cached_data.object_id));
+    )));
+    let book_task = tokio::task::spawn(propagate(get_book(
+        config::CONFIG.library_source(&cached_data.tenant),
+        cached_data.object_id,
+    )));
 
     let response = match response_task.await.unwrap() {
+        Err(err) if err.is::<ChatMigrated>() => {
+            let new_chat_id = err.downcast_ref::<ChatMigrated>().unwrap().new_chat_id;
+
+            handle_chat_migration(&db, &cached_data.tenant, cached_data.chat_id, new_chat_id).await;
+
+            storage
+                .download(&cached_data.tenant, cached_data.message_id, new_chat_id)
+                .await
+        }
+        v => v,
+    };
+
+    let response = match (
+        response,
+        cached_data.replica_chat_id,
+        cached_data.replica_message_id,
+    ) {
+        (Ok(v), _, _) if v.status() == 200 => Ok(v),
+        (primary, Some(replica_chat_id), Some(replica_message_id)) => {
+            match storage
+                .download(&cached_data.tenant, replica_message_id, replica_chat_id)
+                .await
+            {
+                Ok(v) if v.status() == 200 => {
+                    log::warn!(
+                        "Primary message of cached file {} is gone, serving the replica",
+                        cached_data.id
+                    );
+                    Ok(v)
+                }
+                _ => primary,
+            }
+        }
+        (primary, _, _) => primary,
+    };
+
+    let response = match response {
         Ok(v) => {
             if v.status() != 200 {
-                let cached_file_repo = CachedFileRepository::new(db.clone());
-
-                let _ = cached_file_repo
-                    .delete_by_object_id_object_type(
-                        cached_data.object_id,
-                        cached_data.object_type.clone(),
-                    )
-                    .await;
+                discard_broken(&db, &cached_data).await;
 
                 return None;
             }
@@ -202,14 +1322,7 @@ pub async fn download_from_cache(cached_data: CachedFile, db: Database) -> Optio
             v
         }
         Err(err) => {
-            let cached_file_repo = CachedFileRepository::new(db.clone());
-
-            let _ = cached_file_repo
-                .delete_by_object_id_object_type(
-                    cached_data.object_id,
-                    cached_data.object_type.clone(),
-                )
-                .await;
+            discard_broken(&db, &cached_data).await;
 
             log::error!("{:?}", err);
             return None;
@@ -238,11 +1351,55 @@ pub async fn download_from_cache(cached_data: CachedFile, db: Database) -> Optio
     } = filename_data;
     let caption = book.get_caption();
 
-    Some(DownloadResult {
+    let tenant = cached_data.tenant.clone();
+    let url = response.url().clone();
+    let body = resumable_body(
         response,
+        move |offset| {
+            let storage = storage.clone();
+            let tenant = tenant.clone();
+            let url = url.clone();
+
+            async move { storage.resume_download(&tenant, url, offset).await }
+        },
+        config::CONFIG.download_resume_attempts,
+    );
+
+    let body = match Encryption::of(&cached_data) {
+        Some(encryption) => match decrypt_body(body, &encryption) {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Can't decrypt cached file {}: {:?}", cached_data.id, err);
+                quarantine(&db, ACTOR_API, &cached_data, "can't be decrypted").await;
+                return None;
+            }
+        },
+        None => body,
+    };
+
+    let compression = match cached_data.compression.as_deref().map(|v| (v, codec(v))) {
+        Some((_, Some(codec))) => Some(codec),
+        Some((name, None)) => {
+            log::error!("Cached file {} has unknown compression {}", cached_data.id, name);
+            quarantine(&db, ACTOR_API, &cached_data, "unknown compression").await;
+            return None;
+        }
+        None => None,
+    };
+
+    let (body, content_encoding) = match compression {
+        Some(codec) if accepts(codec) => (body, Some(codec)),
+        Some(codec) => (decompress_body(body, codec), None),
+        None => (body, None),
+    };
+
+    Some(DownloadResult {
+        body,
         filename,
         filename_ascii,
         caption,
+        content_encoding,
+        content_hash: cached_data.content_hash,
     })
 }
 
@@ -254,9 +1411,66 @@ pub struct FileLinkResult {
     pub caption: String,
 }
 
+const BOOKS_PAGE_ATTEMPTS: u32 = 4;
+const BOOKS_PAGE_RETRY_BASE_DELAY_MS: u64 = 500;
+
+/// Retries a single page with exponential backoff; the jitter keeps tenants
+/// updating at the same time from hammering the library in lockstep.
+async fn get_books_page(
+    library_source: &str,
+    page: u32,
+    page_size: u32,
+    uploaded_gte: &str,
+    uploaded_lte: &str,
+) -> Result<Page<BaseBook>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut attempt = 1;
+
+    loop {
+        let err = match get_books(
+            library_source,
+            page,
+            page_size,
+            uploaded_gte.to_string(),
+            uploaded_lte.to_string(),
+        )
+        .await
+        {
+            Ok(v) => return Ok(v),
+            Err(err) => err,
+        };
+
+        if attempt >= BOOKS_PAGE_ATTEMPTS {
+            return Err(err);
+        }
+
+        let base_delay = BOOKS_PAGE_RETRY_BASE_DELAY_MS << (attempt - 1);
+        let delay = rand::thread_rng().gen_range(base_delay / 2..=base_delay);
+
+        log::warn!(
+            "Fetching books page {} failed (attempt {}/{}), retrying in {}ms: {:?}",
+            page,
+            attempt,
+            BOOKS_PAGE_ATTEMPTS,
+            delay,
+            err
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+        attempt += 1;
+    }
+}
+
+pub struct BooksForUpdate {
+    pub books: Vec<BaseBook>,
+    /// Pages that still failed after retrying; their books are left for the next run.
+    pub skipped_pages: Vec<u32>,
+}
+
 pub async fn get_books_for_update(
-) -> Result<Vec<BaseBook>, Box<dyn std::error::Error + Send + Sync>> {
+    library_source: &str,
+) -> Result<BooksForUpdate, Box<dyn std::error::Error + Send + Sync>> {
     let mut result: Vec<BaseBook> = vec![];
+    let mut skipped_pages: Vec<u32> = vec![];
 
     let page_size = 50;
 
@@ -266,11 +1480,9 @@ pub async fn get_books_for_update(
     let uploaded_gte = subset_3.format("%Y-%m-%d").to_string();
     let uploaded_lte = now.format("%Y-%m-%d").to_string();
 
-    let first_page = match get_books(1, page_size, uploaded_gte.clone(), uploaded_lte.clone()).await
-    {
-        Ok(v) => v,
-        Err(err) => return Err(err),
-    };
+    // Without the first page there's no page count to go on.
+    let first_page =
+        get_books_page(library_source, 1, page_size, &uploaded_gte, &uploaded_lte).await?;
 
     result.extend(first_page.items);
 
@@ -278,57 +1490,306 @@ pub async fn get_books_for_update(
     let page_count = first_page.pages;
 
     while current_page <= page_count {
-        let page = match get_books(
+        match get_books_page(
+            library_source,
             current_page,
             page_size,
-            uploaded_gte.clone(),
-            uploaded_lte.clone(),
+            &uploaded_gte,
+            &uploaded_lte,
         )
         .await
         {
-            Ok(v) => v,
-            Err(err) => return Err(err),
+            Ok(page) => result.extend(page.items),
+            Err(err) => {
+                log::error!("Skipping books page {}: {:?}", current_page, err);
+                skipped_pages.push(current_page);
+            }
         };
-        result.extend(page.items);
 
         current_page += 1;
     }
 
-    Ok(result)
+    Ok(BooksForUpdate {
+        books: result,
+        skipped_pages,
+    })
+}
+
+/// Pushes the run's metrics and announces it to event consumers.
+async fn report_update_cache_finished(
+    tenant: &str,
+    started: std::time::Instant,
+    success: bool,
+    cached: u64,
+    failed: u64,
+    skipped_pages: usize,
+) {
+    push_job_metrics(
+        "update_cache",
+        &[("tenant", tenant)],
+        &[
+            (
+                "update_cache_duration_seconds",
+                started.elapsed().as_secs_f64(),
+            ),
+            ("update_cache_success", if success { 1.0 } else { 0.0 }),
+            ("update_cache_files_cached", cached as f64),
+            ("update_cache_failures", failed as f64),
+            ("update_cache_skipped_pages", skipped_pages as f64),
+            (
+                "update_cache_last_completion_timestamp_seconds",
+                chrono::offset::Utc::now().timestamp() as f64,
+            ),
+        ],
+    )
+    .await;
+
+    events::publish(CacheEvent::UpdateFinished {
+        tenant,
+        success,
+        cached,
+        failed,
+        skipped_pages,
+    })
+    .await;
 }
 
-pub async fn start_update_cache(db: Database) {
-    let books = match get_books_for_update().await {
+const UPDATE_CACHE_CHUNK_SIZE: usize = 50;
+
+pub async fn start_update_cache(tenant: String, db: Database) {
+    let started = std::time::Instant::now();
+
+    let library_source = config::CONFIG.library_source(&tenant);
+
+    let BooksForUpdate {
+        books,
+        skipped_pages,
+    } = match get_books_for_update(library_source).await {
         Ok(v) => v,
         Err(err) => {
             log::error!("{:?}", err);
+            notify_admins(format!(
+                "❌ Update cache run for {tenant} failed to fetch books: {err}"
+            ))
+            .await;
+            report_update_cache_finished(&tenant, started, false, 0, 0, 0).await;
             return;
         }
     };
 
-    for book in books {
-        'types: for available_type in book.available_types {
-            let cached_file = match sqlx::query_as!(
-                CachedFile,
-                r#"SELECT * FROM cached_files WHERE object_id = $1 AND object_type = $2"#,
-                book.id,
-                available_type.clone()
-            )
-            .fetch_optional(&db)
-            .await
-            {
-                Ok(v) => v,
-                Err(err) => {
-                    log::error!("{:?}", err);
-                    continue 'types;
-                }
-            };
+    if !skipped_pages.is_empty() {
+        let pages: Vec<String> = skipped_pages.iter().map(|v| v.to_string()).collect();
+
+        notify_admins(format!(
+            "⚠️ Update cache run for {tenant} skipped book pages after retrying: {}",
+            pages.join(", ")
+        ))
+        .await;
+    }
+
+    let mut cached = 0;
+    let mut failed = 0;
+
+    for chunk in books.chunks(UPDATE_CACHE_CHUNK_SIZE) {
+        // The next run picks up the rest.
+        if drain::is_draining() {
+            break;
+        }
+
+        let mut missing: Vec<(i32, ObjectType)> = vec![];
+
+        for book in chunk {
+            for available_type in config::CONFIG.canonical_object_types(&book.available_types) {
+                match sqlx::query_as!(
+                    CachedFile,
+                    r#"SELECT * FROM cached_files
+                    WHERE tenant = $1 AND object_id = $2 AND object_type = $3
+                      AND deleted_at IS NULL"#,
+                    tenant,
+                    book.id,
+                    available_type.as_str()
+                )
+                .fetch_optional(db.reader())
+                .await
+                {
+                    Ok(Some(_)) => {}
+                    Ok(None) => missing.push((book.id, available_type)),
+                    Err(err) => log::error!("{:?}", err),
+                };
+            }
+        }
+
+        // Warms the metadata cache so `cache_file` doesn't fetch the books one by one.
+        let mut book_ids: Vec<i32> = missing.iter().map(|(book_id, _)| *book_id).collect();
+        book_ids.dedup();
+
+        if let Err(err) = get_books_by_ids(library_source, &book_ids).await {
+            log::error!("{:?}", err);
+        }
+
+        let mut results = run_batch(missing, |(book_id, available_type)| {
+            let tenant = tenant.clone();
+            let db = db.clone();
+
+            async move {
+                let cached_file = cache_file(tenant, book_id, available_type.clone(), db).await;
+
+                (book_id, available_type, cached_file)
+            }
+        });
+
+        while let Some((book_id, available_type, cached_file)) = results.next().await {
+            record_cache_population(available_type.as_str(), cached_file.is_some());
 
             if cached_file.is_some() {
-                continue 'types;
+                cached += 1;
+            } else {
+                failed += 1;
             }
 
-            cache_file(book.id, available_type, db.clone()).await;
+            audit::record(
+                &db,
+                ACTOR_UPDATE_CACHE,
+                &tenant,
+                AuditAction::Recache,
+                book_id,
+                available_type.as_str(),
+                cached_file.as_ref().map(|v| v.id),
+                if cached_file.is_some() {
+                    RESULT_OK
+                } else {
+                    RESULT_FAILED
+                },
+            )
+            .await;
+        }
+    }
+
+    if failed > 0 {
+        notify_admins(format!(
+            "❌ Update cache run for {tenant} failed to cache {failed} files"
+        ))
+        .await;
+    }
+
+    report_update_cache_finished(&tenant, started, true, cached, failed, skipped_pages.len()).await;
+}
+
+/// Purges every `PURGE_INTERVAL_HOURS` from the leader, so deleted files get
+/// their grace period without an outside cron.
+pub async fn start_purge_scheduler(db: Database) {
+    if config::CONFIG.purge_interval_hours == 0 {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        config::CONFIG.purge_interval_hours * 60 * 60,
+    ));
+
+    // The first tick completes immediately, before any leader is elected.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        if !is_leader() {
+            continue;
+        }
+
+        run_exclusive(
+            db.clone(),
+            "purge_deleted".to_string(),
+            start_purge_deleted(db.clone()),
+        )
+        .await;
+    }
+}
+
+/// Deduplicated rows share messages, so only the last reference removes one.
+/// The message may already be gone (that's often why the row was
+/// soft-deleted), so a failed delete doesn't block the purge.
+async fn delete_unreferenced_message(
+    cached_file_repo: &CachedFileRepository,
+    id: i32,
+    chat_id: i64,
+    message_id: i64,
+) {
+    let references = cached_file_repo
+        .count_message_references(id, chat_id, message_id)
+        .await
+        .unwrap_or(1);
+
+    if references > 0 {
+        return;
+    }
+
+    telegram_turn(TelegramOp::Delete).await;
+
+    let bot = ROUND_ROBIN_BOT.get_bot();
+
+    let _ = bot
+        .delete_message(
+            Recipient::Id(ChatId(chat_id)),
+            MessageId(message_id.try_into().unwrap()),
+        )
+        .await;
+}
+
+pub async fn start_purge_deleted(db: Database) {
+    let cached_file_repo = CachedFileRepository::new(db.clone());
+
+    let deleted_before =
+        chrono::offset::Utc::now() - Duration::days(config::CONFIG.purge_after_days);
+
+    let cached_files = match cached_file_repo.get_deleted_before(deleted_before).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            return;
+        }
+    };
+
+    for cached_file in cached_files {
+        let messages = std::iter::once((cached_file.chat_id, cached_file.message_id)).chain(
+            cached_file
+                .replica_chat_id
+                .zip(cached_file.replica_message_id),
+        );
+
+        for (chat_id, message_id) in messages {
+            delete_unreferenced_message(&cached_file_repo, cached_file.id, chat_id, message_id)
+                .await;
+        }
+
+        let result = cached_file_repo
+            .purge(cached_file.id, cached_file.object_id)
+            .await;
+
+        if let Err(err) = &result {
+            log::error!("{:?}", err);
+        }
+
+        audit::record(
+            &db,
+            ACTOR_PURGE_DELETED,
+            &cached_file.tenant,
+            AuditAction::Purge,
+            cached_file.object_id,
+            cached_file.object_type.as_str(),
+            Some(cached_file.id),
+            if result.is_ok() {
+                RESULT_OK
+            } else {
+                RESULT_FAILED
+            },
+        )
+        .await;
+
+        if result.is_ok() {
+            events::publish(CacheEvent::Evicted {
+                cached_file: &cached_file,
+            })
+            .await;
         }
     }
 }
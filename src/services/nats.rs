@@ -0,0 +1,30 @@
+use async_nats::{Client, ConnectOptions};
+use tokio::sync::OnceCell;
+use tracing::log;
+
+use crate::config::CONFIG;
+
+static CLIENT: OnceCell<Option<Client>> = OnceCell::const_new();
+
+/// The shared connection, or `None` without `NATS_URL`. It connects in the
+/// background, so a server that's down at startup doesn't hold anything up.
+pub async fn get_nats_client() -> Option<&'static Client> {
+    CLIENT
+        .get_or_init(|| async {
+            let url = CONFIG.nats_url.as_deref()?;
+
+            match ConnectOptions::new()
+                .retry_on_initial_connect()
+                .connect(url)
+                .await
+            {
+                Ok(v) => Some(v),
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    None
+                }
+            }
+        })
+        .await
+        .as_ref()
+}
@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use teloxide::{requests::Requester, types::ChatId};
+use tracing::{log, Event, Level, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{config::CONFIG, services::bots::ROUND_ROBIN_BOT};
+
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts ERROR events so the monitor can alert on spikes without every call
+/// site having to report failures explicitly.
+pub struct ErrorCountLayer;
+
+impl<S: Subscriber> Layer<S> for ErrorCountLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() == Level::ERROR {
+            ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sends a message to the admin chat, if one is configured.
+pub async fn notify_admins(text: String) {
+    let Some(admin_chat_id) = CONFIG.admin_chat_id else {
+        return;
+    };
+
+    let bot = ROUND_ROBIN_BOT.get_bot();
+
+    if let Err(err) = bot.send_message(ChatId(admin_chat_id), text).await {
+        ROUND_ROBIN_BOT.report_error(&bot, &err);
+        log::error!("{:?}", err);
+    }
+}
+
+pub async fn start_error_rate_monitor() {
+    if CONFIG.admin_chat_id.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        CONFIG.alert_window_secs.get(),
+    ));
+
+    loop {
+        interval.tick().await;
+
+        let errors = ERRORS.swap(0, Ordering::Relaxed);
+
+        if errors >= CONFIG.alert_error_threshold {
+            notify_admins(format!(
+                "⚠️ {} errors logged in the last {} seconds",
+                errors, CONFIG.alert_window_secs
+            ))
+            .await;
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt::Write;
+
+use once_cell::sync::Lazy;
+use tracing::log;
+
+use crate::{config::CONFIG, services::http_client::build_client};
+
+pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| build_client("pushgateway"));
+
+/// Replaces the metrics of a finished batch job in the Pushgateway, if one is
+/// configured. Runs shorter than the scrape interval are otherwise never seen.
+pub async fn push_job_metrics(job: &str, grouping: &[(&str, &str)], metrics: &[(&str, f64)]) {
+    let Some(pushgateway_url) = CONFIG.pushgateway_url.as_deref() else {
+        return;
+    };
+
+    let mut url = format!(
+        "{}/metrics/job/{job}",
+        pushgateway_url.trim_end_matches('/')
+    );
+    for (label, value) in grouping {
+        write!(url, "/{label}/{value}").unwrap();
+    }
+
+    let mut body = String::new();
+    for (name, value) in metrics {
+        writeln!(body, "# TYPE {name} gauge\n{name} {value}").unwrap();
+    }
+
+    let response = CLIENT
+        .put(url)
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    if let Err(err) = response {
+        log::error!("{:?}", err);
+    }
+}
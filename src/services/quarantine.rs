@@ -0,0 +1,192 @@
+//! Files held back from serving. A file that fails its integrity checks, or
+//! keeps failing to download after being cached again, would otherwise go
+//! back and forth between failed downloads and re-caches; quarantined, it
+//! stays out of serving and caching until it's repaired, released or
+//! deleted through the admin API.
+
+use tracing::log;
+
+use crate::{
+    config::CONFIG,
+    object_type::ObjectType,
+    repository::{CachedFileRepository, DownloadFailureRepository},
+    serializers::CachedFile,
+    views::Database,
+};
+
+use super::{
+    audit::{self, AuditAction, RESULT_FAILED, RESULT_NOT_FOUND, RESULT_OK},
+    cache_file_on_demand, delete_from_cache,
+    events::{self, CacheEvent},
+    hooks::run_hooks,
+};
+
+/// Quarantines a live file on behalf of `actor`.
+pub async fn quarantine(
+    db: &Database,
+    actor: &str,
+    cached_file: &CachedFile,
+    reason: &str,
+) -> Option<CachedFile> {
+    let result = CachedFileRepository::new(db.clone())
+        .quarantine(cached_file.id, cached_file.object_id, reason)
+        .await;
+
+    if let Err(err) = &result {
+        log::error!("{:?}", err);
+    }
+
+    audit::record(
+        db,
+        actor,
+        &cached_file.tenant,
+        AuditAction::Quarantine,
+        cached_file.object_id,
+        cached_file.object_type.as_str(),
+        Some(cached_file.id),
+        match &result {
+            Ok(Some(_)) => RESULT_OK,
+            Ok(None) => RESULT_NOT_FOUND,
+            Err(_) => RESULT_FAILED,
+        },
+    )
+    .await;
+
+    let quarantined = result.ok().flatten()?;
+
+    log::warn!(
+        "Quarantined cached file {} ({}/{}): {}",
+        quarantined.id,
+        quarantined.object_id,
+        quarantined.object_type,
+        reason
+    );
+
+    run_hooks(|hooks| hooks.on_quarantine(&quarantined));
+
+    events::publish(CacheEvent::Quarantined {
+        cached_file: &quarantined,
+    })
+    .await;
+
+    Some(quarantined)
+}
+
+/// Counts a failed download of the file and quarantines it once
+/// `QUARANTINE_AFTER_FAILURES` is reached; true if it was.
+pub async fn record_download_failure(db: &Database, actor: &str, cached_file: &CachedFile) -> bool {
+    if CONFIG.quarantine_after_failures <= 0 {
+        return false;
+    }
+
+    let failures = match DownloadFailureRepository::new(db.clone())
+        .record(
+            &cached_file.tenant,
+            cached_file.object_id,
+            cached_file.object_type.as_str(),
+            CONFIG.quarantine_failure_window_hours,
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            return false;
+        }
+    };
+
+    if failures < CONFIG.quarantine_after_failures {
+        return false;
+    }
+
+    let reason = format!("download failed {failures} times");
+
+    quarantine(db, actor, cached_file, &reason).await.is_some()
+}
+
+pub async fn find_quarantined(
+    tenant: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+    db: &Database,
+) -> Option<CachedFile> {
+    CachedFileRepository::new(db.clone())
+        .find_quarantined(tenant, object_id, object_type)
+        .await
+        .unwrap()
+}
+
+async fn reset_failures(db: &Database, tenant: &str, object_id: i32, object_type: &ObjectType) {
+    if let Err(err) = DownloadFailureRepository::new(db.clone())
+        .reset(tenant, object_id, object_type.as_str())
+        .await
+    {
+        log::error!("{:?}", err);
+    }
+}
+
+/// Clears a quarantined file for serving as it is, on behalf of `actor`.
+pub async fn release(
+    actor: &str,
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) -> Option<CachedFile> {
+    let cached_file = CachedFileRepository::new(db.clone())
+        .release_quarantine(&tenant, object_id, &object_type)
+        .await
+        .unwrap();
+
+    audit::record(
+        &db,
+        actor,
+        &tenant,
+        AuditAction::Release,
+        object_id,
+        object_type.as_str(),
+        cached_file.as_ref().map(|v| v.id),
+        if cached_file.is_some() {
+            RESULT_OK
+        } else {
+            RESULT_NOT_FOUND
+        },
+    )
+    .await;
+
+    let cached_file = cached_file?;
+
+    reset_failures(&db, &tenant, object_id, &object_type).await;
+
+    events::publish(CacheEvent::Released {
+        cached_file: &cached_file,
+    })
+    .await;
+
+    Some(cached_file)
+}
+
+/// Deletes a quarantined file and caches it again, on behalf of `actor`.
+/// `None` if it isn't quarantined; otherwise the new file, if caching worked.
+pub async fn repair(
+    actor: &str,
+    tenant: String,
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+) -> Option<Option<CachedFile>> {
+    find_quarantined(&tenant, object_id, &object_type, &db).await?;
+
+    delete_from_cache(
+        actor,
+        tenant.clone(),
+        object_id,
+        object_type.clone(),
+        db.clone(),
+    )
+    .await;
+
+    reset_failures(&db, &tenant, object_id, &object_type).await;
+
+    Some(cache_file_on_demand(actor, tenant, object_id, object_type, db).await)
+}
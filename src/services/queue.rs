@@ -0,0 +1,213 @@
+//! Cache requests consumed from a NATS subject, for pipelines that would
+//! otherwise have to call the HTTP API.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::{Arc, Mutex},
+};
+
+use async_nats::Client;
+use axum_prometheus::metrics::gauge;
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tracing::log;
+
+use crate::{config::CONFIG, object_type::ObjectType, views::Database};
+
+use super::{
+    audit::ACTOR_QUEUE, cache_file_on_demand, drain::draining, find_cached_file,
+    instrument::record_cache_lookup, nats::get_nats_client,
+};
+
+const QUEUE_PENDING: &str = "cache_queue_pending";
+
+#[derive(Deserialize)]
+pub struct CacheRequest {
+    #[serde(default = "crate::config::default_tenant")]
+    pub tenant: String,
+    pub object_id: i32,
+    pub object_type: ObjectType,
+    /// Higher goes first; requests of equal priority are taken in arrival order.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+struct Pending {
+    seq: u64,
+    request: CacheRequest,
+    reply: Option<async_nats::Subject>,
+}
+
+impl Pending {
+    fn key(&self) -> (i32, Reverse<u64>) {
+        (self.request.priority, Reverse(self.seq))
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[derive(Default)]
+struct PriorityQueue {
+    heap: Mutex<BinaryHeap<Pending>>,
+    notify: Notify,
+}
+
+impl PriorityQueue {
+    fn push(&self, pending: Pending) {
+        let mut heap = self.heap.lock().unwrap();
+        heap.push(pending);
+        gauge!(QUEUE_PENDING).set(heap.len() as f64);
+        drop(heap);
+
+        self.notify.notify_one();
+    }
+
+    async fn pop(&self) -> Pending {
+        loop {
+            {
+                let mut heap = self.heap.lock().unwrap();
+
+                if let Some(pending) = heap.pop() {
+                    gauge!(QUEUE_PENDING).set(heap.len() as f64);
+                    return pending;
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Subscribes to `CACHE_QUEUE_SUBJECT` as part of `CACHE_QUEUE_GROUP`, so
+/// replicas share the stream. Requests sent with a reply subject get the
+/// cached file (or `null`) back once it's done. Does nothing without `NATS_URL`.
+pub async fn start_cache_queue_consumer(db: Database) {
+    let Some(client) = get_nats_client().await else {
+        return;
+    };
+
+    let mut subscriber = match client
+        .queue_subscribe(
+            CONFIG.cache_queue_subject.clone(),
+            CONFIG.cache_queue_group.clone(),
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            return;
+        }
+    };
+
+    let queue = Arc::new(PriorityQueue::default());
+
+    for _ in 0..CONFIG.cache_queue_concurrency {
+        tokio::spawn(run_worker(queue.clone(), client, db.clone()));
+    }
+
+    let mut seq = 0;
+
+    loop {
+        // Leaves the group while draining, so other replicas get the requests.
+        let message = tokio::select! {
+            message = subscriber.next() => message,
+            _ = draining() => break,
+        };
+
+        let Some(message) = message else {
+            log::warn!("Cache queue subscription closed");
+            return;
+        };
+
+        let request: CacheRequest = match serde_json::from_slice(&message.payload) {
+            Ok(v) => v,
+            Err(err) => {
+                log::warn!("Skipping invalid cache request: {}", err);
+                continue;
+            }
+        };
+
+        if !request.object_type.is_accepted() {
+            log::warn!(
+                "Skipping cache request for unknown object type {}",
+                request.object_type
+            );
+            continue;
+        }
+
+        seq += 1;
+
+        queue.push(Pending {
+            seq,
+            request,
+            reply: message.reply,
+        });
+    }
+
+    if let Err(err) = subscriber.unsubscribe().await {
+        log::error!("{:?}", err);
+    }
+}
+
+async fn run_worker(queue: Arc<PriorityQueue>, client: &'static Client, db: Database) {
+    loop {
+        let Pending { request, reply, .. } = queue.pop().await;
+
+        let cached_file = match find_cached_file(
+            request.tenant.clone(),
+            request.object_id,
+            request.object_type.clone(),
+            db.clone(),
+        )
+        .await
+        {
+            Some(cached_file) => {
+                record_cache_lookup(request.object_type.as_str(), true);
+                Some(cached_file)
+            }
+            None => {
+                record_cache_lookup(request.object_type.as_str(), false);
+
+                cache_file_on_demand(
+                    ACTOR_QUEUE,
+                    request.tenant,
+                    request.object_id,
+                    request.object_type,
+                    db.clone(),
+                )
+                .await
+            }
+        };
+
+        let Some(reply) = reply else {
+            continue;
+        };
+
+        let payload = serde_json::to_vec(&cached_file).unwrap();
+
+        if let Err(err) = client.publish(reply, payload.into()).await {
+            log::error!("{:?}", err);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use tracing::log;
+
+use crate::{
+    config::{ApiKey, Quota},
+    repository::ApiKeyUsageRepository,
+    serializers::ApiKeyUsageTotals,
+    views::Database,
+};
+
+#[derive(Clone, Default)]
+pub struct Usage(pub ApiKeyUsageTotals);
+
+impl Usage {
+    pub fn can_cache(&self, quota: &Quota) -> bool {
+        quota
+            .daily_cached_files
+            .is_none_or(|limit| self.0.files_cached_today < limit)
+    }
+
+    pub fn can_download(&self, quota: &Quota) -> bool {
+        quota
+            .daily_bytes
+            .is_none_or(|limit| self.0.bytes_served_today < limit)
+            && quota
+                .monthly_bytes
+                .is_none_or(|limit| self.0.bytes_served_month < limit)
+    }
+
+    pub fn append_headers(&self, headers: &mut HeaderMap, quota: &Quota) {
+        let limits = [
+            (
+                "x-quota-daily-files",
+                quota.daily_cached_files,
+                self.0.files_cached_today,
+            ),
+            (
+                "x-quota-daily-bytes",
+                quota.daily_bytes,
+                self.0.bytes_served_today,
+            ),
+            (
+                "x-quota-monthly-bytes",
+                quota.monthly_bytes,
+                self.0.bytes_served_month,
+            ),
+        ];
+
+        for (name, limit, used) in limits {
+            let Some(limit) = limit else {
+                continue;
+            };
+
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(limit));
+            headers.insert(
+                HeaderName::try_from(format!("{name}-remaining")).unwrap(),
+                HeaderValue::from((limit - used).max(0)),
+            );
+        }
+    }
+}
+
+pub async fn get_usage(db: &Database, api_key: &ApiKey) -> Usage {
+    if api_key.quota.is_unlimited() {
+        return Usage::default();
+    }
+
+    let usage_repo = ApiKeyUsageRepository::new(db.clone());
+
+    match usage_repo.get_totals(&api_key.name).await {
+        Ok(v) => Usage(v),
+        Err(err) => {
+            log::error!("{:?}", err);
+            Usage::default()
+        }
+    }
+}
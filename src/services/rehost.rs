@@ -0,0 +1,180 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    requests::Requester,
+    types::{ChatId, MessageId, Recipient},
+};
+use tracing::log;
+
+use crate::{repository::CachedFileRepository, views::Database};
+
+use super::{
+    audit::{self, AuditAction, ACTOR_REHOST, RESULT_FAILED, RESULT_OK},
+    bots::ROUND_ROBIN_BOT,
+    throttle::{telegram_turn, TelegramOp},
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RehostRequest {
+    pub from_chat_id: i64,
+    pub to_chat_id: i64,
+    #[serde(default)]
+    pub delete_originals: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, async_graphql::SimpleObject)]
+pub struct RehostProgress {
+    pub tenant: String,
+    pub from_chat_id: i64,
+    pub to_chat_id: i64,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub total: usize,
+    pub moved: usize,
+    pub failed: usize,
+}
+
+/// Progress of the latest re-host job; only one job runs at a time.
+pub static REHOST_PROGRESS: Lazy<Mutex<Option<RehostProgress>>> = Lazy::new(|| Mutex::new(None));
+
+fn update_progress(f: impl FnOnce(&mut RehostProgress)) {
+    if let Some(progress) = REHOST_PROGRESS.lock().unwrap().as_mut() {
+        f(progress);
+    }
+}
+
+pub fn get_rehost_progress() -> Option<RehostProgress> {
+    REHOST_PROGRESS.lock().unwrap().clone()
+}
+
+/// Registers a new job unless one is still running.
+pub fn try_start_rehost(tenant: &str, request: &RehostRequest) -> bool {
+    let mut progress = REHOST_PROGRESS.lock().unwrap();
+
+    if progress.as_ref().is_some_and(|p| p.finished_at.is_none()) {
+        return false;
+    }
+
+    *progress = Some(RehostProgress {
+        tenant: tenant.to_string(),
+        from_chat_id: request.from_chat_id,
+        to_chat_id: request.to_chat_id,
+        started_at: Utc::now(),
+        finished_at: None,
+        total: 0,
+        moved: 0,
+        failed: 0,
+    });
+
+    true
+}
+
+pub async fn start_rehost(tenant: String, request: RehostRequest, db: Database) {
+    let cached_file_repo = CachedFileRepository::new(db.clone());
+
+    let cached_files = match cached_file_repo
+        .get_by_chat(&tenant, request.from_chat_id)
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            update_progress(|p| p.finished_at = Some(Utc::now()));
+            return;
+        }
+    };
+
+    update_progress(|p| p.total = cached_files.len());
+
+    // Deduplicated rows share a message; copy it once and point every row at the copy.
+    let mut copied: HashMap<MessageId, MessageId> = HashMap::new();
+
+    for cached_file in cached_files {
+        let bot = ROUND_ROBIN_BOT.get_bot();
+
+        let original_message_id = MessageId(cached_file.message_id.try_into().unwrap());
+
+        let copy_result = match copied.get(&original_message_id) {
+            Some(v) => Ok(*v),
+            None => {
+                telegram_turn(TelegramOp::Copy).await;
+
+                bot.copy_message(
+                    Recipient::Id(ChatId(request.to_chat_id)),
+                    Recipient::Id(ChatId(request.from_chat_id)),
+                    original_message_id,
+                )
+                .await
+            }
+        };
+
+        let new_message_id = match copy_result {
+            Ok(v) => v,
+            Err(err) => {
+                ROUND_ROBIN_BOT.report_error(&bot, &err);
+                log::error!("{:?}", err);
+                update_progress(|p| p.failed += 1);
+
+                audit::record(
+                    &db,
+                    ACTOR_REHOST,
+                    &cached_file.tenant,
+                    AuditAction::Rehost,
+                    cached_file.object_id,
+                    cached_file.object_type.as_str(),
+                    Some(cached_file.id),
+                    RESULT_FAILED,
+                )
+                .await;
+
+                continue;
+            }
+        };
+
+        if let Err(err) = cached_file_repo
+            .update_location(
+                cached_file.id,
+                cached_file.object_id,
+                request.to_chat_id,
+                new_message_id.0.into(),
+            )
+            .await
+        {
+            log::error!("{:?}", err);
+            update_progress(|p| p.failed += 1);
+            continue;
+        }
+
+        let first_copy = copied.insert(original_message_id, new_message_id).is_none();
+
+        if request.delete_originals && first_copy {
+            telegram_turn(TelegramOp::Delete).await;
+
+            let _ = bot
+                .delete_message(
+                    Recipient::Id(ChatId(request.from_chat_id)),
+                    original_message_id,
+                )
+                .await;
+        }
+
+        audit::record(
+            &db,
+            ACTOR_REHOST,
+            &cached_file.tenant,
+            AuditAction::Rehost,
+            cached_file.object_id,
+            cached_file.object_type.as_str(),
+            Some(cached_file.id),
+            RESULT_OK,
+        )
+        .await;
+
+        update_progress(|p| p.moved += 1);
+    }
+
+    update_progress(|p| p.finished_at = Some(Utc::now()));
+}
@@ -0,0 +1,45 @@
+//! Moves uploads off storage chats that reached `CHAT_MESSAGE_LIMIT` or
+//! `CHAT_BYTES_LIMIT` onto the next configured chat, without a restart.
+//! Which chats are full comes from the chat stats, so it's as fresh as
+//! `CACHE_STATS_INTERVAL_SECS`.
+
+use std::{collections::HashSet, sync::RwLock};
+
+use once_cell::sync::Lazy;
+
+use crate::{config::CONFIG, serializers::ChatStats};
+
+static FULL_CHATS: Lazy<RwLock<HashSet<i64>>> = Lazy::new(Default::default);
+
+fn is_full(stats: &ChatStats) -> bool {
+    CONFIG
+        .chat_message_limit
+        .is_some_and(|limit| stats.messages >= limit)
+        || CONFIG
+            .chat_bytes_limit
+            .is_some_and(|limit| stats.bytes >= limit)
+}
+
+/// Records which chats are full and returns the ones that weren't before.
+pub fn update_full_chats(chat_stats: &[ChatStats]) -> Vec<i64> {
+    let full: HashSet<i64> = chat_stats
+        .iter()
+        .filter(|stats| is_full(stats))
+        .map(|stats| stats.chat_id)
+        .collect();
+
+    let mut current = FULL_CHATS.write().unwrap();
+
+    let filled = full.difference(&current).copied().collect();
+
+    *current = full;
+
+    filled
+}
+
+/// The chat a new upload goes to, passing over full ones.
+pub fn upload_chat_id(tenant: &str, object_id: i32) -> Option<i64> {
+    let full = FULL_CHATS.read().unwrap();
+
+    CONFIG.upload_chat_id(tenant, object_id, |chat_id| full.contains(&chat_id))
+}
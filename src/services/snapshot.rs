@@ -0,0 +1,298 @@
+use std::io::SeekFrom;
+
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+use chrono::Utc;
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    requests::Requester,
+    types::{ChatId, InputFile, Recipient},
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio_util::io::StreamReader;
+use tracing::log;
+
+use crate::{
+    config::{default_tenant, CONFIG},
+    repository::{AuditLogRepository, CachedFileRepository, PendingCacheJobRepository},
+    serializers::{AuditLogEntry, CachedFile, PendingCacheJob},
+    views::Database,
+};
+
+use super::{
+    bots::ROUND_ROBIN_BOT,
+    leader::is_leader,
+    notifier::notify_admins,
+    telegram_files::download_from_telegram_files,
+    throttle::{telegram_turn, TelegramOp},
+};
+
+type SnapshotError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Rows restored per transaction.
+const RESTORE_BATCH_SIZE: usize = 1000;
+
+/// A snapshot is gzipped JSON lines, one row each, so neither taking nor
+/// restoring it holds a whole table in memory.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "table", content = "row", rename_all = "snake_case")]
+enum SnapshotRow {
+    CachedFiles(Box<CachedFile>),
+    AuditLog(AuditLogEntry),
+    PendingCacheJobs(PendingCacheJob),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotLocation {
+    /// Whose telegram_files server the parts are read back through.
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    pub chat_id: i64,
+    /// The parts in order; concatenated, they make up the snapshot.
+    pub message_ids: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestoreResult {
+    pub cached_files: u64,
+    pub audit_log: u64,
+    pub pending_cache_jobs: u64,
+}
+
+async fn write_rows<T>(
+    output: &mut (impl AsyncWriteExt + Unpin),
+    rows: impl Stream<Item = Result<T, sqlx::Error>>,
+    to_row: fn(T) -> SnapshotRow,
+) -> Result<(), SnapshotError> {
+    let mut rows = std::pin::pin!(rows);
+    let mut line = vec![];
+
+    while let Some(row) = rows.try_next().await? {
+        line.clear();
+        serde_json::to_writer(&mut line, &to_row(row))?;
+        line.push(b'\n');
+
+        output.write_all(&line).await?;
+    }
+
+    Ok(())
+}
+
+/// Writes every table to a gzipped temp file, returned rewound, with its size.
+async fn write_snapshot(db: Database) -> Result<(tokio::fs::File, u64), SnapshotError> {
+    let file = tokio::fs::File::from_std(tempfile::tempfile()?);
+    let mut output = GzipEncoder::new(BufWriter::new(file));
+
+    let cached_file_repo = CachedFileRepository::new(db.clone());
+    write_rows(&mut output, cached_file_repo.stream_all(), |v| {
+        SnapshotRow::CachedFiles(Box::new(v))
+    })
+    .await?;
+
+    let audit_log_repo = AuditLogRepository::new(db.clone());
+    write_rows(
+        &mut output,
+        audit_log_repo.stream_all(),
+        SnapshotRow::AuditLog,
+    )
+    .await?;
+
+    let pending_cache_job_repo = PendingCacheJobRepository::new(db);
+    write_rows(
+        &mut output,
+        pending_cache_job_repo.stream_all(),
+        SnapshotRow::PendingCacheJobs,
+    )
+    .await?;
+
+    output.shutdown().await?;
+
+    let mut file = output.into_inner().into_inner();
+    let size = file.metadata().await?.len();
+    file.seek(SeekFrom::Start(0)).await?;
+
+    Ok((file, size))
+}
+
+pub async fn create_snapshot(
+    db: Database,
+    chat_id: i64,
+) -> Result<SnapshotLocation, SnapshotError> {
+    let created_at = Utc::now();
+
+    let (file, size) = write_snapshot(db).await?;
+
+    let part_bytes = CONFIG.snapshot_part_bytes;
+    let parts = size.div_ceil(part_bytes).max(1);
+
+    let mut message_ids = vec![];
+
+    for part in 0..parts {
+        // Clones share the file position, so each part seeks before its
+        // upload reads it, and the next part waits for that upload.
+        let mut reader = file.try_clone().await?;
+        reader.seek(SeekFrom::Start(part * part_bytes)).await?;
+
+        let filename = format!(
+            "cached_files_snapshot_{}.jsonl.gz.{:03}",
+            created_at.format("%Y%m%d_%H%M%S"),
+            part + 1
+        );
+
+        telegram_turn(TelegramOp::Upload).await;
+
+        let bot = ROUND_ROBIN_BOT.get_bot();
+
+        let message = bot
+            .send_document(
+                Recipient::Id(ChatId(chat_id)),
+                InputFile::read(reader.take(part_bytes)).file_name(filename),
+            )
+            .await
+            .inspect_err(|err| {
+                ROUND_ROBIN_BOT.report_error(&bot, err);
+            })?;
+
+        message_ids.push(message.id.0.into());
+    }
+
+    Ok(SnapshotLocation {
+        tenant: CONFIG.snapshot_tenant.clone(),
+        chat_id,
+        message_ids,
+    })
+}
+
+/// Rows waiting to be restored, flushed a batch at a time.
+#[derive(Default)]
+struct RestoreBatches {
+    cached_files: Vec<CachedFile>,
+    audit_log: Vec<AuditLogEntry>,
+    pending_cache_jobs: Vec<PendingCacheJob>,
+}
+
+impl RestoreBatches {
+    fn push(&mut self, row: SnapshotRow) {
+        match row {
+            SnapshotRow::CachedFiles(v) => self.cached_files.push(*v),
+            SnapshotRow::AuditLog(v) => self.audit_log.push(v),
+            SnapshotRow::PendingCacheJobs(v) => self.pending_cache_jobs.push(v),
+        }
+    }
+
+    /// Restores the batches that are full, or all of them when `all`.
+    async fn flush(
+        &mut self,
+        db: &Database,
+        result: &mut RestoreResult,
+        all: bool,
+    ) -> Result<(), sqlx::Error> {
+        let due = |len: usize| len > 0 && (all || len >= RESTORE_BATCH_SIZE);
+
+        if due(self.cached_files.len()) {
+            result.cached_files += CachedFileRepository::new(db.clone())
+                .restore(&std::mem::take(&mut self.cached_files))
+                .await?;
+        }
+
+        if due(self.audit_log.len()) {
+            result.audit_log += AuditLogRepository::new(db.clone())
+                .restore(&std::mem::take(&mut self.audit_log))
+                .await?;
+        }
+
+        if due(self.pending_cache_jobs.len()) {
+            result.pending_cache_jobs += PendingCacheJobRepository::new(db.clone())
+                .restore(&std::mem::take(&mut self.pending_cache_jobs))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Restores a snapshot from its parts; rows that still exist are left untouched.
+pub async fn restore_snapshot(
+    db: Database,
+    location: SnapshotLocation,
+) -> Result<RestoreResult, SnapshotError> {
+    let SnapshotLocation {
+        tenant,
+        chat_id,
+        message_ids,
+    } = location;
+
+    let body = async_stream::stream! {
+        for message_id in message_ids {
+            let response = match download_from_telegram_files(tenant.clone(), message_id, chat_id).await {
+                Ok(v) => v,
+                Err(err) => {
+                    yield Err(std::io::Error::other(err));
+                    return;
+                }
+            };
+
+            let mut chunks = response.bytes_stream();
+
+            while let Some(chunk) = chunks.next().await {
+                yield chunk.map_err(std::io::Error::other);
+            }
+        }
+    };
+
+    let mut lines = BufReader::new(GzipDecoder::new(StreamReader::new(Box::pin(body)))).lines();
+
+    let mut result = RestoreResult {
+        cached_files: 0,
+        audit_log: 0,
+        pending_cache_jobs: 0,
+    };
+    let mut batches = RestoreBatches::default();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            continue;
+        }
+
+        batches.push(serde_json::from_str(&line)?);
+        batches.flush(&db, &mut result, false).await?;
+    }
+
+    batches.flush(&db, &mut result, true).await?;
+
+    Ok(result)
+}
+
+pub async fn start_snapshot_scheduler(db: Database) {
+    let chat_id = match CONFIG.snapshot_chat_id {
+        Some(v) => v,
+        None => return,
+    };
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        CONFIG.snapshot_interval_hours.get() * 60 * 60,
+    ));
+
+    // The first tick completes immediately; skip it so restarts don't spam the chat.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        if !is_leader() {
+            continue;
+        }
+
+        match create_snapshot(db.clone(), chat_id).await {
+            Ok(location) => log::info!(
+                "Snapshot uploaded to chat {} messages {:?}",
+                location.chat_id,
+                location.message_ids
+            ),
+            Err(err) => {
+                log::error!("{:?}", err);
+                notify_admins(format!("❌ Scheduled snapshot failed: {err}")).await;
+            }
+        }
+    }
+}
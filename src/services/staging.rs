@@ -0,0 +1,156 @@
+//! Downloads kept in `CACHE_STAGING_DIR` until they're uploaded, so a job cut
+//! short by a crash or shutdown resumes from the upload instead of downloading
+//! again. A staged file is also recorded as a pending job (see
+//! [`drain`](super::drain)) for some replica to pick up; its bytes are only
+//! reused on the host that has them, anywhere else the job starts over.
+
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tracing::log;
+
+use crate::{
+    config::CONFIG, object_type::ObjectType, repository::PendingCacheJobRepository, views::Database,
+};
+
+use super::{download_utils::HashedFile, drain};
+
+/// Written after the data, so its presence means the download is complete.
+#[derive(Serialize, Deserialize)]
+struct StagedMeta {
+    filename: String,
+    size: u64,
+    sha256: String,
+}
+
+fn staged_paths(
+    tenant: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+) -> Option<(PathBuf, PathBuf)> {
+    let dir = PathBuf::from(CONFIG.cache_staging_dir.as_ref()?);
+    let name = format!("{tenant}_{object_id}_{object_type}");
+
+    Some((dir.join(&name), dir.join(format!("{name}.json"))))
+}
+
+/// A download staged by an earlier attempt, along with its filename.
+pub async fn find_staged(
+    tenant: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+) -> Option<(HashedFile, String)> {
+    let (data_path, meta_path) = staged_paths(tenant, object_id, object_type)?;
+
+    let meta: StagedMeta = serde_json::from_slice(&tokio::fs::read(&meta_path).await.ok()?).ok()?;
+
+    let file = tokio::fs::File::open(&data_path).await.ok()?;
+
+    if file.metadata().await.ok()?.len() != meta.size {
+        log::warn!("Ignoring truncated staged file {}", data_path.display());
+        return None;
+    }
+
+    log::info!(
+        "Resuming {} {} from its staged download",
+        object_id,
+        object_type
+    );
+
+    Some((
+        HashedFile {
+            file,
+            size: meta.size,
+            sha256: meta.sha256,
+        },
+        meta.filename,
+    ))
+}
+
+async fn write_staged(
+    data_path: &Path,
+    meta_path: &Path,
+    file: &mut HashedFile,
+    filename: &str,
+) -> Result<HashedFile, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(dir) = data_path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+
+    let mut staged = tokio::fs::File::create(data_path).await?;
+    tokio::io::copy(&mut file.file, &mut staged).await?;
+    staged.sync_all().await?;
+
+    let meta = StagedMeta {
+        filename: filename.to_string(),
+        size: file.size,
+        sha256: file.sha256.clone(),
+    };
+
+    let mut meta_file = tokio::fs::File::create(meta_path).await?;
+    meta_file.write_all(&serde_json::to_vec(&meta)?).await?;
+    meta_file.sync_all().await?;
+
+    Ok(HashedFile {
+        file: tokio::fs::File::open(data_path).await?,
+        size: file.size,
+        sha256: file.sha256.clone(),
+    })
+}
+
+/// Stages a download and records the pending job. Staging is best effort: on
+/// failure the download is handed back rewound, and only failing to rewind it
+/// is an error.
+pub async fn stage(
+    db: &Database,
+    tenant: &str,
+    object_id: i32,
+    object_type: &ObjectType,
+    mut file: HashedFile,
+    filename: &str,
+) -> std::io::Result<HashedFile> {
+    let Some((data_path, meta_path)) = staged_paths(tenant, object_id, object_type) else {
+        return Ok(file);
+    };
+
+    match write_staged(&data_path, &meta_path, &mut file, filename).await {
+        Ok(staged) => {
+            drain::persist(db, tenant, object_id, object_type).await;
+            Ok(staged)
+        }
+        Err(err) => {
+            log::warn!("Can't stage {}: {:?}", data_path.display(), err);
+
+            let _ = tokio::fs::remove_file(&data_path).await;
+
+            file.file.seek(SeekFrom::Start(0)).await?;
+            Ok(file)
+        }
+    }
+}
+
+/// Removes a staged download and its pending job once it's no longer needed.
+pub async fn clear_staged(db: &Database, tenant: &str, object_id: i32, object_type: &ObjectType) {
+    let Some((data_path, meta_path)) = staged_paths(tenant, object_id, object_type) else {
+        return;
+    };
+
+    for path in [meta_path, data_path] {
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Can't remove staged file {}: {:?}", path.display(), err);
+            }
+        }
+    }
+
+    if let Err(err) = PendingCacheJobRepository::new(db.clone())
+        .delete(tenant, object_id, object_type)
+        .await
+    {
+        log::error!("{:?}", err);
+    }
+}
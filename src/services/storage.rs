@@ -0,0 +1,138 @@
+//! Where cached files are kept. By default that's Telegram, through
+//! telegram_files; embedders can supply their own through
+//! [`ServerBuilder::storage`](crate::views::ServerBuilder::storage).
+//!
+//! Rows still address files by chat and message id, and bots still copy,
+//! replicate and re-host messages, so a backend has to keep that addressing.
+
+use std::sync::{Arc, RwLock};
+
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use reqwest::{Response, Url};
+
+use crate::config::CONFIG;
+
+use super::{
+    download_utils::HashedFile,
+    telegram_files::{
+        download_from_telegram_files, edit_caption_in_telegram_files,
+        resume_download_from_telegram_files, upload_to_telegram_files, UploadData, UploadMedia,
+    },
+};
+
+pub type StorageFuture<'a, T> = BoxFuture<'a, Result<T, Box<dyn std::error::Error + Send + Sync>>>;
+
+pub trait Storage: Send + Sync + 'static {
+    fn upload<'a>(
+        &'a self,
+        tenant: &'a str,
+        chat_id: Option<i64>,
+        file: HashedFile,
+        filename: String,
+        caption: String,
+        media: &'a UploadMedia,
+    ) -> StorageFuture<'a, UploadData>;
+
+    /// Fails with [`ChatMigrated`](super::telegram_files::ChatMigrated) when
+    /// the chat has moved, so rows can be pointed at the new one.
+    fn download<'a>(
+        &'a self,
+        tenant: &'a str,
+        message_id: i64,
+        chat_id: i64,
+    ) -> StorageFuture<'a, Response>;
+
+    /// Re-requests a download from `offset` on; `url` is the one the original
+    /// response came from.
+    fn resume_download<'a>(
+        &'a self,
+        tenant: &'a str,
+        url: Url,
+        offset: u64,
+    ) -> StorageFuture<'a, Response>;
+
+    fn edit_caption<'a>(
+        &'a self,
+        tenant: &'a str,
+        chat_id: i64,
+        message_id: i64,
+        caption: String,
+    ) -> StorageFuture<'a, ()>;
+
+    /// Whether [`Storage::edit_caption`] works for `tenant`; callers don't
+    /// try it otherwise.
+    fn can_edit_caption(&self, _tenant: &str) -> bool {
+        true
+    }
+}
+
+pub struct TelegramFiles;
+
+impl Storage for TelegramFiles {
+    fn upload<'a>(
+        &'a self,
+        tenant: &'a str,
+        chat_id: Option<i64>,
+        file: HashedFile,
+        filename: String,
+        caption: String,
+        media: &'a UploadMedia,
+    ) -> StorageFuture<'a, UploadData> {
+        Box::pin(upload_to_telegram_files(
+            tenant, chat_id, file, filename, caption, media,
+        ))
+    }
+
+    fn download<'a>(
+        &'a self,
+        tenant: &'a str,
+        message_id: i64,
+        chat_id: i64,
+    ) -> StorageFuture<'a, Response> {
+        Box::pin(download_from_telegram_files(
+            tenant.to_string(),
+            message_id,
+            chat_id,
+        ))
+    }
+
+    fn resume_download<'a>(
+        &'a self,
+        tenant: &'a str,
+        url: Url,
+        offset: u64,
+    ) -> StorageFuture<'a, Response> {
+        Box::pin(resume_download_from_telegram_files(
+            tenant.to_string(),
+            url,
+            offset,
+        ))
+    }
+
+    fn edit_caption<'a>(
+        &'a self,
+        tenant: &'a str,
+        chat_id: i64,
+        message_id: i64,
+        caption: String,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(edit_caption_in_telegram_files(
+            tenant, chat_id, message_id, caption,
+        ))
+    }
+
+    fn can_edit_caption(&self, tenant: &str) -> bool {
+        CONFIG.files_edit_caption(tenant)
+    }
+}
+
+static STORAGE: Lazy<RwLock<Arc<dyn Storage>>> = Lazy::new(|| RwLock::new(Arc::new(TelegramFiles)));
+
+pub fn set_storage(storage: Arc<dyn Storage>) {
+    *STORAGE.write().unwrap() = storage;
+}
+
+pub fn storage() -> Arc<dyn Storage> {
+    STORAGE.read().unwrap().clone()
+}
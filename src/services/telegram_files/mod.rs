@@ -1,20 +1,33 @@
-use base64::{engine::general_purpose, Engine};
 use once_cell::sync::Lazy;
 use reqwest::{
-    header,
+    header::RANGE,
     multipart::{Form, Part},
-    Response,
+    Body, Response, StatusCode, Url,
 };
 use serde::Deserialize;
 
-use crate::config::CONFIG;
+use crate::{
+    config::{MediaKind, CONFIG},
+    services::{
+        buffer_pool::read_frames,
+        download_utils::HashedFile,
+        http_client::build_client,
+        instrument::observe,
+        throttle::{telegram_turn, TelegramOp},
+        trace_context::{traceparent, TRACEPARENT},
+    },
+};
 
-pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| build_client("telegram_files"));
 
 #[derive(Deserialize)]
 pub struct UploadData {
     pub chat_id: i64,
     pub message_id: i64,
+    #[serde(default)]
+    pub file_id: Option<String>,
+    #[serde(default)]
+    pub file_unique_id: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -23,68 +36,236 @@ pub struct UploadResult {
     pub data: UploadData,
 }
 
+/// Telegram reports a group upgraded to a supergroup via `migrate_to_chat_id`
+/// in the error parameters, which telegram_files passes through.
+#[derive(Debug)]
+pub struct ChatMigrated {
+    pub new_chat_id: i64,
+}
+
+impl std::fmt::Display for ChatMigrated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "chat migrated to {}", self.new_chat_id)
+    }
+}
+
+impl std::error::Error for ChatMigrated {}
+
+fn find_migrate_to_chat_id(value: &serde_json::Value) -> Option<i64> {
+    match value {
+        serde_json::Value::Object(map) => map
+            .get("migrate_to_chat_id")
+            .and_then(|v| v.as_i64())
+            .or_else(|| map.values().find_map(find_migrate_to_chat_id)),
+        serde_json::Value::Array(items) => items.iter().find_map(find_migrate_to_chat_id),
+        _ => None,
+    }
+}
+
 pub async fn download_from_telegram_files(
+    tenant: String,
     message_id: i64,
     chat_id: i64,
 ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
     let url = format!(
         "{}/api/v1/files/download_by_message/{chat_id}/{message_id}",
-        CONFIG.files_url
+        CONFIG.files_url(&tenant)
     );
 
-    let response = CLIENT
-        .get(url)
-        .header("Authorization", CONFIG.files_api_key.clone())
-        .send()
-        .await?
-        .error_for_status()?;
+    telegram_turn(TelegramOp::Download).await;
+
+    observe("telegram_files_download", &url, async {
+        let response = CLIENT
+            .get(&url)
+            .header("Authorization", CONFIG.files_api_key(&tenant))
+            .header(TRACEPARENT, traceparent())
+            .send()
+            .await?;
+
+        if let Err(err) = response.error_for_status_ref() {
+            let body = response.json::<serde_json::Value>().await.ok();
+
+            if let Some(new_chat_id) = body.as_ref().and_then(find_migrate_to_chat_id) {
+                return Err::<_, Box<dyn std::error::Error + Send + Sync>>(Box::new(
+                    ChatMigrated { new_chat_id },
+                ));
+            }
+
+            return Err(Box::new(err));
+        }
+
+        Ok(response)
+    })
+    .await
+}
+
+/// Re-requests a download from `offset` on; `url` is the one the original
+/// response came from, so a replica or migrated chat is kept.
+pub async fn resume_download_from_telegram_files(
+    tenant: String,
+    url: Url,
+    offset: u64,
+) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+    telegram_turn(TelegramOp::Download).await;
+
+    let response = observe("telegram_files_download", url.as_str(), async {
+        CLIENT
+            .get(url.clone())
+            .header("Authorization", CONFIG.files_api_key(&tenant))
+            .header(TRACEPARENT, traceparent())
+            .header(RANGE, format!("bytes={offset}-"))
+            .send()
+            .await?
+            .error_for_status()
+    })
+    .await?;
+
+    // A full response would repeat what the client already has.
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(format!("range request answered with {}", response.status()).into());
+    }
 
     Ok(response)
 }
 
+/// What Telegram shows alongside the file; title and performer only apply to
+/// audio. Duration is left for Telegram to read from the file.
+pub struct UploadMedia {
+    pub kind: MediaKind,
+    pub title: String,
+    pub performer: String,
+    pub thumbnail: Option<bytes::Bytes>,
+}
+
 pub async fn upload_to_telegram_files(
-    data_response: Response,
+    tenant: &str,
+    chat_id: Option<i64>,
+    file: HashedFile,
+    filename: String,
     caption: String,
+    media: &UploadMedia,
 ) -> Result<UploadData, Box<dyn std::error::Error + Send + Sync>> {
-    let url = format!("{}/api/v1/files/upload/", CONFIG.files_url);
+    let url = format!("{}/api/v1/files/upload/", CONFIG.files_url(tenant));
+    let context = format!("{url} {filename} ({} bytes)", file.size);
 
-    let headers = data_response.headers();
+    let part = Part::stream_with_length(Body::wrap_stream(read_frames(file.file)), file.size)
+        .file_name(filename.clone());
+
+    let mut form = Form::new()
+        .text("caption", caption)
+        .text("file_size", file.size.to_string())
+        .text("filename", filename)
+        .text("media_kind", media.kind.as_str());
 
-    let file_size = headers
-        .get(header::CONTENT_LENGTH)
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
+    form = match media.kind {
+        MediaKind::Document => form,
+        MediaKind::Audio => form
+            .text("title", media.title.clone())
+            .text("performer", media.performer.clone()),
+        MediaKind::Video => form.text("supports_streaming", "true"),
+    };
 
-    let base64_encoder = general_purpose::STANDARD;
+    if let Some(chat_id) = chat_id {
+        form = form.text("chat_id", chat_id.to_string());
+    }
 
-    let filename = std::str::from_utf8(
-        &base64_encoder
-            .decode(headers.get("x-filename-b64-ascii").unwrap())
-            .unwrap(),
-    )
-    .unwrap()
-    .to_string();
+    if let Some(thumbnail) = &media.thumbnail {
+        form = form.part(
+            "thumbnail",
+            Part::stream(thumbnail.clone()).file_name("thumbnail.jpg"),
+        );
+    }
 
-    let part = Part::stream(data_response).file_name(filename.clone());
+    let form = form.part("file", part);
 
-    let form = Form::new()
-        .text("caption", caption)
-        .text("file_size", file_size)
-        .text("filename", filename)
-        .part("file", part);
+    telegram_turn(TelegramOp::Upload).await;
 
-    let response = CLIENT
-        .post(url)
-        .header("Authorization", CONFIG.files_api_key.clone())
-        .multipart(form)
-        .send()
-        .await?
-        .error_for_status()?;
+    let response = observe("telegram_files_upload", &context, async {
+        CLIENT
+            .post(url)
+            .header("Authorization", CONFIG.files_api_key(tenant))
+            .header(TRACEPARENT, traceparent())
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()
+    })
+    .await?;
 
     match response.json::<UploadResult>().await {
         Ok(v) => Ok(v.data),
         Err(err) => Err(Box::new(err)),
     }
 }
+
+/// Needs a telegram_files with the endpoint, declared by
+/// `FILES_SERVER_EDIT_CAPTION`.
+pub async fn edit_caption_in_telegram_files(
+    tenant: &str,
+    chat_id: i64,
+    message_id: i64,
+    caption: String,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("{}/api/v1/files/edit_caption/", CONFIG.files_url(tenant));
+    let context = format!("{url} {chat_id}/{message_id}");
+
+    let form = Form::new()
+        .text("chat_id", chat_id.to_string())
+        .text("message_id", message_id.to_string())
+        .text("caption", caption);
+
+    telegram_turn(TelegramOp::EditCaption).await;
+
+    observe("telegram_files_edit_caption", &context, async {
+        CLIENT
+            .post(&url)
+            .header("Authorization", CONFIG.files_api_key(tenant))
+            .header(TRACEPARENT, traceparent())
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn finds_the_migration_in_telegram_error_parameters() {
+        let body = json!({
+            "ok": false,
+            "error_code": 400,
+            "parameters": { "migrate_to_chat_id": -1001234567890_i64 },
+        });
+
+        assert_eq!(find_migrate_to_chat_id(&body), Some(-1001234567890));
+    }
+
+    #[test]
+    fn finds_the_migration_however_deeply_it_is_wrapped() {
+        let body = json!({
+            "detail": [{ "error": { "parameters": { "migrate_to_chat_id": -100 } } }],
+        });
+
+        assert_eq!(find_migrate_to_chat_id(&body), Some(-100));
+    }
+
+    #[test]
+    fn other_errors_have_no_migration() {
+        for body in [
+            json!({ "detail": "message not found" }),
+            json!({ "parameters": { "retry_after": 5 } }),
+            json!({ "parameters": { "migrate_to_chat_id": "-100" } }),
+            json!(null),
+        ] {
+            assert_eq!(find_migrate_to_chat_id(&body), None, "{body}");
+        }
+    }
+}
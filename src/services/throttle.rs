@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError},
+    time::Instant,
+};
+
+use crate::config::{ApiKey, CONFIG};
+
+use super::{
+    instrument::{record_background_throttle, record_telegram_throttle},
+    trace_context::current_job,
+};
+
+/// Idle time banks at most this much allowance for a burst.
+const MAX_BURST: Duration = Duration::from_secs(1);
+
+/// Caps the rate of bytes (or requests) passed through it; chunks that run
+/// ahead of the rate wait for their turn.
+pub struct RateLimiter {
+    per_sec: f64,
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(per_sec: NonZeroU64) -> Self {
+        Self::with_rate(per_sec.get() as f64)
+    }
+
+    fn per_minute(per_minute: NonZeroU64) -> Self {
+        Self::with_rate(per_minute.get() as f64 / 60.0)
+    }
+
+    fn with_rate(per_sec: f64) -> Self {
+        RateLimiter {
+            per_sec,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub async fn acquire(&self, bytes: usize) {
+        let delay = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let now = Instant::now();
+
+            let start = (*next_free).max(now.checked_sub(MAX_BURST).unwrap_or(now));
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.per_sec);
+
+            next_free.saturating_duration_since(now)
+        };
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+static GLOBAL_LIMITER: Lazy<Option<Arc<RateLimiter>>> = Lazy::new(|| {
+    CONFIG
+        .download_rate_limit
+        .map(|v| Arc::new(RateLimiter::new(v)))
+});
+
+static DOWNLOAD_SLOTS: Lazy<Option<Arc<Semaphore>>> = Lazy::new(|| {
+    CONFIG
+        .max_concurrent_downloads
+        .map(|v| Arc::new(Semaphore::new(v.get())))
+});
+
+/// Every active stream buffers chunks, so their number is capped to keep
+/// memory bounded. The slot is held until the permit is dropped; `None`
+/// means there is no cap.
+pub fn try_acquire_download_slot() -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+    DOWNLOAD_SLOTS
+        .as_ref()
+        .map(|slots| slots.clone().try_acquire_owned())
+        .transpose()
+}
+
+/// One limiter per key, shared by all of its concurrent downloads.
+static KEY_LIMITERS: Lazy<Mutex<HashMap<String, Arc<RateLimiter>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The limiters a download by `api_key` has to pass; empty when unthrottled.
+pub fn get_limiters(api_key: &ApiKey) -> Vec<Arc<RateLimiter>> {
+    let key_limiter = api_key.download_rate_limit.map(|bytes_per_sec| {
+        KEY_LIMITERS
+            .lock()
+            .unwrap()
+            .entry(api_key.name.clone())
+            .or_insert_with(|| Arc::new(RateLimiter::new(bytes_per_sec)))
+            .clone()
+    });
+
+    GLOBAL_LIMITER.iter().cloned().chain(key_limiter).collect()
+}
+
+pub enum TelegramOp {
+    Upload,
+    Download,
+    Copy,
+    EditCaption,
+    Delete,
+}
+
+impl TelegramOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TelegramOp::Upload => "upload",
+            TelegramOp::Download => "download",
+            TelegramOp::Copy => "copy",
+            TelegramOp::EditCaption => "edit_caption",
+            TelegramOp::Delete => "delete",
+        }
+    }
+}
+
+static TELEGRAM_LIMITER: Lazy<Option<RateLimiter>> =
+    Lazy::new(|| CONFIG.telegram_rate_limit.map(RateLimiter::new));
+
+static TELEGRAM_OP_LIMITERS: Lazy<HashMap<String, RateLimiter>> = Lazy::new(|| {
+    CONFIG
+        .telegram_op_rate_limits
+        .iter()
+        .map(|(op, per_sec)| (op.clone(), RateLimiter::new(*per_sec)))
+        .collect()
+});
+
+/// Waits for a turn at Telegram. Everything done there, by requests and
+/// background jobs alike, goes through here, so it all shares one budget
+/// instead of each caller assuming it has the whole of it.
+pub async fn telegram_turn(op: TelegramOp) {
+    let started = Instant::now();
+
+    // The operation's own cap comes first so that waiting on it doesn't hold
+    // up other operations in the shared one.
+    if let Some(limiter) = TELEGRAM_OP_LIMITERS.get(op.as_str()) {
+        limiter.acquire(1).await;
+    }
+
+    if let Some(limiter) = TELEGRAM_LIMITER.as_ref() {
+        limiter.acquire(1).await;
+    }
+
+    record_telegram_throttle(op.as_str(), started.elapsed());
+}
+
+/// Keyed by upstream; each is configured in requests per minute.
+static BACKGROUND_LIMITERS: Lazy<HashMap<String, RateLimiter>> = Lazy::new(|| {
+    CONFIG
+        .background_request_budgets
+        .iter()
+        .map(|(upstream, per_minute)| (upstream.clone(), RateLimiter::per_minute(*per_minute)))
+        .collect()
+});
+
+/// Waits for a turn at `upstream` when called from a background job, so
+/// that a backfill can't crowd out the upstream's other consumers. Requests
+/// made while serving the API are never held up here.
+pub async fn background_turn(upstream: &'static str) {
+    let Some(job) = current_job() else {
+        return;
+    };
+
+    let Some(limiter) = BACKGROUND_LIMITERS.get(upstream) else {
+        return;
+    };
+
+    let started = Instant::now();
+
+    limiter.acquire(1).await;
+
+    record_background_throttle(upstream, job, started.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(v: u64) -> NonZeroU64 {
+        NonZeroU64::new(v).unwrap()
+    }
+
+    /// As if the limiter had been left alone long enough to bank a burst.
+    fn idle(limiter: RateLimiter) -> RateLimiter {
+        *limiter.next_free.lock().unwrap() = Instant::now() - MAX_BURST;
+        limiter
+    }
+
+    async fn timed(f: impl std::future::Future<Output = ()>) -> Duration {
+        let started = Instant::now();
+        f.await;
+        started.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_new_limiter_has_no_burst_banked() {
+        let limiter = RateLimiter::new(rate(1000));
+
+        assert_eq!(
+            timed(limiter.acquire(250)).await,
+            Duration::from_millis(250)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_idle_limiter_lets_a_burst_through() {
+        let limiter = idle(RateLimiter::new(rate(1000)));
+
+        assert_eq!(timed(limiter.acquire(1000)).await, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn past_the_burst_chunks_wait_for_the_rate() {
+        let limiter = idle(RateLimiter::new(rate(1000)));
+        limiter.acquire(1000).await;
+
+        assert_eq!(
+            timed(limiter.acquire(250)).await,
+            Duration::from_millis(250)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_add_up_across_callers() {
+        let limiter = idle(RateLimiter::new(rate(1000)));
+        limiter.acquire(1000).await;
+
+        let waited = timed(async {
+            futures::join!(limiter.acquire(250), limiter.acquire(250));
+        })
+        .await;
+
+        assert_eq!(waited, Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn per_minute_rates_are_spread_over_the_minute() {
+        // Four a second, so a second's burst covers four and the next waits.
+        let limiter = idle(RateLimiter::per_minute(rate(240)));
+
+        assert_eq!(timed(limiter.acquire(4)).await, Duration::ZERO);
+        assert_eq!(timed(limiter.acquire(1)).await, Duration::from_millis(250));
+    }
+}
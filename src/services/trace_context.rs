@@ -0,0 +1,79 @@
+use std::future::Future;
+
+use axum::http::HeaderMap;
+use rand::Rng;
+
+pub const TRACEPARENT: &str = "traceparent";
+
+tokio::task_local! {
+    static TRACE_ID: String;
+    static JOB: &'static str;
+}
+
+pub fn new_trace_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 16]>())
+}
+
+/// Takes the trace id from an incoming W3C `traceparent` header, or starts a new trace.
+pub fn trace_id_from_headers(headers: &HeaderMap) -> String {
+    headers
+        .get(TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            let mut parts = v.split('-');
+            let (_version, trace_id) = (parts.next()?, parts.next()?);
+
+            let valid = trace_id.len() == 32
+                && trace_id.bytes().all(|b| b.is_ascii_hexdigit())
+                && trace_id.bytes().any(|b| b != b'0');
+
+            valid.then(|| trace_id.to_ascii_lowercase())
+        })
+        .unwrap_or_else(new_trace_id)
+}
+
+pub async fn scope<F: Future>(trace_id: String, f: F) -> F::Output {
+    TRACE_ID.scope(trace_id, f).await
+}
+
+/// Runs `f` as part of the background job `name`.
+pub async fn job_scope<F: Future>(name: &'static str, f: F) -> F::Output {
+    JOB.scope(name, f).await
+}
+
+/// The background job the current task works for; `None` while serving a request.
+pub fn current_job() -> Option<&'static str> {
+    JOB.try_with(|v| *v).ok()
+}
+
+/// Carries the current trace, and the job it belongs to, into a future that
+/// will be spawned on another task.
+pub fn propagate<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    let trace_id = TRACE_ID.try_with(|v| v.clone()).ok();
+    let job = current_job();
+
+    async move {
+        let f = async move {
+            match trace_id {
+                Some(trace_id) => scope(trace_id, f).await,
+                None => f.await,
+            }
+        };
+
+        match job {
+            Some(job) => job_scope(job, f).await,
+            None => f.await,
+        }
+    }
+}
+
+/// A `traceparent` value for an outgoing request: the current trace with a
+/// fresh span id for the call.
+pub fn traceparent() -> String {
+    let trace_id = TRACE_ID
+        .try_with(|v| v.clone())
+        .unwrap_or_else(|_| new_trace_id());
+    let span_id = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+
+    format!("00-{trace_id}-{span_id}-01")
+}
@@ -0,0 +1,81 @@
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+
+use axum_prometheus::metrics::Counter;
+use tracing::log;
+
+use crate::{
+    config::ApiKey, repository::ApiKeyUsageRepository, services::instrument::bytes_served_counter,
+    views::Database,
+};
+
+#[derive(Default)]
+pub struct UsageDelta {
+    pub requests: i64,
+    pub cache_misses: i64,
+    pub files_cached: i64,
+    pub bytes_served: i64,
+}
+
+pub async fn record_usage(db: &Database, api_key: &ApiKey, object_type: &str, delta: UsageDelta) {
+    let usage_repo = ApiKeyUsageRepository::new(db.clone());
+
+    if let Err(err) = usage_repo
+        .add(&api_key.name, &api_key.tenant, object_type, &delta)
+        .await
+    {
+        log::error!("{:?}", err);
+    }
+}
+
+/// Counts bytes streamed to a client and records them once the body is dropped,
+/// so aborted downloads are charged only for what was actually sent.
+pub struct BytesCounter {
+    db: Database,
+    api_key: Arc<ApiKey>,
+    object_type: String,
+    bytes: AtomicI64,
+    metric: Counter,
+}
+
+impl BytesCounter {
+    pub fn new(db: Database, api_key: Arc<ApiKey>, object_type: String) -> Self {
+        Self {
+            metric: bytes_served_counter(&object_type),
+            db,
+            api_key,
+            object_type,
+            bytes: AtomicI64::new(0),
+        }
+    }
+
+    pub fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as i64, Ordering::Relaxed);
+        self.metric.increment(bytes as u64);
+    }
+}
+
+impl Drop for BytesCounter {
+    fn drop(&mut self) {
+        let bytes_served = self.bytes.load(Ordering::Relaxed);
+
+        if bytes_served == 0 {
+            return;
+        }
+
+        let db = self.db.clone();
+        let api_key = self.api_key.clone();
+        let object_type = std::mem::take(&mut self.object_type);
+
+        tokio::spawn(async move {
+            let delta = UsageDelta {
+                bytes_served,
+                ..Default::default()
+            };
+
+            record_usage(&db, &api_key, &object_type, delta).await
+        });
+    }
+}
@@ -0,0 +1,51 @@
+//! A runtime of its own for background jobs, so update runs, the cache queue
+//! and schedulers don't take threads from handling requests.
+
+use std::{future::Future, time::Duration};
+
+use once_cell::sync::Lazy;
+use tokio::runtime::Runtime;
+use tracing::log;
+
+use crate::config::CONFIG;
+
+static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(CONFIG.job_worker_threads.max(1))
+        .thread_name("job-worker")
+        .enable_all()
+        .build()
+        .unwrap()
+});
+
+/// Runs `job` on the job runtime. A job listed in `JOB_TIMEOUTS` is cancelled
+/// once it runs over, and a panic only ends the job that raised it.
+pub fn spawn<F>(name: &'static str, job: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let timeout = CONFIG
+        .job_timeouts
+        .get(name)
+        .copied()
+        .map(Duration::from_secs);
+
+    let handle = RUNTIME.spawn(async move {
+        match timeout {
+            Some(timeout) => {
+                if tokio::time::timeout(timeout, job).await.is_err() {
+                    log::error!("Job {} cancelled after running for {:?}", name, timeout);
+                }
+            }
+            None => job.await,
+        }
+    });
+
+    RUNTIME.spawn(async move {
+        if let Err(err) = handle.await {
+            if err.is_panic() {
+                log::error!("Job {} panicked", name);
+            }
+        }
+    });
+}
@@ -1,77 +1,340 @@
 use axum::{
     body::Body,
-    extract::{Path, Query},
-    http::{self, header, Request, StatusCode},
+    extract::{rejection::PathRejection, FromRequestParts, OriginalUri, Path, Query, State},
+    http::{self, header, request::Parts, HeaderMap, HeaderValue, Request, StatusCode},
     middleware::{self, Next},
-    response::{AppendHeaders, IntoResponse, Response},
-    routing::{delete, get, post},
+    response::{AppendHeaders, Html, IntoResponse, Response},
+    routing::{any, delete, get, patch, post, put},
     Extension, Json, Router,
 };
-use axum_prometheus::PrometheusMetricLayer;
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum_prometheus::PrometheusMetricLayerBuilder;
 use base64::{engine::general_purpose, Engine};
+use chrono::{NaiveDate, Utc};
+use futures::StreamExt;
+use percent_encoding::percent_decode_str;
+use sentry::{Hub, SentryFutureExt};
 use sqlx::PgPool;
-use tokio_util::io::ReaderStream;
 use tower_http::trace::{self, TraceLayer};
-use tracing::Level;
+use tracing::{log, Level};
 
 use crate::{
-    config::CONFIG,
-    db::get_pg_pool,
-    serializers::CachedFile,
+    auth::{authenticator_from_config, Authenticator},
+    build_info::{get_build_info, register_build_info_metric, BuildInfo},
+    config::{ApiKey, CONFIG},
+    db::{get_database, run_migrations},
+    graphql::{build_schema, CacheSchema},
+    logging::{get_log_filter, set_log_filter},
+    metrics_exporter::{install_metrics_recorder, EXPORTER_PROMETHEUS},
+    mock_upstreams::{seed, start_mock_upstreams, SeedRequest, SeedResult},
+    negotiation::ResponseFormat,
+    negotiation::{accepts_encoding, MSGPACK},
+    object_type::ObjectType,
+    repository::{
+        ApiKeyUsageRepository, AuditLogRepository, CachedFileRepository, DownloadRepository,
+        FeatureFlagRepository,
+    },
+    serializers::{
+        error_envelope, shape_response, shapes_responses, AuditLogEntry, CacheStats, CachedFile,
+        CachedFileDetails, ChatStats, DownloadEntry, TimeseriesPoint, TopBook, UsageRow,
+        WithDownloadUrl,
+    },
     services::{
-        download_from_cache, download_utils::get_response_async_read, get_cached_file_copy,
-        get_cached_file_or_cache, start_update_cache, CacheData,
+        audit::{self, AuditAction, ACTOR_API, RESULT_FAILED, RESULT_OK},
+        bots::{BotStats, ROUND_ROBIN_BOT},
+        cache_file_on_demand,
+        cache_jobs::{get_cache_jobs, CacheJobProgress},
+        cache_stats::start_cache_stats_updater,
+        conversion::resolve_object_type,
+        delete_from_cache,
+        details::{self, get_cached_file_details},
+        download_from_cache_encoded,
+        download_utils::{throttled_body, windowed_body},
+        downloader::start_downloader_health_checks,
+        downloads::{start_downloads_retention, DownloadRecorder},
+        drain::start_resuming_pending,
+        edit_caption, find_cached_file,
+        flags::{get_flag_state, reload_flags, start_flags_refresher, Flag, FlagState},
+        get_cached_file_copy, get_cached_file_or_cache,
+        hooks::{register_hooks, CacheHooks},
+        instrument::record_cache_lookup,
+        is_stale,
+        leader::{run_exclusive, start_leader_election},
+        notifier::start_error_rate_monitor,
+        quarantine,
+        queue::start_cache_queue_consumer,
+        quota::{get_usage, Usage},
+        rehost::{
+            get_rehost_progress, start_rehost, try_start_rehost, RehostProgress, RehostRequest,
+        },
+        revalidate_file,
+        snapshot::{
+            create_snapshot, restore_snapshot, start_snapshot_scheduler, RestoreResult,
+            SnapshotLocation,
+        },
+        spawn_job, start_purge_deleted, start_purge_scheduler, start_update_cache,
+        storage::{set_storage, Storage},
+        throttle::{get_limiters, try_acquire_download_slot},
+        trace_context::{self, trace_id_from_headers},
+        undelete_from_cache,
+        usage::{record_usage, BytesCounter, UsageDelta},
+        CacheData, CaptionEditUnsupported,
     },
+    webdav::{self, DavPath, Resolved},
 };
 
-pub type Database = PgPool;
+pub use crate::db::Database;
 
 //
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct GetCachedFileQuery {
     pub copy: bool,
+    pub convert: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DownloadQuery {
+    pub convert: Option<String>,
+}
+
+/// How a request was served, reported to clients via `X-Cache`.
+#[derive(Clone, Copy)]
+pub enum CacheStatus {
+    Hit,
+    Miss {
+        populate: Duration,
+    },
+    /// Served from a copy past `REVALIDATE_AFTER_SECS`, which is being cached
+    /// again in the background.
+    Stale,
+    /// The cached copy turned out to be broken and was cached again.
+    Revalidated {
+        populate: Duration,
+    },
+}
+
+impl CacheStatus {
+    pub fn append_headers(&self, headers: &mut HeaderMap) {
+        let (value, populate) = match self {
+            CacheStatus::Hit => ("HIT", None),
+            CacheStatus::Stale => ("STALE", None),
+            CacheStatus::Miss { populate } => ("MISS", Some(populate)),
+            CacheStatus::Revalidated { populate } => ("REVALIDATED", Some(populate)),
+        };
+
+        headers.insert(
+            header::HeaderName::from_static("x-cache"),
+            HeaderValue::from_static(value),
+        );
+
+        if let Some(populate) = populate {
+            headers.insert(
+                header::HeaderName::from_static("x-cache-populate-ms"),
+                HeaderValue::from(populate.as_millis() as u64),
+            );
+        }
+    }
+}
+
+/// Looks up a file for an API request, caching it on a miss if the key's quota
+/// allows, and accounts the request in the key's usage.
+async fn get_cached_file_or_cache_within_quota(
+    object_id: i32,
+    object_type: ObjectType,
+    db: Database,
+    api_key: &ApiKey,
+    usage: &Usage,
+) -> Result<Option<(CachedFile, CacheStatus)>, StatusCode> {
+    let cached_file = find_cached_file(
+        api_key.tenant.clone(),
+        object_id,
+        object_type.clone(),
+        db.clone(),
+    )
+    .await;
+
+    record_cache_lookup(object_type.as_str(), cached_file.is_some());
+
+    if let Some(cached_file) = cached_file {
+        let delta = UsageDelta {
+            requests: 1,
+            ..Default::default()
+        };
+        record_usage(&db, api_key, object_type.as_str(), delta).await;
+
+        if is_stale(&cached_file) {
+            spawn_job(
+                "revalidate",
+                revalidate_file(api_key.tenant.clone(), object_id, object_type, db),
+            );
+
+            return Ok(Some((cached_file, CacheStatus::Stale)));
+        }
+
+        return Ok(Some((cached_file, CacheStatus::Hit)));
+    }
+
+    if !usage.can_cache(&api_key.quota) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let started = Instant::now();
+
+    let cached_file = cache_file_on_demand(
+        ACTOR_API,
+        api_key.tenant.clone(),
+        object_id,
+        object_type.clone(),
+        db.clone(),
+    )
+    .await;
+
+    let delta = UsageDelta {
+        requests: 1,
+        cache_misses: 1,
+        files_cached: cached_file.is_some().into(),
+        ..Default::default()
+    };
+    record_usage(&db, api_key, object_type.as_str(), delta).await;
+
+    let status = CacheStatus::Miss {
+        populate: started.elapsed(),
+    };
+
+    Ok(cached_file.map(|cached_file| (cached_file, status)))
 }
 
 async fn get_cached_file(
-    Path((object_id, object_type)): Path<(i32, String)>,
-    Query(GetCachedFileQuery { copy }): Query<GetCachedFileQuery>,
+    ObjectPath(object_id, object_type): ObjectPath,
+    Query(GetCachedFileQuery { copy, convert }): Query<GetCachedFileQuery>,
     Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Extension(usage): Extension<Usage>,
+    format: ResponseFormat,
+    base_url: BaseUrl,
 ) -> impl IntoResponse {
-    let cached_file = match get_cached_file_or_cache(object_id, object_type, db.clone()).await {
-        Some(cached_file) => cached_file,
-        None => return StatusCode::NO_CONTENT.into_response(),
+    let Some(object_type) = resolve_object_type(object_type, convert) else {
+        return StatusCode::BAD_REQUEST.into_response();
     };
 
+    let (cached_file, cache_status) = match get_cached_file_or_cache_within_quota(
+        object_id,
+        object_type,
+        db.clone(),
+        &api_key,
+        &usage,
+    )
+    .await
+    {
+        Ok(Some(v)) => v,
+        Ok(None) => return StatusCode::NO_CONTENT.into_response(),
+        Err(status) => return status.into_response(),
+    };
+
+    let mut headers = HeaderMap::new();
+    cache_status.append_headers(&mut headers);
+
+    let download_url =
+        base_url.download_url(cached_file.object_id, cached_file.object_type.as_str());
+
     if !copy {
-        return Json(cached_file).into_response();
+        let response = WithDownloadUrl {
+            inner: cached_file,
+            download_url,
+        };
+
+        return (headers, format.render(response)).into_response();
     }
 
     let copy_file: CacheData = get_cached_file_copy(cached_file, db).await;
 
-    Json(copy_file).into_response()
+    let response = WithDownloadUrl {
+        inner: copy_file,
+        download_url,
+    };
+
+    (headers, format.render(response)).into_response()
 }
 
 async fn download_cached_file(
-    Path((object_id, object_type)): Path<(i32, String)>,
+    ObjectPath(object_id, object_type): ObjectPath,
+    Query(DownloadQuery { convert }): Query<DownloadQuery>,
     Extension(Ext { db }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Extension(usage): Extension<Usage>,
+    request_headers: HeaderMap,
 ) -> impl IntoResponse {
-    let cached_file =
-        match get_cached_file_or_cache(object_id, object_type.clone(), db.clone()).await {
-            Some(cached_file) => cached_file,
-            None => return StatusCode::NO_CONTENT.into_response(),
-        };
+    let request_started = Instant::now();
+
+    let Some(object_type) = resolve_object_type(object_type, convert) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    if !usage.can_download(&api_key.quota) {
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+
+    let Ok(download_slot) = try_acquire_download_slot() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(
+                header::RETRY_AFTER,
+                CONFIG.download_retry_after_secs.to_string(),
+            )],
+        )
+            .into_response();
+    };
+
+    let (cached_file, mut cache_status) = match get_cached_file_or_cache_within_quota(
+        object_id,
+        object_type.clone(),
+        db.clone(),
+        &api_key,
+        &usage,
+    )
+    .await
+    {
+        Ok(Some(v)) => v,
+        Ok(None) => return StatusCode::NO_CONTENT.into_response(),
+        Err(status) => return status.into_response(),
+    };
 
-    let data = match download_from_cache(cached_file, db.clone()).await {
+    let accept_encoding = request_headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let accepts = |coding: &str| accepts_encoding(&accept_encoding, coding);
+
+    let data = match download_from_cache_encoded(cached_file, db.clone(), accepts).await {
         Some(v) => v,
         None => {
-            let cached_file =
-                match get_cached_file_or_cache(object_id, object_type, db.clone()).await {
-                    Some(v) => v,
-                    None => return StatusCode::NO_CONTENT.into_response(),
-                };
+            let started = Instant::now();
+
+            let cached_file = match get_cached_file_or_cache(
+                api_key.tenant.clone(),
+                object_id,
+                object_type.clone(),
+                db.clone(),
+            )
+            .await
+            {
+                Some(v) => v,
+                None => return StatusCode::NO_CONTENT.into_response(),
+            };
 
-            match download_from_cache(cached_file, db).await {
+            cache_status = CacheStatus::Revalidated {
+                populate: started.elapsed(),
+            };
+
+            match download_from_cache_encoded(cached_file, db.clone(), accepts).await {
                 Some(v) => v,
                 None => return StatusCode::NO_CONTENT.into_response(),
             }
@@ -84,14 +347,70 @@ async fn download_cached_file(
 
     let encoder = general_purpose::STANDARD;
 
-    let reader = get_response_async_read(data.response);
-    let stream = ReaderStream::new(reader);
+    let client = request_headers
+        .get("x-client")
+        .or_else(|| request_headers.get(header::USER_AGENT))
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
+    let download_recorder = DownloadRecorder::new(
+        db.clone(),
+        api_key.tenant.clone(),
+        api_key.name.clone(),
+        object_id,
+        object_type.to_string(),
+        client,
+        request_started,
+    );
+    let bytes_counter = BytesCounter::new(db, api_key.clone(), object_type.to_string());
+
+    let mut chunks = throttled_body(
+        windowed_body(data.body, CONFIG.download_window_bytes),
+        get_limiters(&api_key),
+    );
+    let stream = async_stream::stream! {
+        let _download_slot = download_slot;
+
+        while let Some(chunk) = chunks.next().await {
+            match &chunk {
+                Ok(chunk) => {
+                    bytes_counter.add(chunk.len());
+                    download_recorder.add(chunk.len());
+                }
+                Err(_) => {
+                    yield chunk;
+                    return;
+                }
+            }
+
+            yield chunk;
+        }
+
+        download_recorder.complete();
+    };
     let body = Body::from_stream(stream);
 
+    let mut cache_headers = HeaderMap::new();
+    cache_status.append_headers(&mut cache_headers);
+
+    // Compressed or not depends on Accept-Encoding wherever compression is on.
+    cache_headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if let Some(content_encoding) = data.content_encoding {
+        cache_headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(content_encoding),
+        );
+    }
+    append_digest_headers(
+        &mut cache_headers,
+        data.content_hash.as_deref(),
+        data.content_encoding,
+    );
+
     let headers = AppendHeaders([
         (
             header::CONTENT_DISPOSITION,
-            format!("attachment; filename={filename_ascii}"),
+            format!("attachment; filename=\"{filename_ascii}\""),
         ),
         (
             header::HeaderName::from_static("x-filename-b64"),
@@ -103,91 +422,1499 @@ async fn download_cached_file(
         ),
     ]);
 
-    (headers, body).into_response()
+    (cache_headers, headers, body).into_response()
+}
+
+const CONTENT_SHA256: &str = "x-content-sha256";
+const DIGEST: &str = "digest";
+
+/// `X-Content-Sha256` is the hash of the file once decoded. `Digest` covers
+/// the bytes as sent, so it is left out when they are still compressed.
+fn append_digest_headers(
+    headers: &mut HeaderMap,
+    content_hash: Option<&str>,
+    content_encoding: Option<&str>,
+) {
+    let Some(content_hash) = content_hash else {
+        return;
+    };
+
+    let Ok(value) = HeaderValue::from_str(content_hash) else {
+        return;
+    };
+    headers.insert(CONTENT_SHA256, value);
+
+    if content_encoding.is_some() {
+        return;
+    }
+
+    if let Ok(hash) = hex::decode(content_hash) {
+        let digest = format!("sha-256={}", general_purpose::STANDARD.encode(hash));
+        headers.insert(DIGEST, HeaderValue::from_str(&digest).unwrap());
+    }
 }
 
 async fn delete_cached_file(
-    Path((object_id, object_type)): Path<(i32, String)>,
+    ObjectPath(object_id, object_type): ObjectPath,
     Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
 ) -> impl IntoResponse {
-    let cached_file: Option<CachedFile> = sqlx::query_as!(
-        CachedFile,
-        r#"DELETE FROM cached_files
-            WHERE object_id = $1 AND object_type = $2
-            RETURNING *"#,
+    match delete_from_cache(
+        ACTOR_API,
+        api_key.tenant.clone(),
         object_id,
-        object_type
+        object_type,
+        db,
     )
-    .fetch_optional(&db)
     .await
-    .unwrap();
-
-    match cached_file {
+    {
         Some(v) => Json::<CachedFile>(v).into_response(),
         None => StatusCode::NO_CONTENT.into_response(),
     }
 }
 
-async fn update_cache(Extension(Ext { db, .. }): Extension<Ext>) -> impl IntoResponse {
-    tokio::spawn(start_update_cache(db));
+/// Restores a deleted file until it's purged; 409 when a file has been
+/// cached in its place meanwhile.
+async fn undelete_cached_file(
+    ObjectPath(object_id, object_type): ObjectPath,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+) -> impl IntoResponse {
+    match undelete_from_cache(
+        ACTOR_API,
+        api_key.tenant.clone(),
+        object_id,
+        object_type,
+        db,
+    )
+    .await
+    {
+        Ok(Some(v)) => Json::<CachedFile>(v).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::CONFLICT.into_response(),
+    }
+}
+
+/// Telegram's limit for media captions.
+const MAX_CAPTION_LENGTH: usize = 1024;
 
-    StatusCode::OK.into_response()
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct EditCaptionRequest {
+    pub caption: String,
+    pub title: Option<String>,
+    pub authors: Option<String>,
 }
 
-//
+async fn edit_cached_file_caption(
+    ObjectPath(object_id, object_type): ObjectPath,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    base_url: BaseUrl,
+    Json(EditCaptionRequest {
+        caption,
+        title,
+        authors,
+    }): Json<EditCaptionRequest>,
+) -> impl IntoResponse {
+    if caption.chars().count() > MAX_CAPTION_LENGTH {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
 
-async fn auth(req: Request<axum::body::Body>, next: Next) -> Result<Response, StatusCode> {
-    let auth_header = req
-        .headers()
-        .get(http::header::AUTHORIZATION)
-        .and_then(|header| header.to_str().ok());
+    let Some(cached_file) = find_cached_file(
+        api_key.tenant.clone(),
+        object_id,
+        object_type.clone(),
+        db.clone(),
+    )
+    .await
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
 
-    let auth_header = if let Some(auth_header) = auth_header {
-        auth_header
-    } else {
-        return Err(StatusCode::UNAUTHORIZED);
+    let result = match edit_caption(&cached_file, caption).await {
+        Ok(()) => CachedFileRepository::new(db.clone())
+            .update_metadata(cached_file.id, cached_file.object_id, title, authors)
+            .await
+            .map_err(|err| err.into()),
+        Err(err) => Err(err),
     };
 
-    if auth_header != CONFIG.api_key {
-        return Err(StatusCode::UNAUTHORIZED);
+    audit::record(
+        &db,
+        ACTOR_API,
+        &api_key.tenant,
+        AuditAction::EditCaption,
+        object_id,
+        object_type.as_str(),
+        Some(cached_file.id),
+        if result.is_ok() {
+            RESULT_OK
+        } else {
+            RESULT_FAILED
+        },
+    )
+    .await;
+
+    match result {
+        Ok(v) => Json(WithDownloadUrl {
+            download_url: base_url.download_url(v.object_id, v.object_type.as_str()),
+            inner: v,
+        })
+        .into_response(),
+        Err(err) if err.is::<CaptionEditUnsupported>() => {
+            StatusCode::NOT_IMPLEMENTED.into_response()
+        }
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::BAD_GATEWAY.into_response()
+        }
     }
+}
 
-    Ok(next.run(req).await)
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
 }
 
-#[derive(Clone)]
-struct Ext {
-    pub db: PgPool,
+async fn get_book_manifest(
+    Path(object_id): Path<i32>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    format: ResponseFormat,
+    base_url: BaseUrl,
+) -> impl IntoResponse {
+    let download_url = |object_id, object_type: &str| base_url.download_url(object_id, object_type);
+
+    match details::get_book_manifest(&api_key.tenant, object_id, db, download_url).await {
+        Ok(Some(v)) => format.render(v),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
-pub async fn get_router() -> Router {
-    let db = get_pg_pool().await;
+async fn search_cached_files(
+    Query(SearchQuery { q, limit }): Query<SearchQuery>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    format: ResponseFormat,
+    base_url: BaseUrl,
+) -> impl IntoResponse {
+    let cached_file_repo = CachedFileRepository::new(db.clone());
 
-    let ext = Ext { db };
+    let limit = limit.unwrap_or(20).clamp(1, 100);
 
-    let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
+    let cached_files = match cached_file_repo
+        .search(&api_key.tenant, q.trim(), limit)
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
 
-    let app_router = Router::new()
-        .route("/{object_id}/{object_type}/", get(get_cached_file))
-        .route(
-            "/download/{object_id}/{object_type}/",
-            get(download_cached_file),
+    match get_cached_file_details(cached_files, db).await {
+        Ok(v) => format.render::<Vec<WithDownloadUrl<CachedFileDetails>>>(
+            v.into_iter()
+                .map(|details| WithDownloadUrl {
+                    download_url: base_url.download_url(
+                        details.cached_file.object_id,
+                        details.cached_file.object_type.as_str(),
+                    ),
+                    inner: details,
+                })
+                .collect(),
+        ),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct GetQuarantineQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Files held back from serving in the key's tenant, oldest first.
+async fn get_quarantined(
+    Query(GetQuarantineQuery { limit, offset }): Query<GetQuarantineQuery>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    let limit = limit.unwrap_or(100).clamp(1, 1000);
+    let offset = offset.unwrap_or(0).max(0);
+
+    match CachedFileRepository::new(db)
+        .get_quarantined(&api_key.tenant, limit, offset)
+        .await
+    {
+        Ok(v) => format.render::<Vec<CachedFile>>(v),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Caches a quarantined file again; 204 if that fails, in which case the
+/// next request tries again.
+async fn repair_quarantined(
+    ObjectPath(object_id, object_type): ObjectPath,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+) -> impl IntoResponse {
+    match quarantine::repair(
+        ACTOR_API,
+        api_key.tenant.clone(),
+        object_id,
+        object_type,
+        db,
+    )
+    .await
+    {
+        Some(Some(v)) => Json::<CachedFile>(v).into_response(),
+        Some(None) => StatusCode::NO_CONTENT.into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn release_quarantined(
+    ObjectPath(object_id, object_type): ObjectPath,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+) -> impl IntoResponse {
+    match quarantine::release(
+        ACTOR_API,
+        api_key.tenant.clone(),
+        object_id,
+        object_type,
+        db,
+    )
+    .await
+    {
+        Some(v) => Json::<CachedFile>(v).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Deletes a quarantined file, to be purged like any other deleted file.
+async fn delete_quarantined(
+    ObjectPath(object_id, object_type): ObjectPath,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+) -> impl IntoResponse {
+    if quarantine::find_quarantined(&api_key.tenant, object_id, &object_type, &db)
+        .await
+        .is_none()
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match delete_from_cache(
+        ACTOR_API,
+        api_key.tenant.clone(),
+        object_id,
+        object_type,
+        db,
+    )
+    .await
+    {
+        Some(v) => Json::<CachedFile>(v).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct GetDownloadsQuery {
+    pub object_id: Option<i32>,
+    pub object_type: Option<String>,
+    pub api_key: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Recorded downloads, newest first; only admins see other tenants'.
+async fn get_downloads(
+    Query(GetDownloadsQuery {
+        object_id,
+        object_type,
+        api_key,
+        limit,
+        offset,
+    }): Query<GetDownloadsQuery>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(caller)): Extension<AuthenticatedKey>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    let download_repo = DownloadRepository::new(db);
+
+    let limit = limit.unwrap_or(100).clamp(1, 1000);
+    let offset = offset.unwrap_or(0).max(0);
+
+    match download_repo
+        .list(
+            caller.visible_tenant(),
+            object_id,
+            object_type,
+            api_key,
+            limit,
+            offset,
         )
-        .route("/{object_id}/{object_type}/", delete(delete_cached_file))
-        .route("/update_cache", post(update_cache))
-        .layer(middleware::from_fn(auth))
-        .layer(Extension(ext))
-        .layer(prometheus_layer);
+        .await
+    {
+        Ok(v) => format.render::<Vec<DownloadEntry>>(v),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct GetTopQuery {
+    pub period: Option<String>,
+    pub object_type: Option<String>,
+    pub limit: Option<i64>,
+}
 
-    let metric_router =
-        Router::new().route("/metrics", get(|| async move { metric_handle.render() }));
+/// Parses periods like `24h` or `7d`.
+pub(crate) fn parse_period(period: &str) -> Option<chrono::Duration> {
+    let parse = |value: &str| value.parse::<i64>().ok().filter(|v| *v > 0);
 
-    Router::new()
-        .nest("/api/v1/", app_router)
-        .merge(metric_router)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+    if let Some(hours) = period.strip_suffix('h') {
+        return chrono::Duration::try_hours(parse(hours)?);
+    }
+
+    chrono::Duration::try_days(parse(period.strip_suffix('d')?)?)
+}
+
+/// Most downloaded books in the key's tenant; empty unless `RECORD_DOWNLOADS` is on.
+async fn get_top_books(
+    Query(GetTopQuery {
+        period,
+        object_type,
+        limit,
+    }): Query<GetTopQuery>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    let Some(period) = parse_period(period.as_deref().unwrap_or("7d")) else {
+        return (StatusCode::BAD_REQUEST, "invalid period").into_response();
+    };
+
+    let download_repo = DownloadRepository::new(db);
+
+    let limit = limit.unwrap_or(50).clamp(1, 500);
+
+    match download_repo
+        .top(&api_key.tenant, Utc::now() - period, object_type, limit)
+        .await
+    {
+        Ok(v) => format.render::<Vec<TopBook>>(v),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeseriesMetric {
+    Downloads,
+    CacheMisses,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct GetTimeseriesQuery {
+    pub metric: TimeseriesMetric,
+    pub interval: Option<String>,
+    pub period: Option<String>,
+}
+
+/// Bucketed event counts in the key's tenant. Downloads need `RECORD_DOWNLOADS`;
+/// cache misses come from the audit log.
+async fn get_timeseries(
+    Query(GetTimeseriesQuery {
+        metric,
+        interval,
+        period,
+    }): Query<GetTimeseriesQuery>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    let (Some(interval), Some(period)) = (
+        parse_period(interval.as_deref().unwrap_or("1h")),
+        parse_period(period.as_deref().unwrap_or("7d")),
+    ) else {
+        return (StatusCode::BAD_REQUEST, "invalid interval or period").into_response();
+    };
+
+    if period.num_seconds() / interval.num_seconds() > 10_000 {
+        return (StatusCode::BAD_REQUEST, "too many buckets").into_response();
+    }
+
+    let since = Utc::now() - period;
+
+    let result = match metric {
+        TimeseriesMetric::Downloads => {
+            DownloadRepository::new(db)
+                .timeseries(&api_key.tenant, since, interval.num_seconds())
+                .await
+        }
+        TimeseriesMetric::CacheMisses => {
+            AuditLogRepository::new(db)
+                .timeseries(
+                    &api_key.tenant,
+                    ACTOR_API,
+                    AuditAction::Create.as_str(),
+                    since,
+                    interval.num_seconds(),
+                )
+                .await
+        }
+    };
+
+    match result {
+        Ok(v) => format.render::<Vec<TimeseriesPoint>>(v),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct GetAuditLogQuery {
+    pub object_id: Option<i32>,
+    pub object_type: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Audit entries, newest first; only admins see other tenants'.
+async fn get_audit_log(
+    Query(GetAuditLogQuery {
+        object_id,
+        object_type,
+        limit,
+        offset,
+    }): Query<GetAuditLogQuery>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    let audit_log_repo = AuditLogRepository::new(db);
+
+    let limit = limit.unwrap_or(100).clamp(1, 1000);
+    let offset = offset.unwrap_or(0).max(0);
+
+    match audit_log_repo
+        .list(
+            api_key.visible_tenant(),
+            object_id,
+            object_type,
+            limit,
+            offset,
         )
+        .await
+    {
+        Ok(v) => format.render::<Vec<AuditLogEntry>>(v),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct GetUsageQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+/// Daily usage per key; only admins see other tenants' keys.
+async fn get_usage_report(
+    Query(GetUsageQuery { from, to }): Query<GetUsageQuery>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    let usage_repo = ApiKeyUsageRepository::new(db);
+
+    let to = to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = from.unwrap_or(to - chrono::Duration::days(30));
+
+    match usage_repo.list(api_key.visible_tenant(), from, to).await {
+        Ok(v) => format.render::<Vec<UsageRow>>(v),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn create_snapshot_now(Extension(Ext { db, .. }): Extension<Ext>) -> impl IntoResponse {
+    let chat_id = match CONFIG.snapshot_chat_id {
+        Some(v) => v,
+        None => return StatusCode::NOT_IMPLEMENTED.into_response(),
+    };
+
+    match create_snapshot(db, chat_id).await {
+        Ok(v) => Json::<SnapshotLocation>(v).into_response(),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+const MAX_SEED_COUNT: u32 = 10_000;
+
+/// Only exists with `MOCK_UPSTREAMS`, whose fake Telegram holds the files.
+async fn seed_cache(
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Json(request): Json<SeedRequest>,
+) -> impl IntoResponse {
+    if !CONFIG.mock_upstreams {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    if request.count == 0 || request.count > MAX_SEED_COUNT || request.deleted > request.count {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    match seed(api_key.tenant.clone(), request, db).await {
+        Ok(v) => Json::<SeedResult>(v).into_response(),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn restore_snapshot_from(
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Json(location): Json<SnapshotLocation>,
+) -> impl IntoResponse {
+    match restore_snapshot(db, location).await {
+        Ok(v) => Json::<RestoreResult>(v).into_response(),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RemapChatRequest {
+    pub from_chat_id: i64,
+    pub to_chat_id: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RemapChatResult {
+    pub updated: u64,
+}
+
+async fn remap_chat(
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Json(RemapChatRequest {
+        from_chat_id,
+        to_chat_id,
+    }): Json<RemapChatRequest>,
+) -> impl IntoResponse {
+    let cached_file_repo = CachedFileRepository::new(db);
+
+    match cached_file_repo
+        .remap_chat(&api_key.tenant, from_chat_id, to_chat_id)
+        .await
+    {
+        Ok(updated) => Json(RemapChatResult { updated }).into_response(),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LogFilter {
+    pub filter: String,
+}
+
+async fn get_bots() -> Json<Vec<BotStats>> {
+    Json(ROUND_ROBIN_BOT.get_stats())
+}
+
+async fn get_log_level() -> impl IntoResponse {
+    match get_log_filter() {
+        Some(filter) => Json(LogFilter { filter }).into_response(),
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+async fn set_log_level(
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Json(LogFilter { filter }): Json<LogFilter>,
+) -> impl IntoResponse {
+    match set_log_filter(&filter) {
+        Ok(()) => {
+            log::warn!("Log filter set to {:?} by {}", filter, api_key.name);
+            Json(LogFilter { filter }).into_response()
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+async fn get_flags() -> impl IntoResponse {
+    let flags: Vec<FlagState> = Flag::ALL.iter().map(|flag| get_flag_state(*flag)).collect();
+
+    Json(flags)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SetFlagRequest {
+    pub enabled: bool,
+}
+
+async fn set_flag(
+    Path(name): Path<String>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Json(SetFlagRequest { enabled }): Json<SetFlagRequest>,
+) -> impl IntoResponse {
+    let Some(flag) = Flag::from_name(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let result = FeatureFlagRepository::new(db.clone())
+        .set(flag.name(), enabled)
+        .await;
+
+    flag_changed(flag, result, &db, &api_key.name).await
+}
+
+/// Drops the shared override so each instance falls back to its own config.
+async fn delete_flag(
+    Path(name): Path<String>,
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+) -> impl IntoResponse {
+    let Some(flag) = Flag::from_name(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let result = FeatureFlagRepository::new(db.clone())
+        .delete(flag.name())
+        .await;
+
+    flag_changed(flag, result, &db, &api_key.name).await
+}
+
+async fn flag_changed(
+    flag: Flag,
+    result: Result<(), sqlx::Error>,
+    db: &Database,
+    changed_by: &str,
+) -> Response {
+    if let Err(err) = result {
+        tracing::error!("{:?}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    if let Err(err) = reload_flags(db).await {
+        tracing::error!("{:?}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let state = get_flag_state(flag);
+    log::warn!(
+        "Flag {} is now {} ({}) after change by {}",
+        state.name,
+        state.enabled,
+        state.source,
+        changed_by
+    );
+
+    Json(state).into_response()
+}
+
+async fn rehost(
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Json(request): Json<RehostRequest>,
+) -> impl IntoResponse {
+    if !try_start_rehost(&api_key.tenant, &request) {
+        return StatusCode::CONFLICT.into_response();
+    }
+
+    spawn_job("rehost", start_rehost(api_key.tenant.clone(), request, db));
+
+    StatusCode::OK.into_response()
+}
+
+async fn rehost_progress() -> impl IntoResponse {
+    match get_rehost_progress() {
+        Some(v) => Json::<RehostProgress>(v).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+async fn get_cache_jobs_progress() -> Json<Vec<CacheJobProgress>> {
+    Json(get_cache_jobs())
+}
+
+async fn get_cache_stats(
+    Extension(Ext { db }): Extension<Ext>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    match CachedFileRepository::new(db).get_stats().await {
+        Ok(v) => format.render::<Vec<CacheStats>>(v),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn get_chat_stats(
+    Extension(Ext { db }): Extension<Ext>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    match CachedFileRepository::new(db).get_chat_stats().await {
+        Ok(v) => format.render::<Vec<ChatStats>>(v),
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn graphql(
+    Extension(Ext { db }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Extension(schema): Extension<CacheSchema>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    schema
+        .execute(request.into_inner().data(db).data(api_key))
+        .await
+        .into()
+}
+
+/// Read-only WebDAV, see [`crate::webdav`]. File bodies go through the
+/// regular download path, quota and rate limits included.
+async fn webdav(
+    Extension(Ext { db }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+    Extension(usage): Extension<Usage>,
+    req: Request<Body>,
+) -> Response {
+    if !CONFIG.webdav {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let method = req.method();
+
+    if method == http::Method::OPTIONS {
+        return (
+            [
+                (header::HeaderName::from_static("dav"), "1"),
+                (header::ALLOW, webdav::ALLOW),
+            ],
+            StatusCode::OK,
+        )
+            .into_response();
+    }
+
+    let propfind = method.as_str() == webdav::PROPFIND;
+    let head = method == http::Method::HEAD;
+
+    if !propfind && !head && method != http::Method::GET {
+        return (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, webdav::ALLOW)],
+        )
+            .into_response();
+    }
+
+    // Hrefs are absolute paths, so they need the prefix `nest` stripped.
+    let path = req.uri().path();
+    let prefix = original_uri(&req)
+        .path()
+        .strip_suffix(path)
+        .unwrap_or_default();
+    let base = format!("{prefix}/dav");
+
+    let Some(dav_path) = path
+        .strip_prefix("/dav")
+        .map(|v| percent_decode_str(v).decode_utf8_lossy().into_owned())
+        .and_then(|v| DavPath::parse(&v))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // `Depth: infinity` is answered like 1.
+    let depth_zero = req.headers().get("depth").is_some_and(|v| v == "0");
+
+    let resolved = match webdav::resolve(
+        db.clone(),
+        &api_key.tenant,
+        &base,
+        &dav_path,
+        !(propfind && depth_zero),
+    )
+    .await
+    {
+        Ok(Some(v)) => v,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            log::error!("{:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let entries = match resolved {
+        Resolved::Directory(entries) if !propfind => {
+            return Html(webdav::html_listing(&entries)).into_response();
+        }
+        Resolved::Directory(entries) => entries,
+        Resolved::File(entry, _) if propfind => vec![entry],
+        Resolved::File(_, cached_file) if head => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            if let Some(size) = cached_file.file_size {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+            }
+
+            return (StatusCode::OK, headers).into_response();
+        }
+        Resolved::File(_, cached_file) => {
+            let request_headers = req.headers().clone();
+
+            return download_cached_file(
+                ObjectPath(cached_file.object_id, cached_file.object_type),
+                Query(DownloadQuery { convert: None }),
+                Extension(Ext { db }),
+                Extension(AuthenticatedKey(api_key)),
+                Extension(usage),
+                request_headers,
+            )
+            .await
+            .into_response();
+        }
+    };
+
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        webdav::multistatus(&entries),
+    )
+        .into_response()
+}
+
+async fn get_info() -> Json<BuildInfo> {
+    Json(get_build_info())
+}
+
+async fn update_cache(
+    Extension(Ext { db, .. }): Extension<Ext>,
+    Extension(AuthenticatedKey(api_key)): Extension<AuthenticatedKey>,
+) -> impl IntoResponse {
+    spawn_job(
+        "update_cache",
+        run_exclusive(
+            db.clone(),
+            format!("update_cache:{}", api_key.tenant),
+            start_update_cache(api_key.tenant.clone(), db),
+        ),
+    );
+
+    StatusCode::OK.into_response()
+}
+
+async fn purge_deleted(Extension(Ext { db, .. }): Extension<Ext>) -> impl IntoResponse {
+    spawn_job(
+        "purge_deleted",
+        run_exclusive(
+            db.clone(),
+            "purge_deleted".to_string(),
+            start_purge_deleted(db),
+        ),
+    );
+
+    StatusCode::OK.into_response()
+}
+
+//
+
+#[derive(Clone)]
+pub struct AuthenticatedKey(pub Arc<ApiKey>);
+
+/// Where clients reach the API: `PUBLIC_BASE_URL` if set, otherwise the
+/// request's `X-Forwarded-Proto`/`X-Forwarded-Host`, falling back to `Host`,
+/// followed by the path the API is mounted at.
+pub struct BaseUrl(Option<String>);
+
+impl BaseUrl {
+    pub fn download_url(&self, object_id: i32, object_type: &str) -> Option<String> {
+        self.0
+            .as_ref()
+            .map(|base_url| format!("{base_url}/download/{object_id}/{object_type}/"))
+    }
+}
+
+/// `{object_id}/{object_type}` path parameters, with the object type
+/// resolved through `OBJECT_TYPE_ALIASES`.
+pub struct ObjectPath(i32, ObjectType);
+
+impl<S: Send + Sync> FromRequestParts<S> for ObjectPath {
+    type Rejection = PathRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path((object_id, object_type)) =
+            Path::<(i32, String)>::from_request_parts(parts, state).await?;
+
+        Ok(ObjectPath(
+            object_id,
+            CONFIG.canonical_object_type(object_type),
+        ))
+    }
+}
+
+fn first_forwarded<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for BaseUrl {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // `nest` strips the mount path, `/api/v1` under any `ServerBuilder::prefix`.
+        let mount = parts
+            .extensions
+            .get::<OriginalUri>()
+            .and_then(|OriginalUri(uri)| uri.path().strip_suffix(parts.uri.path()))
+            .unwrap_or_default();
+
+        if let Some(public_base_url) = &CONFIG.public_base_url {
+            return Ok(BaseUrl(Some(format!(
+                "{}{mount}",
+                public_base_url.trim_end_matches('/')
+            ))));
+        }
+
+        let proto = first_forwarded(&parts.headers, "x-forwarded-proto").unwrap_or("http");
+        let host = first_forwarded(&parts.headers, "x-forwarded-host")
+            .or_else(|| first_forwarded(&parts.headers, header::HOST.as_str()));
+
+        Ok(BaseUrl(host.map(|host| format!("{proto}://{host}{mount}"))))
+    }
+}
+
+async fn auth(
+    State(authenticator): State<Arc<dyn Authenticator>>,
+    mut req: Request<axum::body::Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let api_key = match authenticator.authenticate(req.headers()).await {
+        Some(v) => v,
+        // WebDAV clients only send credentials once challenged.
+        None if req.uri().path().starts_with("/dav") => {
+            return Ok((
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, webdav::REALM)],
+            )
+                .into_response());
+        }
+        None => return Err(StatusCode::UNAUTHORIZED),
+    };
+
+    req.extensions_mut().insert(AuthenticatedKey(api_key));
+
+    Ok(next.run(req).await)
+}
+
+/// Runs after `auth`, which it relies on for the caller's key.
+async fn require_admin(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
+    let is_admin = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .is_some_and(|AuthenticatedKey(api_key)| api_key.admin);
+
+    if !is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Continues the caller's W3C trace, if any, so upstream calls join it.
+async fn propagate_trace_context(req: Request<Body>, next: Next) -> Response {
+    let trace_id = trace_id_from_headers(req.headers());
+
+    trace_context::scope(trace_id, next.run(req)).await
+}
+
+/// The request URI before `nest` stripped the `/api/v1` prefix.
+fn original_uri(req: &Request<Body>) -> &http::Uri {
+    req.extensions()
+        .get::<OriginalUri>()
+        .map(|OriginalUri(uri)| uri)
+        .unwrap_or(req.uri())
+}
+
+/// Gives each request its own Sentry scope with request context, and reports
+/// server errors that didn't log anything themselves.
+async fn sentry_context(req: Request<Body>, next: Next) -> Response {
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+
+    let method = req.method().to_string();
+    let uri = original_uri(&req).to_string();
+    let api_key = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .map(|AuthenticatedKey(api_key)| api_key.name.clone());
+
+    hub.configure_scope(|scope| {
+        scope.set_tag("http.method", &method);
+        scope.set_tag("http.uri", &uri);
+        if let Some(api_key) = &api_key {
+            scope.set_user(Some(sentry::User {
+                username: Some(api_key.clone()),
+                ..Default::default()
+            }));
+        }
+    });
+
+    let response = next.run(req).bind_hub(hub.clone()).await;
+
+    if response.status().is_server_error() {
+        hub.capture_message(
+            &format!("{method} {uri} responded with {}", response.status()),
+            sentry::Level::Error,
+        );
+    }
+
+    response
+}
+
+/// Warns about requests slower than the configured threshold, with enough
+/// context to find the offending book.
+async fn log_slow_requests(req: Request<Body>, next: Next) -> Response {
+    let started = Instant::now();
+
+    let method = req.method().clone();
+    let uri = original_uri(&req).clone();
+    let api_key = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .map(|AuthenticatedKey(api_key)| api_key.name.clone());
+
+    let response = next.run(req).await;
+
+    let elapsed = started.elapsed();
+    if elapsed >= CONFIG.slow_request_threshold() {
+        log::warn!(
+            "Slow request {} {} by {} took {:?} (status {})",
+            method,
+            uri,
+            api_key.as_deref().unwrap_or("-"),
+            elapsed,
+            response.status()
+        );
+    }
+
+    response
+}
+
+/// Rewrites JSON and MessagePack bodies per `JSON_FIELD_CASE` and
+/// `JSON_ENVELOPE`. With the envelope on, failures answered with a bare status
+/// or plain text get an envelope too; other bodies pass through untouched, as
+/// does GraphQL, which has a response format of its own.
+async fn shape_responses(req: Request<Body>, next: Next) -> Response {
+    if !shapes_responses() || req.uri().path() == "/graphql" {
+        return next.run(req).await;
+    }
+
+    let response = next.run(req).await;
+
+    let (mut parts, body) = response.into_parts();
+
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    let format = if content_type.starts_with("application/json") {
+        Some(ResponseFormat::Json)
+    } else if content_type.starts_with(MSGPACK) {
+        Some(ResponseFormat::MessagePack)
+    } else {
+        None
+    };
+
+    let failed = parts.status.is_client_error() || parts.status.is_server_error();
+
+    if format.is_none() && !(failed && CONFIG.json_envelope) {
+        return Response::from_parts(parts, body);
+    }
+
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("{:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let body: Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> = match format {
+        Some(ResponseFormat::Json) => serde_json::from_slice(&bytes)
+            .map(shape_response)
+            .and_then(|v| serde_json::to_vec(&v))
+            .map_err(Into::into),
+        Some(ResponseFormat::MessagePack) => rmp_serde::from_slice(&bytes)
+            .map_err(Into::into)
+            .map(shape_response)
+            .and_then(|v| rmp_serde::to_vec_named(&v).map_err(Into::into)),
+        None => {
+            let message = String::from_utf8_lossy(&bytes);
+            let message = match message.trim() {
+                "" => parts.status.canonical_reason().unwrap_or_default(),
+                message => message,
+            };
+
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+
+            serde_json::to_vec(&error_envelope(parts.status, message)).map_err(Into::into)
+        }
+    };
+
+    match body {
+        Ok(body) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(body))
+        }
+        Err(err) => {
+            log::error!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn quota(
+    Extension(Ext { db }): Extension<Ext>,
+    mut req: Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    let api_key = match req.extensions().get::<AuthenticatedKey>() {
+        Some(AuthenticatedKey(api_key)) => api_key.clone(),
+        None => return next.run(req).await,
+    };
+
+    let usage = get_usage(&db, &api_key).await;
+
+    req.extensions_mut().insert(usage.clone());
+
+    let mut response = next.run(req).await;
+
+    usage.append_headers(response.headers_mut(), &api_key.quota);
+
+    response
+}
+
+#[derive(Clone)]
+struct Ext {
+    pub db: Database,
+}
+
+/// Builds the server's router for embedding; [`get_router`] is the same with
+/// everything taken from the environment. Settings without a method here are
+/// still read from [`CONFIG`].
+#[derive(Default)]
+pub struct ServerBuilder {
+    database: Option<Database>,
+    storage: Option<Arc<dyn Storage>>,
+    hooks: Vec<Arc<dyn CacheHooks>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    prefix: Option<String>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Used for reads and writes alike; see [`ServerBuilder::database`] to
+    /// pass a replica too.
+    pub fn pool(self, pool: PgPool) -> Self {
+        self.database(Database {
+            primary: pool.clone(),
+            replica: pool,
+        })
+    }
+
+    /// Replaces the pools built from the `POSTGRES_*` settings.
+    pub fn database(mut self, database: Database) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    /// Like hooks, storage is process-wide.
+    pub fn storage(mut self, storage: impl Storage) -> Self {
+        self.storage = Some(Arc::new(storage));
+        self
+    }
+
+    /// Hooks are process-wide, so they also see background jobs and anything
+    /// else calling into the service layer.
+    pub fn hooks(mut self, hooks: impl CacheHooks) -> Self {
+        self.hooks.push(Arc::new(hooks));
+        self
+    }
+
+    /// Replaces the one `AUTH_BACKEND` selects.
+    pub fn authenticator(mut self, authenticator: impl Authenticator) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Mounts every route, `/metrics` included, below `prefix`, e.g.
+    /// `/files` for `/files/api/v1/...`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub async fn build(self) -> Router {
+        for hooks in self.hooks {
+            register_hooks(hooks);
+        }
+
+        if let Some(storage) = self.storage {
+            set_storage(storage);
+        }
+
+        let db = match self.database {
+            Some(v) => v,
+            None => get_database().await,
+        };
+
+        let router = build_router(db, self.authenticator).await;
+
+        match self.prefix.as_deref().map(|v| v.trim_end_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => Router::new().nest(prefix, router),
+            _ => router,
+        }
+    }
+}
+
+pub async fn get_router() -> Router {
+    ServerBuilder::new().build().await
+}
+
+async fn build_router(db: Database, authenticator: Option<Arc<dyn Authenticator>>) -> Router {
+    let authenticator = authenticator.unwrap_or_else(|| authenticator_from_config(db.clone()));
+
+    if CONFIG.run_migrations {
+        run_migrations(db.writer()).await;
+    }
+
+    if CONFIG.mock_upstreams {
+        start_mock_upstreams().await;
+    }
+
+    spawn_job("leader_election", start_leader_election(db.clone()));
+    spawn_job("snapshot", start_snapshot_scheduler(db.clone()));
+    spawn_job("error_rate_monitor", start_error_rate_monitor());
+    spawn_job("flags_refresher", start_flags_refresher(db.clone()));
+    spawn_job("downloads_retention", start_downloads_retention(db.clone()));
+    spawn_job("purge_scheduler", start_purge_scheduler(db.clone()));
+    spawn_job("downloader_health_checks", start_downloader_health_checks());
+    spawn_job("cache_queue", start_cache_queue_consumer(db.clone()));
+    spawn_job("resume_pending", start_resuming_pending(db.clone()));
+
+    let ext = Ext { db };
+
+    let (prometheus_layer, metric_handle) = PrometheusMetricLayerBuilder::new()
+        .with_metrics_from_fn(install_metrics_recorder)
+        .build_pair();
+
+    register_build_info_metric();
+
+    spawn_job("cache_stats", start_cache_stats_updater(ext.db.clone()));
+
+    // Routes that reach across tenants or change the whole server.
+    let admin_router = Router::new()
+        .route("/purge_deleted", post(purge_deleted))
+        .route("/admin/remap_chat", post(remap_chat))
+        .route("/admin/bots", get(get_bots))
+        .route("/admin/cache_jobs", get(get_cache_jobs_progress))
+        .route("/admin/cache_stats", get(get_cache_stats))
+        .route("/admin/chat_stats", get(get_chat_stats))
+        .route("/admin/flags", get(get_flags))
+        .route("/admin/flags/{name}", put(set_flag).delete(delete_flag))
+        .route("/admin/log_level", get(get_log_level).put(set_log_level))
+        .route("/admin/rehost", post(rehost).get(rehost_progress))
+        .route("/admin/snapshot", post(create_snapshot_now))
+        .route("/admin/snapshot/restore", post(restore_snapshot_from))
+        .route("/dev/seed", post(seed_cache))
+        .route_layer(middleware::from_fn(require_admin));
+
+    let app_router = Router::new()
+        .route("/{object_id}/{object_type}/", get(get_cached_file))
+        .route(
+            "/download/{object_id}/{object_type}/",
+            get(download_cached_file),
+        )
+        .route("/{object_id}/{object_type}/", delete(delete_cached_file))
+        .route(
+            "/{object_id}/{object_type}/undelete",
+            post(undelete_cached_file),
+        )
+        .route(
+            "/{object_id}/{object_type}/caption",
+            patch(edit_cached_file_caption),
+        )
+        .route("/book/{object_id}", get(get_book_manifest))
+        .route("/info", get(get_info))
+        .route("/stats/top", get(get_top_books))
+        .route("/stats/timeseries", get(get_timeseries))
+        .route("/update_cache", post(update_cache))
+        .route("/admin/audit_log", get(get_audit_log))
+        .route("/admin/downloads", get(get_downloads))
+        .route("/admin/search", get(search_cached_files))
+        .route("/admin/quarantine", get(get_quarantined))
+        .route(
+            "/admin/quarantine/{object_id}/{object_type}",
+            delete(delete_quarantined),
+        )
+        .route(
+            "/admin/quarantine/{object_id}/{object_type}/repair",
+            post(repair_quarantined),
+        )
+        .route(
+            "/admin/quarantine/{object_id}/{object_type}/release",
+            post(release_quarantined),
+        )
+        .route("/admin/usage", get(get_usage_report))
+        .merge(admin_router)
+        .route("/graphql", post(graphql))
+        .route("/dav", any(webdav))
+        .route("/dav/", any(webdav))
+        .route("/dav/{*path}", any(webdav))
+        .layer(middleware::from_fn(quota))
+        .layer(middleware::from_fn(log_slow_requests))
+        .layer(middleware::from_fn(sentry_context))
+        .layer(middleware::from_fn_with_state(authenticator, auth))
+        .layer(middleware::from_fn(shape_responses))
+        .layer(middleware::from_fn(propagate_trace_context))
+        .layer(Extension(ext))
+        .layer(Extension(build_schema()))
+        .layer(prometheus_layer);
+
+    let mut metric_router = Router::new();
+
+    if CONFIG.metrics_exporter == EXPORTER_PROMETHEUS {
+        metric_router =
+            metric_router.route("/metrics", get(|| async move { metric_handle.render() }));
+    }
+
+    Router::new()
+        .nest("/api/v1/", app_router)
+        .merge(metric_router)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periods_are_hours_or_days() {
+        assert_eq!(parse_period("24h"), Some(chrono::Duration::hours(24)));
+        assert_eq!(parse_period("7d"), Some(chrono::Duration::days(7)));
+    }
+
+    #[test]
+    fn invalid_periods_are_rejected() {
+        for period in ["", "7", "d", "0d", "-1h", "1w", "1.5d", "h7"] {
+            assert_eq!(parse_period(period), None, "{period:?}");
+        }
+    }
+
+    #[test]
+    fn periods_too_long_for_a_duration_are_rejected() {
+        assert_eq!(parse_period(&format!("{}d", i64::MAX)), None);
+    }
+
+    #[test]
+    fn digest_headers_carry_the_stored_hash() {
+        let hash = "c07108f15212675fc52b6d8c921deb43db21708346b756aa6e3e80e56522114d";
+        let mut headers = HeaderMap::new();
+
+        append_digest_headers(&mut headers, Some(hash), None);
+
+        assert_eq!(headers[CONTENT_SHA256], hash);
+        assert_eq!(
+            headers[DIGEST],
+            format!(
+                "sha-256={}",
+                general_purpose::STANDARD.encode(hex::decode(hash).unwrap())
+            )
+            .as_str()
+        );
+    }
+
+    #[test]
+    fn compressed_bodies_get_no_digest() {
+        let mut headers = HeaderMap::new();
+
+        append_digest_headers(&mut headers, Some("00ff"), Some("gzip"));
+
+        assert_eq!(headers[CONTENT_SHA256], "00ff");
+        assert!(!headers.contains_key(DIGEST));
+    }
+
+    #[test]
+    fn a_missing_or_non_hex_hash_gets_no_digest() {
+        let mut headers = HeaderMap::new();
+
+        append_digest_headers(&mut headers, None, None);
+        // Not hex, so there are no bytes to encode for `Digest`.
+        append_digest_headers(&mut headers, Some("not-hex"), None);
+
+        assert!(!headers.contains_key(DIGEST));
+        assert_eq!(headers[CONTENT_SHA256], "not-hex");
+    }
 }
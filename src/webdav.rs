@@ -0,0 +1,274 @@
+//! A read-only WebDAV view of the cache as `/dav/{author}/{title}/{file}`, so
+//! readers like KOReader can mount it as a share. Only `OPTIONS`, `PROPFIND`
+//! and `GET`/`HEAD` are served; a `GET` of a directory returns a plain HTML
+//! listing for browsers.
+//!
+//! Directories are named after the `authors` and `title` recorded at caching
+//! time, and files `{title} ({object_id}).{object_type}`; covers are left out.
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::{repository::CachedFileRepository, serializers::CachedFile, views::Database};
+
+pub const PROPFIND: &str = "PROPFIND";
+pub const ALLOW: &str = "OPTIONS, GET, HEAD, PROPFIND";
+pub const REALM: &str = "Basic realm=\"telegram_files_cache\"";
+
+/// Everything but unreserved characters and sub-delimiters is escaped in a
+/// path segment.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// A directory name; the repository's WebDAV queries derive the same in SQL.
+pub fn dav_name(value: Option<&str>, fallback: &str) -> String {
+    match value {
+        Some(v) if !v.is_empty() => v.replace('/', "_"),
+        _ => fallback.to_string(),
+    }
+}
+
+fn author_name(cached_file: &CachedFile) -> String {
+    dav_name(cached_file.authors.as_deref(), "Unknown")
+}
+
+fn title_name(cached_file: &CachedFile) -> String {
+    dav_name(cached_file.title.as_deref(), "Untitled")
+}
+
+pub fn file_name(cached_file: &CachedFile) -> String {
+    format!(
+        "{} ({}).{}",
+        title_name(cached_file),
+        cached_file.object_id,
+        cached_file.object_type
+    )
+}
+
+/// A decoded path below `/dav`.
+pub enum DavPath {
+    Root,
+    Author(String),
+    Title(String, String),
+    File(String, String, String),
+}
+
+impl DavPath {
+    pub fn parse(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.split('/').filter(|v| !v.is_empty()).collect();
+
+        match segments[..] {
+            [] => Some(DavPath::Root),
+            [author] => Some(DavPath::Author(author.to_string())),
+            [author, title] => Some(DavPath::Title(author.to_string(), title.to_string())),
+            [author, title, file] => Some(DavPath::File(
+                author.to_string(),
+                title.to_string(),
+                file.to_string(),
+            )),
+            _ => None,
+        }
+    }
+
+    fn segments(&self) -> Vec<&str> {
+        match self {
+            DavPath::Root => vec![],
+            DavPath::Author(author) => vec![author],
+            DavPath::Title(author, title) => vec![author, title],
+            DavPath::File(author, title, file) => vec![author, title, file],
+        }
+    }
+}
+
+pub struct DavEntry {
+    pub href: String,
+    pub name: String,
+    /// Directories have none.
+    pub file: Option<DavFile>,
+}
+
+pub struct DavFile {
+    pub size: Option<i64>,
+    pub etag: Option<String>,
+}
+
+impl DavEntry {
+    fn directory(base: &str, segments: &[&str]) -> Self {
+        let mut href = base.to_string();
+        for segment in segments {
+            href.push('/');
+            href.extend(utf8_percent_encode(segment, SEGMENT));
+        }
+        href.push('/');
+
+        DavEntry {
+            href,
+            name: segments.last().unwrap_or(&"").to_string(),
+            file: None,
+        }
+    }
+
+    fn file(base: &str, cached_file: &CachedFile) -> Self {
+        let name = file_name(cached_file);
+        let author = author_name(cached_file);
+        let title = title_name(cached_file);
+
+        let mut entry = Self::directory(base, &[&author, &title]);
+        entry.href.extend(utf8_percent_encode(&name, SEGMENT));
+        entry.name = name;
+        entry.file = Some(DavFile {
+            size: cached_file.file_size,
+            etag: cached_file.content_hash.clone(),
+        });
+
+        entry
+    }
+}
+
+/// What a path names.
+pub enum Resolved {
+    Directory(Vec<DavEntry>),
+    File(DavEntry, Box<CachedFile>),
+}
+
+/// Looks up a path; `base` is the URL path `/dav` is mounted at. A directory
+/// resolves to itself followed, with `children`, by what it contains.
+pub async fn resolve(
+    db: Database,
+    tenant: &str,
+    base: &str,
+    path: &DavPath,
+    children: bool,
+) -> Result<Option<Resolved>, sqlx::Error> {
+    let repo = CachedFileRepository::new(db);
+
+    let mut entries = vec![DavEntry::directory(base, &path.segments())];
+
+    match path {
+        DavPath::Root => {
+            if children {
+                for author in repo.get_dav_authors(tenant).await? {
+                    entries.push(DavEntry::directory(base, &[&author]));
+                }
+            }
+        }
+        DavPath::Author(author) => {
+            let titles = repo.get_dav_titles(tenant, author).await?;
+            if titles.is_empty() {
+                return Ok(None);
+            }
+
+            if children {
+                for title in titles {
+                    entries.push(DavEntry::directory(base, &[author, &title]));
+                }
+            }
+        }
+        DavPath::Title(author, title) => {
+            let files = repo.get_dav_files(tenant, author, title).await?;
+            if files.is_empty() {
+                return Ok(None);
+            }
+
+            if children {
+                entries.extend(files.iter().map(|v| DavEntry::file(base, v)));
+            }
+        }
+        DavPath::File(author, title, name) => {
+            let files = repo.get_dav_files(tenant, author, title).await?;
+
+            return Ok(files
+                .into_iter()
+                .find(|v| file_name(v) == *name)
+                .map(|v| Resolved::File(DavEntry::file(base, &v), Box::new(v))));
+        }
+    }
+
+    Ok(Some(Resolved::Directory(entries)))
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn propstat(entry: &DavEntry) -> String {
+    let props = match &entry.file {
+        None => "<D:resourcetype><D:collection/></D:resourcetype>".to_string(),
+        Some(file) => {
+            let mut props = "<D:resourcetype/>\
+                <D:getcontenttype>application/octet-stream</D:getcontenttype>"
+                .to_string();
+
+            if let Some(size) = file.size {
+                props.push_str(&format!("<D:getcontentlength>{size}</D:getcontentlength>"));
+            }
+            if let Some(etag) = &file.etag {
+                props.push_str(&format!("<D:getetag>\"{}\"</D:getetag>", escape_xml(etag)));
+            }
+
+            props
+        }
+    };
+
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname>{props}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape_xml(&entry.href),
+        escape_xml(&entry.name),
+    )
+}
+
+/// A `207 Multi-Status` body with every property this view knows, whatever
+/// the request asked for.
+pub fn multistatus(entries: &[DavEntry]) -> String {
+    let responses: String = entries.iter().map(propstat).collect();
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <D:multistatus xmlns:D=\"DAV:\">{responses}</D:multistatus>"
+    )
+}
+
+/// The first entry is the directory itself.
+pub fn html_listing(entries: &[DavEntry]) -> String {
+    let (directory, children) = entries.split_first().unwrap();
+
+    let items: String = children
+        .iter()
+        .map(|entry| {
+            let suffix = if entry.file.is_none() { "/" } else { "" };
+
+            format!(
+                "<li><a href=\"{}\">{}{suffix}</a></li>",
+                escape_xml(&entry.href),
+                escape_xml(&entry.name)
+            )
+        })
+        .collect();
+
+    let title = escape_xml(&format!("/{}", directory.name));
+
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title></head>\
+         <body><h1>{title}</h1><ul><li><a href=\"../\">../</a></li>{items}</ul></body></html>"
+    )
+}